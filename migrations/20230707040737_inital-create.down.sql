@@ -1,4 +1,4 @@
 -- Add down migration script here
+drop table photo_categories;
 drop table photos;
 drop table categories;
-drop table photo_categories;
@@ -0,0 +1,25 @@
+-- Add up migration script here
+create table photo_exif
+(
+    photo_id      int not null
+        constraint photo_exif_pk
+            primary key
+        constraint photo_exif_photos_id_fk
+            references public.photos
+            on update cascade on delete cascade,
+    captured_at   timestamp,
+    camera_make   varchar(255),
+    camera_model  varchar(255),
+    lens_model    varchar(255),
+    exposure_time varchar(32),
+    f_number      double precision,
+    iso           int,
+    focal_length  double precision,
+    latitude      double precision,
+    longitude     double precision,
+    altitude      double precision,
+    caption       text,
+    raw           jsonb                                  not null default '{}'::jsonb,
+    created_at    timestamp with time zone default now() not null,
+    updated_at    timestamp with time zone default now() not null
+);
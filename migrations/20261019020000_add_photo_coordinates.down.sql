@@ -0,0 +1,6 @@
+-- Add down migration script here
+drop index photos_coordinates_index;
+alter table photos
+    drop constraint photos_coordinates_pair,
+    drop column latitude,
+    drop column longitude;
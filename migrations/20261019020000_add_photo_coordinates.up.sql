@@ -0,0 +1,17 @@
+-- Add up migration script here
+alter table photos
+    add latitude  double precision
+        constraint photos_latitude_range check (latitude between -90 and 90),
+    add longitude double precision
+        constraint photos_longitude_range check (longitude between -180 and 180),
+    add constraint photos_coordinates_pair check ((latitude is null) = (longitude is null));
+
+create index photos_coordinates_index on photos (latitude, longitude);
+
+update photos
+set latitude  = photo_exif.latitude,
+    longitude = photo_exif.longitude
+from photo_exif
+where photo_exif.photo_id = photos.id
+  and photo_exif.latitude is not null
+  and photo_exif.longitude is not null;
@@ -0,0 +1,5 @@
+-- Add down migration script here
+drop index photos_location_id_index;
+alter table photos
+    drop column location_id;
+drop table locations;
@@ -0,0 +1,25 @@
+-- Add up migration script here
+create table locations
+(
+    id          serial                                 not null
+        constraint locations_pk
+            primary key,
+    name        varchar(255)                           not null,
+    kind        varchar(32)                            not null,
+    parent_id   int
+        constraint locations_locations_id_fk
+            references public.locations
+            on update cascade on delete restrict,
+    created_at  timestamp with time zone default now() not null,
+    updated_at  timestamp with time zone default now() not null
+);
+
+create unique index locations_parent_id_name_key on locations (coalesce(parent_id, 0), lower(name));
+
+alter table photos
+    add location_id int
+        constraint photos_locations_id_fk
+            references public.locations
+            on update cascade on delete set null;
+
+create index photos_location_id_index on photos (location_id);
@@ -0,0 +1,20 @@
+-- Add up migration script here
+create table photo_variants
+(
+    id          serial
+        constraint photo_variants_pk
+            primary key,
+    photo_id    int                                    not null
+        constraint photo_variants_photos_id_fk
+            references public.photos
+            on update cascade on delete cascade,
+    variant     varchar(32)                            not null,
+    format      varchar(16)                            not null,
+    width       int                                    not null,
+    height      int                                    not null,
+    byte_size   int                                    not null,
+    storage_key varchar(255)                           not null,
+    created_at  timestamp with time zone default now() not null,
+    constraint photo_variants_photo_variant_format_uq
+        unique (photo_id, variant, format)
+);
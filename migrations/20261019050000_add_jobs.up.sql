@@ -0,0 +1,29 @@
+-- Add up migration script here
+create table jobs
+(
+    id           bigserial
+        constraint jobs_pk
+            primary key,
+    kind         varchar(64)                            not null,
+    payload      jsonb                                  not null,
+    status       varchar(16) default 'pending'          not null
+        constraint jobs_status_check
+            check (status in ('pending', 'running', 'completed', 'dead')),
+    attempts     int         default 0                  not null,
+    max_attempts int                                    not null,
+    run_at       timestamp with time zone default now() not null,
+    locked_until timestamp with time zone,
+    locked_by    varchar(255),
+    last_error   text,
+    dedupe_key   varchar(255),
+    created_at   timestamp with time zone default now() not null,
+    updated_at   timestamp with time zone default now() not null,
+    completed_at timestamp with time zone
+);
+
+-- workers only ever look at jobs that still have work to do
+create index jobs_ready_idx on jobs (run_at) where status in ('pending', 'running');
+
+-- at most one outstanding job per dedupe key; dead jobs count as outstanding
+-- so a failing job is retried from the admin endpoint rather than piling up
+create unique index jobs_dedupe_key_uq on jobs (dedupe_key) where status <> 'completed';
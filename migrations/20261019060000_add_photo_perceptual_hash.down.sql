@@ -0,0 +1,3 @@
+-- Add down migration script here
+alter table photos
+    drop column perceptual_hash;
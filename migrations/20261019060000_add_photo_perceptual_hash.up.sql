@@ -0,0 +1,4 @@
+-- Add up migration script here
+-- 64-bit dHash; existing photos are hashed by the background worker
+alter table photos
+    add perceptual_hash bigint;
@@ -0,0 +1,8 @@
+-- Add down migration script here
+alter table jobs
+    drop column result;
+
+drop index photos_content_sha256_uq;
+
+alter table photos
+    drop column content_sha256;
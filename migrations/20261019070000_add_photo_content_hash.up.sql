@@ -0,0 +1,11 @@
+-- Add up migration script here
+-- hex SHA-256 of the stored original; existing photos are hashed by the
+-- background worker
+alter table photos
+    add content_sha256 varchar(64);
+
+create unique index photos_content_sha256_uq on photos (content_sha256);
+
+-- jobs such as storage verification report their findings here
+alter table jobs
+    add result jsonb;
@@ -0,0 +1,7 @@
+-- Add down migration script here
+drop index jobs_dedupe_key_uq;
+
+create unique index jobs_dedupe_key_uq on jobs (dedupe_key) where status <> 'completed';
+
+alter table photos
+    drop column content_duplicate_of;
@@ -0,0 +1,15 @@
+-- Add up migration script here
+-- photos uploaded before hashes were kept can't take the hash of an earlier
+-- byte-identical photo, so the worker records which photo holds it instead;
+-- once that photo is deleted the duplicate is hashed again
+alter table photos
+    add content_duplicate_of integer
+        constraint photos_photos_id_fk
+            references public.photos
+            on delete set null;
+
+-- dead jobs no longer hold their dedupe key, so the same work can be
+-- queued again without retrying the dead job
+drop index jobs_dedupe_key_uq;
+
+create unique index jobs_dedupe_key_uq on jobs (dedupe_key) where status in ('pending', 'running');
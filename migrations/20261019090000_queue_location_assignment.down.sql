@@ -0,0 +1,4 @@
+-- Add down migration script here
+delete from jobs
+where dedupe_key = 'assign_locations'
+  and status = 'pending';
@@ -0,0 +1,6 @@
+-- Add up migration script here
+-- photos added before locations existed are placed into the hierarchy by the
+-- workers, see Job::AssignLocations; the country comes from the configuration
+insert into jobs (kind, payload, dedupe_key, max_attempts)
+values ('assign_locations', '{"kind": "assign_locations", "photo_id": null}', 'assign_locations', 5)
+on conflict (dedupe_key) where status in ('pending', 'running') do nothing;
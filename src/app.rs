@@ -4,69 +4,110 @@ use tower_http::trace::TraceLayer;
 
 use utoipa_swagger_ui::{SwaggerUi};
 
-use axum::error_handling::HandleErrorLayer;
+use axum::extract::DefaultBodyLimit;
 use axum::extract::MatchedPath;
+use axum::extract::State;
+use axum::http::HeaderName;
 use axum::http::Method;
-use axum::http::StatusCode;
+use axum::middleware;
+use axum::middleware::Next;
 use axum::response::Response;
 use axum::routing::get;
 use axum::Router;
-use hyper::body::Bytes;
-use hyper::{HeaderMap, Request};
+use hyper::Request;
 
+use std::convert::Infallible;
 use std::time::Duration;
-use tokio::time::error::Elapsed;
-use tower::BoxError;
+use tower::service_fn;
+use tower::Layer;
 use tower::ServiceBuilder;
+use tower::ServiceExt;
 use tower_http::classify::ServerErrorsFailureClass;
-use tower_http::cors::{Any, CorsLayer};
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
 use tracing::{info_span, Span};
 
 use tower_http::services::ServeFile;
 
+use anyhow::Context;
+
 use crate::auth::default_auth;
 use crate::auth::login_authorized;
+use crate::cache::conditional_get;
+use crate::configuration::CorsSettings;
 use crate::domain::AppState;
+use crate::error_handling::plain_errors_as_problems;
+use crate::rate_limit::rate_limit;
+use crate::reload::LiveSettings;
+use crate::request_id::{drop_invalid_request_id, scope_request_id, REQUEST_ID_HEADER};
+use crate::request_limits::request_limits;
+use crate::routes::catalog_router;
 use crate::routes::categories_router;
 use crate::routes::health_check;
+use crate::routes::health_router;
+use crate::routes::jobs_router;
+use crate::routes::locations_router;
+use crate::routes::logging_router;
+use crate::routes::maintenance_router;
 use crate::routes::photo_router;
+use crate::telemetry::{access_log, set_remote_parent, track_requests};
 
 pub fn app(swagger_ui: SwaggerUi, app_state: AppState) -> Router {
-    let cors = CorsLayer::new()
-        // allow `GET` and `POST` when accessing the resource
-        .allow_methods([Method::GET, Method::POST])
-        // allow requests from any origin
-        .allow_origin(Any);
-
+    // layers only apply to routes added before them, so health checks and
+    // docs are merged in after rate limiting
     Router::new()
-        .merge(swagger_ui)
-        .nest_service(
-            "/enchanted-natures.openapi.spec.yaml",
-            ServeFile::new("specs/enchanted-natures.openapi.spec.yaml"),
-        )
         .route("/authorize", get(default_auth))
         .route("/authorized", get(login_authorized))
-        .route("/health_check", get(health_check))
         .nest(
             "/api/v0",
             Router::new()
                 .merge(photo_router())
-                .merge(categories_router()),
+                .merge(categories_router())
+                .merge(catalog_router())
+                .merge(locations_router())
+                .merge(jobs_router())
+                .merge(logging_router())
+                .merge(maintenance_router()),
+        )
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            rate_limit,
+        ))
+        .merge(swagger_ui)
+        .nest_service(
+            "/enchanted-natures.openapi.spec.yaml",
+            ServeFile::new("specs/enchanted-natures.openapi.spec.yaml"),
         )
+        .route("/health_check", get(health_check))
+        .merge(health_router())
         .layer(
             ServiceBuilder::new()
-                .layer(cors)
-                .layer(HandleErrorLayer::new(|error: BoxError| async move {
-                    if error.is::<Elapsed>() {
-                        Ok(StatusCode::REQUEST_TIMEOUT)
-                    } else {
-                        Err((
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            format!("Unhandled internal error: {}", error),
-                        ))
-                    }
-                }))
-                .timeout(Duration::from_secs(10))
+                // outermost, so every other layer and the access log see it
+                .layer(middleware::from_fn(drop_invalid_request_id))
+                .layer(SetRequestIdLayer::new(
+                    HeaderName::from_static(REQUEST_ID_HEADER),
+                    MakeRequestUuid,
+                ))
+                .layer(PropagateRequestIdLayer::new(HeaderName::from_static(
+                    REQUEST_ID_HEADER,
+                )))
+                .layer(middleware::from_fn(scope_request_id))
+                .layer(middleware::from_fn(access_log))
+                .layer(middleware::from_fn(track_requests))
+                .layer(middleware::from_fn_with_state(app_state.clone(), cors))
+                // negotiated from Accept-Encoding; images and small bodies
+                // are left alone
+                .layer(CompressionLayer::new())
+                // inside compression, which would hide the text to convert
+                .layer(middleware::from_fn(plain_errors_as_problems))
+                .layer(middleware::from_fn(conditional_get))
+                // limits are applied per route by `request_limits`
+                .layer(DefaultBodyLimit::disable())
+                .layer(middleware::from_fn_with_state(
+                    app_state.clone(),
+                    request_limits,
+                ))
                 .layer(
                     TraceLayer::new_for_http()
                         .make_span_with(|request: &Request<_>| {
@@ -77,34 +118,35 @@ pub fn app(swagger_ui: SwaggerUi, app_state: AppState) -> Router {
                                 .get::<MatchedPath>()
                                 .map(MatchedPath::as_str);
 
-                            info_span!(
+                            let request_id = request
+                                .headers()
+                                .get(REQUEST_ID_HEADER)
+                                .and_then(|x| x.to_str().ok());
+
+                            let span = info_span!(
                                 "http_request",
                                 method = ?request.method(),
                                 matched_path,
-                                some_other_field = tracing::field::Empty,
-                            )
+                                request_id,
+                                status = tracing::field::Empty,
+                                otel.name = format!(
+                                    "{} {}",
+                                    request.method(),
+                                    matched_path.unwrap_or("unmatched")
+                                ),
+                                otel.kind = "server",
+                                otel.status_code = tracing::field::Empty,
+                            );
+                            set_remote_parent(&span, request.headers());
+                            span
                         })
-                        .on_request(|_request: &Request<_>, _span: &Span| {
-                            // You can use `_span.record("some_other_field", value)` in one of these
-                            // closures to attach a value to the initially empty field in the info_span
-                            // created above.
+                        .on_response(|response: &Response, _latency: Duration, span: &Span| {
+                            span.record("status", response.status().as_u16());
                         })
-                        .on_response(|_response: &Response, _latency: Duration, _span: &Span| {
-                            // ...
-                        })
-                        .on_body_chunk(|_chunk: &Bytes, _latency: Duration, _span: &Span| {
-                            // ...
-                        })
-                        .on_eos(
-                            |_trailers: Option<&HeaderMap>,
-                             _stream_duration: Duration,
-                             _span: &Span| {
-                                // ...
-                            },
-                        )
                         .on_failure(
-                            |_error: ServerErrorsFailureClass, _latency: Duration, _span: &Span| {
-                                // ...
+                            |error: ServerErrorsFailureClass, _latency: Duration, span: &Span| {
+                                span.record("otel.status_code", "ERROR");
+                                tracing::error!("request failed: {}", error);
                             },
                         ),
                 )
@@ -112,3 +154,61 @@ pub fn app(swagger_ui: SwaggerUi, app_state: AppState) -> Router {
         )
         .with_state(app_state)
 }
+
+/// Applies the CORS settings in effect when the request comes in, so they
+/// can be reloaded.
+async fn cors(
+    State(live_settings): State<LiveSettings>,
+    request: axum::extract::Request,
+    next: Next,
+) -> Response {
+    let next = service_fn(move |request| {
+        let next = next.clone();
+        async move { Ok::<_, Infallible>(next.run(request).await) }
+    });
+    let Ok(response) = live_settings.cors().layer(next).oneshot(request).await;
+    response
+}
+
+pub fn cors_layer(settings: &CorsSettings) -> anyhow::Result<CorsLayer> {
+    let origins = if settings.allowed_origins.iter().any(|x| x == "*") {
+        // tower-http only rejects this once the first request comes in
+        anyhow::ensure!(
+            !settings.allow_credentials,
+            "allowed_origins can't be `*` when allow_credentials is set"
+        );
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            settings
+                .allowed_origins
+                .iter()
+                .map(|x| x.parse().with_context(|| format!("invalid origin {:?}", x)))
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        )
+    };
+    let methods = settings
+        .allowed_methods
+        .iter()
+        .map(|x| {
+            Method::from_bytes(x.as_bytes()).with_context(|| format!("invalid method {:?}", x))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let header_names = |names: &[String]| {
+        names
+            .iter()
+            .map(|x| {
+                HeaderName::from_bytes(x.as_bytes())
+                    .with_context(|| format!("invalid header {:?}", x))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+    };
+
+    Ok(CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(methods)
+        .allow_headers(header_names(&settings.allowed_headers)?)
+        .expose_headers(header_names(&settings.exposed_headers)?)
+        .allow_credentials(settings.allow_credentials)
+        .max_age(Duration::from_secs(settings.max_age_secs)))
+}
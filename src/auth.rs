@@ -1,6 +1,9 @@
+use std::future::Future;
+
 use anyhow::{Context, Result};
 
 use async_session::Session;
+use axum_extra::headers::HeaderMapExt;
 use axum_extra::typed_header::TypedHeaderRejectionReason;
 use axum_extra::{headers, TypedHeader};
 use hyper::HeaderMap;
@@ -11,8 +14,10 @@ use oauth2::{IntrospectionUrl, RevocationUrl};
 
 use crate::configuration::AuthSettings;
 use crate::sessions::SessionManager;
+use crate::telemetry::{trace_headers, AccessLogUser, HttpClient};
 use axum::extract::{Query, State};
 use axum::http::header::SET_COOKIE;
+use axum::http::StatusCode;
 use axum::response::{IntoResponse, Redirect, Response};
 
 use axum::http::header;
@@ -25,6 +30,7 @@ use axum::{
 use serde::Deserialize;
 
 use oauth2::{reqwest::async_http_client, AuthorizationCode, TokenResponse};
+use oauth2::{HttpRequest, HttpResponse};
 use serde::Serialize;
 
 static COOKIE_NAME: &str = "SESSION";
@@ -79,11 +85,28 @@ where
             .ok_or(AuthRedirect)?;
 
         let user = session.get::<User>("user").ok_or(AuthRedirect)?;
+        AccessLogUser::record(&parts.extensions, &user.sub);
 
         Ok(user)
     }
 }
 
+/// Whether the request carries a session cookie at all, which is cheap to
+/// check before looking the session up.
+pub(crate) fn has_session_cookie(headers: &HeaderMap) -> bool {
+    headers
+        .typed_get::<headers::Cookie>()
+        .is_some_and(|x| x.get(COOKIE_NAME).is_some())
+}
+
+/// The signed-in user, if the request carries a live session. Unlike the
+/// `User` extractor this never redirects.
+pub(crate) async fn current_user(store: &SessionManager, headers: &HeaderMap) -> Option<User> {
+    let cookies = headers.typed_get::<headers::Cookie>()?;
+    let session = store.get_session(cookies.get(COOKIE_NAME)?).await.ok()??;
+    session.get::<User>("user")
+}
+
 #[tracing::instrument(name = "Protected area")]
 pub async fn protected(user: User) -> impl IntoResponse {
     format!(
@@ -92,12 +115,13 @@ pub async fn protected(user: User) -> impl IntoResponse {
     )
 }
 
-#[tracing::instrument(name = "Login authorized", skip(store, oauth_client))]
+#[tracing::instrument(name = "Login authorized", skip(store, oauth_client, http_client))]
 pub async fn login_authorized(
     Query(query): Query<AuthRequest>,
     State(store): State<SessionManager>,
     State(oauth_client): State<BasicClient>,
-) -> impl IntoResponse {
+    State(http_client): State<HttpClient>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
     let AuthRequest {
         code,
         state: _state,
@@ -105,22 +129,22 @@ pub async fn login_authorized(
 
     let token = oauth_client
         .exchange_code(AuthorizationCode::new(code))
-        .request_async(async_http_client)
+        .request_async(traced_http_client)
         .await
-        .unwrap();
+        .map_err(|e| exchange_failed("token", e))?;
 
     let access_token_secret = token.access_token().secret();
 
-    let client = reqwest::Client::new();
-    let user_data: User = client
+    let user_data: User = http_client
         .get(oauth_client.introspection_url().unwrap().url().as_str())
         .bearer_auth(access_token_secret)
         .send()
         .await
-        .unwrap()
+        .and_then(|x| x.error_for_status())
+        .map_err(|e| exchange_failed("userinfo", e))?
         .json()
         .await
-        .unwrap();
+        .map_err(|e| exchange_failed("userinfo", e))?;
 
     // Create a new session filled with user data
     let mut session = Session::new();
@@ -136,7 +160,31 @@ pub async fn login_authorized(
     let mut headers = HeaderMap::new();
     headers.insert(SET_COOKIE, cookie.parse().unwrap());
 
-    (headers, Redirect::to("/swagger-ui"))
+    Ok((headers, Redirect::to("/swagger-ui")))
+}
+
+/// `async_http_client` carrying the current trace context to the provider.
+fn traced_http_client(
+    mut request: HttpRequest,
+) -> impl Future<Output = Result<HttpResponse, impl std::error::Error + 'static>> {
+    for (name, value) in trace_headers() {
+        if let (Ok(name), Ok(value)) = (
+            oauth2::http::HeaderName::from_bytes(name.as_bytes()),
+            oauth2::http::HeaderValue::from_str(&value),
+        ) {
+            request.headers.insert(name, value);
+        }
+    }
+    async_http_client(request)
+}
+
+fn exchange_failed(stage: &'static str, error: impl std::fmt::Debug) -> (StatusCode, String) {
+    metrics::counter!("oauth_exchange_failures_total", "stage" => stage).increment(1);
+    tracing::error!("OAuth {} request failed: {:?}", stage, error);
+    (
+        StatusCode::BAD_GATEWAY,
+        "Failed to complete login with the identity provider".into(),
+    )
 }
 
 #[tracing::instrument(name = "Default Auth", skip(client))]
@@ -154,7 +202,9 @@ pub async fn default_auth(State(client): State<BasicClient>) -> impl IntoRespons
 pub fn create_oauth_client(auth_settings: AuthSettings) -> Result<BasicClient> {
     Ok(BasicClient::new(
         ClientId::new(auth_settings.client_id),
-        Some(ClientSecret::new(auth_settings.client_secret)),
+        Some(ClientSecret::new(
+            auth_settings.client_secret.expose().to_owned(),
+        )),
         AuthUrl::new(auth_settings.auth_url)
             .context("failed to create new authorization server URL")?,
         Some(
@@ -0,0 +1,380 @@
+use std::future::Future;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use axum::extract::Request;
+use axum::http::{header, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum_extra::headers::{
+    ETag, HeaderMapExt, IfModifiedSince, IfNoneMatch, LastModified as LastModifiedHeader,
+};
+use chrono::{DateTime, Utc};
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use redis::Script;
+use serde::Serialize;
+use tokio::sync::OnceCell;
+
+use crate::configuration::CacheSettings;
+use crate::storage::content_hash;
+
+const KEY_PREFIX: &str = "cache";
+// a slow Redis must not be slower than going to Postgres
+const REDIS_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// What a cached response was built from. Mutating handlers invalidate the
+/// tags they touch, dropping every response that depends on them.
+///
+/// Each tag has a version that is part of the key of every response built
+/// from it. Invalidating bumps the version, so entries written by requests
+/// that started before are never read again and expire after the TTL.
+/// It also records when the tag changed, which is sent as `Last-Modified`
+/// since deletes and category membership changes leave no `updated_at`
+/// behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheTag {
+    Photos,
+    Categories,
+    Locations,
+}
+
+impl CacheTag {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheTag::Photos => "photos",
+            CacheTag::Categories => "categories",
+            CacheTag::Locations => "locations",
+        }
+    }
+
+    fn version_key(&self) -> String {
+        format!("{}:version:{}", KEY_PREFIX, self.as_str())
+    }
+
+    fn changed_key(&self) -> String {
+        format!("{}:changed:{}", KEY_PREFIX, self.as_str())
+    }
+}
+
+// Bumps the version of each tag and records when it changed. KEYS holds
+// each tag's version key followed by its changed key. Uses the Redis clock
+// so replicas agree on it.
+static BUMP_VERSIONS: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+local now = redis.call('TIME')[1]
+for i = 1, #KEYS, 2 do
+    redis.call('INCR', KEYS[i])
+    redis.call('SET', KEYS[i + 1], now)
+end
+",
+    )
+});
+
+/// Where a response is cached and when the data behind it last changed.
+struct Versions {
+    key: String,
+    last_modified: Option<DateTime<Utc>>,
+}
+
+/// Read-through cache of serialized JSON responses, kept in Redis.
+///
+/// Redis being unavailable never fails a request: lookups fall through to
+/// the handler and the error is counted in `cache_requests_total`.
+#[derive(Clone)]
+pub struct ResponseCache {
+    redis: redis::Client,
+    connection: Arc<OnceCell<ConnectionManager>>,
+    settings: Arc<CacheSettings>,
+}
+
+impl std::fmt::Debug for ResponseCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResponseCache")
+            .field("settings", &self.settings)
+            .finish()
+    }
+}
+
+impl ResponseCache {
+    pub fn new(redis: redis::Client, settings: CacheSettings) -> Self {
+        Self {
+            redis,
+            connection: Arc::new(OnceCell::new()),
+            settings: Arc::new(settings),
+        }
+    }
+
+    /// The connection is made on first use so the API can start while
+    /// Redis is down; the manager reconnects by itself afterwards.
+    async fn connection(&self) -> Result<ConnectionManager> {
+        let connection = self
+            .connection
+            .get_or_try_init(|| {
+                let config = ConnectionManagerConfig::new()
+                    .set_connection_timeout(REDIS_TIMEOUT)
+                    .set_response_timeout(REDIS_TIMEOUT)
+                    .set_number_of_retries(1);
+                ConnectionManager::new_with_config(self.redis.clone(), config)
+            })
+            .await?;
+        Ok(connection.clone())
+    }
+
+    /// Returns the cached response for `key`, or runs `load`, caches its
+    /// result under `tags` and returns that. Errors are never cached.
+    pub async fn json<T, F>(
+        &self,
+        name: &'static str,
+        key: &str,
+        tags: &[CacheTag],
+        load: F,
+    ) -> Result<Response, (StatusCode, String)>
+    where
+        T: Serialize,
+        F: Future<Output = Result<T, (StatusCode, String)>>,
+    {
+        self.json_as("application/json", name, key, tags, load)
+            .await
+    }
+
+    /// [`ResponseCache::json`] for JSON served as another media type, such
+    /// as GeoJSON.
+    pub async fn json_as<T, F>(
+        &self,
+        content_type: &'static str,
+        name: &'static str,
+        key: &str,
+        tags: &[CacheTag],
+        load: F,
+    ) -> Result<Response, (StatusCode, String)>
+    where
+        T: Serialize,
+        F: Future<Output = Result<T, (StatusCode, String)>>,
+    {
+        let response = |body, versions: Option<&Versions>, cache_status| {
+            let last_modified = versions.and_then(|x| x.last_modified);
+            self.response(content_type, body, last_modified, cache_status)
+        };
+        if !self.settings.enabled {
+            return Ok(response(serialize(&load.await?)?, None, None));
+        }
+        // the versions are read before loading, so an invalidation while
+        // loading leaves the result under a key no one reads any more
+        let lookup = async {
+            let versions = self.versions(name, key, tags).await?;
+            let body = self.get(&versions.key).await?;
+            anyhow::Ok((versions, body))
+        };
+        let (status, versions) = match lookup.await {
+            Ok((versions, Some(body))) => {
+                metrics::counter!("cache_requests_total", "cache" => name, "result" => "hit")
+                    .increment(1);
+                return Ok(response(body, Some(&versions), Some("HIT")));
+            }
+            Ok((versions, None)) => {
+                metrics::counter!("cache_requests_total", "cache" => name, "result" => "miss")
+                    .increment(1);
+                ("MISS", Some(versions))
+            }
+            Err(e) => {
+                metrics::counter!("cache_requests_total", "cache" => name, "result" => "error")
+                    .increment(1);
+                tracing::warn!("Failed to read {}:{} from cache: {:?}", name, key, e);
+                ("BYPASS", None)
+            }
+        };
+
+        let body = serialize(&load.await?)?;
+        if let Some(versions) = &versions {
+            if let Err(e) = self.set(&versions.key, &body).await {
+                tracing::warn!("Failed to write {} to cache: {:?}", versions.key, e);
+            }
+        }
+        Ok(response(body, versions.as_ref(), Some(status)))
+    }
+
+    /// Serves what `load` returns the way [`ResponseCache::json_as`] would,
+    /// without caching it, for responses keyed by arbitrary client input
+    /// that would grow the cache without bound.
+    pub async fn uncached<T, F>(
+        &self,
+        content_type: &'static str,
+        load: F,
+    ) -> Result<Response, (StatusCode, String)>
+    where
+        T: Serialize,
+        F: Future<Output = Result<T, (StatusCode, String)>>,
+    {
+        let body = serialize(&load.await?)?;
+        let cache_status = self.settings.enabled.then_some("BYPASS");
+        Ok(self.response(content_type, body, None, cache_status))
+    }
+
+    /// Drops every cached response built from any of `tags`.
+    pub async fn invalidate(&self, tags: &[CacheTag]) {
+        if !self.settings.enabled {
+            return;
+        }
+        if let Err(e) = self.bump_versions(tags).await {
+            // entries still expire after the TTL
+            tracing::error!("Failed to invalidate cache tags {:?}: {:?}", tags, e);
+        } else {
+            for tag in tags {
+                metrics::counter!("cache_invalidations_total", "tag" => tag.as_str()).increment(1);
+            }
+        }
+    }
+
+    /// `key` under `name` and the current version of each of `tags`, and
+    /// when the last of them changed.
+    #[tracing::instrument(name = "redis.versions", target = "redis", skip_all)]
+    async fn versions(&self, name: &str, key: &str, tags: &[CacheTag]) -> Result<Versions> {
+        let mut versions = Vec::new();
+        let mut last_modified = None;
+        if !tags.is_empty() {
+            let mut con = self.connection().await?;
+            let keys: Vec<String> = tags
+                .iter()
+                .flat_map(|x| [x.version_key(), x.changed_key()])
+                .collect();
+            let values: Vec<Option<i64>> =
+                redis::cmd("MGET").arg(keys).query_async(&mut con).await?;
+            versions = values.iter().step_by(2).map(|x| x.unwrap_or(0)).collect();
+            // unknown if any tag hasn't changed since Redis lost its data
+            last_modified = values
+                .iter()
+                .skip(1)
+                .step_by(2)
+                .copied()
+                .collect::<Option<Vec<i64>>>()
+                .and_then(|x| x.into_iter().max())
+                .and_then(|x| DateTime::from_timestamp(x, 0));
+        }
+        let versions = versions
+            .iter()
+            .map(i64::to_string)
+            .collect::<Vec<_>>()
+            .join(".");
+        Ok(Versions {
+            key: format!("{}:{}:{}:{}", KEY_PREFIX, name, versions, key),
+            last_modified,
+        })
+    }
+
+    #[tracing::instrument(name = "redis.get", target = "redis", skip_all)]
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        let mut con = self.connection().await?;
+        Ok(redis::cmd("GET").arg(key).query_async(&mut con).await?)
+    }
+
+    #[tracing::instrument(name = "redis.set", target = "redis", skip_all)]
+    async fn set(&self, key: &str, body: &str) -> Result<()> {
+        let mut con = self.connection().await?;
+        redis::cmd("SET")
+            .arg(key)
+            .arg(body)
+            .arg("EX")
+            .arg(self.settings.ttl_secs)
+            .query_async::<()>(&mut con)
+            .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "redis.invalidate", target = "redis", skip_all)]
+    async fn bump_versions(&self, tags: &[CacheTag]) -> Result<()> {
+        let mut con = self.connection().await?;
+        let mut invocation = BUMP_VERSIONS.prepare_invoke();
+        for tag in tags {
+            invocation.key(tag.version_key()).key(tag.changed_key());
+        }
+        invocation.invoke_async::<()>(&mut con).await?;
+        Ok(())
+    }
+
+    fn response(
+        &self,
+        content_type: &'static str,
+        body: String,
+        last_modified: Option<DateTime<Utc>>,
+        cache_status: Option<&'static str>,
+    ) -> Response {
+        let etag = format!("W/\"{}\"", &content_hash(body.as_bytes())[..16]);
+        let mut response = (
+            [(header::CONTENT_TYPE, HeaderValue::from_static(content_type))],
+            body,
+        )
+            .into_response();
+        let headers = response.headers_mut();
+        if let Ok(value) = HeaderValue::from_str(&self.settings.cache_control()) {
+            headers.insert(header::CACHE_CONTROL, value);
+        }
+        if let Ok(value) = HeaderValue::from_str(&etag) {
+            headers.insert(header::ETAG, value);
+        }
+        if let Some(last_modified) = last_modified {
+            headers.typed_insert(LastModifiedHeader::from(SystemTime::from(last_modified)));
+        }
+        if let Some(status) = cache_status {
+            headers.insert("x-cache", HeaderValue::from_static(status));
+        }
+        response
+    }
+}
+
+/// Answers `304 Not Modified` when a GET's `If-None-Match` or
+/// `If-Modified-Since` shows the client's copy is still current, using the
+/// validators set by [`ResponseCache::json`].
+pub async fn conditional_get(request: Request, next: Next) -> Response {
+    if !matches!(*request.method(), Method::GET | Method::HEAD) {
+        return next.run(request).await;
+    }
+    let if_none_match = request.headers().typed_get::<IfNoneMatch>();
+    let if_modified_since = request.headers().typed_get::<IfModifiedSince>();
+    if if_none_match.is_none() && if_modified_since.is_none() {
+        return next.run(request).await;
+    }
+
+    let response = next.run(request).await;
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+    let headers = response.headers();
+    // If-Modified-Since is ignored when If-None-Match is present, RFC 9110
+    // section 13.1.3
+    let unchanged = match if_none_match {
+        Some(condition) => headers
+            .typed_get::<ETag>()
+            .is_some_and(|etag| !condition.precondition_passes(&etag)),
+        None => if_modified_since
+            .zip(headers.typed_get::<LastModifiedHeader>())
+            .is_some_and(|(condition, last_modified)| !condition.is_modified(last_modified.into())),
+    };
+    if !unchanged {
+        return response;
+    }
+
+    let mut not_modified = StatusCode::NOT_MODIFIED.into_response();
+    for name in [
+        header::CACHE_CONTROL,
+        header::ETAG,
+        header::LAST_MODIFIED,
+        header::VARY,
+    ] {
+        if let Some(value) = headers.get(&name) {
+            not_modified.headers_mut().insert(name, value.clone());
+        }
+    }
+    not_modified
+}
+
+fn serialize<T: Serialize>(value: &T) -> Result<String, (StatusCode, String)> {
+    serde_json::to_string(value).map_err(|e| {
+        tracing::error!("Failed to serialize response: {:?}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to serialize response: {}", e),
+        )
+    })
+}
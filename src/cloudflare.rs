@@ -0,0 +1,120 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use reqwest::multipart::{Form, Part};
+use reqwest::{Method, StatusCode};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::configuration::CloudflareSettings;
+use crate::telemetry::HttpClient;
+
+/// Client for the parts of the Cloudflare Images API the background jobs
+/// use.
+#[derive(Debug, Clone)]
+pub struct CloudflareImages {
+    http: HttpClient,
+    settings: Arc<CloudflareSettings>,
+}
+
+/// The envelope every Cloudflare API response comes in.
+#[derive(Debug, Deserialize)]
+struct Envelope<T> {
+    success: bool,
+    #[serde(default)]
+    errors: Vec<ApiError>,
+    result: Option<T>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadedImage {
+    id: Uuid,
+}
+
+impl CloudflareImages {
+    pub fn new(http: HttpClient, settings: CloudflareSettings) -> Self {
+        Self {
+            http,
+            settings: Arc::new(settings),
+        }
+    }
+
+    fn images_url(&self) -> String {
+        format!(
+            "{}/accounts/{}/images/v1",
+            self.settings.api_url.trim_end_matches('/'),
+            self.settings.account_id
+        )
+    }
+
+    /// Uploads an original, returning the id Cloudflare gave it.
+    #[tracing::instrument(name = "cloudflare.upload", skip(self, bytes))]
+    pub async fn upload(&self, filename: &str, bytes: Vec<u8>) -> Result<Uuid> {
+        let form = Form::new().part("file", Part::bytes(bytes).file_name(filename.to_owned()));
+        let response = self
+            .http
+            .post(self.images_url())
+            .bearer_auth(self.settings.api_token.expose())
+            .multipart(form)
+            .send()
+            .await?;
+        let status = response.status();
+        let envelope: Envelope<UploadedImage> = response.json().await?;
+        match envelope {
+            Envelope {
+                success: true,
+                result: Some(image),
+                ..
+            } => Ok(image.id),
+            Envelope { errors, .. } => bail!(
+                "Cloudflare refused to store {} ({}): {}",
+                filename,
+                status,
+                describe(&errors)
+            ),
+        }
+    }
+
+    /// Deletes an image. One that is already gone counts as deleted, so a
+    /// retried delete succeeds.
+    #[tracing::instrument(name = "cloudflare.delete", skip(self))]
+    pub async fn delete(&self, id: Uuid) -> Result<()> {
+        let response = self
+            .http
+            .request(Method::DELETE, format!("{}/{}", self.images_url(), id))
+            .bearer_auth(self.settings.api_token.expose())
+            .send()
+            .await?;
+        let status = response.status();
+        if status == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        let envelope: Envelope<serde_json::Value> = response.json().await?;
+        if !envelope.success {
+            bail!(
+                "Cloudflare refused to delete image {} ({}): {}",
+                id,
+                status,
+                describe(&envelope.errors)
+            );
+        }
+        Ok(())
+    }
+}
+
+fn describe(errors: &[ApiError]) -> String {
+    if errors.is_empty() {
+        return "no reason given".into();
+    }
+    errors
+        .iter()
+        .map(|x| format!("{} {}", x.code, x.message))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -1,11 +1,20 @@
-use anyhow::Result;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use sqlx::postgres::{PgConnectOptions, PgSslMode};
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions, PgSslMode};
+use tracing_subscriber::EnvFilter;
+
+use crate::images::{VariantFormat, MAX_DUPLICATE_DISTANCE};
+use crate::secrets::{self, Secret, SecretSource, VaultSource};
+use crate::storage::content_hash;
 
 #[derive(Debug, Deserialize)]
 pub struct AuthSettings {
     pub(crate) client_id: String,
-    pub(crate) client_secret: String,
+    pub(crate) client_secret: Secret,
     pub(crate) redirect_url: String,
     pub(crate) token_url: String,
     pub(crate) auth_url: String,
@@ -15,25 +24,140 @@ pub struct AuthSettings {
 
 #[derive(Debug, Deserialize)]
 pub struct DatabaseSettings {
+    /// `postgres://` URL used instead of host, port, database and user;
+    /// `DATABASE_URL` from the environment sets it
+    #[serde(default)]
+    pub url: Option<Secret>,
     pub host: String,
     pub port: u16,
     pub database: String,
     pub user: String,
+    /// overrides a password in `url`
+    #[serde(default)]
+    pub password: Option<Secret>,
     pub application_name: String,
+    /// also replaces an `sslmode` in `url`
+    #[serde(default)]
+    pub ssl_mode: SslMode,
+    /// CA the server certificate is checked against with `verify_ca` and
+    /// `verify_full`
+    #[serde(default = "DatabaseSettings::default_ssl_root_cert")]
+    pub ssl_root_cert: Option<PathBuf>,
+    /// client certificate and key, for servers requiring them
+    #[serde(default = "DatabaseSettings::default_ssl_client_cert")]
+    pub ssl_client_cert: Option<PathBuf>,
+    #[serde(default = "DatabaseSettings::default_ssl_client_key")]
+    pub ssl_client_key: Option<PathBuf>,
+    #[serde(default)]
+    pub pool: PoolSettings,
 }
 
-impl From<DatabaseSettings> for PgConnectOptions {
-    fn from(value: DatabaseSettings) -> Self {
-        PgConnectOptions::new()
-            .host(value.host.as_ref())
-            .port(value.port)
-            .database(value.database.as_ref())
-            .username(value.user.as_ref())
-            .application_name(value.application_name.as_ref())
-            .ssl_mode(PgSslMode::VerifyCa)
-            .ssl_root_cert("./certs/ca.crt")
-            .ssl_client_key("./certs/client.key")
-            .ssl_client_cert("./certs/client.crt")
+impl DatabaseSettings {
+    fn default_ssl_root_cert() -> Option<PathBuf> {
+        Some("./certs/ca.crt".into())
+    }
+
+    fn default_ssl_client_cert() -> Option<PathBuf> {
+        Some("./certs/client.crt".into())
+    }
+
+    fn default_ssl_client_key() -> Option<PathBuf> {
+        Some("./certs/client.key".into())
+    }
+}
+
+impl TryFrom<&DatabaseSettings> for PgConnectOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &DatabaseSettings) -> Result<Self> {
+        let mut options = match &value.url {
+            Some(url) => url
+                .expose()
+                .parse::<PgConnectOptions>()
+                .context("invalid database url")?,
+            None => PgConnectOptions::new()
+                .host(&value.host)
+                .port(value.port)
+                .database(&value.database)
+                .username(&value.user),
+        }
+        .application_name(&value.application_name);
+
+        if let Some(password) = &value.password {
+            options = options.password(password.expose());
+        }
+        options = options.ssl_mode(value.ssl_mode.into());
+        if let Some(path) = &value.ssl_root_cert {
+            options = options.ssl_root_cert(path);
+        }
+        if let Some(path) = &value.ssl_client_cert {
+            options = options.ssl_client_cert(path);
+        }
+        if let Some(path) = &value.ssl_client_key {
+            options = options.ssl_client_key(path);
+        }
+        Ok(options)
+    }
+}
+
+/// libpq's `sslmode`. Defaults to `verify_ca`, so the server has to
+/// present a certificate signed by `ssl_root_cert`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SslMode {
+    Disable,
+    Allow,
+    Prefer,
+    Require,
+    #[default]
+    VerifyCa,
+    VerifyFull,
+}
+
+impl From<SslMode> for PgSslMode {
+    fn from(value: SslMode) -> Self {
+        match value {
+            SslMode::Disable => PgSslMode::Disable,
+            SslMode::Allow => PgSslMode::Allow,
+            SslMode::Prefer => PgSslMode::Prefer,
+            SslMode::Require => PgSslMode::Require,
+            SslMode::VerifyCa => PgSslMode::VerifyCa,
+            SslMode::VerifyFull => PgSslMode::VerifyFull,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PoolSettings {
+    pub max_connections: u32,
+    /// connections kept open even when idle
+    pub min_connections: u32,
+    /// how long a query waits for a free connection before failing
+    pub acquire_timeout_secs: u64,
+    /// idle connections above `min_connections` are closed after this;
+    /// never when unset
+    pub idle_timeout_secs: Option<u64>,
+}
+
+impl Default for PoolSettings {
+    fn default() -> Self {
+        Self {
+            max_connections: 5,
+            min_connections: 0,
+            acquire_timeout_secs: 30,
+            idle_timeout_secs: Some(600),
+        }
+    }
+}
+
+impl From<&PoolSettings> for PgPoolOptions {
+    fn from(value: &PoolSettings) -> Self {
+        PgPoolOptions::new()
+            .max_connections(value.max_connections)
+            .min_connections(value.min_connections)
+            .acquire_timeout(Duration::from_secs(value.acquire_timeout_secs))
+            .idle_timeout(value.idle_timeout_secs.map(Duration::from_secs))
     }
 }
 
@@ -45,46 +169,1235 @@ pub struct Settings {
     // pub aws_bucket_name: String,
     pub auth_settings: AuthSettings,
     pub app_settings: ApplicationSettings,
-    pub redis_url: String,
+    pub redis_url: Secret,
+    #[serde(default)]
+    pub storage_settings: StorageSettings,
+    #[serde(default)]
+    pub image_settings: ImageSettings,
+    #[serde(default)]
+    pub job_settings: JobSettings,
+    #[serde(default)]
+    pub location_settings: LocationSettings,
+    /// copying photos to Cloudflare Images is off unless this is set
+    #[serde(default)]
+    pub cloudflare_settings: Option<CloudflareSettings>,
+    #[serde(default)]
+    pub cache_settings: CacheSettings,
+    #[serde(default)]
+    pub tracing_settings: TracingSettings,
+    #[serde(default)]
+    pub health_settings: HealthSettings,
+    #[serde(default)]
+    pub shutdown_settings: ShutdownSettings,
+    #[serde(default)]
+    pub request_limit_settings: RequestLimitSettings,
+    #[serde(default)]
+    pub reload_settings: ReloadSettings,
+    /// deserialized separately by `load_config`, from the same sources
+    #[serde(skip)]
+    pub dynamic: DynamicSettings,
+    /// the rest as configured, to tell when a reload changes them
+    #[serde(skip)]
+    pub static_settings: StaticSettings,
+}
+
+/// Settings that are applied without a restart when the configuration is
+/// reloaded, see `ReloadSettings`. The rest only take effect on restart.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DynamicSettings {
+    /// `EnvFilter` directives, e.g. `info` or `info,api::jobs=debug`
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+    #[serde(default)]
+    pub rate_limit_settings: RateLimitSettings,
+    /// defaults depend on `ENVIRONMENT`, see `CorsSettings::for_environment`
+    pub cors: CorsSettings,
+}
+
+fn default_log_level() -> String {
+    "info".into()
+}
+
+impl Default for DynamicSettings {
+    fn default() -> Self {
+        Self {
+            log_level: default_log_level(),
+            rate_limit_settings: RateLimitSettings::default(),
+            cors: CorsSettings::default(),
+        }
+    }
+}
+
+/// Settings that differ between environments unless configured.
+#[derive(Serialize)]
+struct EnvironmentDefaults {
+    cors: CorsSettings,
+}
+
+/// Where settings referring to external secrets are looked up.
+#[derive(Debug, Default, Deserialize)]
+pub struct SecretSettings {
+    pub vault: Option<VaultSettings>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct VaultSettings {
+    /// e.g. `https://vault.internal:8200`
+    pub address: String,
+    /// usually given as `token_file`
+    pub token: Secret,
+    #[serde(default = "default_vault_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_vault_timeout_ms() -> u64 {
+    5000
+}
+
+/// What's needed to resolve the rest of the settings.
+#[derive(Deserialize)]
+struct Bootstrap {
+    #[serde(default)]
+    secrets: SecretSettings,
+}
+
+/// Where `base.yaml` and the file for each environment are.
+pub fn configuration_directory() -> Result<PathBuf> {
+    Ok(std::env::current_dir()?.join("config"))
+}
+
+/// Loads `base.yaml`, the file for `ENVIRONMENT` and `APP_` variables, then
+/// resolves `*_file` settings.
+fn load_files() -> Result<config::Config> {
+    let configuration_directory = configuration_directory()?;
+
+    let environment: Environment = match std::env::var("ENVIRONMENT") {
+        Ok(value) => value.try_into().map_err(anyhow::Error::msg)?,
+        Err(std::env::VarError::NotPresent) => Environment::Local,
+        Err(e) => return Err(e).context("invalid ENVIRONMENT"),
+    };
+
+    let environment_filename = format!("{}.yaml", &environment.as_str());
+    let defaults = EnvironmentDefaults {
+        cors: CorsSettings::for_environment(&environment),
+    };
+    let settings = config::Config::builder()
+        .add_source(config::Config::try_from(&defaults)?)
+        .add_source(config::File::from(
+            configuration_directory.join("base.yaml"),
+        ))
+        .add_source(config::File::from(
+            configuration_directory.join(environment_filename),
+        ))
+        // Add in settings from environment variables (with a prefix of APP and '__' as separator)
+        // E.g. `APP_APPLICATION__PORT=5001 would set `Settings.application.port`
+        .add_source(
+            config::Environment::with_prefix("APP")
+                .prefix_separator("_")
+                .separator("__"),
+        )
+        .set_override_option("database_settings.url", std::env::var("DATABASE_URL").ok())?
+        .build()?;
+
+    secrets::resolve_files(settings)
+}
+
+/// Resolves references to secrets in `settings`, e.g.
+/// `client_secret: vault://secret/data/enchanted-natures#client_secret`.
+async fn resolve_secrets(settings: config::Config) -> Result<config::Config> {
+    let Bootstrap { secrets } = settings.clone().try_deserialize()?;
+    let mut sources: Vec<Box<dyn SecretSource>> = Vec::new();
+    if let Some(vault) = secrets.vault {
+        sources.push(Box::new(VaultSource::new(vault)?));
+    }
+    secrets::resolve_references(settings, &sources).await
 }
 
 impl Settings {
-    pub fn load_config() -> Result<Self> {
-        let base_path = std::env::current_dir()?;
-        let configuration_directory = base_path.join("config");
-
-        let environment: Environment = std::env::var("ENVIRONMENT")
-            .unwrap_or_else(|_| "local".into())
-            .try_into()
-            .unwrap_or(Environment::Local);
-
-        let environment_filename = format!("{}.yaml", &environment.as_str());
-        let settings = config::Config::builder()
-            .add_source(config::File::from(
-                configuration_directory.join("base.yaml"),
-            ))
-            .add_source(config::File::from(
-                configuration_directory.join(environment_filename),
-            ))
-            // Add in settings from environment variables (with a prefix of APP and '__' as separator)
-            // E.g. `APP_APPLICATION__PORT=5001 would set `Settings.application.port`
-            .add_source(
-                config::Environment::with_prefix("APP")
-                    .prefix_separator("_")
-                    .separator("__"),
-            )
-            .build()?;
-
-        Ok(settings.try_deserialize::<Self>()?)
+    /// Loads the settings as described in `load_files` and
+    /// `resolve_secrets` and validates them.
+    pub async fn load_config() -> Result<Self> {
+        let files = load_files()?;
+        let static_settings = StaticSettings::new(&files)?;
+        let sources = resolve_secrets(files).await?;
+        let mut settings = Self::from_config(&sources)?;
+        settings.static_settings = static_settings;
+        Ok(settings)
+    }
+
+    /// Deserializes and validates `config`. If a section can't be
+    /// deserialized, the others are still checked, so every problem is
+    /// reported rather than the first.
+    pub fn from_config(config: &config::Config) -> Result<Self, InvalidSettings> {
+        let settings = config.clone().try_deserialize::<Self>();
+        let dynamic = config.clone().try_deserialize::<DynamicSettings>();
+        match (settings, dynamic) {
+            (Ok(mut settings), Ok(dynamic)) => {
+                settings.dynamic = dynamic;
+                settings.validate()?;
+                Ok(settings)
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                let mut problems = Problems::default();
+                Self::check_sections(config, &mut problems);
+                Err(problems.or_else(e))
+            }
+        }
+    }
+
+    /// Checks what can be checked without connecting anywhere, reporting
+    /// every problem rather than the first.
+    pub fn validate(&self) -> Result<(), InvalidSettings> {
+        let mut problems = Problems::default();
+        self.auth_settings.check(&mut problems);
+        check_redis_url(&self.redis_url, &mut problems);
+        self.database_settings.check(&mut problems);
+        self.app_settings.check(&mut problems);
+        self.tracing_settings.check(&mut problems);
+        self.health_settings.check(&mut problems);
+        self.image_settings.check(&mut problems);
+        self.job_settings.check(&mut problems);
+        self.location_settings.check(&mut problems);
+        if let Some(cloudflare) = &self.cloudflare_settings {
+            cloudflare.check(&mut problems);
+        }
+        self.request_limit_settings.check(&mut problems);
+        self.reload_settings.check(&mut problems);
+        self.dynamic.check(&mut problems);
+        problems.into_result()
+    }
+
+    /// `validate` for each section on its own, noting the ones that can't
+    /// be deserialized.
+    fn check_sections(config: &config::Config, problems: &mut Problems) {
+        if let Some(auth) = section::<AuthSettings>(config, "auth_settings", true, problems) {
+            auth.check(problems);
+        }
+        if let Some(redis_url) = section::<Secret>(config, "redis_url", true, problems) {
+            check_redis_url(&redis_url, problems);
+        }
+        if let Some(database) =
+            section::<DatabaseSettings>(config, "database_settings", true, problems)
+        {
+            database.check(problems);
+        }
+        if let Some(app) = section::<ApplicationSettings>(config, "app_settings", true, problems) {
+            app.check(problems);
+        }
+        section::<StorageSettings>(config, "storage_settings", false, problems);
+        if let Some(tracing) =
+            section::<TracingSettings>(config, "tracing_settings", false, problems)
+        {
+            tracing.check(problems);
+        }
+        if let Some(health) = section::<HealthSettings>(config, "health_settings", false, problems)
+        {
+            health.check(problems);
+        }
+        if let Some(image) = section::<ImageSettings>(config, "image_settings", false, problems) {
+            image.check(problems);
+        }
+        if let Some(job) = section::<JobSettings>(config, "job_settings", false, problems) {
+            job.check(problems);
+        }
+        if let Some(locations) =
+            section::<LocationSettings>(config, "location_settings", false, problems)
+        {
+            locations.check(problems);
+        }
+        if let Some(cloudflare) =
+            section::<CloudflareSettings>(config, "cloudflare_settings", false, problems)
+        {
+            cloudflare.check(problems);
+        }
+        section::<CacheSettings>(config, "cache_settings", false, problems);
+        section::<ShutdownSettings>(config, "shutdown_settings", false, problems);
+        if let Some(request_limits) =
+            section::<RequestLimitSettings>(config, "request_limit_settings", false, problems)
+        {
+            request_limits.check(problems);
+        }
+        if let Some(reload) = section::<ReloadSettings>(config, "reload_settings", false, problems)
+        {
+            reload.check(problems);
+        }
+        DynamicSettings::check_sections(config, problems);
+    }
+}
+
+/// What the `check` methods of the settings found wrong.
+#[derive(Default)]
+struct Problems(Vec<String>);
+
+impl Problems {
+    fn check(&mut self, ok: bool, problem: String) {
+        if !ok {
+            self.0.push(problem);
+        }
+    }
+
+    fn into_result(self) -> Result<(), InvalidSettings> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(InvalidSettings(self.0))
+        }
+    }
+
+    /// The problems found, or `error` if there weren't any.
+    fn or_else(mut self, error: config::ConfigError) -> InvalidSettings {
+        if self.0.is_empty() {
+            self.0.push(error.to_string());
+        }
+        InvalidSettings(self.0)
+    }
+}
+
+/// Deserializes the section under `key`, noting why if it can't be. A
+/// missing optional section is left to its default.
+fn section<T: serde::de::DeserializeOwned>(
+    config: &config::Config,
+    key: &str,
+    required: bool,
+    problems: &mut Problems,
+) -> Option<T> {
+    // `Config::get` would name only the section in errors, not the field
+    let value = match config.get::<config::Value>(key) {
+        Ok(value) => value,
+        Err(config::ConfigError::NotFound(_)) if !required => return None,
+        Err(config::ConfigError::NotFound(_)) => {
+            problems.check(false, format!("{} is required", key));
+            return None;
+        }
+        Err(e) => {
+            problems.check(false, format!("{}: {}", key, e));
+            return None;
+        }
+    };
+    match T::deserialize(value) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            problems.check(false, format!("{}: {}", key, e));
+            None
+        }
+    }
+}
+
+fn check_redis_url(redis_url: &Secret, problems: &mut Problems) {
+    // the value may hold a password, so it's left out of the message
+    problems.check(
+        redis::IntoConnectionInfo::into_connection_info(redis_url.expose()).is_ok(),
+        "redis_url must be a redis:// URL".into(),
+    );
+}
+
+impl AuthSettings {
+    fn check(&self, problems: &mut Problems) {
+        problems.check(
+            !self.client_id.is_empty(),
+            "auth_settings.client_id is required".into(),
+        );
+        problems.check(
+            !self.client_secret.expose().is_empty(),
+            "auth_settings.client_secret is required".into(),
+        );
+        for (name, url) in [
+            ("auth_settings.auth_url", &self.auth_url),
+            ("auth_settings.token_url", &self.token_url),
+            ("auth_settings.introspection_url", &self.introspection_url),
+            ("auth_settings.revocation_url", &self.revocation_url),
+            ("auth_settings.redirect_url", &self.redirect_url),
+        ] {
+            problems.check(
+                is_http_url(url),
+                format!("{} must be an http(s) URL, got {:?}", name, url),
+            );
+        }
+    }
+}
+
+impl DatabaseSettings {
+    fn check(&self, problems: &mut Problems) {
+        if let Err(e) = PgConnectOptions::try_from(self) {
+            problems.check(false, format!("database_settings: {:#}", e));
+        }
+        if self.url.is_none() {
+            problems.check(
+                !self.host.is_empty(),
+                "database_settings.host is required".into(),
+            );
+            problems.check(self.port != 0, "database_settings.port can't be 0".into());
+        }
+        problems.check(
+            self.pool.max_connections > 0,
+            "database_settings.pool.max_connections must be at least 1".into(),
+        );
+        problems.check(
+            self.pool.min_connections <= self.pool.max_connections,
+            "database_settings.pool.min_connections exceeds max_connections".into(),
+        );
+    }
+}
+
+impl ApplicationSettings {
+    fn check(&self, problems: &mut Problems) {
+        problems.check(self.port != 0, "app_settings.port can't be 0".into());
+        problems.check(
+            self.metrics_port != 0,
+            "app_settings.metrics_port can't be 0".into(),
+        );
+        problems.check(
+            (self.addr, self.port) != (self.metrics_addr, self.metrics_port),
+            "app_settings.metrics_port is the same as port".into(),
+        );
+        if let Some(tls) = &self.tls {
+            for (name, path) in [
+                ("app_settings.tls.cert_path", &tls.cert_path),
+                ("app_settings.tls.key_path", &tls.key_path),
+            ] {
+                // opened to find out whether it is readable, not only there
+                let readable = std::fs::File::open(path)
+                    .and_then(|x| x.metadata())
+                    .is_ok_and(|x| x.is_file());
+                problems.check(
+                    readable,
+                    format!("{} {:?} is not a readable file", name, path),
+                );
+            }
+            if let Some(redirect_port) = tls.redirect_port {
+                problems.check(
+                    redirect_port != 0,
+                    "app_settings.tls.redirect_port can't be 0".into(),
+                );
+                problems.check(
+                    redirect_port != self.port,
+                    "app_settings.tls.redirect_port is the same as port".into(),
+                );
+                problems.check(
+                    (self.addr, redirect_port) != (self.metrics_addr, self.metrics_port),
+                    "app_settings.tls.redirect_port is the same as metrics_port".into(),
+                );
+            }
+        }
+    }
+}
+
+impl TracingSettings {
+    fn check(&self, problems: &mut Problems) {
+        if let Some(endpoint) = &self.otlp_endpoint {
+            problems.check(
+                is_http_url(endpoint),
+                format!(
+                    "tracing_settings.otlp_endpoint must be an http(s) URL, got {:?}",
+                    endpoint
+                ),
+            );
+        }
+        problems.check(
+            (0.0..=1.0).contains(&self.sample_ratio),
+            "tracing_settings.sample_ratio must be between 0 and 1".into(),
+        );
+    }
+}
+
+impl LocationSettings {
+    fn check(&self, problems: &mut Problems) {
+        problems.check(
+            !self.country.trim().is_empty(),
+            "location_settings.country must not be empty".into(),
+        );
+    }
+}
+
+impl HealthSettings {
+    fn check(&self, problems: &mut Problems) {
+        if let Some(url) = &self.oauth_discovery_url {
+            problems.check(
+                is_http_url(url),
+                format!(
+                    "health_settings.oauth_discovery_url must be an http(s) URL, got {:?}",
+                    url
+                ),
+            );
+        }
+    }
+}
+
+impl ImageSettings {
+    fn check(&self, problems: &mut Problems) {
+        problems.check(
+            (1..=100).contains(&self.quality),
+            "image_settings.quality must be between 1 and 100".into(),
+        );
+        problems.check(
+            (0..=MAX_DUPLICATE_DISTANCE).contains(&self.duplicate_distance),
+            format!(
+                "image_settings.duplicate_distance must be between 0 and {}",
+                MAX_DUPLICATE_DISTANCE
+            ),
+        );
+    }
+}
+
+impl JobSettings {
+    fn check(&self, problems: &mut Problems) {
+        problems.check(
+            self.max_attempts >= 1,
+            "job_settings.max_attempts must be at least 1".into(),
+        );
+        problems.check(
+            self.visibility_timeout_secs > 0,
+            "job_settings.visibility_timeout_secs can't be 0".into(),
+        );
+    }
+}
+
+impl CloudflareSettings {
+    fn check(&self, problems: &mut Problems) {
+        problems.check(
+            is_http_url(&self.api_url),
+            format!(
+                "cloudflare_settings.api_url must be an http(s) URL, got {:?}",
+                self.api_url
+            ),
+        );
+        problems.check(
+            !self.account_id.is_empty(),
+            "cloudflare_settings.account_id is required".into(),
+        );
+        problems.check(
+            !self.api_token.expose().is_empty(),
+            "cloudflare_settings.api_token is required".into(),
+        );
+    }
+}
+
+impl RequestLimitSettings {
+    fn check(&self, problems: &mut Problems) {
+        let limits = std::iter::once(("request_limit_settings.default".to_string(), self.default))
+            .chain(self.routes.iter().map(|x| {
+                (
+                    format!("request_limit_settings.routes {}", x.path),
+                    x.limit(self.default),
+                )
+            }));
+        for (name, limit) in limits {
+            problems.check(
+                limit.timeout_secs > 0,
+                format!("{}: timeout_secs can't be 0", name),
+            );
+            problems.check(
+                limit.body_limit_bytes > 0,
+                format!("{}: body_limit_bytes can't be 0", name),
+            );
+        }
+        for route in &self.routes {
+            problems.check(
+                route.path.starts_with('/'),
+                format!(
+                    "request_limit_settings.routes {}: path must start with /",
+                    route.path
+                ),
+            );
+        }
+    }
+}
+
+impl ReloadSettings {
+    fn check(&self, problems: &mut Problems) {
+        problems.check(
+            !self.watch || self.poll_interval_ms > 0,
+            "reload_settings.poll_interval_ms can't be 0".into(),
+        );
+    }
+}
+
+impl DynamicSettings {
+    /// Loads the configuration files again, returning what can be applied
+    /// without a restart and the rest as configured.
+    pub async fn load() -> Result<(Self, StaticSettings)> {
+        // the config crate only reads files synchronously
+        let (files, static_settings) = tokio::task::spawn_blocking(|| {
+            let files = load_files()?;
+            let static_settings = StaticSettings::new(&files)?;
+            Ok::<_, anyhow::Error>((files, static_settings))
+        })
+        .await??;
+        let settings = Self::resolve(files).await?;
+        Ok((settings, static_settings))
+    }
+
+    /// Resolves references to secrets in `files`, e.g. API keys kept in
+    /// Vault, then deserializes and validates the dynamic settings.
+    pub async fn resolve(files: config::Config) -> Result<Self> {
+        let sources = resolve_secrets(files).await?;
+        Ok(Self::from_config(&sources)?)
+    }
+
+    /// Deserializes and validates `config` like `Settings::from_config`.
+    pub fn from_config(config: &config::Config) -> Result<Self, InvalidSettings> {
+        match config.clone().try_deserialize::<Self>() {
+            Ok(settings) => {
+                settings.validate()?;
+                Ok(settings)
+            }
+            Err(e) => {
+                let mut problems = Problems::default();
+                Self::check_sections(config, &mut problems);
+                Err(problems.or_else(e))
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), InvalidSettings> {
+        let mut problems = Problems::default();
+        self.check(&mut problems);
+        problems.into_result()
+    }
+
+    fn check(&self, problems: &mut Problems) {
+        check_log_level(&self.log_level, problems);
+        check_cors(&self.cors, problems);
+        self.rate_limit_settings.check(problems);
+    }
+
+    fn check_sections(config: &config::Config, problems: &mut Problems) {
+        if let Some(log_level) = section::<String>(config, "log_level", false, problems) {
+            check_log_level(&log_level, problems);
+        }
+        if let Some(cors) = section::<CorsSettings>(config, "cors", true, problems) {
+            check_cors(&cors, problems);
+        }
+        if let Some(rate_limits) =
+            section::<RateLimitSettings>(config, "rate_limit_settings", false, problems)
+        {
+            rate_limits.check(problems);
+        }
+    }
+    /// Top-level settings that differ from `previous`.
+    pub fn changed_from(&self, previous: &Self) -> Vec<&'static str> {
+        [
+            ("log_level", self.log_level != previous.log_level),
+            (
+                "rate_limit_settings",
+                self.rate_limit_settings != previous.rate_limit_settings,
+            ),
+            ("cors", self.cors != previous.cors),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| name)
+        .collect()
+    }
+}
+
+fn check_log_level(log_level: &str, problems: &mut Problems) {
+    if let Err(e) = EnvFilter::try_new(log_level) {
+        problems.check(false, format!("log_level: {}", e));
+    }
+}
+
+fn check_cors(cors: &CorsSettings, problems: &mut Problems) {
+    if let Err(e) = crate::cors_layer(cors) {
+        problems.check(false, format!("cors: {:#}", e));
     }
 }
 
+impl RateLimitSettings {
+    fn check(&self, problems: &mut Problems) {
+        let limits = self
+            .default
+            .iter()
+            .map(|x| ("rate_limit_settings.default".to_string(), *x))
+            .chain(
+                self.routes
+                    .iter()
+                    .map(|x| (format!("rate_limit_settings.routes {}", x.path), x.limit())),
+            );
+        for (name, limit) in limits {
+            problems.check(
+                limit.burst > 0,
+                format!("{}: burst must be at least 1", name),
+            );
+            problems.check(
+                limit.per_second > 0.0 && limit.per_second.is_finite(),
+                format!("{}: per_second must be positive", name),
+            );
+        }
+        for route in &self.routes {
+            problems.check(
+                route.path.starts_with('/'),
+                format!(
+                    "rate_limit_settings.routes {}: path must start with /",
+                    route.path
+                ),
+            );
+        }
+    }
+}
+
+/// The top-level settings that only take effect on restart, as configured
+/// before references to secrets are resolved.
+#[derive(Clone, Default, PartialEq)]
+pub struct StaticSettings(BTreeMap<String, serde_json::Value>);
+
+impl StaticSettings {
+    /// The top-level fields of `DynamicSettings`.
+    const DYNAMIC: [&'static str; 3] = ["log_level", "rate_limit_settings", "cors"];
+
+    fn new(config: &config::Config) -> Result<Self> {
+        let mut settings: BTreeMap<String, serde_json::Value> = config.clone().try_deserialize()?;
+        settings.retain(|key, _| !Self::DYNAMIC.contains(&key.as_str()));
+        Ok(Self(settings))
+    }
+
+    /// Top-level settings that differ from `previous`.
+    pub fn changed_from(&self, previous: &Self) -> Vec<String> {
+        let mut changed: Vec<_> = self
+            .0
+            .keys()
+            .chain(previous.0.keys())
+            .filter(|x| self.0.get(*x) != previous.0.get(*x))
+            .cloned()
+            .collect();
+        changed.sort();
+        changed.dedup();
+        changed
+    }
+}
+
+// the values may hold secrets read from `*_file` settings
+impl std::fmt::Debug for StaticSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+fn is_http_url(value: &str) -> bool {
+    reqwest::Url::parse(value).is_ok_and(|x| matches!(x.scheme(), "http" | "https"))
+}
+
+/// Every problem `Settings::validate` found.
+#[derive(Debug)]
+pub struct InvalidSettings(pub Vec<String>);
+
+impl std::fmt::Display for InvalidSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} invalid settings:", self.0.len())?;
+        for problem in &self.0 {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for InvalidSettings {}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ApplicationSettings {
     pub addr: [u8; 4],
     pub port: u16,
+    /// `/metrics` gets its own listener so it isn't exposed with the API
+    #[serde(default = "default_metrics_addr")]
+    pub metrics_addr: [u8; 4],
+    #[serde(default = "default_metrics_port")]
+    pub metrics_port: u16,
+    /// serve HTTPS on `port` instead of plain HTTP
+    #[serde(default)]
+    pub tls: Option<TlsSettings>,
+}
+
+/// TLS termination for deployments without a proxy in front. Both HTTP/1.1
+/// and HTTP/2 are offered. The certificate and key are reloaded when either
+/// file changes, see `ReloadSettings`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsSettings {
+    /// PEM, leaf certificate first followed by any intermediates
+    pub cert_path: PathBuf,
+    /// PEM, PKCS#8, PKCS#1 or SEC1
+    pub key_path: PathBuf,
+    /// plain HTTP port that redirects every request to HTTPS
+    #[serde(default)]
+    pub redirect_port: Option<u16>,
+}
+
+fn default_metrics_addr() -> [u8; 4] {
+    [127, 0, 0, 1]
+}
+
+fn default_metrics_port() -> u16 {
+    9000
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StorageSettings {
+    pub path: PathBuf,
+}
+
+impl Default for StorageSettings {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("photos"),
+        }
+    }
+}
+
+/// Resized copies generated for every uploaded photo. Each variant is
+/// encoded once per format.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageSettings {
+    pub variants: Vec<VariantSettings>,
+    pub formats: Vec<VariantFormat>,
+    /// 1-100, for the lossy encoders (JPEG and AVIF); WebP is always lossless
+    pub quality: u8,
+    /// perceptual hashes differing in at most this many bits (of 64) are
+    /// reported as likely duplicates
+    pub duplicate_distance: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VariantSettings {
+    pub name: String,
+    pub width: u32,
+}
+
+impl Default for ImageSettings {
+    fn default() -> Self {
+        let variant = |name: &str, width| VariantSettings {
+            name: name.into(),
+            width,
+        };
+        Self {
+            variants: vec![
+                variant("thumb", 320),
+                variant("medium", 1280),
+                variant("large", 2560),
+            ],
+            formats: vec![
+                VariantFormat::Jpeg,
+                VariantFormat::Webp,
+                VariantFormat::Avif,
+            ],
+            quality: 80,
+            duplicate_distance: 10,
+        }
+    }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobSettings {
+    /// workers started alongside the HTTP server; `0` leaves jobs to a
+    /// separate `worker` process
+    pub workers: usize,
+    pub poll_interval_ms: u64,
+    /// how long a claimed job is hidden from other workers before it is
+    /// considered abandoned and handed out again; running jobs renew it
+    /// every third of this
+    pub visibility_timeout_secs: u64,
+    pub max_attempts: i32,
+    pub backoff_base_secs: u64,
+    pub backoff_max_secs: u64,
+}
+
+impl JobSettings {
+    /// Exponential backoff before retrying after the given attempt.
+    pub fn backoff(&self, attempt: i32) -> Duration {
+        let exponent = attempt.saturating_sub(1).clamp(0, 16) as u32;
+        let delay = self.backoff_base_secs.saturating_mul(1 << exponent);
+        Duration::from_secs(delay.min(self.backoff_max_secs))
+    }
+}
+
+impl Default for JobSettings {
+    fn default() -> Self {
+        Self {
+            workers: 2,
+            poll_interval_ms: 1000,
+            visibility_timeout_secs: 300,
+            max_attempts: 5,
+            backoff_base_secs: 10,
+            backoff_max_secs: 3600,
+        }
+    }
+}
+
+/// How photos are placed into the location hierarchy from their
+/// `location_taken`, e.g. "The Narrows, Zion National Park, Utah".
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocationSettings {
+    /// country the states in `location_taken` belong to
+    pub country: String,
+}
+
+impl Default for LocationSettings {
+    fn default() -> Self {
+        Self {
+            country: "United States".into(),
+        }
+    }
+}
+
+/// Cloudflare Images, which the website serves photos from. Uploads are
+/// copied there and deleted photos removed by background jobs.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CloudflareSettings {
+    #[serde(default = "CloudflareSettings::default_api_url")]
+    pub api_url: String,
+    pub account_id: String,
+    /// needs the `Cloudflare Images: Edit` permission
+    pub api_token: Secret,
+}
+
+impl CloudflareSettings {
+    fn default_api_url() -> String {
+        "https://api.cloudflare.com/client/v4".into()
+    }
+}
+
+/// Caching of the public read endpoints, in Redis and by clients/CDNs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheSettings {
+    pub enabled: bool,
+    /// how long a response stays in Redis unless invalidated earlier
+    pub ttl_secs: u64,
+    /// `max-age` for browsers
+    pub max_age_secs: u64,
+    /// `s-maxage` for shared caches such as CDNs
+    pub shared_max_age_secs: u64,
+    pub stale_while_revalidate_secs: u64,
+}
+
+impl CacheSettings {
+    pub fn cache_control(&self) -> String {
+        format!(
+            "public, max-age={}, s-maxage={}, stale-while-revalidate={}",
+            self.max_age_secs, self.shared_max_age_secs, self.stale_while_revalidate_secs
+        )
+    }
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ttl_secs: 300,
+            max_age_secs: 60,
+            shared_max_age_secs: 300,
+            stale_while_revalidate_secs: 60,
+        }
+    }
+}
+
+/// Export of spans to an OpenTelemetry collector.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TracingSettings {
+    /// OTLP/HTTP traces endpoint, e.g. `http://localhost:4318/v1/traces`.
+    /// Spans are only exported when this is set.
+    pub otlp_endpoint: Option<String>,
+    pub service_name: String,
+    /// fraction of new traces to sample; traces continued from a caller
+    /// follow the caller's decision
+    pub sample_ratio: f64,
+}
+
+impl Default for TracingSettings {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: "enchanted_natures".into(),
+            sample_ratio: 1.0,
+        }
+    }
+}
+
+/// Dependencies probed by `/health/ready`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthSettings {
+    /// each probe fails if it takes longer than this
+    pub timeout_ms: u64,
+    /// OpenID discovery document of the identity provider; probed only
+    /// when set
+    pub oauth_discovery_url: Option<String>,
+}
+
+impl Default for HealthSettings {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 2000,
+            oauth_discovery_url: None,
+        }
+    }
+}
+
+/// How the server drains on SIGTERM/SIGINT.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShutdownSettings {
+    /// time between failing readiness and closing the listener, so load
+    /// balancers stop routing here before connections are refused
+    pub drain_delay_secs: u64,
+    /// how long in-flight requests and jobs get to finish once the
+    /// listener is closed before they're dropped
+    pub timeout_secs: u64,
+}
+
+impl ShutdownSettings {
+    pub fn drain_delay(&self) -> Duration {
+        Duration::from_secs(self.drain_delay_secs)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+impl Default for ShutdownSettings {
+    fn default() -> Self {
+        Self {
+            drain_delay_secs: 5,
+            timeout_secs: 30,
+        }
+    }
+}
+
+/// How long requests may take and how large their bodies may be. Routes not
+/// listed get `default`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestLimitSettings {
+    pub default: RequestLimit,
+    /// uploads, imports and exports get longer or larger limits when unset
+    #[serde(default = "RequestLimitSettings::default_routes")]
+    pub routes: Vec<RouteRequestLimit>,
+}
+
+impl RequestLimitSettings {
+    /// Limits for a request to the route template `path`.
+    pub fn limit_for(&self, method: &str, path: &str) -> RequestLimit {
+        self.routes
+            .iter()
+            .find(|route| {
+                route.path == path
+                    && route
+                        .method
+                        .as_deref()
+                        .is_none_or(|x| x.eq_ignore_ascii_case(method))
+            })
+            .map(|route| route.limit(self.default))
+            .unwrap_or(self.default)
+    }
+
+    fn default_routes() -> Vec<RouteRequestLimit> {
+        let route = |method: &str, path: &str, timeout_secs, body_limit_bytes| RouteRequestLimit {
+            path: path.into(),
+            method: Some(method.into()),
+            timeout_secs: Some(timeout_secs),
+            body_limit_bytes,
+        };
+        vec![
+            route("POST", "/api/v0/photos", 300, Some(64 * 1024 * 1024)),
+            route("POST", "/api/v0/import", 600, Some(64 * 1024 * 1024)),
+            route("GET", "/api/v0/export", 120, None),
+        ]
+    }
+}
+
+impl Default for RequestLimitSettings {
+    fn default() -> Self {
+        Self {
+            default: RequestLimit {
+                timeout_secs: 10,
+                // axum's default
+                body_limit_bytes: 2 * 1024 * 1024,
+            },
+            routes: Self::default_routes(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RequestLimit {
+    /// time until the response starts, after which the request is
+    /// abandoned
+    pub timeout_secs: u64,
+    pub body_limit_bytes: usize,
+}
+
+impl RequestLimit {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteRequestLimit {
+    /// route template as registered, e.g. `/api/v0/photos/:id`
+    pub path: String,
+    /// applies to every method when unset
+    pub method: Option<String>,
+    /// `default`'s when unset
+    pub timeout_secs: Option<u64>,
+    /// `default`'s when unset
+    pub body_limit_bytes: Option<usize>,
+}
+
+impl RouteRequestLimit {
+    pub fn limit(&self, default: RequestLimit) -> RequestLimit {
+        RequestLimit {
+            timeout_secs: self.timeout_secs.unwrap_or(default.timeout_secs),
+            body_limit_bytes: self.body_limit_bytes.unwrap_or(default.body_limit_bytes),
+        }
+    }
+}
+
+/// When `DynamicSettings` and the TLS certificate are reloaded. SIGHUP
+/// always reloads them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReloadSettings {
+    /// reload when a file in `config/` or the TLS certificate or key changes
+    pub watch: bool,
+    /// how often the files are checked for changes
+    pub poll_interval_ms: u64,
+}
+
+impl ReloadSettings {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms)
+    }
+}
+
+impl Default for ReloadSettings {
+    fn default() -> Self {
+        Self {
+            watch: true,
+            poll_interval_ms: 2000,
+        }
+    }
+}
+
+/// Token buckets limiting how often each client may call a route. Buckets
+/// are kept in Redis so every replica enforces the same limit.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// take the client address from the last `X-Forwarded-For` entry; only
+    /// enable behind a proxy that sets it, otherwise clients can pick
+    /// their own bucket
+    #[serde(default)]
+    pub trust_forwarded_for: bool,
+    /// limit for routes not listed in `routes`, shared between them;
+    /// routes aren't limited when unset
+    pub default: Option<RateLimit>,
+    #[serde(default)]
+    pub routes: Vec<RouteRateLimit>,
+    /// `X-Api-Key` values by client name. A client sending one of these
+    /// gets a bucket of its own; any other key is ignored and the client is
+    /// limited by address.
+    #[serde(default, skip_serializing)]
+    pub api_keys: BTreeMap<String, Secret>,
+}
+
+impl RateLimitSettings {
+    /// Name of the client `key` belongs to, if it's one of `api_keys`.
+    pub fn api_key_client(&self, key: &[u8]) -> Option<&str> {
+        // digests are compared so the time taken says nothing about the keys
+        let digest = content_hash(key);
+        self.api_keys
+            .iter()
+            .find(|(_, x)| content_hash(x.expose().as_bytes()) == digest)
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// requests a client can make at once with a full bucket
+    pub burst: u32,
+    /// rate the bucket refills at, in requests per second
+    pub per_second: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RouteRateLimit {
+    /// route template as registered, e.g. `/api/v0/photos/:id`
+    pub path: String,
+    /// limits every method when unset
+    pub method: Option<String>,
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+impl RouteRateLimit {
+    pub fn limit(&self) -> RateLimit {
+        RateLimit {
+            burst: self.burst,
+            per_second: self.per_second,
+        }
+    }
+}
+
+/// Which browser origins may call the API. Applies to preflight and actual
+/// requests alike.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CorsSettings {
+    /// exact origins such as `https://enchantednatures.com`, or `*` for any
+    /// origin; `*` can't be combined with `allow_credentials`
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    /// response headers scripts may read besides the CORS-safelisted ones
+    pub exposed_headers: Vec<String>,
+    /// lets browsers send the session cookie
+    pub allow_credentials: bool,
+    /// how long browsers may cache a preflight response
+    pub max_age_secs: u64,
+}
+
+impl CorsSettings {
+    pub fn for_environment(environment: &Environment) -> Self {
+        let allowed_origins = match environment {
+            Environment::Local | Environment::Development => vec![
+                "http://localhost:3000".into(),
+                "http://127.0.0.1:3000".into(),
+            ],
+            Environment::Staging | Environment::Production => vec![
+                "https://enchantednatures.com".into(),
+                "https://www.enchantednatures.com".into(),
+            ],
+        };
+        let max_age_secs = match environment {
+            Environment::Local | Environment::Development => 60,
+            Environment::Staging | Environment::Production => 3600,
+        };
+        Self {
+            allowed_origins,
+            allowed_methods: ["GET", "POST", "PUT", "DELETE"].map(Into::into).to_vec(),
+            allowed_headers: ["content-type", "x-api-key", "x-request-id", "traceparent"]
+                .map(Into::into)
+                .to_vec(),
+            exposed_headers: [
+                "location",
+                "x-cache",
+                "x-request-id",
+                "retry-after",
+                "ratelimit-limit",
+                "ratelimit-remaining",
+                "ratelimit-reset",
+            ]
+            .map(Into::into)
+            .to_vec(),
+            allow_credentials: true,
+            max_age_secs,
+        }
+    }
+}
+
+impl Default for CorsSettings {
+    fn default() -> Self {
+        Self::for_environment(&Environment::Production)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum Environment {
     Development,
     Local,
@@ -123,7 +1436,172 @@ impl TryFrom<String> for Environment {
             "development" => Ok(Environment::Development),
             "staging" => Ok(Environment::Staging),
             "production" => Ok(Environment::Production),
-            _ => Err(format!("Failed to parse{}", value)),
+            _ => Err(format!(
+                "unknown ENVIRONMENT {:?}, expected one of local, development, staging, production",
+                value
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALID: &str = r#"
+database_settings:
+  host: localhost
+  port: 5432
+  database: enchanted
+  user: postgres
+  application_name: api
+auth_settings:
+  client_id: api
+  client_secret: secret
+  redirect_url: http://localhost:8080/auth/callback
+  token_url: https://auth.example.com/token
+  auth_url: https://auth.example.com/authorize
+  introspection_url: https://auth.example.com/introspect
+  revocation_url: https://auth.example.com/revoke
+app_settings:
+  addr: [127, 0, 0, 1]
+  port: 8080
+redis_url: redis://127.0.0.1:6379
+"#;
+
+    fn config(overrides: &str) -> config::Config {
+        let defaults = EnvironmentDefaults {
+            cors: CorsSettings::default(),
+        };
+        config::Config::builder()
+            .add_source(config::Config::try_from(&defaults).unwrap())
+            .add_source(config::File::from_str(VALID, config::FileFormat::Yaml))
+            .add_source(config::File::from_str(overrides, config::FileFormat::Yaml))
+            .build()
+            .unwrap()
+    }
+
+    fn problems(settings: &Settings) -> Vec<String> {
+        match settings.validate() {
+            Ok(()) => Vec::new(),
+            Err(InvalidSettings(problems)) => problems,
+        }
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        // name, change to valid settings and the start of each problem
+        type Case = (&'static str, fn(&mut Settings), &'static [&'static str]);
+        let cases: [Case; 9] = [
+            ("valid", |_| {}, &[]),
+            (
+                "empty client id and a relative url",
+                |x| {
+                    x.auth_settings.client_id.clear();
+                    x.auth_settings.token_url = "/token".into();
+                },
+                &[
+                    "auth_settings.client_id is required",
+                    "auth_settings.token_url must be an http(s) URL, got \"/token\"",
+                ],
+            ),
+            (
+                "redis url",
+                |x| x.redis_url = Secret::new("localhost:6379:x"),
+                &["redis_url must be a redis:// URL"],
+            ),
+            (
+                "pool",
+                |x| {
+                    x.database_settings.pool.max_connections = 0;
+                    x.database_settings.pool.min_connections = 1;
+                },
+                &[
+                    "database_settings.pool.max_connections must be at least 1",
+                    "database_settings.pool.min_connections exceeds max_connections",
+                ],
+            ),
+            (
+                "metrics on the api port",
+                |x| x.app_settings.metrics_port = x.app_settings.port,
+                &["app_settings.metrics_port is the same as port"],
+            ),
+            (
+                "sample ratio and image quality",
+                |x| {
+                    x.tracing_settings.sample_ratio = 1.5;
+                    x.image_settings.quality = 0;
+                },
+                &[
+                    "tracing_settings.sample_ratio must be between 0 and 1",
+                    "image_settings.quality must be between 1 and 100",
+                ],
+            ),
+            (
+                "jobs",
+                |x| x.job_settings.max_attempts = 0,
+                &["job_settings.max_attempts must be at least 1"],
+            ),
+            (
+                "reload interval",
+                |x| {
+                    x.reload_settings.watch = true;
+                    x.reload_settings.poll_interval_ms = 0;
+                },
+                &["reload_settings.poll_interval_ms can't be 0"],
+            ),
+            (
+                "dynamic settings",
+                |x| x.dynamic.log_level = "info,[".into(),
+                &["log_level: "],
+            ),
+        ];
+        for (name, change, expected) in cases {
+            let mut settings = Settings::from_config(&config("")).unwrap();
+            change(&mut settings);
+            let problems = problems(&settings);
+            assert_eq!(problems.len(), expected.len(), "{}: {:?}", name, problems);
+            for (problem, expected) in problems.iter().zip(expected) {
+                assert!(problem.starts_with(expected), "{}: {:?}", name, problems);
+            }
         }
     }
+
+    #[test]
+    fn sections_that_dont_deserialize_are_reported_with_the_other_problems() {
+        let Err(InvalidSettings(problems)) = Settings::from_config(&config(
+            r#"
+app_settings:
+  port: not-a-port
+reload_settings:
+  watch: true
+  poll_interval_ms: 0
+log_level: "info,["
+rate_limit_settings:
+  default:
+    burst: many
+"#,
+        )) else {
+            panic!("invalid settings were accepted");
+        };
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+        assert_eq!(
+            problems[0],
+            "app_settings: invalid type: string \"not-a-port\", expected an integer for key `port`"
+        );
+        assert_eq!(problems[1], "reload_settings.poll_interval_ms can't be 0");
+        assert!(problems[2].starts_with("log_level: "), "{:?}", problems);
+        assert!(
+            problems[3].starts_with("rate_limit_settings: ") && problems[3].contains("burst"),
+            "{:?}",
+            problems
+        );
+
+        let Err(InvalidSettings(problems)) = DynamicSettings::from_config(&config("cors: []"))
+        else {
+            panic!("invalid settings were accepted");
+        };
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].contains("cors"), "{:?}", problems);
+    }
 }
@@ -1,61 +1,234 @@
-use std::sync::Arc;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
-use chrono::NaiveDate;
-use sqlx::PgPool;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use futures::TryStreamExt;
+use sqlx::{Connection, PgConnection, PgExecutor, PgPool};
+use tokio::sync::mpsc;
+use tracing::Instrument;
+use uuid::Uuid;
 
-use crate::models::{Category, CategoryPhotos, Photo};
+use crate::images::EncodedVariant;
+use crate::metadata::PhotoMetadata;
+use crate::models::{
+    BoundingBox, CatalogExport, CatalogRow, Category, CategoryPhotos, Coordinates, DuplicatePair,
+    JobRecord, Location, LocationIndex, MigrationStatus, NewPhoto, Photo, PhotoCategory,
+    PhotoChanges, PhotoCloudflareResource, PhotoExif, PhotoVariant, SimilarPhoto,
+};
 
-#[derive(sqlx::Type)]
-#[sqlx(transparent)]
-struct Id(i32);
+/// Returned by [`PhotoRepository::import_catalog`] when the target database
+/// already holds photos or categories and `replace` was not requested.
+#[derive(Debug)]
+pub struct CatalogNotEmptyError;
+
+impl fmt::Display for CatalogNotEmptyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the catalog already contains photos or categories")
+    }
+}
+
+impl std::error::Error for CatalogNotEmptyError {}
+
+async fn send_row(rows: &mpsc::Sender<Result<CatalogRow>>, row: CatalogRow) -> Result<()> {
+    rows.send(Ok(row))
+        .await
+        .map_err(|_| anyhow::anyhow!("the export was abandoned"))
+}
+
+/// Whether `e` is Postgres refusing a duplicate in a unique index.
+pub fn is_unique_violation(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<sqlx::Error>()
+        .and_then(|x| x.as_database_error())
+        .is_some_and(|x| x.is_unique_violation())
+}
+
+// other replicas and the CLI change locations too, so a cached index is
+// only trusted this long
+const LOCATION_INDEX_TTL: Duration = Duration::from_secs(60);
+
+/// Spelling fixes for `location_taken` values in the original seed data, so
+/// they end up in the same locations as the other spellings.
+const LOCATION_TAKEN_FIXES: [(&str, &str); 3] = [
+    ("The Narrows Zion", "The Narrows, Zion"),
+    (
+        "Upper Geyser Basin - Yellowstone,",
+        "Upper Geyser Basin, Yellowstone National Park,",
+    ),
+    (", Yellowstone,", ", Yellowstone National Park,"),
+];
+
+/// Postal abbreviations of US states, expanded so "Duluth, MN" and
+/// "Duluth, Minnesota" share a state.
+const STATE_ABBREVIATIONS: [(&str, &str); 51] = [
+    ("AL", "Alabama"),
+    ("AK", "Alaska"),
+    ("AZ", "Arizona"),
+    ("AR", "Arkansas"),
+    ("CA", "California"),
+    ("CO", "Colorado"),
+    ("CT", "Connecticut"),
+    ("DE", "Delaware"),
+    ("DC", "District of Columbia"),
+    ("FL", "Florida"),
+    ("GA", "Georgia"),
+    ("HI", "Hawaii"),
+    ("ID", "Idaho"),
+    ("IL", "Illinois"),
+    ("IN", "Indiana"),
+    ("IA", "Iowa"),
+    ("KS", "Kansas"),
+    ("KY", "Kentucky"),
+    ("LA", "Louisiana"),
+    ("ME", "Maine"),
+    ("MD", "Maryland"),
+    ("MA", "Massachusetts"),
+    ("MI", "Michigan"),
+    ("MN", "Minnesota"),
+    ("MS", "Mississippi"),
+    ("MO", "Missouri"),
+    ("MT", "Montana"),
+    ("NE", "Nebraska"),
+    ("NV", "Nevada"),
+    ("NH", "New Hampshire"),
+    ("NJ", "New Jersey"),
+    ("NM", "New Mexico"),
+    ("NY", "New York"),
+    ("NC", "North Carolina"),
+    ("ND", "North Dakota"),
+    ("OH", "Ohio"),
+    ("OK", "Oklahoma"),
+    ("OR", "Oregon"),
+    ("PA", "Pennsylvania"),
+    ("RI", "Rhode Island"),
+    ("SC", "South Carolina"),
+    ("SD", "South Dakota"),
+    ("TN", "Tennessee"),
+    ("TX", "Texas"),
+    ("UT", "Utah"),
+    ("VT", "Vermont"),
+    ("VA", "Virginia"),
+    ("WA", "Washington"),
+    ("WV", "West Virginia"),
+    ("WI", "Wisconsin"),
+    ("WY", "Wyoming"),
+];
 
 #[derive(Debug, Clone)]
 pub struct PhotoRepository {
     pub db_pool: Arc<PgPool>,
+    location_index: Arc<RwLock<Option<CachedLocationIndex>>>,
 }
 
+/// A location index and when it was loaded.
+type CachedLocationIndex = (Instant, Arc<LocationIndex>);
+
 impl PhotoRepository {
     pub fn new(pg_pool: PgPool) -> Self {
         PhotoRepository {
             db_pool: Arc::new(pg_pool),
+            location_index: Arc::new(RwLock::new(None)),
         }
     }
     pub async fn migrate(&self) -> Result<()> {
-        sqlx::migrate!().run(&*self.db_pool).await?;
+        let mut conn = self.migration_connection().await?;
+        sqlx::migrate!().run(&mut conn).await?;
+        conn.close().await?;
         Ok(())
     }
 
-    pub async fn add_photo(
+    // the legacy data migrations create temporary tables, which last as long
+    // as the connection, so running them again on it would fail
+    async fn migration_connection(&self) -> Result<PgConnection> {
+        Ok(self.db_pool.acquire().await?.detach())
+    }
+
+    #[tracing::instrument(name = "db.ping", target = "db", skip_all)]
+    pub async fn ping(&self) -> Result<()> {
+        sqlx::query("SELECT 1").execute(&*self.db_pool).await?;
+        Ok(())
+    }
+
+    /// Versions of migrations shipped with this build that haven't been
+    /// applied to the database.
+    #[tracing::instrument(name = "db.pending_migrations", target = "db", skip_all)]
+    pub async fn pending_migrations(&self) -> Result<Vec<i64>> {
+        Ok(self
+            .migration_status()
+            .await?
+            .into_iter()
+            .filter(|x| !x.applied)
+            .map(|x| x.version)
+            .collect())
+    }
+
+    /// Every migration shipped with this build, oldest first.
+    #[tracing::instrument(name = "db.migration_status", target = "db", skip_all)]
+    pub async fn migration_status(&self) -> Result<Vec<MigrationStatus>> {
+        let applied = self.applied_migrations().await?;
+        Ok(sqlx::migrate!()
+            .iter()
+            .filter(|x| !x.migration_type.is_down_migration())
+            .map(|x| MigrationStatus {
+                version: x.version,
+                description: x.description.to_string(),
+                applied: applied.contains(&x.version),
+            })
+            .collect())
+    }
+
+    /// Reverts applied migrations newer than `target`, or only the latest
+    /// one if `target` is `None`. Returns the reverted versions.
+    #[tracing::instrument(name = "db.revert_migrations", target = "db", skip_all)]
+    pub async fn revert_migrations(&self, target: Option<i64>) -> Result<Vec<i64>> {
+        let applied = self.applied_migrations().await?;
+        let target = match target {
+            Some(target) => target,
+            None => applied.iter().rev().nth(1).copied().unwrap_or(0),
+        };
+        let mut conn = self.migration_connection().await?;
+        sqlx::migrate!().undo(&mut conn, target).await?;
+        conn.close().await?;
+        Ok(applied.into_iter().filter(|x| *x > target).collect())
+    }
+
+    async fn applied_migrations(&self) -> Result<Vec<i64>> {
+        // sqlx creates the table with the first migration
+        let exists: bool = sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+            .fetch_one(&*self.db_pool)
+            .await?;
+        if !exists {
+            return Ok(Vec::new());
+        }
+        Ok(sqlx::query_scalar(
+            "SELECT version FROM _sqlx_migrations WHERE success ORDER BY version",
+        )
+        .fetch_all(&*self.db_pool)
+        .await?)
+    }
+
+    #[tracing::instrument(name = "db.add_photo", target = "db", skip_all)]
+    pub async fn add_photo(&self, photo: NewPhoto) -> Result<Photo> {
+        insert_photo(&*self.db_pool, photo).await
+    }
+
+    /// Adds a photo and its metadata together, so neither is kept without
+    /// the other.
+    #[tracing::instrument(name = "db.add_photo_with_exif", target = "db", skip_all)]
+    pub async fn add_photo_with_exif(
         &self,
-        title: String,
-        filename: String,
-        location_taken: String,
-        date_taken: NaiveDate,
+        photo: NewPhoto,
+        metadata: &PhotoMetadata,
     ) -> Result<Photo> {
-        let response = sqlx::query_as!(
-            Photo,
-            r#"
-                INSERT INTO photos (title, filename, location_taken, date_taken)
-                VALUES ($1, $2, $3, $4) RETURNING id as "id!",
-                    title as "title!",
-                    filename as "filename!",
-                    location_taken as "location_taken!",
-                    date_taken as "date_taken!",
-                    created_at as "created_at!",
-                    updated_at as "updated_at!"
-                    
-            "#,
-            title,
-            filename,
-            location_taken,
-            date_taken
-        )
-        .fetch_one(&*self.db_pool)
-        .await?;
-        Ok(response)
+        let mut transaction = self.db_pool.begin().await?;
+        let photo = insert_photo(&mut *transaction, photo).await?;
+        insert_photo_exif(&mut *transaction, photo.id, metadata).await?;
+        transaction.commit().await?;
+        Ok(photo)
     }
 
+    #[tracing::instrument(name = "db.get_photo", target = "db", skip_all)]
     pub async fn get_photo(&self, id: i32) -> Result<Photo> {
         let response = sqlx::query_as!(
             Photo,
@@ -66,7 +239,12 @@ impl PhotoRepository {
                     location_taken as "location_taken!",
                     date_taken as "date_taken!",
                     created_at as "created_at!",
-                    updated_at as "updated_at!"
+                    updated_at as "updated_at!",
+                    latitude,
+                    longitude,
+                    location_id,
+                    perceptual_hash,
+                    content_sha256
                 FROM photos
                 WHERE id = $1;
             "#,
@@ -77,16 +255,11 @@ impl PhotoRepository {
         Ok(response)
     }
 
-    pub async fn update_photo(
-        &self,
-        id: i32,
-        title: Option<String>,
-        filename: Option<String>,
-        location_taken: Option<String>,
-        date_taken: Option<NaiveDate>,
-    ) -> Result<Photo> {
+    #[tracing::instrument(name = "db.update_photo", target = "db", skip_all)]
+    pub async fn update_photo(&self, id: i32, changes: PhotoChanges) -> Result<Photo> {
         match self.get_photo(id).await {
             Ok(photo) => {
+                let coordinates = changes.coordinates.unwrap_or(photo.coordinates());
                 let response = sqlx::query_as!(
                     Photo,
                     r#"
@@ -94,7 +267,11 @@ impl PhotoRepository {
                     SET title = $2,
                         filename = $3,
                         location_taken = $4,
-                        date_taken = $5
+                        date_taken = $5,
+                        latitude = $6,
+                        longitude = $7,
+                        location_id = $8,
+                        updated_at = now()
                     WHERE 
                         id = $1
                     RETURNING 
@@ -104,13 +281,21 @@ impl PhotoRepository {
                         location_taken as "location_taken!",
                         date_taken as "date_taken!",
                         created_at as "created_at!",
-                        updated_at as "updated_at!"
+                        updated_at as "updated_at!",
+                        latitude,
+                        longitude,
+                        location_id,
+                        perceptual_hash,
+                        content_sha256
                     "#,
                     id,
-                    title.unwrap_or(photo.title),
-                    filename.unwrap_or(photo.filename),
-                    location_taken.unwrap_or(photo.location_taken),
-                    date_taken.unwrap_or(photo.date_taken)
+                    changes.title.unwrap_or(photo.title),
+                    changes.filename.unwrap_or(photo.filename),
+                    changes.location_taken.unwrap_or(photo.location_taken),
+                    changes.date_taken.unwrap_or(photo.date_taken),
+                    coordinates.map(|x| x.latitude),
+                    coordinates.map(|x| x.longitude),
+                    changes.location_id.unwrap_or(photo.location_id)
                 )
                 .fetch_one(&*self.db_pool)
                 .await?;
@@ -120,6 +305,7 @@ impl PhotoRepository {
         }
     }
 
+    #[tracing::instrument(name = "db.get_photos_in_category", target = "db", skip_all)]
     pub async fn get_photos_in_category(&self, id: i32) -> Result<Vec<Photo>> {
         let response = sqlx::query_as!(
             Photo,
@@ -130,7 +316,12 @@ impl PhotoRepository {
                     p.location_taken as "location_taken!",
                     p.date_taken as "date_taken!",
                     p.created_at as "created_at!",
-                    p.updated_at as "updated_at!"
+                    p.updated_at as "updated_at!",
+                    p.latitude,
+                    p.longitude,
+                    p.location_id,
+                    p.perceptual_hash,
+                    p.content_sha256
                 FROM categories
                         JOIN photo_categories pc on categories.id = pc.category_id
                         JOIN photos p on p.id = pc.photo_id
@@ -143,6 +334,7 @@ impl PhotoRepository {
         Ok(response)
     }
 
+    #[tracing::instrument(name = "db.get_photos", target = "db", skip_all)]
     pub async fn get_photos(&self) -> Result<Vec<Photo>> {
         let response = sqlx::query_as!(
             Photo,
@@ -153,7 +345,12 @@ impl PhotoRepository {
                     location_taken as "location_taken!",
                     date_taken as "date_taken!",
                     created_at as "created_at!",
-                    updated_at as "updated_at!"
+                    updated_at as "updated_at!",
+                    latitude,
+                    longitude,
+                    location_id,
+                    perceptual_hash,
+                    content_sha256
                 FROM photos
             "#
         )
@@ -162,20 +359,409 @@ impl PhotoRepository {
         Ok(response)
     }
 
-    pub async fn delete_photo(&self, id: i32) -> Result<()> {
+    /// Photos with coordinates, optionally limited to a category, a bounding
+    /// box and/or a radius (in kilometres) around a point. Results within a
+    /// radius are ordered nearest first.
+    #[tracing::instrument(name = "db.search_photos", target = "db", skip_all)]
+    pub async fn search_photos(
+        &self,
+        category_id: Option<i32>,
+        bbox: Option<BoundingBox>,
+        near: Option<(Coordinates, f64)>,
+    ) -> Result<Vec<Photo>> {
+        let response = sqlx::query_as!(
+            Photo,
+            r#"
+                WITH located AS (
+                    SELECT p.*,
+                        6371.0 * 2 * asin(sqrt(
+                            power(sin(radians(p.latitude - $6) / 2), 2)
+                            + cos(radians($6)) * cos(radians(p.latitude))
+                            * power(sin(radians(p.longitude - $7) / 2), 2)
+                        )) as distance
+                    FROM photos p
+                    WHERE p.latitude IS NOT NULL
+                )
+                SELECT id as "id!",
+                    title as "title!",
+                    filename as "filename!",
+                    location_taken as "location_taken!",
+                    date_taken as "date_taken!",
+                    created_at as "created_at!",
+                    updated_at as "updated_at!",
+                    latitude,
+                    longitude,
+                    location_id,
+                    perceptual_hash,
+                    content_sha256
+                FROM located
+                WHERE ($1::int4 IS NULL OR EXISTS(
+                        SELECT 1 FROM photo_categories pc
+                        WHERE pc.photo_id = located.id AND pc.category_id = $1))
+                    AND ($2::float8 IS NULL OR (
+                        latitude BETWEEN $3 AND $5
+                        AND CASE WHEN $2 <= $4
+                            THEN longitude BETWEEN $2 AND $4
+                            ELSE longitude >= $2 OR longitude <= $4
+                        END))
+                    AND ($6::float8 IS NULL OR distance <= $8)
+                ORDER BY distance NULLS LAST, id
+            "#,
+            category_id,
+            bbox.map(|x| x.min_longitude),
+            bbox.map(|x| x.min_latitude),
+            bbox.map(|x| x.max_longitude),
+            bbox.map(|x| x.max_latitude),
+            near.map(|x| x.0.latitude),
+            near.map(|x| x.0.longitude),
+            near.map(|x| x.1)
+        )
+        .fetch_all(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+
+    /// Deletes the photo, returning the Cloudflare images it had, which go
+    /// with it.
+    #[tracing::instrument(name = "db.delete_photo", target = "db", skip_all)]
+    pub async fn delete_photo(&self, id: i32) -> Result<Vec<Uuid>> {
+        let response = sqlx::query_scalar!(
+            r#"
+                WITH resources AS (
+                    SELECT resource_id
+                    FROM photo_cloudflare_resource
+                    WHERE photo_id = $1
+                ), deleted AS (
+                    DELETE
+                    FROM photos
+                    WHERE id = $1
+                )
+                SELECT resource_id as "resource_id!"
+                FROM resources
+            "#,
+            id
+        )
+        .fetch_all(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+
+    #[tracing::instrument(name = "db.get_cloudflare_resource", target = "db", skip_all)]
+    pub async fn get_cloudflare_resource(&self, photo_id: i32) -> Result<Option<Uuid>> {
+        let response = sqlx::query_scalar!(
+            r#"
+                SELECT resource_id
+                FROM photo_cloudflare_resource
+                WHERE photo_id = $1
+                LIMIT 1
+            "#,
+            photo_id
+        )
+        .fetch_optional(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+
+    #[tracing::instrument(name = "db.add_cloudflare_resource", target = "db", skip_all)]
+    pub async fn add_cloudflare_resource(&self, photo_id: i32, resource_id: Uuid) -> Result<()> {
         sqlx::query!(
             r#"
-                DELETE
+                INSERT INTO photo_cloudflare_resource (photo_id, resource_id)
+                VALUES ($1, $2)
+            "#,
+            photo_id,
+            resource_id
+        )
+        .execute(&*self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Photos that haven't been copied to Cloudflare Images.
+    #[tracing::instrument(name = "db.get_unsynced_photo_ids", target = "db", skip_all)]
+    pub async fn get_unsynced_photo_ids(&self) -> Result<Vec<i32>> {
+        let response = sqlx::query_scalar!(
+            r#"
+                SELECT p.id
+                FROM photos p
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM photo_cloudflare_resource r WHERE r.photo_id = p.id
+                )
+                ORDER BY p.id
+            "#
+        )
+        .fetch_all(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+
+    #[tracing::instrument(name = "db.upsert_photo_exif", target = "db", skip_all)]
+    pub async fn upsert_photo_exif(
+        &self,
+        photo_id: i32,
+        metadata: &PhotoMetadata,
+    ) -> Result<PhotoExif> {
+        insert_photo_exif(&*self.db_pool, photo_id, metadata).await
+    }
+
+    #[tracing::instrument(name = "db.get_photo_exif", target = "db", skip_all)]
+    pub async fn get_photo_exif(&self, photo_id: i32) -> Result<Option<PhotoExif>> {
+        let response = sqlx::query_as!(
+            PhotoExif,
+            r#"
+                SELECT photo_id,
+                    captured_at,
+                    camera_make,
+                    camera_model,
+                    lens_model,
+                    exposure_time,
+                    f_number,
+                    iso,
+                    focal_length,
+                    latitude,
+                    longitude,
+                    altitude,
+                    caption,
+                    raw,
+                    updated_at
+                FROM photo_exif
+                WHERE photo_id = $1
+            "#,
+            photo_id
+        )
+        .fetch_optional(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+
+    #[tracing::instrument(name = "db.upsert_photo_variant", target = "db", skip_all)]
+    pub async fn upsert_photo_variant(
+        &self,
+        photo_id: i32,
+        variant: &EncodedVariant,
+        storage_key: &str,
+    ) -> Result<PhotoVariant> {
+        let response = sqlx::query_as!(
+            PhotoVariant,
+            r#"
+                INSERT INTO photo_variants (photo_id, variant, format, width, height, byte_size, storage_key)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+                ON CONFLICT (photo_id, variant, format) DO UPDATE
+                SET width = excluded.width,
+                    height = excluded.height,
+                    byte_size = excluded.byte_size,
+                    storage_key = excluded.storage_key,
+                    created_at = now()
+                RETURNING id, photo_id, variant, format, width, height, byte_size, storage_key, created_at
+            "#,
+            photo_id,
+            variant.name,
+            variant.format.as_str(),
+            variant.width as i32,
+            variant.height as i32,
+            variant.bytes.len() as i32,
+            storage_key
+        )
+        .fetch_one(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+
+    #[tracing::instrument(name = "db.get_photo_variants", target = "db", skip_all)]
+    pub async fn get_photo_variants(&self, photo_id: i32) -> Result<Vec<PhotoVariant>> {
+        let response = sqlx::query_as!(
+            PhotoVariant,
+            r#"
+                SELECT id, photo_id, variant, format, width, height, byte_size, storage_key, created_at
+                FROM photo_variants
+                WHERE photo_id = $1
+                ORDER BY width, format
+            "#,
+            photo_id
+        )
+        .fetch_all(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+
+    /// Ids of photos that are missing variants, a perceptual hash or a
+    /// content hash. Duplicates of another photo's contents never get one.
+    #[tracing::instrument(name = "db.get_unprocessed_photo_ids", target = "db", skip_all)]
+    pub async fn get_unprocessed_photo_ids(&self) -> Result<Vec<i32>> {
+        let response = sqlx::query_scalar!(
+            r#"
+                SELECT p.id
+                FROM photos p
+                WHERE p.perceptual_hash IS NULL
+                    OR (p.content_sha256 IS NULL AND p.content_duplicate_of IS NULL)
+                    OR NOT EXISTS (SELECT 1 FROM photo_variants v WHERE v.photo_id = p.id)
+                ORDER BY p.id
+            "#
+        )
+        .fetch_all(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+
+    /// Fails with a unique violation if another photo holds the hash, see
+    /// [`is_unique_violation`].
+    #[tracing::instrument(name = "db.set_photo_content_hash", target = "db", skip_all)]
+    pub async fn set_photo_content_hash(&self, id: i32, content_sha256: &str) -> Result<()> {
+        sqlx::query!(
+            r#"
+                UPDATE photos
+                SET content_sha256 = $2,
+                    content_duplicate_of = NULL
+                WHERE id = $1
+            "#,
+            id,
+            content_sha256
+        )
+        .execute(&*self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Marks a photo as a copy of the one holding `content_sha256`, which
+    /// is returned. `None` if that photo is gone by now.
+    #[tracing::instrument(name = "db.set_photo_content_duplicate", target = "db", skip_all)]
+    pub async fn set_photo_content_duplicate(
+        &self,
+        id: i32,
+        content_sha256: &str,
+    ) -> Result<Option<i32>> {
+        let response = sqlx::query_scalar!(
+            r#"
+                UPDATE photos
+                SET content_duplicate_of = (SELECT o.id FROM photos o WHERE o.content_sha256 = $2)
+                WHERE id = $1
+                RETURNING content_duplicate_of
+            "#,
+            id,
+            content_sha256
+        )
+        .fetch_one(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+
+    #[tracing::instrument(name = "db.find_photo_by_content_hash", target = "db", skip_all)]
+    pub async fn find_photo_by_content_hash(&self, content_sha256: &str) -> Result<Option<i32>> {
+        let response = sqlx::query_scalar!(
+            r#"
+                SELECT id
                 FROM photos
+                WHERE content_sha256 = $1
+            "#,
+            content_sha256
+        )
+        .fetch_optional(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+
+    #[tracing::instrument(name = "db.set_photo_perceptual_hash", target = "db", skip_all)]
+    pub async fn set_photo_perceptual_hash(&self, id: i32, hash: i64) -> Result<()> {
+        sqlx::query!(
+            r#"
+                UPDATE photos
+                SET perceptual_hash = $2
                 WHERE id = $1
             "#,
-            id
+            id,
+            hash
         )
         .execute(&*self.db_pool)
         .await?;
         Ok(())
     }
 
+    /// Photos whose perceptual hash differs from `hash` in at most
+    /// `max_distance` bits, closest first.
+    #[tracing::instrument(name = "db.find_similar_photos", target = "db", skip_all)]
+    pub async fn find_similar_photos(
+        &self,
+        hash: i64,
+        max_distance: i32,
+        exclude_id: Option<i32>,
+    ) -> Result<Vec<SimilarPhoto>> {
+        let response = sqlx::query_as!(
+            SimilarPhoto,
+            r#"
+                SELECT id as "photo_id!", distance as "distance!"
+                FROM (
+                    SELECT id, bit_count((perceptual_hash # $1)::bit(64))::int4 as distance
+                    FROM photos
+                    WHERE perceptual_hash IS NOT NULL
+                        AND id IS DISTINCT FROM $3
+                ) d
+                WHERE distance <= $2
+                ORDER BY distance, id
+            "#,
+            hash,
+            max_distance,
+            exclude_id
+        )
+        .fetch_all(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+
+    /// Every pair of photos within `max_distance` bits of each other.
+    #[tracing::instrument(name = "db.find_duplicate_pairs", target = "db", skip_all)]
+    pub async fn find_duplicate_pairs(&self, max_distance: i32) -> Result<Vec<DuplicatePair>> {
+        let response = sqlx::query_as!(
+            DuplicatePair,
+            r#"
+                SELECT photo_id as "photo_id!", duplicate_id as "duplicate_id!", distance as "distance!"
+                FROM (
+                    SELECT a.id as photo_id,
+                        b.id as duplicate_id,
+                        bit_count((a.perceptual_hash # b.perceptual_hash)::bit(64))::int4 as distance
+                    FROM photos a
+                        JOIN photos b ON a.id < b.id
+                    WHERE a.perceptual_hash IS NOT NULL
+                        AND b.perceptual_hash IS NOT NULL
+                ) d
+                WHERE distance <= $1
+                ORDER BY distance, photo_id, duplicate_id
+            "#,
+            max_distance
+        )
+        .fetch_all(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+
+    #[tracing::instrument(name = "db.get_photos_by_ids", target = "db", skip_all)]
+    pub async fn get_photos_by_ids(&self, ids: &[i32]) -> Result<Vec<Photo>> {
+        let response = sqlx::query_as!(
+            Photo,
+            r#"
+                SELECT id as "id!",
+                    title as "title!",
+                    filename as "filename!",
+                    location_taken as "location_taken!",
+                    date_taken as "date_taken!",
+                    created_at as "created_at!",
+                    updated_at as "updated_at!",
+                    latitude,
+                    longitude,
+                    location_id,
+                    perceptual_hash,
+                    content_sha256
+                FROM photos
+                WHERE id = ANY($1)
+                ORDER BY id
+            "#,
+            ids
+        )
+        .fetch_all(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+
+    #[tracing::instrument(name = "db.add_photo_to_category", target = "db", skip_all)]
     pub async fn add_photo_to_category(
         &self,
         photo_id: i32,
@@ -267,6 +853,7 @@ impl PhotoRepository {
         Ok(())
     }
 
+    #[tracing::instrument(name = "db.add_category", target = "db", skip_all)]
     pub async fn add_category(&self, name: String) -> Result<Category> {
         let response = sqlx::query_as!(
             Category,
@@ -285,6 +872,19 @@ impl PhotoRepository {
         Ok(response)
     }
 
+    #[tracing::instrument(name = "db.delete_category", target = "db", skip_all)]
+    pub async fn delete_category(&self, id: i32) -> Result<()> {
+        sqlx::query!(
+            r#" DELETE FROM categories
+                WHERE id = $1 "#,
+            id
+        )
+        .execute(&*self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "db.get_category", target = "db", skip_all)]
     pub async fn get_category(&self, id: i32) -> Result<CategoryPhotos> {
         let response = sqlx::query_as!(
             Category,
@@ -309,7 +909,12 @@ impl PhotoRepository {
                     p.location_taken as "location_taken!",
                     p.date_taken as "date_taken!",
                     p.created_at as "created_at!",
-                    p.updated_at as "updated_at!"
+                    p.updated_at as "updated_at!",
+                    p.latitude,
+                    p.longitude,
+                    p.location_id,
+                    p.perceptual_hash,
+                    p.content_sha256
                 FROM categories
                         JOIN photo_categories pc on categories.id = pc.category_id
                         JOIN photos p on p.id = pc.photo_id
@@ -322,6 +927,18 @@ impl PhotoRepository {
         Ok((response, photos_in_category))
     }
 
+    #[tracing::instrument(name = "db.category_exists", target = "db", skip_all)]
+    pub async fn category_exists(&self, id: i32) -> Result<bool> {
+        let response = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM categories WHERE id = $1) as "exists!""#,
+            id
+        )
+        .fetch_one(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+
+    #[tracing::instrument(name = "db.get_categories", target = "db", skip_all)]
     pub async fn get_categories(&self) -> Result<Vec<Category>> {
         let response = sqlx::query_as!(
             Category,
@@ -337,4 +954,1025 @@ impl PhotoRepository {
         .await?;
         Ok(response)
     }
+
+    /// Reads the whole catalog from one snapshot and sends it row by row,
+    /// table by table, so an export never holds the gallery in memory. A
+    /// failure is sent as the last item.
+    pub fn export_catalog(&self) -> mpsc::Receiver<Result<CatalogRow>> {
+        let (rows, receiver) = mpsc::channel(64);
+        let repo = self.clone();
+        tokio::spawn(
+            async move {
+                if let Err(e) = repo.send_catalog(&rows).await {
+                    // if this fails the reader has gone, so there is nobody to tell
+                    let _ = rows.send(Err(e)).await;
+                }
+            }
+            .in_current_span(),
+        );
+        receiver
+    }
+
+    #[tracing::instrument(name = "db.export_catalog", target = "db", skip_all)]
+    async fn send_catalog(&self, rows: &mpsc::Sender<Result<CatalogRow>>) -> Result<()> {
+        let mut transaction = self.db_pool.begin().await?;
+        sqlx::query!("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ READ ONLY")
+            .execute(&mut *transaction)
+            .await?;
+
+        {
+            let mut table = sqlx::query_as!(
+                Photo,
+                r#"
+                    SELECT id as "id!",
+                        title as "title!",
+                        filename as "filename!",
+                        location_taken as "location_taken!",
+                        date_taken as "date_taken!",
+                        created_at as "created_at!",
+                        updated_at as "updated_at!",
+                        latitude,
+                        longitude,
+                        location_id,
+                        perceptual_hash,
+                        content_sha256
+                    FROM photos
+                    ORDER BY id
+                "#
+            )
+            .fetch(&mut *transaction);
+            while let Some(row) = table.try_next().await? {
+                send_row(rows, CatalogRow::Photo(row)).await?;
+            }
+        }
+        {
+            let mut table = sqlx::query_as!(
+                Category,
+                r#"
+                    SELECT id as "id!",
+                        name as "name!",
+                        created_at as "created_at!",
+                        updated_at as "updated_at!"
+                    FROM categories
+                    ORDER BY id
+                "#
+            )
+            .fetch(&mut *transaction);
+            while let Some(row) = table.try_next().await? {
+                send_row(rows, CatalogRow::Category(row)).await?;
+            }
+        }
+        {
+            let mut table = sqlx::query_as!(
+                PhotoCategory,
+                r#"
+                    SELECT photo_id as "photo_id!",
+                        category_id as "category_id!",
+                        display_order as "display_order!"
+                    FROM photo_categories
+                    ORDER BY category_id, display_order
+                "#
+            )
+            .fetch(&mut *transaction);
+            while let Some(row) = table.try_next().await? {
+                send_row(rows, CatalogRow::PhotoCategory(row)).await?;
+            }
+        }
+        {
+            let mut table = sqlx::query_as!(
+                Location,
+                r#"
+                    SELECT id as "id!",
+                        name as "name!",
+                        kind as "kind!",
+                        parent_id,
+                        created_at as "created_at!",
+                        updated_at as "updated_at!"
+                    FROM locations
+                    ORDER BY id
+                "#
+            )
+            .fetch(&mut *transaction);
+            while let Some(row) = table.try_next().await? {
+                send_row(rows, CatalogRow::Location(row)).await?;
+            }
+        }
+        {
+            let mut table = sqlx::query_as!(
+                PhotoCloudflareResource,
+                r#"
+                    SELECT photo_id as "photo_id!",
+                        resource_id as "resource_id!"
+                    FROM photo_cloudflare_resource
+                    ORDER BY photo_id
+                "#
+            )
+            .fetch(&mut *transaction);
+            while let Some(row) = table.try_next().await? {
+                send_row(rows, CatalogRow::CloudflareResource(row)).await?;
+            }
+        }
+        {
+            let mut table = sqlx::query_as!(
+                PhotoExif,
+                r#"
+                    SELECT photo_id,
+                        captured_at,
+                        camera_make,
+                        camera_model,
+                        lens_model,
+                        exposure_time,
+                        f_number,
+                        iso,
+                        focal_length,
+                        latitude,
+                        longitude,
+                        altitude,
+                        caption,
+                        raw,
+                        updated_at
+                    FROM photo_exif
+                    ORDER BY photo_id
+                "#
+            )
+            .fetch(&mut *transaction);
+            while let Some(row) = table.try_next().await? {
+                send_row(rows, CatalogRow::PhotoExif(row)).await?;
+            }
+        }
+
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    /// Restores a catalog export, keeping the original ids. The database must
+    /// be empty unless `replace` is set, in which case the existing catalog is
+    /// truncated inside the same transaction.
+    #[tracing::instrument(name = "db.import_catalog", target = "db", skip_all)]
+    pub async fn import_catalog(&self, catalog: CatalogExport, replace: bool) -> Result<()> {
+        catalog.check_version()?;
+        let mut transaction = self.db_pool.begin().await?;
+
+        if replace {
+            sqlx::query!(
+                "TRUNCATE photos, categories, photo_categories, photo_cloudflare_resource, locations CASCADE"
+            )
+            .execute(&mut *transaction)
+            .await?;
+        } else {
+            let has_rows = sqlx::query_scalar!(
+                r#"SELECT EXISTS(SELECT 1 FROM photos) OR EXISTS(SELECT 1 FROM categories) as "has_rows!""#
+            )
+            .fetch_one(&mut *transaction)
+            .await?;
+            if has_rows {
+                return Err(CatalogNotEmptyError.into());
+            }
+        }
+
+        let mut ids = Vec::with_capacity(catalog.locations.len());
+        let mut names = Vec::with_capacity(catalog.locations.len());
+        let mut kinds = Vec::with_capacity(catalog.locations.len());
+        let mut parent_ids: Vec<Option<i32>> = Vec::with_capacity(catalog.locations.len());
+        let mut created: Vec<DateTime<Utc>> = Vec::with_capacity(catalog.locations.len());
+        let mut updated: Vec<DateTime<Utc>> = Vec::with_capacity(catalog.locations.len());
+        for location in catalog.locations {
+            ids.push(location.id);
+            names.push(location.name);
+            kinds.push(location.kind);
+            parent_ids.push(location.parent_id);
+            created.push(location.created_at);
+            updated.push(location.updated_at);
+        }
+        sqlx::query!(
+            r#"
+                INSERT INTO locations (id, name, kind, parent_id, created_at, updated_at)
+                SELECT * FROM UNNEST($1::int4[], $2::varchar[], $3::varchar[], $4::int4[], $5::timestamptz[], $6::timestamptz[])
+            "#,
+            &ids,
+            &names,
+            &kinds,
+            &parent_ids as &[Option<i32>],
+            &created,
+            &updated
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        let mut ids = Vec::with_capacity(catalog.photos.len());
+        let mut titles = Vec::with_capacity(catalog.photos.len());
+        let mut filenames = Vec::with_capacity(catalog.photos.len());
+        let mut locations = Vec::with_capacity(catalog.photos.len());
+        let mut dates: Vec<NaiveDate> = Vec::with_capacity(catalog.photos.len());
+        let mut created: Vec<DateTime<Utc>> = Vec::with_capacity(catalog.photos.len());
+        let mut updated: Vec<DateTime<Utc>> = Vec::with_capacity(catalog.photos.len());
+        let mut latitudes: Vec<Option<f64>> = Vec::with_capacity(catalog.photos.len());
+        let mut longitudes: Vec<Option<f64>> = Vec::with_capacity(catalog.photos.len());
+        let mut location_ids: Vec<Option<i32>> = Vec::with_capacity(catalog.photos.len());
+        let mut hashes: Vec<Option<i64>> = Vec::with_capacity(catalog.photos.len());
+        let mut checksums: Vec<Option<String>> = Vec::with_capacity(catalog.photos.len());
+        for photo in catalog.photos {
+            ids.push(photo.id);
+            titles.push(photo.title);
+            filenames.push(photo.filename);
+            locations.push(photo.location_taken);
+            dates.push(photo.date_taken);
+            created.push(photo.created_at);
+            updated.push(photo.updated_at);
+            latitudes.push(photo.latitude);
+            longitudes.push(photo.longitude);
+            location_ids.push(photo.location_id);
+            hashes.push(photo.perceptual_hash);
+            checksums.push(photo.content_sha256);
+        }
+        sqlx::query!(
+            r#"
+                INSERT INTO photos (id, title, filename, location_taken, date_taken, created_at, updated_at, latitude, longitude, location_id, perceptual_hash, content_sha256)
+                SELECT * FROM UNNEST($1::int4[], $2::varchar[], $3::varchar[], $4::varchar[], $5::date[], $6::timestamptz[], $7::timestamptz[], $8::float8[], $9::float8[], $10::int4[], $11::int8[], $12::varchar[])
+            "#,
+            &ids,
+            &titles,
+            &filenames,
+            &locations,
+            &dates,
+            &created,
+            &updated,
+            &latitudes as &[Option<f64>],
+            &longitudes as &[Option<f64>],
+            &location_ids as &[Option<i32>],
+            &hashes as &[Option<i64>],
+            &checksums as &[Option<String>]
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        let mut ids = Vec::with_capacity(catalog.categories.len());
+        let mut names = Vec::with_capacity(catalog.categories.len());
+        let mut created: Vec<DateTime<Utc>> = Vec::with_capacity(catalog.categories.len());
+        let mut updated: Vec<DateTime<Utc>> = Vec::with_capacity(catalog.categories.len());
+        for category in catalog.categories {
+            ids.push(category.id);
+            names.push(category.name);
+            created.push(category.created_at);
+            updated.push(category.updated_at);
+        }
+        sqlx::query!(
+            r#"
+                INSERT INTO categories (id, name, created_at, updated_at)
+                SELECT * FROM UNNEST($1::int4[], $2::varchar[], $3::timestamptz[], $4::timestamptz[])
+            "#,
+            &ids,
+            &names,
+            &created,
+            &updated
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        let (photo_ids, (category_ids, display_orders)): (Vec<i32>, (Vec<i32>, Vec<i32>)) = catalog
+            .photo_categories
+            .into_iter()
+            .map(|x| (x.photo_id, (x.category_id, x.display_order)))
+            .unzip();
+        sqlx::query!(
+            r#"
+                INSERT INTO photo_categories (photo_id, category_id, display_order)
+                SELECT * FROM UNNEST($1::int4[], $2::int4[], $3::int4[])
+            "#,
+            &photo_ids,
+            &category_ids,
+            &display_orders
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        let (photo_ids, resource_ids): (Vec<i32>, Vec<Uuid>) = catalog
+            .cloudflare_resources
+            .into_iter()
+            .map(|x| (x.photo_id, x.resource_id))
+            .unzip();
+        sqlx::query!(
+            r#"
+                INSERT INTO photo_cloudflare_resource (photo_id, resource_id)
+                SELECT * FROM UNNEST($1::int4[], $2::uuid[])
+            "#,
+            &photo_ids,
+            &resource_ids
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        let rows = catalog.photo_exif.len();
+        let mut photo_ids = Vec::with_capacity(rows);
+        let mut captured: Vec<Option<NaiveDateTime>> = Vec::with_capacity(rows);
+        let mut makes: Vec<Option<String>> = Vec::with_capacity(rows);
+        let mut models: Vec<Option<String>> = Vec::with_capacity(rows);
+        let mut lenses: Vec<Option<String>> = Vec::with_capacity(rows);
+        let mut exposures: Vec<Option<String>> = Vec::with_capacity(rows);
+        let mut f_numbers: Vec<Option<f64>> = Vec::with_capacity(rows);
+        let mut isos: Vec<Option<i32>> = Vec::with_capacity(rows);
+        let mut focal_lengths: Vec<Option<f64>> = Vec::with_capacity(rows);
+        let mut latitudes: Vec<Option<f64>> = Vec::with_capacity(rows);
+        let mut longitudes: Vec<Option<f64>> = Vec::with_capacity(rows);
+        let mut altitudes: Vec<Option<f64>> = Vec::with_capacity(rows);
+        let mut captions: Vec<Option<String>> = Vec::with_capacity(rows);
+        let mut raws: Vec<serde_json::Value> = Vec::with_capacity(rows);
+        let mut updated: Vec<DateTime<Utc>> = Vec::with_capacity(rows);
+        for exif in catalog.photo_exif {
+            photo_ids.push(exif.photo_id);
+            captured.push(exif.captured_at);
+            makes.push(exif.camera_make);
+            models.push(exif.camera_model);
+            lenses.push(exif.lens_model);
+            exposures.push(exif.exposure_time);
+            f_numbers.push(exif.f_number);
+            isos.push(exif.iso);
+            focal_lengths.push(exif.focal_length);
+            latitudes.push(exif.latitude);
+            longitudes.push(exif.longitude);
+            altitudes.push(exif.altitude);
+            captions.push(exif.caption);
+            raws.push(exif.raw);
+            updated.push(exif.updated_at);
+        }
+        sqlx::query!(
+            r#"
+                INSERT INTO photo_exif (photo_id, captured_at, camera_make, camera_model, lens_model,
+                                        exposure_time, f_number, iso, focal_length, latitude, longitude,
+                                        altitude, caption, raw, updated_at)
+                SELECT * FROM UNNEST($1::int4[], $2::timestamp[], $3::varchar[], $4::varchar[], $5::varchar[],
+                                     $6::varchar[], $7::float8[], $8::int4[], $9::float8[], $10::float8[],
+                                     $11::float8[], $12::float8[], $13::text[], $14::jsonb[], $15::timestamptz[])
+            "#,
+            &photo_ids,
+            &captured as &[Option<NaiveDateTime>],
+            &makes as &[Option<String>],
+            &models as &[Option<String>],
+            &lenses as &[Option<String>],
+            &exposures as &[Option<String>],
+            &f_numbers as &[Option<f64>],
+            &isos as &[Option<i32>],
+            &focal_lengths as &[Option<f64>],
+            &latitudes as &[Option<f64>],
+            &longitudes as &[Option<f64>],
+            &altitudes as &[Option<f64>],
+            &captions as &[Option<String>],
+            &raws,
+            &updated
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        // keep the serial sequences ahead of the imported ids
+        sqlx::query!(
+            r#"
+                SELECT setval('photos_id_seq', GREATEST((SELECT MAX(id) FROM photos), (SELECT last_value FROM photos_id_seq))) as photos_id,
+                    setval('categories_id_seq', GREATEST((SELECT MAX(id) FROM categories), (SELECT last_value FROM categories_id_seq))) as categories_id,
+                    setval('locations_id_seq', GREATEST((SELECT MAX(id) FROM locations), (SELECT last_value FROM locations_id_seq))) as locations_id
+            "#
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+
+        transaction.commit().await?;
+        self.forget_location_index();
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "db.get_locations", target = "db", skip_all)]
+    pub async fn get_locations(&self) -> Result<Vec<Location>> {
+        let response = sqlx::query_as!(
+            Location,
+            r#"
+                SELECT id as "id!",
+                    name as "name!",
+                    kind as "kind!",
+                    parent_id,
+                    created_at as "created_at!",
+                    updated_at as "updated_at!"
+                FROM locations
+                ORDER BY id
+            "#
+        )
+        .fetch_all(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+
+    /// Every location, loaded at most once per `LOCATION_INDEX_TTL` and
+    /// shared between requests.
+    pub async fn get_location_index(&self) -> Result<Arc<LocationIndex>> {
+        if let Some((loaded, index)) = &*self.location_index.read().unwrap() {
+            if loaded.elapsed() < LOCATION_INDEX_TTL {
+                return Ok(index.clone());
+            }
+        }
+        let index = Arc::new(LocationIndex::from(self.get_locations().await?));
+        *self.location_index.write().unwrap() = Some((Instant::now(), index.clone()));
+        Ok(index)
+    }
+
+    fn forget_location_index(&self) {
+        *self.location_index.write().unwrap() = None;
+    }
+
+    /// Places photos without a location, or only `photo_id` if given, into
+    /// the hierarchy under `country`, creating locations from the parts of
+    /// their `location_taken`, e.g. "The Narrows, Zion National Park, Utah".
+    /// Names are matched case-insensitively so different spellings share a
+    /// location. Returns how many photos were placed.
+    #[tracing::instrument(name = "db.assign_locations", target = "db", skip_all)]
+    pub async fn assign_locations(&self, country: &str, photo_id: Option<i32>) -> Result<u64> {
+        let mut transaction = self.db_pool.begin().await?;
+        let existing_country = sqlx::query_scalar!(
+            "SELECT id FROM locations WHERE parent_id IS NULL AND lower(name) = lower($1)",
+            country
+        )
+        .fetch_optional(&mut *transaction)
+        .await?;
+        let country_id = match existing_country {
+            Some(id) => id,
+            None => {
+                sqlx::query_scalar!(
+                    "INSERT INTO locations (name, kind) VALUES ($1, 'country') RETURNING id",
+                    country
+                )
+                .fetch_one(&mut *transaction)
+                .await?
+            }
+        };
+
+        // capitalized spellings first, so they name the locations
+        let values = sqlx::query_scalar!(
+            r#"
+                SELECT location_taken as "location_taken!"
+                FROM photos
+                WHERE location_id IS NULL AND ($1::int IS NULL OR id = $1)
+                GROUP BY location_taken
+                ORDER BY location_taken = lower(location_taken), location_taken
+            "#,
+            photo_id
+        )
+        .fetch_all(&mut *transaction)
+        .await?;
+
+        let mut assigned = 0;
+        for location_taken in values {
+            let parts = location_parts(&location_taken);
+            // values without a state (e.g. "Studio") aren't places we can
+            // put on the map
+            if parts.len() < 2 {
+                continue;
+            }
+            let mut parent = country_id;
+            let mut parent_kind = "country".to_string();
+            for (index, part) in parts.iter().enumerate().rev() {
+                let existing = sqlx::query!(
+                    r#"
+                        SELECT id, kind
+                        FROM locations
+                        WHERE parent_id = $1 AND lower(name) = lower($2)
+                    "#,
+                    parent,
+                    part
+                )
+                .fetch_optional(&mut *transaction)
+                .await?;
+                let (id, kind) = match existing {
+                    Some(location) => (location.id, location.kind),
+                    None => {
+                        let kind = location_kind(part, index, parts.len(), &parent_kind);
+                        let id = sqlx::query_scalar!(
+                            "INSERT INTO locations (name, kind, parent_id) VALUES ($1, $2, $3) RETURNING id",
+                            part,
+                            kind,
+                            parent
+                        )
+                        .fetch_one(&mut *transaction)
+                        .await?;
+                        (id, kind.to_string())
+                    }
+                };
+                parent = id;
+                parent_kind = kind;
+            }
+            assigned += sqlx::query!(
+                r#"
+                    UPDATE photos
+                    SET location_id = $1
+                    WHERE location_taken = $2 AND location_id IS NULL AND ($3::int IS NULL OR id = $3)
+                "#,
+                parent,
+                location_taken,
+                photo_id
+            )
+            .execute(&mut *transaction)
+            .await?
+            .rows_affected();
+        }
+
+        transaction.commit().await?;
+        self.forget_location_index();
+        Ok(assigned)
+    }
+
+    /// Photos taken at a location or anywhere below it in the hierarchy.
+    #[tracing::instrument(name = "db.get_photos_in_location", target = "db", skip_all)]
+    pub async fn get_photos_in_location(&self, id: i32) -> Result<Vec<Photo>> {
+        let response = sqlx::query_as!(
+            Photo,
+            r#"
+                WITH RECURSIVE descendants AS (
+                    SELECT id FROM locations WHERE id = $1
+                    UNION
+                    SELECT l.id FROM locations l JOIN descendants d ON l.parent_id = d.id
+                )
+                SELECT p.id as "id!",
+                    p.title as "title!",
+                    p.filename as "filename!",
+                    p.location_taken as "location_taken!",
+                    p.date_taken as "date_taken!",
+                    p.created_at as "created_at!",
+                    p.updated_at as "updated_at!",
+                    p.latitude,
+                    p.longitude,
+                    p.location_id,
+                    p.perceptual_hash,
+                    p.content_sha256
+                FROM photos p
+                    JOIN descendants d ON d.id = p.location_id
+                ORDER BY p.date_taken DESC, p.id
+            "#,
+            id
+        )
+        .fetch_all(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+
+    /// Adds a job to the queue. Returns `None` when an outstanding job with
+    /// the same `dedupe_key` already exists.
+    #[tracing::instrument(name = "db.enqueue_job", target = "db", skip_all)]
+    pub async fn enqueue_job(
+        &self,
+        kind: &str,
+        payload: &serde_json::Value,
+        dedupe_key: Option<&str>,
+        max_attempts: i32,
+    ) -> Result<Option<JobRecord>> {
+        let response = sqlx::query_as!(
+            JobRecord,
+            r#"
+                INSERT INTO jobs (kind, payload, dedupe_key, max_attempts)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT (dedupe_key) WHERE status IN ('pending', 'running') DO NOTHING
+                RETURNING
+                    id as "id!",
+                    kind as "kind!",
+                    payload as "payload!",
+                    status as "status!",
+                    attempts as "attempts!",
+                    max_attempts as "max_attempts!",
+                    run_at as "run_at!",
+                    locked_until,
+                    locked_by,
+                    last_error,
+                    dedupe_key,
+                    created_at as "created_at!",
+                    updated_at as "updated_at!",
+                    completed_at,
+                    result
+            "#,
+            kind,
+            payload,
+            dedupe_key,
+            max_attempts
+        )
+        .fetch_optional(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+
+    /// Leases the next runnable job to `worker` for `visibility_timeout`
+    /// seconds. Jobs whose lease expired are picked up again, unless they
+    /// have used up their attempts, in which case they are dead-lettered.
+    #[tracing::instrument(name = "db.claim_job", target = "db", skip_all)]
+    pub async fn claim_job(
+        &self,
+        worker: &str,
+        visibility_timeout: f64,
+    ) -> Result<Option<JobRecord>> {
+        sqlx::query!(
+            r#"
+                UPDATE jobs
+                SET status = 'dead',
+                    locked_until = NULL,
+                    locked_by = NULL,
+                    last_error = 'visibility timeout expired on the last attempt',
+                    updated_at = now()
+                WHERE status = 'running'
+                    AND locked_until < now()
+                    AND attempts >= max_attempts
+            "#
+        )
+        .execute(&*self.db_pool)
+        .await?;
+
+        let response = sqlx::query_as!(
+            JobRecord,
+            r#"
+                WITH next AS (
+                    SELECT id
+                    FROM jobs
+                    WHERE (status = 'pending' AND run_at <= now())
+                        OR (status = 'running' AND locked_until < now())
+                    ORDER BY run_at, id
+                    LIMIT 1
+                    FOR UPDATE SKIP LOCKED
+                )
+                UPDATE jobs j
+                SET status = 'running',
+                    attempts = j.attempts + 1,
+                    locked_by = $1,
+                    locked_until = now() + make_interval(secs => $2),
+                    updated_at = now()
+                FROM next
+                WHERE j.id = next.id
+                RETURNING
+                    j.id as "id!",
+                    j.kind as "kind!",
+                    j.payload as "payload!",
+                    j.status as "status!",
+                    j.attempts as "attempts!",
+                    j.max_attempts as "max_attempts!",
+                    j.run_at as "run_at!",
+                    j.locked_until,
+                    j.locked_by,
+                    j.last_error,
+                    j.dedupe_key,
+                    j.created_at as "created_at!",
+                    j.updated_at as "updated_at!",
+                    j.completed_at,
+                    j.result
+            "#,
+            worker,
+            visibility_timeout
+        )
+        .fetch_optional(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+
+    /// Extends `worker`'s lease on a running job by another
+    /// `visibility_timeout` seconds. Returns `false` once the lease is lost,
+    /// i.e. it expired and the job was handed to another worker.
+    #[tracing::instrument(name = "db.renew_job_lease", target = "db", skip_all)]
+    pub async fn renew_job_lease(
+        &self,
+        id: i64,
+        worker: &str,
+        visibility_timeout: f64,
+    ) -> Result<bool> {
+        let response = sqlx::query!(
+            r#"
+                UPDATE jobs
+                SET locked_until = now() + make_interval(secs => $3),
+                    updated_at = now()
+                WHERE id = $1 AND locked_by = $2 AND status = 'running'
+            "#,
+            id,
+            worker,
+            visibility_timeout
+        )
+        .execute(&*self.db_pool)
+        .await?;
+        Ok(response.rows_affected() > 0)
+    }
+
+    #[tracing::instrument(name = "db.complete_job", target = "db", skip_all)]
+    pub async fn complete_job(
+        &self,
+        id: i64,
+        worker: &str,
+        result: Option<serde_json::Value>,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"
+                UPDATE jobs
+                SET status = 'completed',
+                    result = $3,
+                    locked_until = NULL,
+                    locked_by = NULL,
+                    completed_at = now(),
+                    updated_at = now()
+                WHERE id = $1 AND locked_by = $2
+            "#,
+            id,
+            worker,
+            result
+        )
+        .execute(&*self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Records a failed attempt. The job runs again after `retry_in`
+    /// seconds, or is dead-lettered when `retry_in` is `None`.
+    #[tracing::instrument(name = "db.fail_job", target = "db", skip_all)]
+    pub async fn fail_job(
+        &self,
+        id: i64,
+        worker: &str,
+        error: &str,
+        retry_in: Option<f64>,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"
+                UPDATE jobs
+                SET status = CASE WHEN $4::float8 IS NULL THEN 'dead' ELSE 'pending' END,
+                    run_at = CASE WHEN $4::float8 IS NULL THEN run_at ELSE now() + make_interval(secs => $4) END,
+                    last_error = $3,
+                    locked_until = NULL,
+                    locked_by = NULL,
+                    updated_at = now()
+                WHERE id = $1 AND locked_by = $2
+            "#,
+            id,
+            worker,
+            error,
+            retry_in
+        )
+        .execute(&*self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "db.get_jobs", target = "db", skip_all)]
+    pub async fn get_jobs(&self, status: Option<&str>, limit: i64) -> Result<Vec<JobRecord>> {
+        let response = sqlx::query_as!(
+            JobRecord,
+            r#"
+                SELECT
+                    id as "id!",
+                    kind as "kind!",
+                    payload as "payload!",
+                    status as "status!",
+                    attempts as "attempts!",
+                    max_attempts as "max_attempts!",
+                    run_at as "run_at!",
+                    locked_until,
+                    locked_by,
+                    last_error,
+                    dedupe_key,
+                    created_at as "created_at!",
+                    updated_at as "updated_at!",
+                    completed_at,
+                    result
+                FROM jobs
+                WHERE $1::varchar IS NULL OR status = $1
+                ORDER BY id DESC
+                LIMIT $2
+            "#,
+            status,
+            limit
+        )
+        .fetch_all(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+
+    #[tracing::instrument(name = "db.get_job", target = "db", skip_all)]
+    pub async fn get_job(&self, id: i64) -> Result<Option<JobRecord>> {
+        let response = sqlx::query_as!(
+            JobRecord,
+            r#"
+                SELECT
+                    id as "id!",
+                    kind as "kind!",
+                    payload as "payload!",
+                    status as "status!",
+                    attempts as "attempts!",
+                    max_attempts as "max_attempts!",
+                    run_at as "run_at!",
+                    locked_until,
+                    locked_by,
+                    last_error,
+                    dedupe_key,
+                    created_at as "created_at!",
+                    updated_at as "updated_at!",
+                    completed_at,
+                    result
+                FROM jobs
+                WHERE id = $1
+            "#,
+            id
+        )
+        .fetch_optional(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+
+    /// The job holding `dedupe_key`, if it hasn't completed.
+    #[tracing::instrument(name = "db.get_outstanding_job", target = "db", skip_all)]
+    pub async fn get_outstanding_job(&self, dedupe_key: &str) -> Result<Option<JobRecord>> {
+        let response = sqlx::query_as!(
+            JobRecord,
+            r#"
+                SELECT
+                    id as "id!",
+                    kind as "kind!",
+                    payload as "payload!",
+                    status as "status!",
+                    attempts as "attempts!",
+                    max_attempts as "max_attempts!",
+                    run_at as "run_at!",
+                    locked_until,
+                    locked_by,
+                    last_error,
+                    dedupe_key,
+                    created_at as "created_at!",
+                    updated_at as "updated_at!",
+                    completed_at,
+                    result
+                FROM jobs
+                WHERE dedupe_key = $1 AND status IN ('pending', 'running')
+            "#,
+            dedupe_key
+        )
+        .fetch_optional(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+
+    /// Puts a dead or waiting job back at the front of the queue with a
+    /// fresh set of attempts. Returns `None` if the job is running or done,
+    /// and fails with a unique violation if the job is dead and the same
+    /// work has been queued again.
+    #[tracing::instrument(name = "db.retry_job", target = "db", skip_all)]
+    pub async fn retry_job(&self, id: i64) -> Result<Option<JobRecord>> {
+        let response = sqlx::query_as!(
+            JobRecord,
+            r#"
+                UPDATE jobs
+                SET status = 'pending',
+                    attempts = 0,
+                    run_at = now(),
+                    updated_at = now()
+                WHERE id = $1 AND status IN ('dead', 'pending')
+                RETURNING
+                    id as "id!",
+                    kind as "kind!",
+                    payload as "payload!",
+                    status as "status!",
+                    attempts as "attempts!",
+                    max_attempts as "max_attempts!",
+                    run_at as "run_at!",
+                    locked_until,
+                    locked_by,
+                    last_error,
+                    dedupe_key,
+                    created_at as "created_at!",
+                    updated_at as "updated_at!",
+                    completed_at,
+                    result
+            "#,
+            id
+        )
+        .fetch_optional(&*self.db_pool)
+        .await?;
+        Ok(response)
+    }
+}
+
+/// The comma separated parts of a `location_taken` value, most specific
+/// first, with known misspellings fixed.
+fn location_parts(location_taken: &str) -> Vec<String> {
+    let mut normalized = location_taken.trim().to_string();
+    for (from, to) in LOCATION_TAKEN_FIXES {
+        normalized = normalized.replace(from, to);
+    }
+    let mut parts: Vec<String> = normalized
+        .split(',')
+        .map(|x| x.trim().to_string())
+        .collect();
+    if let Some(state) = parts.last_mut() {
+        if let Some((_, name)) = STATE_ABBREVIATIONS
+            .iter()
+            .find(|(abbreviation, _)| state.eq_ignore_ascii_case(abbreviation))
+        {
+            *state = name.to_string();
+        }
+    }
+    parts
+}
+
+/// What a new location made from part `index` of `depth` parts is: the last
+/// part is the state, anything inside a park is a place within it.
+fn location_kind(part: &str, index: usize, depth: usize, parent_kind: &str) -> &'static str {
+    if index == depth - 1 {
+        "state"
+    } else if part.to_lowercase().contains("national park") {
+        "park"
+    } else if matches!(parent_kind, "park" | "place") || (index == 0 && depth > 2) {
+        "place"
+    } else {
+        "city"
+    }
+}
+
+async fn insert_photo(executor: impl PgExecutor<'_>, photo: NewPhoto) -> Result<Photo> {
+    let response = sqlx::query_as!(
+        Photo,
+        r#"
+            INSERT INTO photos (title, filename, location_taken, date_taken, latitude, longitude, location_id, perceptual_hash, content_sha256)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING id as "id!",
+                title as "title!",
+                filename as "filename!",
+                location_taken as "location_taken!",
+                date_taken as "date_taken!",
+                created_at as "created_at!",
+                updated_at as "updated_at!",
+                latitude,
+                longitude,
+                location_id,
+                perceptual_hash,
+                content_sha256
+                
+        "#,
+        photo.title,
+        photo.filename,
+        photo.location_taken,
+        photo.date_taken,
+        photo.coordinates.map(|x| x.latitude),
+        photo.coordinates.map(|x| x.longitude),
+        photo.location_id,
+        photo.perceptual_hash,
+        photo.content_sha256
+    )
+    .fetch_one(executor)
+    .await?;
+    Ok(response)
+}
+
+/// Inserts or replaces the metadata read from a photo's file.
+async fn insert_photo_exif(
+    executor: impl PgExecutor<'_>,
+    photo_id: i32,
+    metadata: &PhotoMetadata,
+) -> Result<PhotoExif> {
+    let response = sqlx::query_as!(
+        PhotoExif,
+        r#"
+            INSERT INTO photo_exif (photo_id, captured_at, camera_make, camera_model, lens_model,
+                exposure_time, f_number, iso, focal_length, latitude, longitude, altitude, caption, raw)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
+            ON CONFLICT (photo_id) DO UPDATE
+            SET captured_at = EXCLUDED.captured_at,
+                camera_make = EXCLUDED.camera_make,
+                camera_model = EXCLUDED.camera_model,
+                lens_model = EXCLUDED.lens_model,
+                exposure_time = EXCLUDED.exposure_time,
+                f_number = EXCLUDED.f_number,
+                iso = EXCLUDED.iso,
+                focal_length = EXCLUDED.focal_length,
+                latitude = EXCLUDED.latitude,
+                longitude = EXCLUDED.longitude,
+                altitude = EXCLUDED.altitude,
+                caption = EXCLUDED.caption,
+                raw = EXCLUDED.raw,
+                updated_at = now()
+            RETURNING photo_id,
+                captured_at,
+                camera_make,
+                camera_model,
+                lens_model,
+                exposure_time,
+                f_number,
+                iso,
+                focal_length,
+                latitude,
+                longitude,
+                altitude,
+                caption,
+                raw,
+                updated_at
+        "#,
+        photo_id,
+        metadata.captured_at,
+        metadata.camera_make,
+        metadata.camera_model,
+        metadata.lens_model,
+        metadata.exposure_time,
+        metadata.f_number,
+        metadata.iso,
+        metadata.focal_length,
+        metadata.latitude,
+        metadata.longitude,
+        metadata.altitude,
+        metadata.caption,
+        metadata.raw
+    )
+    .fetch_one(executor)
+    .await?;
+    Ok(response)
 }
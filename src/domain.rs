@@ -1,33 +1,94 @@
-use crate::{database::PhotoRepository, sessions::SessionManager};
-use anyhow::{Ok, Result};
+use std::sync::Arc;
+
+use crate::{
+    cache::ResponseCache,
+    configuration::{HealthSettings, ImageSettings, RequestLimitSettings},
+    database::PhotoRepository,
+    jobs::JobQueue,
+    rate_limit::RateLimiter,
+    reload::LiveSettings,
+    sessions::SessionManager,
+    shutdown::Shutdown,
+    storage::PhotoStorage,
+    telemetry::HttpClient,
+};
 
 use axum::extract::FromRef;
 use oauth2::basic::BasicClient;
-use reqwest;
 
 #[derive(Debug, Clone)]
 pub struct AppState {
     pub repo: PhotoRepository,
-    pub http_client: reqwest::Client,
+    pub http_client: HttpClient,
     pub oauth_client: BasicClient,
+    pub storage: PhotoStorage,
+    pub jobs: JobQueue,
+    pub image_settings: Arc<ImageSettings>,
+    pub cache: ResponseCache,
+    pub health_settings: Arc<HealthSettings>,
+    pub request_limit_settings: Arc<RequestLimitSettings>,
+    pub shutdown: Shutdown,
+    pub rate_limiter: Option<RateLimiter>,
+    pub live_settings: LiveSettings,
     session_store: SessionManager,
-    bucket_name: String,
 }
 
 impl AppState {
+    /// The rest start out with defaults, which the `with_*` methods
+    /// replace.
     pub fn new(
         repo: PhotoRepository,
         oauth_client: BasicClient,
         session_store: SessionManager,
+        storage: PhotoStorage,
+        jobs: JobQueue,
+        image_settings: Arc<ImageSettings>,
+        cache: ResponseCache,
     ) -> Self {
         Self {
             repo,
-            http_client: reqwest::Client::new(),
+            http_client: HttpClient::default(),
             oauth_client,
-            bucket_name: "photos".into(),
+            storage,
+            jobs,
+            image_settings,
+            cache,
+            health_settings: Arc::new(HealthSettings::default()),
+            request_limit_settings: Arc::new(RequestLimitSettings::default()),
+            shutdown: Shutdown::new(),
+            rate_limiter: None,
+            live_settings: LiveSettings::default(),
             session_store,
         }
     }
+
+    pub fn with_health_settings(mut self, health_settings: Arc<HealthSettings>) -> Self {
+        self.health_settings = health_settings;
+        self
+    }
+
+    pub fn with_request_limit_settings(
+        mut self,
+        request_limit_settings: Arc<RequestLimitSettings>,
+    ) -> Self {
+        self.request_limit_settings = request_limit_settings;
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    pub fn with_live_settings(mut self, live_settings: LiveSettings) -> Self {
+        self.live_settings = live_settings;
+        self
+    }
 }
 
 impl FromRef<AppState> for PhotoRepository {
@@ -36,7 +97,7 @@ impl FromRef<AppState> for PhotoRepository {
     }
 }
 
-impl FromRef<AppState> for reqwest::Client {
+impl FromRef<AppState> for HttpClient {
     fn from_ref(state: &AppState) -> Self {
         state.http_client.clone()
     }
@@ -48,8 +109,32 @@ impl FromRef<AppState> for BasicClient {
     }
 }
 
+impl FromRef<AppState> for PhotoStorage {
+    fn from_ref(state: &AppState) -> Self {
+        state.storage.clone()
+    }
+}
+
 impl FromRef<AppState> for SessionManager {
     fn from_ref(state: &AppState) -> Self {
         state.session_store.clone()
     }
 }
+
+impl FromRef<AppState> for ResponseCache {
+    fn from_ref(state: &AppState) -> Self {
+        state.cache.clone()
+    }
+}
+
+impl FromRef<AppState> for JobQueue {
+    fn from_ref(state: &AppState) -> Self {
+        state.jobs.clone()
+    }
+}
+
+impl FromRef<AppState> for LiveSettings {
+    fn from_ref(state: &AppState) -> Self {
+        state.live_settings.clone()
+    }
+}
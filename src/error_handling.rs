@@ -1,17 +1,24 @@
+use axum::extract::Request;
+use axum::middleware::Next;
 use axum::response::{IntoResponse, Response};
-use hyper::StatusCode;
+use hyper::{header, StatusCode};
+use serde_json::json;
+
+use crate::request_id::current_request_id;
 
 // Make our own error that wraps `anyhow::Error`.
 pub struct AppError(anyhow::Error);
 
-// Tell axum how to convert `AppError` into a response.
+// Tell axum how to convert `AppError` into a response: a problem like
+// every other error, whose request id finds the logged error.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (
+        tracing::error!("Request failed: {:?}", self.0);
+        Problem::new(
             StatusCode::INTERNAL_SERVER_ERROR,
             format!("Something went wrong: {}", self.0),
         )
-            .into_response()
+        .into_response()
     }
 }
 
@@ -25,3 +32,77 @@ where
         Self(err.into())
     }
 }
+
+/// An RFC 9457 problem details response, the shape of every error: raised
+/// outside of the handlers such as timeouts, by `AppError`, or turned from
+/// plain text by `plain_errors_as_problems`.
+#[derive(Debug)]
+pub struct Problem {
+    pub status: StatusCode,
+    pub detail: String,
+}
+
+impl Problem {
+    pub const CONTENT_TYPE: &'static str = "application/problem+json";
+
+    pub fn new(status: StatusCode, detail: impl Into<String>) -> Self {
+        Self {
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let body = json!({
+            "type": "about:blank",
+            "title": self.status.canonical_reason(),
+            "status": self.status.as_u16(),
+            "detail": self.detail,
+            "request_id": current_request_id(),
+        });
+        (
+            self.status,
+            [(header::CONTENT_TYPE, Self::CONTENT_TYPE)],
+            body.to_string(),
+        )
+            .into_response()
+    }
+}
+
+/// Longest plain-text error read into a problem response.
+const MAX_PLAIN_ERROR_BYTES: usize = 64 * 1024;
+
+/// Turns the plain-text errors handlers and extractors answer with into
+/// problem responses, so they carry the request id too. Must run inside
+/// `scope_request_id`.
+pub async fn plain_errors_as_problems(request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+    let is_plain_error = (response.status().is_client_error()
+        || response.status().is_server_error())
+        && response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .is_some_and(|x| x.as_bytes().starts_with(b"text/plain"));
+    if !is_plain_error {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let detail = match axum::body::to_bytes(body, MAX_PLAIN_ERROR_BYTES).await {
+        Ok(body) => String::from_utf8_lossy(&body).into_owned(),
+        Err(e) => {
+            tracing::warn!("Failed to read a {} error body: {:?}", parts.status, e);
+            parts
+                .status
+                .canonical_reason()
+                .unwrap_or_default()
+                .to_owned()
+        }
+    };
+    // headers such as Retry-After are kept
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let problem = Problem::new(parts.status, detail).into_response();
+    parts.headers.extend(problem.headers().clone());
+    Response::from_parts(parts, problem.into_body())
+}
@@ -0,0 +1,340 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::io::Cursor;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use image::codecs::avif::AvifEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use serde::{Deserialize, Serialize};
+
+use crate::configuration::ImageSettings;
+use crate::database::{is_unique_violation, PhotoRepository};
+use crate::models::PhotoVariant;
+use crate::storage::{content_hash, PhotoStorage};
+
+// generated variants live next to the originals, in their own directory
+const VARIANTS_DIR: &str = "variants";
+// 1 (slowest, smallest) to 10 (fastest); encoding runs in the background so
+// favour size, but not so much that a large upload ties up a core for minutes
+const AVIF_SPEED: u8 = 6;
+const DHASH_SIZE: u32 = 8;
+/// Most differing bits that duplicate searches accept. Past this nearly
+/// every pair of photos matches and listing them all gets expensive.
+pub const MAX_DUPLICATE_DISTANCE: i32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VariantFormat {
+    Jpeg,
+    Webp,
+    Avif,
+}
+
+impl VariantFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VariantFormat::Jpeg => "jpeg",
+            VariantFormat::Webp => "webp",
+            VariantFormat::Avif => "avif",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            VariantFormat::Jpeg => "image/jpeg",
+            VariantFormat::Webp => "image/webp",
+            VariantFormat::Avif => "image/avif",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            VariantFormat::Jpeg => "jpg",
+            VariantFormat::Webp => "webp",
+            VariantFormat::Avif => "avif",
+        }
+    }
+
+    /// Rank among formats a client accepts equally: smaller files first when
+    /// the client named the format, JPEG first when it only sent a wildcard.
+    /// WebP variants are lossless, so they are larger than JPEG ones.
+    fn preference(&self, explicit: bool) -> u8 {
+        match (self, explicit) {
+            (VariantFormat::Avif, true) | (VariantFormat::Jpeg, false) => 2,
+            (VariantFormat::Jpeg, true) | (VariantFormat::Webp, false) => 1,
+            _ => 0,
+        }
+    }
+
+    fn encode(&self, image: &DynamicImage, quality: u8) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        match self {
+            VariantFormat::Jpeg => image
+                .to_rgb8()
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, quality))?,
+            VariantFormat::Webp => image
+                .to_rgba8()
+                .write_with_encoder(WebPEncoder::new_lossless(&mut bytes))?,
+            VariantFormat::Avif => {
+                image
+                    .to_rgba8()
+                    .write_with_encoder(AvifEncoder::new_with_speed_quality(
+                        &mut bytes, AVIF_SPEED, quality,
+                    ))?
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+impl fmt::Display for VariantFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for VariantFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "jpeg" => Ok(VariantFormat::Jpeg),
+            "webp" => Ok(VariantFormat::Webp),
+            "avif" => Ok(VariantFormat::Avif),
+            _ => Err(anyhow!("unknown image format {}", s)),
+        }
+    }
+}
+
+/// A variant rendered in memory, before it is written to storage.
+#[derive(Debug)]
+pub struct EncodedVariant {
+    pub name: String,
+    pub format: VariantFormat,
+    pub width: u32,
+    pub height: u32,
+    pub bytes: Vec<u8>,
+}
+
+pub fn variant_storage(storage: &PhotoStorage) -> Result<PhotoStorage> {
+    storage.child(VARIANTS_DIR)
+}
+
+/// Renders, stores and records every configured variant of a photo,
+/// replacing any generated earlier.
+pub async fn process_photo(
+    repo: &PhotoRepository,
+    storage: &PhotoStorage,
+    settings: Arc<ImageSettings>,
+    photo_id: i32,
+) -> Result<usize> {
+    let photo = repo.get_photo(photo_id).await?;
+    let original = storage
+        .get(&photo.filename)
+        .await
+        .with_context(|| format!("failed to read {}", photo.filename))?;
+    let (original, hash, variants) = tokio::task::spawn_blocking(move || {
+        let image = decode_image(&original)?;
+        let hash = perceptual_hash(&image);
+        anyhow::Ok((original, hash, render_variants(&image, &settings)?))
+    })
+    .await??;
+    if photo.perceptual_hash != Some(hash) {
+        repo.set_photo_perceptual_hash(photo.id, hash).await?;
+    }
+    if photo.content_sha256.is_none() {
+        record_content_hash(repo, photo.id, content_hash(&original)).await?;
+    }
+
+    let variant_storage = variant_storage(storage)?;
+    for variant in &variants {
+        let key = format!(
+            "{}-{}.{}",
+            photo.id,
+            variant.name,
+            variant.format.extension()
+        );
+        variant_storage.put(&key, &variant.bytes).await?;
+        repo.upsert_photo_variant(photo.id, variant, &key).await?;
+    }
+    Ok(variants.len())
+}
+
+/// Records the content hash of a photo uploaded before hashes were kept.
+/// Byte-identical copies uploaded back then can't share the hash, so only
+/// the first keeps it and the others are marked as its duplicates.
+pub async fn record_content_hash(
+    repo: &PhotoRepository,
+    photo_id: i32,
+    content_sha256: String,
+) -> Result<bool> {
+    // a copy hashed concurrently may take the hash first, which only the
+    // unique index can tell
+    match repo.set_photo_content_hash(photo_id, &content_sha256).await {
+        Ok(()) => Ok(true),
+        Err(e) if is_unique_violation(&e) => {
+            if let Some(existing) = repo
+                .set_photo_content_duplicate(photo_id, &content_sha256)
+                .await?
+            {
+                tracing::warn!(
+                    "photo {} has the same contents as photo {}",
+                    photo_id,
+                    existing
+                );
+            }
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Decodes an original and applies its EXIF orientation.
+pub fn decode_image(original: &[u8]) -> Result<DynamicImage> {
+    let mut decoder = ImageReader::new(Cursor::new(original))
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+/// dHash: shrinks the image to 9x8 greyscale and sets one bit per pixel
+/// that is darker than its right-hand neighbour. Resized, recompressed or
+/// slightly edited copies of a photo differ in only a few bits.
+pub fn perceptual_hash(image: &DynamicImage) -> i64 {
+    let small = image
+        .resize_exact(DHASH_SIZE + 1, DHASH_SIZE, FilterType::Triangle)
+        .to_luma8();
+    let mut hash: u64 = 0;
+    for y in 0..DHASH_SIZE {
+        for x in 0..DHASH_SIZE {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    // stored in a bigint column; only the bits matter
+    hash as i64
+}
+
+/// Encodes an image at each configured width and format. Images are never
+/// upscaled.
+pub fn render_variants(
+    image: &DynamicImage,
+    settings: &ImageSettings,
+) -> Result<Vec<EncodedVariant>> {
+    let mut variants = Vec::new();
+    for variant in &settings.variants {
+        let resized = if variant.width < image.width() {
+            Cow::Owned(image.resize(variant.width, u32::MAX, FilterType::Lanczos3))
+        } else {
+            Cow::Borrowed(image)
+        };
+        for format in &settings.formats {
+            variants.push(EncodedVariant {
+                name: variant.name.clone(),
+                format: *format,
+                width: resized.width(),
+                height: resized.height(),
+                bytes: format.encode(&resized, settings.quality)?,
+            });
+        }
+    }
+    Ok(variants)
+}
+
+/// Content type of an original, judged by its file extension.
+pub fn original_content_type(filename: &str) -> &'static str {
+    ImageFormat::from_path(filename)
+        .map(|x| x.to_mime_type())
+        .unwrap_or("application/octet-stream")
+}
+
+/// Picks the variant to serve: the requested variant (or any, if none was
+/// named) in the format the client prefers, at the smallest width that is at
+/// least `width`, falling back to the largest available.
+pub fn best_variant<'a>(
+    variants: &'a [PhotoVariant],
+    name: Option<&str>,
+    width: Option<u32>,
+    accept: Option<&str>,
+) -> Option<&'a PhotoVariant> {
+    let candidates: Vec<&PhotoVariant> = variants
+        .iter()
+        .filter(|x| name.is_none_or(|name| x.variant == name))
+        .collect();
+    let mut formats: Vec<VariantFormat> = Vec::new();
+    for format in candidates.iter().filter_map(|x| x.format.parse().ok()) {
+        if !formats.contains(&format) {
+            formats.push(format);
+        }
+    }
+    let format = negotiate_format(accept, &formats)?;
+
+    let mut candidates = candidates
+        .into_iter()
+        .filter(|x| x.format == format.as_str())
+        .collect::<Vec<_>>();
+    candidates.sort_by_key(|x| x.width);
+    match width {
+        Some(width) => candidates
+            .iter()
+            .find(|x| x.width >= width as i32)
+            .or(candidates.last())
+            .copied(),
+        None => candidates.last().copied(),
+    }
+}
+
+/// Chooses among `available` using an HTTP `Accept` header, honouring
+/// q-values and the most specific matching media range. A missing header
+/// accepts anything.
+pub fn negotiate_format(
+    accept: Option<&str>,
+    available: &[VariantFormat],
+) -> Option<VariantFormat> {
+    let accept = accept.unwrap_or("*/*");
+    available
+        .iter()
+        .filter_map(|format| {
+            let (quality, specificity) = accept_quality(accept, format.mime_type())?;
+            (quality > 0).then_some((
+                (quality, specificity, format.preference(specificity == 2)),
+                *format,
+            ))
+        })
+        .max_by_key(|(rank, _)| *rank)
+        .map(|(_, format)| format)
+}
+
+/// q-value in thousandths and specificity (2 exact, 1 `image/*`, 0 `*/*`)
+/// of the most specific media range in `accept` matching `mime`.
+fn accept_quality(accept: &str, mime: &str) -> Option<(u16, u8)> {
+    let mut best: Option<(u16, u8)> = None;
+    for range in accept.split(',') {
+        let mut parts = range.split(';');
+        let media = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let specificity = match media.as_str() {
+            x if x == mime => 2,
+            "image/*" => 1,
+            "*/*" => 0,
+            _ => continue,
+        };
+        let quality = parts
+            .find_map(|x| x.trim().strip_prefix("q="))
+            .and_then(|x| x.trim().parse::<f32>().ok())
+            .map_or(1000, |x| (x.clamp(0.0, 1.0) * 1000.0) as u16);
+        if best.is_none_or(|(_, current)| specificity > current) {
+            best = Some((quality, specificity));
+        }
+    }
+    best
+}
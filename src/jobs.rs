@@ -0,0 +1,486 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{info, Instrument};
+use uuid::Uuid;
+
+use crate::cache::{CacheTag, ResponseCache};
+use crate::cloudflare::CloudflareImages;
+use crate::configuration::{ImageSettings, JobSettings, LocationSettings};
+use crate::database::PhotoRepository;
+use crate::images::{process_photo, record_content_hash};
+use crate::models::JobRecord;
+use crate::shutdown::Shutdown;
+use crate::storage::PhotoStorage;
+
+/// Work that runs outside of a request. Serialized into `jobs.payload`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Job {
+    GenerateVariants {
+        photo_id: i32,
+    },
+    VerifyStorage,
+    /// Copies a photo's original to Cloudflare Images.
+    SyncCloudflare {
+        photo_id: i32,
+    },
+    /// Deletes an image of a deleted photo from Cloudflare Images.
+    PurgeCloudflare {
+        resource_id: Uuid,
+    },
+    /// Places photos without a location into the location hierarchy, only
+    /// the given one if `photo_id` is set. Queued by the migration that
+    /// added locations, after uploads and after imports.
+    AssignLocations {
+        photo_id: Option<i32>,
+    },
+}
+
+impl Job {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Job::GenerateVariants { .. } => "generate_variants",
+            Job::VerifyStorage => "verify_storage",
+            Job::SyncCloudflare { .. } => "sync_cloudflare",
+            Job::PurgeCloudflare { .. } => "purge_cloudflare",
+            Job::AssignLocations { .. } => "assign_locations",
+        }
+    }
+
+    /// Jobs with the same key are only queued once while outstanding.
+    pub fn dedupe_key(&self) -> Option<String> {
+        match self {
+            Job::GenerateVariants { photo_id } => Some(format!("generate_variants:{}", photo_id)),
+            Job::VerifyStorage => Some("verify_storage".into()),
+            Job::SyncCloudflare { photo_id } => Some(format!("sync_cloudflare:{}", photo_id)),
+            Job::PurgeCloudflare { resource_id } => {
+                Some(format!("purge_cloudflare:{}", resource_id))
+            }
+            Job::AssignLocations { photo_id: None } => Some("assign_locations".into()),
+            Job::AssignLocations {
+                photo_id: Some(photo_id),
+            } => Some(format!("assign_locations:{}", photo_id)),
+        }
+    }
+
+    /// Runs the job, returning what it found if it reports anything.
+    async fn run(self, context: &JobContext) -> Result<Option<serde_json::Value>> {
+        match self {
+            Job::GenerateVariants { photo_id } => {
+                let count = process_photo(
+                    &context.repo,
+                    &context.storage,
+                    context.image_settings.clone(),
+                    photo_id,
+                )
+                .await?;
+                info!("generated {} variants of photo {}", count, photo_id);
+                Ok(None)
+            }
+            Job::VerifyStorage => {
+                let report = verify_storage(context).await?;
+                info!(
+                    "verified {} photos: {} missing, {} corrupted, {} unreadable",
+                    report.checked,
+                    report.missing.len(),
+                    report.corrupted.len(),
+                    report.unreadable.len()
+                );
+                Ok(Some(serde_json::to_value(report)?))
+            }
+            Job::SyncCloudflare { photo_id } => sync_cloudflare(context, photo_id).await,
+            Job::PurgeCloudflare { resource_id } => {
+                context.cloudflare()?.delete(resource_id).await?;
+                info!("deleted image {} from Cloudflare", resource_id);
+                Ok(None)
+            }
+            Job::AssignLocations { photo_id } => {
+                let assigned = context
+                    .repo
+                    .assign_locations(&context.location_settings.country, photo_id)
+                    .await?;
+                if assigned > 0 {
+                    context
+                        .cache
+                        .invalidate(&[CacheTag::Photos, CacheTag::Locations])
+                        .await;
+                }
+                info!("assigned locations to {} photos", assigned);
+                Ok(Some(json!({ "assigned": assigned })))
+            }
+        }
+    }
+}
+
+/// Uploads the photo unless it already has a Cloudflare image. The upload
+/// is deleted again if it can't be recorded, so a retry doesn't leave a
+/// second copy behind.
+async fn sync_cloudflare(context: &JobContext, photo_id: i32) -> Result<Option<serde_json::Value>> {
+    let cloudflare = context.cloudflare()?;
+    if let Some(resource_id) = context.repo.get_cloudflare_resource(photo_id).await? {
+        info!("photo {} is already image {}", photo_id, resource_id);
+        return Ok(Some(json!({ "resource_id": resource_id })));
+    }
+    let photo = match context.repo.get_photo(photo_id).await {
+        Ok(photo) => photo,
+        Err(e)
+            if matches!(
+                e.downcast_ref::<sqlx::Error>(),
+                Some(sqlx::Error::RowNotFound)
+            ) =>
+        {
+            info!("photo {} was deleted before it was synced", photo_id);
+            return Ok(None);
+        }
+        Err(e) => return Err(e),
+    };
+    let bytes = context.storage.get(&photo.filename).await?;
+    let resource_id = cloudflare.upload(&photo.filename, bytes).await?;
+    if let Err(e) = context
+        .repo
+        .add_cloudflare_resource(photo_id, resource_id)
+        .await
+    {
+        if let Err(e) = cloudflare.delete(resource_id).await {
+            tracing::error!("Failed to delete unrecorded image {}: {:?}", resource_id, e);
+        }
+        return Err(e);
+    }
+    info!("uploaded photo {} as image {}", photo_id, resource_id);
+    Ok(Some(json!({ "resource_id": resource_id })))
+}
+
+/// Findings of a [`Job::VerifyStorage`] run, stored as the job's result.
+#[derive(Debug, Default, Serialize)]
+pub struct StorageReport {
+    pub checked: usize,
+    /// photos that had no content hash yet and got one recorded
+    pub recorded: usize,
+    pub missing: Vec<MissingObject>,
+    pub corrupted: Vec<CorruptedObject>,
+    /// photos whose original couldn't be read, e.g. for lack of permission
+    pub unreadable: Vec<UnreadableObject>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MissingObject {
+    pub photo_id: i32,
+    pub filename: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CorruptedObject {
+    pub photo_id: i32,
+    pub filename: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UnreadableObject {
+    pub photo_id: i32,
+    pub filename: String,
+    pub error: String,
+}
+
+/// Re-hashes every stored original and compares it with the recorded hash.
+/// A file that can't be read is reported rather than ending the run.
+async fn verify_storage(context: &JobContext) -> Result<StorageReport> {
+    let mut report = StorageReport::default();
+    for photo in context.repo.get_photos().await? {
+        report.checked += 1;
+        let actual = match context.storage.exists(&photo.filename).await {
+            Ok(false) => {
+                report.missing.push(MissingObject {
+                    photo_id: photo.id,
+                    filename: photo.filename,
+                });
+                continue;
+            }
+            Ok(true) => context.storage.sha256(&photo.filename).await,
+            Err(e) => Err(e),
+        };
+        let actual = match actual {
+            Ok(actual) => actual,
+            Err(e) => {
+                tracing::warn!("Failed to read {}: {:?}", photo.filename, e);
+                report.unreadable.push(UnreadableObject {
+                    photo_id: photo.id,
+                    filename: photo.filename,
+                    error: format!("{:#}", e),
+                });
+                continue;
+            }
+        };
+        match photo.content_sha256 {
+            Some(expected) if expected != actual => {
+                report.corrupted.push(CorruptedObject {
+                    photo_id: photo.id,
+                    filename: photo.filename,
+                    expected,
+                    actual,
+                });
+            }
+            Some(_) => {}
+            None => {
+                if record_content_hash(&context.repo, photo.id, actual).await? {
+                    report.recorded += 1;
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Everything a job may need while running.
+#[derive(Debug, Clone)]
+pub struct JobContext {
+    pub repo: PhotoRepository,
+    pub storage: PhotoStorage,
+    pub image_settings: Arc<ImageSettings>,
+    pub location_settings: Arc<LocationSettings>,
+    /// responses showing what a job changed are dropped from it
+    pub cache: ResponseCache,
+    /// `None` unless `cloudflare_settings` are configured
+    pub cloudflare: Option<CloudflareImages>,
+}
+
+impl JobContext {
+    fn cloudflare(&self) -> Result<&CloudflareImages> {
+        self.cloudflare
+            .as_ref()
+            .context("cloudflare_settings aren't configured")
+    }
+}
+
+/// Handle for adding jobs to the Postgres-backed queue.
+#[derive(Debug, Clone)]
+pub struct JobQueue {
+    repo: PhotoRepository,
+    max_attempts: i32,
+    cloudflare: bool,
+}
+
+impl JobQueue {
+    pub fn new(repo: PhotoRepository, settings: &JobSettings) -> Self {
+        Self {
+            repo,
+            max_attempts: settings.max_attempts,
+            cloudflare: false,
+        }
+    }
+
+    /// Whether photos are copied to Cloudflare Images, which the workers
+    /// can only do with `cloudflare_settings`.
+    pub fn with_cloudflare(mut self, cloudflare: bool) -> Self {
+        self.cloudflare = cloudflare;
+        self
+    }
+
+    pub fn syncs_cloudflare(&self) -> bool {
+        self.cloudflare
+    }
+
+    /// Queues `job`, or returns `None` if an identical job is outstanding.
+    pub async fn enqueue(&self, job: Job) -> Result<Option<JobRecord>> {
+        let payload = serde_json::to_value(&job)?;
+        self.repo
+            .enqueue_job(
+                job.kind(),
+                &payload,
+                job.dedupe_key().as_deref(),
+                self.max_attempts,
+            )
+            .await
+    }
+
+    /// Queues processing for every photo that is missing variants or a
+    /// hash, e.g. photos added before either existed, and copying of those
+    /// missing from Cloudflare Images.
+    pub async fn enqueue_unprocessed_photos(&self) -> Result<usize> {
+        let mut jobs: Vec<Job> = self
+            .repo
+            .get_unprocessed_photo_ids()
+            .await?
+            .into_iter()
+            .map(|photo_id| Job::GenerateVariants { photo_id })
+            .collect();
+        if self.cloudflare {
+            let unsynced = self.repo.get_unsynced_photo_ids().await?;
+            jobs.extend(
+                unsynced
+                    .into_iter()
+                    .map(|photo_id| Job::SyncCloudflare { photo_id }),
+            );
+        }
+        let mut queued = 0;
+        for job in jobs {
+            if self.enqueue(job).await?.is_some() {
+                queued += 1;
+            }
+        }
+        Ok(queued)
+    }
+
+    /// Queues what an imported catalog leaves to the workers: the variants
+    /// and hashes that aren't exported, and placing photos exported before
+    /// locations existed.
+    pub async fn enqueue_after_import(&self) -> Result<usize> {
+        let mut queued = self.enqueue_unprocessed_photos().await?;
+        if self
+            .enqueue(Job::AssignLocations { photo_id: None })
+            .await?
+            .is_some()
+        {
+            queued += 1;
+        }
+        Ok(queued)
+    }
+}
+
+/// Pulls jobs off the queue and runs them, one at a time.
+pub struct Worker {
+    name: String,
+    context: JobContext,
+    settings: JobSettings,
+}
+
+impl Worker {
+    pub fn new(name: String, context: JobContext, settings: JobSettings) -> Self {
+        Self {
+            name,
+            context,
+            settings,
+        }
+    }
+
+    /// Starts `settings.workers` workers on the current runtime. They stop
+    /// claiming jobs once `shutdown` is triggered and exit after finishing
+    /// the one they're running.
+    pub fn spawn_all(
+        context: JobContext,
+        settings: &JobSettings,
+        shutdown: &Shutdown,
+    ) -> Vec<tokio::task::JoinHandle<()>> {
+        let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".into());
+        (0..settings.workers)
+            .map(|n| {
+                let name = format!("{}:{}:{}", host, std::process::id(), n);
+                let worker = Worker::new(name, context.clone(), settings.clone());
+                tokio::spawn(worker.run(shutdown.clone()))
+            })
+            .collect()
+    }
+
+    pub async fn run(self, shutdown: Shutdown) {
+        info!("worker {} started", self.name);
+        let poll_interval = Duration::from_millis(self.settings.poll_interval_ms);
+        let idle = || async {
+            tokio::select! {
+                _ = tokio::time::sleep(poll_interval) => {}
+                _ = shutdown.wait() => {}
+            }
+        };
+        while !shutdown.is_triggered() {
+            match self
+                .context
+                .repo
+                .claim_job(&self.name, self.settings.visibility_timeout_secs as f64)
+                .await
+            {
+                Ok(Some(record)) => self.execute(record).await,
+                Ok(None) => idle().await,
+                Err(e) => {
+                    tracing::error!("Failed to claim job: {:?}", e);
+                    idle().await;
+                }
+            }
+        }
+        info!("worker {} stopped", self.name);
+    }
+
+    async fn execute(&self, record: JobRecord) {
+        let span = tracing::info_span!(
+            "job",
+            id = record.id,
+            kind = %record.kind,
+            attempt = record.attempts,
+        );
+        async {
+            // the lease is renewed for as long as the job runs; once it's
+            // lost another worker may pick the job up, so it stops here
+            let result = match serde_json::from_value::<Job>(record.payload.clone())
+                .context("failed to parse job payload")
+            {
+                Ok(job) => tokio::select! {
+                    result = job.run(&self.context) => result,
+                    e = self.hold_lease(record.id) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+
+            let outcome = match result {
+                Ok(output) => {
+                    info!("job completed");
+                    self.context
+                        .repo
+                        .complete_job(record.id, &self.name, output)
+                        .await
+                }
+                Err(e) => {
+                    let retry_in = (record.attempts < record.max_attempts)
+                        .then(|| self.settings.backoff(record.attempts));
+                    match retry_in {
+                        Some(delay) => {
+                            tracing::warn!("job failed, retrying in {:?}: {:?}", delay, e)
+                        }
+                        None => tracing::error!("job failed, moving to dead letter: {:?}", e),
+                    }
+                    self.context
+                        .repo
+                        .fail_job(
+                            record.id,
+                            &self.name,
+                            &format!("{:#}", e),
+                            retry_in.map(|x| x.as_secs_f64()),
+                        )
+                        .await
+                }
+            };
+            if let Err(e) = outcome {
+                tracing::error!("Failed to record job outcome: {:?}", e);
+            }
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Renews the lease on job `id` three times per visibility timeout.
+    /// Only returns once the lease is lost, or would expire before it can
+    /// be renewed again.
+    async fn hold_lease(&self, id: i64) -> anyhow::Error {
+        let timeout = Duration::from_secs(self.settings.visibility_timeout_secs);
+        let interval = timeout / 3;
+        let mut renewed = Instant::now();
+        loop {
+            tokio::time::sleep(interval).await;
+            match self
+                .context
+                .repo
+                .renew_job_lease(id, &self.name, timeout.as_secs_f64())
+                .await
+            {
+                Ok(true) => renewed = Instant::now(),
+                Ok(false) => return anyhow::anyhow!("lost the lease to another worker"),
+                Err(e) if renewed.elapsed() + interval >= timeout => {
+                    return e.context("failed to renew the lease before it expired");
+                }
+                Err(e) => tracing::warn!("Failed to renew job lease: {:?}", e),
+            }
+        }
+    }
+}
@@ -1,38 +1,73 @@
 mod app;
 pub mod auth;
+pub mod cache;
+pub mod cloudflare;
 pub mod configuration;
 pub mod database;
 pub mod domain;
 pub mod error_handling;
+pub mod images;
+pub mod jobs;
+pub mod metadata;
 pub mod models;
+pub mod rate_limit;
+pub mod reload;
+pub mod request_id;
+pub mod request_limits;
 pub mod routes;
+pub mod secrets;
 pub mod sessions;
-use anyhow::Result;
-pub use app::app;
-use configuration::DatabaseSettings;
-use sqlx::postgres::{PgConnectOptions, PgPoolOptions, PgSslMode};
+pub mod shutdown;
+pub mod storage;
+pub mod telemetry;
+pub mod tls;
+pub use app::{app, cors_layer};
+use anyhow::{Context, Result};
+use configuration::{DatabaseSettings, TracingSettings};
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
 use sqlx::PgPool;
+use telemetry::LogFilter;
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
+use tracing_subscriber::filter::filter_fn;
 use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Layer;
 use tracing_subscriber::{EnvFilter, Registry};
 
-pub fn setup_logging() {
-    let formatting_layer = BunyanFormattingLayer::new("enchanted_natures".into(), std::io::stdout);
+// spans around every query and Redis call go to the collector, not the logs
+const CLIENT_SPAN_TARGETS: [&str; 2] = ["db", "redis"];
+
+/// Installs the global subscriber, logging what `RUST_LOG` or, if that's
+/// unset, `log_level` lets through. The returned handle changes the filter
+/// later on.
+pub fn setup_logging(tracing_settings: &TracingSettings, log_level: &str) -> Result<LogFilter> {
+    let formatting_layer = BunyanFormattingLayer::new("enchanted_natures".into(), std::io::stdout)
+        .with_filter(filter_fn(|metadata| {
+            !(metadata.is_span() && CLIENT_SPAN_TARGETS.contains(&metadata.target()))
+        }));
+    let otel_layer = telemetry::init_tracer(tracing_settings)?
+        .map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
+    let filter = match std::env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(directives) if !directives.trim().is_empty() => EnvFilter::try_new(&directives)
+            .with_context(|| format!("Invalid {} {:?}", EnvFilter::DEFAULT_ENV, directives))?,
+        _ => EnvFilter::new(log_level),
+    };
+    let (filter, handle) = tracing_subscriber::reload::Layer::new(filter);
     let subscriber = Registry::default()
+        .with(filter)
         .with(JsonStorageLayer)
-        .with(EnvFilter::new("info"))
-        .with(formatting_layer);
+        .with(formatting_layer)
+        .with(otel_layer);
 
-    tracing::subscriber::set_global_default(subscriber).unwrap();
+    tracing::subscriber::set_global_default(subscriber)?;
+    Ok(LogFilter::new(handle))
 }
 
-pub async fn connect_database(settings: DatabaseSettings) -> PgPool {
-    let options = settings.into();
-    PgPoolOptions::new()
-        .max_connections(5)
+pub async fn connect_database(settings: DatabaseSettings) -> Result<PgPool> {
+    let options = PgConnectOptions::try_from(&settings).context("Invalid database settings")?;
+    PgPoolOptions::from(&settings.pool)
         .connect_with(options)
         .await
-        .expect("can't connect to database")
+        .context("Can't connect to the database")
 }
 
 // pub fn check_env() -> Result<()> {
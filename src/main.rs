@@ -2,28 +2,158 @@
 
 use api::app;
 use api::auth::create_oauth_client;
-use api::configuration::Settings;
+use api::cache::{CacheTag, ResponseCache};
+use api::cloudflare::CloudflareImages;
+use api::configuration::{CacheSettings, JobSettings, Settings};
 use api::connect_database;
-use api::database::PhotoRepository;
+use api::database::{CatalogNotEmptyError, PhotoRepository};
 use api::domain::AppState;
+use api::jobs::{JobContext, JobQueue, Worker};
+use api::models::{write_catalog, CatalogExport, JsonCatalogWriter, ZipCatalogWriter};
+use api::rate_limit::RateLimiter;
+use api::reload::LiveSettings;
+use api::secrets::Secret;
 use api::sessions::SessionManager;
 use api::setup_logging;
+use api::shutdown::{self, Shutdown};
+use api::storage::PhotoStorage;
+use api::telemetry::{install_recorder, metrics_router, shutdown_tracer, HttpClient, LogFilter};
+use api::tls;
 
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use clap::{Args, Parser, Subcommand};
 use sqlx::PgPool;
+use std::io::BufWriter;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::net::TcpListener;
-use tower_http::trace::TraceLayer;
+use tracing::info;
 
 use utoipa_swagger_ui::{Config, SwaggerUi};
 
+#[derive(Debug, Parser)]
+#[command(version, about = "Enchanted Natures photo gallery API")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Serve the HTTP API along with `job_settings.workers` job workers (default)
+    Serve(StartArgs),
+    /// Only run job workers, for deployments that scale them separately
+    Worker(StartArgs),
+    /// Apply, revert or list database migrations
+    Migrate {
+        #[command(subcommand)]
+        action: MigrateAction,
+    },
+    /// Load a catalog export into an empty database, does nothing if it
+    /// already has photos or categories
+    Seed {
+        /// Catalog export, JSON or a zip archive
+        file: PathBuf,
+    },
+    /// Add a photo category and print its id
+    CreateCategory { name: String },
+    /// Load a catalog export, like `POST /import`
+    Import {
+        /// Catalog export, JSON or a zip archive
+        file: PathBuf,
+        /// Replace the existing catalog instead of refusing to import into a
+        /// database that has photos or categories
+        #[arg(long)]
+        replace: bool,
+    },
+    /// Write the catalog to a file, as a zip archive if its name ends in `.zip`
+    Export { file: PathBuf },
+    /// Load and validate the configuration, print it with secrets redacted
+    /// and exit non-zero if it's invalid
+    CheckConfig,
+}
+
+#[derive(Debug, Default, Args)]
+struct StartArgs {
+    /// Apply pending migrations before starting, instead of refusing to start
+    /// until `migrate up` has been run
+    #[arg(long)]
+    migrate: bool,
+}
+
+#[derive(Debug, Subcommand)]
+enum MigrateAction {
+    /// Apply pending migrations
+    Up,
+    /// Revert the latest migration, or every migration newer than `--to`
+    Down {
+        #[arg(long)]
+        to: Option<i64>,
+    },
+    /// List migrations and whether they've been applied
+    Status,
+}
+
 #[tokio::main]
 async fn main() {
-    setup_logging();
+    let cli = Cli::parse();
+    // logging is configured by the settings, so this can't be logged
+    let settings = match Settings::load_config().await {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Invalid configuration: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let command = cli.command.unwrap_or(Command::Serve(StartArgs::default()));
+    // check-config's output shouldn't be mixed with log lines
+    let log_filter = match (!matches!(command, Command::CheckConfig))
+        .then(|| setup_logging(&settings.tracing_settings, &settings.dynamic.log_level))
+        .transpose()
+    {
+        Ok(log_filter) => log_filter,
+        Err(e) => {
+            eprintln!("Failed to set up logging: {:#}", e);
+            std::process::exit(1);
+        }
+    };
     // check_env().expect("Environment Variable must be set");
 
-    let settings = Settings::load_config().unwrap();
+    let result = match command {
+        Command::Serve(args) => serve(settings, args, log_filter).await,
+        Command::Worker(args) => worker(settings, args, log_filter).await,
+        Command::Migrate { action } => migrate(settings, action).await,
+        Command::Seed { file } => seed(settings, &file).await,
+        Command::CreateCategory { name } => create_category(settings, name).await,
+        Command::Import { file, replace } => import(settings, &file, replace).await,
+        Command::Export { file } => export(settings, &file).await,
+        Command::CheckConfig => {
+            println!("{:#?}", settings);
+            println!("Configuration is valid");
+            Ok(())
+        }
+    };
+    // logged to stdout, which doesn't depend on the tracer
+    if let Err(e) = shutdown_tracer() {
+        tracing::error!("Failed to flush spans: {:?}", e);
+    }
+    if let Err(e) = result {
+        tracing::error!("Command failed: {:?}", e);
+        eprintln!("Error: {:#}", e);
+        std::process::exit(1);
+    }
+}
 
-    let pool: PgPool = connect_database(settings.database_settings).await;
+async fn serve(settings: Settings, args: StartArgs, log_filter: Option<LogFilter>) -> Result<()> {
+    let shutdown = Shutdown::new();
+    shutdown.trigger_on_signal();
+    let live_settings = LiveSettings::new(settings.dynamic, log_filter)?
+        .with_static_settings(settings.static_settings);
+    live_settings.watch(&settings.reload_settings, shutdown.clone());
+    let metrics = install_recorder().context("Failed to install the metrics recorder")?;
+    let pool: PgPool = connect_database(settings.database_settings).await?;
 
     // let config = aws_config::from_env()
     //     .endpoint_url(&settings.aws_endpoint_url)
@@ -32,23 +162,340 @@ async fn main() {
     //     .await;
 
     // let s3_client = aws_sdk_s3::Client::new(&config);
-    let oauth_client = create_oauth_client(settings.auth_settings).unwrap();
-    let session_manager = SessionManager::new(redis::Client::open(settings.redis_url).unwrap());
+    let oauth_client =
+        create_oauth_client(settings.auth_settings).context("Invalid OAuth settings")?;
+    // the URL may hold a password, so only the error is reported
+    let redis = redis::Client::open(settings.redis_url.expose()).context("Invalid redis_url")?;
+    let session_manager = SessionManager::new(redis.clone());
+    let rate_limiter = RateLimiter::new(redis.clone());
+    let cache = ResponseCache::new(redis, settings.cache_settings);
 
     let photo_repo = PhotoRepository::new(pool.clone());
-    photo_repo.migrate().await.unwrap();
-    let app_state = AppState::new(photo_repo, oauth_client, session_manager);
+    check_migrations(&photo_repo, &args).await?;
+    let storage = PhotoStorage::new(settings.storage_settings.path);
+
+    let cloudflare = settings
+        .cloudflare_settings
+        .map(|x| CloudflareImages::new(HttpClient::default(), x));
+    let jobs = JobQueue::new(photo_repo.clone(), &settings.job_settings)
+        .with_cloudflare(cloudflare.is_some());
+    match jobs.enqueue_unprocessed_photos().await {
+        Ok(count) => info!("queued processing of {} photos", count),
+        Err(e) => tracing::error!("Failed to queue unprocessed photos: {:?}", e),
+    }
+    let image_settings = Arc::new(settings.image_settings);
+    let job_context = JobContext {
+        repo: photo_repo.clone(),
+        storage: storage.clone(),
+        image_settings: image_settings.clone(),
+        location_settings: Arc::new(settings.location_settings),
+        cache: cache.clone(),
+        cloudflare,
+    };
+    let workers = Worker::spawn_all(job_context, &settings.job_settings, &shutdown);
+
+    let app_state = AppState::new(
+        photo_repo,
+        oauth_client,
+        session_manager,
+        storage,
+        jobs,
+        image_settings,
+        cache,
+    )
+    .with_health_settings(Arc::new(settings.health_settings))
+    .with_request_limit_settings(Arc::new(settings.request_limit_settings))
+    .with_shutdown(shutdown.clone())
+    .with_rate_limiter(rate_limiter)
+    .with_live_settings(live_settings);
     let swagger_config = Config::from("/enchanted-natures.openapi.spec.yaml");
     let swagger_ui = SwaggerUi::new("/swagger-ui").config(swagger_config);
     let app = app(swagger_ui, app_state);
 
+    let metrics_addr = SocketAddr::from((
+        settings.app_settings.metrics_addr,
+        settings.app_settings.metrics_port,
+    ));
+    let metrics_listener = TcpListener::bind(metrics_addr)
+        .await
+        .with_context(|| format!("Failed to listen for metrics on {}", metrics_addr))?;
+    info!("serving metrics on {}", metrics_addr);
+    let metrics_server = tokio::spawn(shutdown::serve(
+        metrics_listener,
+        metrics_router(metrics, pool.clone()),
+        shutdown.clone(),
+        settings.shutdown_settings.clone(),
+    ));
+
     let addr = SocketAddr::from((settings.app_settings.addr, settings.app_settings.port));
-    let listener = TcpListener::bind(addr).await.unwrap();
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to listen on {}", addr))?;
 
-    axum::serve(
-        listener,
-        app.layer(TraceLayer::new_for_http()).into_make_service(),
-    )
-    .await
-    .unwrap();
+    match settings.app_settings.tls.clone() {
+        Some(tls_settings) => {
+            let config = tls::rustls_config(&tls_settings).await?;
+            let redirect_server = match tls_settings.redirect_port {
+                Some(port) => {
+                    let redirect_addr = SocketAddr::from((settings.app_settings.addr, port));
+                    let redirect_listener =
+                        TcpListener::bind(redirect_addr).await.with_context(|| {
+                            format!("Failed to listen for HTTP redirects on {}", redirect_addr)
+                        })?;
+                    info!("redirecting {} to HTTPS", redirect_addr);
+                    Some(tokio::spawn(shutdown::serve(
+                        redirect_listener,
+                        tls::redirect_router(addr.port()),
+                        shutdown.clone(),
+                        settings.shutdown_settings.clone(),
+                    )))
+                }
+                None => None,
+            };
+            tls::watch(
+                config.clone(),
+                tls_settings,
+                &settings.reload_settings,
+                shutdown.clone(),
+            );
+            info!("serving HTTPS on {}", addr);
+            shutdown::serve_tls(
+                listener,
+                app,
+                config,
+                shutdown.clone(),
+                settings.shutdown_settings.clone(),
+            )
+            .await
+            .context("HTTPS listener failed")?;
+            if let Some(redirect_server) = redirect_server {
+                if let Ok(Err(e)) = redirect_server.await {
+                    tracing::error!("Redirect listener failed: {:?}", e);
+                }
+            }
+        }
+        None => {
+            shutdown::serve(
+                listener,
+                app,
+                shutdown.clone(),
+                settings.shutdown_settings.clone(),
+            )
+            .await
+            .context("HTTP listener failed")?;
+        }
+    }
+    if let Ok(Err(e)) = metrics_server.await {
+        tracing::error!("Metrics listener failed: {:?}", e);
+    }
+    shutdown::drain_workers(workers, &shutdown, &settings.shutdown_settings).await;
+    pool.close().await;
+    info!("shutdown complete");
+    Ok(())
+}
+
+async fn worker(settings: Settings, args: StartArgs, log_filter: Option<LogFilter>) -> Result<()> {
+    let shutdown = Shutdown::new();
+    shutdown.trigger_on_signal();
+    // only the log level applies to workers
+    LiveSettings::new(settings.dynamic, log_filter)?
+        .with_static_settings(settings.static_settings)
+        .watch(&settings.reload_settings, shutdown.clone());
+    let pool: PgPool = connect_database(settings.database_settings).await?;
+    let photo_repo = PhotoRepository::new(pool.clone());
+    check_migrations(&photo_repo, &args).await?;
+
+    let job_context = JobContext {
+        repo: photo_repo,
+        storage: PhotoStorage::new(settings.storage_settings.path),
+        image_settings: Arc::new(settings.image_settings),
+        location_settings: Arc::new(settings.location_settings),
+        cache: response_cache(&settings.redis_url, settings.cache_settings)?,
+        cloudflare: settings
+            .cloudflare_settings
+            .map(|x| CloudflareImages::new(HttpClient::default(), x)),
+    };
+    let job_settings = JobSettings {
+        workers: settings.job_settings.workers.max(1),
+        ..settings.job_settings
+    };
+    let workers = Worker::spawn_all(job_context, &job_settings, &shutdown);
+    shutdown::drain_workers(workers, &shutdown, &settings.shutdown_settings).await;
+    pool.close().await;
+    info!("shutdown complete");
+    Ok(())
+}
+
+/// Applies pending migrations if asked to, otherwise refuses to run
+/// against a database that hasn't been migrated yet.
+async fn check_migrations(photo_repo: &PhotoRepository, args: &StartArgs) -> Result<()> {
+    if args.migrate {
+        return photo_repo.migrate().await;
+    }
+    let pending = photo_repo.pending_migrations().await?;
+    if !pending.is_empty() {
+        bail!(
+            "{} migrations haven't been applied, run `migrate up` or start with --migrate",
+            pending.len()
+        );
+    }
+    Ok(())
+}
+
+async fn migrate(settings: Settings, action: MigrateAction) -> Result<()> {
+    let pool = connect_database(settings.database_settings).await?;
+    let photo_repo = PhotoRepository::new(pool.clone());
+    match action {
+        MigrateAction::Up => {
+            let pending = photo_repo.pending_migrations().await?;
+            photo_repo.migrate().await?;
+            println!("Applied {} migrations", pending.len());
+        }
+        MigrateAction::Down { to } => {
+            for version in photo_repo.revert_migrations(to).await? {
+                println!("Reverted {}", version);
+            }
+        }
+        MigrateAction::Status => {
+            for migration in photo_repo.migration_status().await? {
+                let status = if migration.applied {
+                    "applied"
+                } else {
+                    "pending"
+                };
+                println!(
+                    "{}  {:<8} {}",
+                    migration.version, status, migration.description
+                );
+            }
+        }
+    }
+    pool.close().await;
+    Ok(())
+}
+
+async fn seed(settings: Settings, file: &Path) -> Result<()> {
+    let catalog = read_catalog(file)?;
+    let pool = connect_database(settings.database_settings).await?;
+    let photo_repo = PhotoRepository::new(pool.clone());
+    let photos = catalog.photos.len();
+    let categories = catalog.categories.len();
+    match photo_repo.import_catalog(catalog, false).await {
+        Ok(()) => {
+            let cloudflare = settings.cloudflare_settings.is_some();
+            queue_import_processing(&photo_repo, &settings.job_settings, cloudflare).await?;
+            response_cache(&settings.redis_url, settings.cache_settings)?
+                .invalidate(&[CacheTag::Photos, CacheTag::Categories, CacheTag::Locations])
+                .await;
+            println!("Seeded {} photos and {} categories", photos, categories);
+        }
+        Err(e) if e.is::<CatalogNotEmptyError>() => println!("Database already seeded"),
+        Err(e) => return Err(e),
+    }
+    pool.close().await;
+    Ok(())
+}
+
+/// Leaves what an import doesn't restore to the workers.
+async fn queue_import_processing(
+    photo_repo: &PhotoRepository,
+    job_settings: &JobSettings,
+    cloudflare: bool,
+) -> Result<()> {
+    JobQueue::new(photo_repo.clone(), job_settings)
+        .with_cloudflare(cloudflare)
+        .enqueue_after_import()
+        .await
+        .context("Failed to queue processing of the imported photos")?;
+    Ok(())
+}
+
+async fn create_category(settings: Settings, name: String) -> Result<()> {
+    let pool = connect_database(settings.database_settings).await?;
+    let category = PhotoRepository::new(pool.clone())
+        .add_category(name)
+        .await?;
+    response_cache(&settings.redis_url, settings.cache_settings)?
+        .invalidate(&[CacheTag::Categories])
+        .await;
+    println!("{}", category.id);
+    pool.close().await;
+    Ok(())
+}
+
+async fn import(settings: Settings, file: &Path, replace: bool) -> Result<()> {
+    let catalog = read_catalog(file)?;
+    let pool = connect_database(settings.database_settings).await?;
+    let photos = catalog.photos.len();
+    let categories = catalog.categories.len();
+    let photo_repo = PhotoRepository::new(pool.clone());
+    photo_repo.import_catalog(catalog, replace).await?;
+    let cloudflare = settings.cloudflare_settings.is_some();
+    queue_import_processing(&photo_repo, &settings.job_settings, cloudflare).await?;
+    response_cache(&settings.redis_url, settings.cache_settings)?
+        .invalidate(&[CacheTag::Photos, CacheTag::Categories, CacheTag::Locations])
+        .await;
+    println!("Imported {} photos and {} categories", photos, categories);
+    pool.close().await;
+    Ok(())
+}
+
+async fn export(settings: Settings, file: &Path) -> Result<()> {
+    let pool = connect_database(settings.database_settings).await?;
+    let mut rows = PhotoRepository::new(pool.clone()).export_catalog();
+    let out = std::fs::File::create(file)
+        .with_context(|| format!("failed to write {}", file.display()))?;
+    let zip = is_zip(file);
+    let exported_at = Utc::now();
+    let counts = tokio::task::spawn_blocking(move || {
+        let rows = std::iter::from_fn(|| rows.blocking_recv());
+        if zip {
+            let mut writer = ZipCatalogWriter::new(out, exported_at)?;
+            let counts = write_catalog(&mut writer, rows)?;
+            writer.finish()?;
+            Ok::<_, anyhow::Error>(counts)
+        } else {
+            let mut writer = JsonCatalogWriter::new(BufWriter::new(out), exported_at)?;
+            let counts = write_catalog(&mut writer, rows)?;
+            writer.finish()?;
+            Ok(counts)
+        }
+    })
+    .await?
+    .with_context(|| format!("failed to export the catalog to {}", file.display()))?;
+    pool.close().await;
+    println!(
+        "Exported {} photos and {} categories to {}",
+        counts.photos,
+        counts.categories,
+        file.display()
+    );
+    Ok(())
+}
+
+fn read_catalog(file: &Path) -> Result<CatalogExport> {
+    let bytes =
+        std::fs::read(file).with_context(|| format!("failed to read {}", file.display()))?;
+    // exports are sometimes renamed, so trust the zip signature over the name
+    let catalog = if is_zip(file) || bytes.starts_with(b"PK\x03\x04") {
+        CatalogExport::from_zip(&bytes)?
+    } else {
+        serde_json::from_slice(&bytes)?
+    };
+    catalog
+        .check_version()
+        .with_context(|| format!("{} isn't a usable catalog export", file.display()))?;
+    Ok(catalog)
+}
+
+fn is_zip(file: &Path) -> bool {
+    file.extension()
+        .is_some_and(|x| x.eq_ignore_ascii_case("zip"))
+}
+
+fn response_cache(redis_url: &Secret, settings: CacheSettings) -> Result<ResponseCache> {
+    Ok(ResponseCache::new(
+        redis::Client::open(redis_url.expose())?,
+        settings,
+    ))
 }
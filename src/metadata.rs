@@ -0,0 +1,284 @@
+use std::collections::BTreeMap;
+use std::io::Cursor;
+
+use chrono::{NaiveDate, NaiveDateTime};
+use exif::{Exif, In, Tag, Value};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::models::Coordinates;
+
+// IPTC-IIM application record (record 2) datasets we care about
+const IPTC_OBJECT_NAME: u8 = 5;
+const IPTC_KEYWORDS: u8 = 25;
+const IPTC_BYLINE: u8 = 80;
+const IPTC_CITY: u8 = 90;
+const IPTC_SUBLOCATION: u8 = 92;
+const IPTC_PROVINCE_STATE: u8 = 95;
+const IPTC_COUNTRY: u8 = 101;
+const IPTC_HEADLINE: u8 = 105;
+const IPTC_CAPTION: u8 = 120;
+
+/// Metadata read from the EXIF and IPTC blocks of an uploaded image.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct PhotoMetadata {
+    pub captured_at: Option<NaiveDateTime>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub lens_model: Option<String>,
+    pub exposure_time: Option<String>,
+    pub f_number: Option<f64>,
+    pub iso: Option<i32>,
+    pub focal_length: Option<f64>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub altitude: Option<f64>,
+    pub caption: Option<String>,
+    pub headline: Option<String>,
+    pub location: Option<String>,
+    pub raw: serde_json::Value,
+}
+
+impl PhotoMetadata {
+    pub fn date_taken(&self) -> Option<NaiveDate> {
+        self.captured_at.map(|x| x.date())
+    }
+
+    pub fn coordinates(&self) -> Option<Coordinates> {
+        Coordinates::new(self.latitude?, self.longitude?).ok()
+    }
+}
+
+/// Reads whatever EXIF and IPTC metadata `bytes` carries. Images without
+/// metadata, or with metadata we can't parse, yield an empty result.
+pub fn extract_metadata(bytes: &[u8]) -> PhotoMetadata {
+    let mut metadata = PhotoMetadata::default();
+    let mut raw_exif = BTreeMap::new();
+
+    match exif::Reader::new().read_from_container(&mut Cursor::new(bytes)) {
+        Ok(exif) => {
+            read_exif(&exif, &mut metadata);
+            for field in exif.fields().filter(|x| x.ifd_num == In::PRIMARY) {
+                if field.tag == Tag::MakerNote {
+                    continue;
+                }
+                raw_exif.insert(
+                    field.tag.to_string(),
+                    field.display_value().with_unit(&exif).to_string(),
+                );
+            }
+        }
+        Err(exif::Error::NotFound(_)) => {}
+        Err(e) => tracing::warn!("failed to read exif metadata: {}", e),
+    }
+
+    let iptc = read_iptc(bytes);
+    if let Some(headline) = iptc_first(&iptc, IPTC_HEADLINE).or(iptc_first(&iptc, IPTC_OBJECT_NAME))
+    {
+        metadata.headline = Some(headline);
+    }
+    if let Some(caption) = iptc_first(&iptc, IPTC_CAPTION) {
+        metadata.caption = Some(caption);
+    }
+    let location: Vec<String> = [
+        IPTC_SUBLOCATION,
+        IPTC_CITY,
+        IPTC_PROVINCE_STATE,
+        IPTC_COUNTRY,
+    ]
+    .into_iter()
+    .filter_map(|x| iptc_first(&iptc, x))
+    .collect();
+    if !location.is_empty() {
+        metadata.location = Some(location.join(", "));
+    }
+
+    let raw_iptc: BTreeMap<String, &Vec<String>> = iptc
+        .iter()
+        .map(|(dataset, values)| (iptc_dataset_name(*dataset), values))
+        .collect();
+    metadata.raw = json!({ "exif": raw_exif, "iptc": raw_iptc });
+    metadata
+}
+
+fn read_exif(exif: &Exif, metadata: &mut PhotoMetadata) {
+    metadata.captured_at = exif_datetime(exif, Tag::DateTimeOriginal)
+        .or_else(|| exif_datetime(exif, Tag::DateTimeDigitized))
+        .or_else(|| exif_datetime(exif, Tag::DateTime));
+    metadata.camera_make = exif_string(exif, Tag::Make);
+    metadata.camera_model = exif_string(exif, Tag::Model);
+    metadata.lens_model = exif_string(exif, Tag::LensModel);
+    metadata.exposure_time = exif
+        .get_field(Tag::ExposureTime, In::PRIMARY)
+        .map(|x| x.display_value().to_string());
+    metadata.f_number = exif_rational(exif, Tag::FNumber, 0);
+    metadata.iso = exif
+        .get_field(Tag::PhotographicSensitivity, In::PRIMARY)
+        .and_then(|x| x.value.get_uint(0))
+        .and_then(|x| i32::try_from(x).ok());
+    metadata.focal_length = exif_rational(exif, Tag::FocalLength, 0);
+    metadata.caption = exif_string(exif, Tag::ImageDescription);
+
+    metadata.latitude = exif_coordinate(exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S");
+    metadata.longitude = exif_coordinate(exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W");
+    metadata.altitude = exif_rational(exif, Tag::GPSAltitude, 0).map(|altitude| {
+        let below_sea_level = exif
+            .get_field(Tag::GPSAltitudeRef, In::PRIMARY)
+            .and_then(|x| x.value.get_uint(0))
+            == Some(1);
+        if below_sea_level {
+            -altitude
+        } else {
+            altitude
+        }
+    });
+}
+
+fn exif_string(exif: &Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => values
+            .first()
+            .map(|x| String::from_utf8_lossy(x).trim().to_string())
+            .filter(|x| !x.is_empty()),
+        _ => None,
+    }
+}
+
+fn exif_rational(exif: &Exif, tag: Tag, index: usize) -> Option<f64> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(values) => values
+            .get(index)
+            .filter(|x| x.denom != 0)
+            .map(|x| x.to_f64()),
+        _ => None,
+    }
+}
+
+fn exif_datetime(exif: &Exif, tag: Tag) -> Option<NaiveDateTime> {
+    let Value::Ascii(values) = &exif.get_field(tag, In::PRIMARY)?.value else {
+        return None;
+    };
+    let datetime = exif::DateTime::from_ascii(values.first()?).ok()?;
+    NaiveDate::from_ymd_opt(
+        datetime.year.into(),
+        datetime.month.into(),
+        datetime.day.into(),
+    )?
+    .and_hms_opt(
+        datetime.hour.into(),
+        datetime.minute.into(),
+        datetime.second.into(),
+    )
+}
+
+fn exif_coordinate(exif: &Exif, tag: Tag, reference: Tag, negative: &str) -> Option<f64> {
+    let degrees = exif_rational(exif, tag, 0)?;
+    let minutes = exif_rational(exif, tag, 1).unwrap_or(0.0);
+    let seconds = exif_rational(exif, tag, 2).unwrap_or(0.0);
+    let value = degrees + minutes / 60.0 + seconds / 3600.0;
+    match exif_string(exif, reference) {
+        Some(x) if x.eq_ignore_ascii_case(negative) => Some(-value),
+        _ => Some(value),
+    }
+}
+
+fn iptc_first(iptc: &BTreeMap<u8, Vec<String>>, dataset: u8) -> Option<String> {
+    iptc.get(&dataset)?
+        .first()
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+}
+
+fn iptc_dataset_name(dataset: u8) -> String {
+    match dataset {
+        IPTC_OBJECT_NAME => "ObjectName".into(),
+        IPTC_KEYWORDS => "Keywords".into(),
+        IPTC_BYLINE => "By-line".into(),
+        IPTC_CITY => "City".into(),
+        IPTC_SUBLOCATION => "Sub-location".into(),
+        IPTC_PROVINCE_STATE => "Province-State".into(),
+        IPTC_COUNTRY => "Country-PrimaryLocationName".into(),
+        IPTC_HEADLINE => "Headline".into(),
+        IPTC_CAPTION => "Caption-Abstract".into(),
+        other => format!("2:{}", other),
+    }
+}
+
+/// Collects the IPTC-IIM application record from the Photoshop APP13
+/// segment of a JPEG, keyed by dataset number.
+fn read_iptc(bytes: &[u8]) -> BTreeMap<u8, Vec<String>> {
+    let mut datasets: BTreeMap<u8, Vec<String>> = BTreeMap::new();
+    for block in photoshop_iptc_blocks(bytes) {
+        let mut offset = 0;
+        while offset + 5 <= block.len() && block[offset] == 0x1c {
+            let record = block[offset + 1];
+            let dataset = block[offset + 2];
+            let size = u16::from_be_bytes([block[offset + 3], block[offset + 4]]) as usize;
+            // extended datasets set the high bit; they never carry text
+            if size & 0x8000 != 0 {
+                break;
+            }
+            let start = offset + 5;
+            let Some(data) = block.get(start..start + size) else {
+                break;
+            };
+            if record == 2 {
+                datasets
+                    .entry(dataset)
+                    .or_default()
+                    .push(String::from_utf8_lossy(data).into_owned());
+            }
+            offset = start + size;
+        }
+    }
+    datasets
+}
+
+fn photoshop_iptc_blocks(bytes: &[u8]) -> Vec<&[u8]> {
+    const PHOTOSHOP: &[u8] = b"Photoshop 3.0\0";
+    let mut blocks = Vec::new();
+    if !bytes.starts_with(&[0xff, 0xd8]) {
+        return blocks;
+    }
+
+    let mut offset = 2;
+    while offset + 4 <= bytes.len() && bytes[offset] == 0xff {
+        let marker = bytes[offset + 1];
+        // start of scan: only entropy-coded data follows
+        if marker == 0xda {
+            break;
+        }
+        let length = u16::from_be_bytes([bytes[offset + 2], bytes[offset + 3]]) as usize;
+        let Some(segment) = bytes.get(offset + 4..offset + 2 + length) else {
+            break;
+        };
+        if marker == 0xed && segment.starts_with(PHOTOSHOP) {
+            blocks.extend(image_resource_blocks(&segment[PHOTOSHOP.len()..], 0x0404));
+        }
+        offset += 2 + length;
+    }
+    blocks
+}
+
+fn image_resource_blocks(mut data: &[u8], resource_id: u16) -> Vec<&[u8]> {
+    let mut blocks = Vec::new();
+    while data.len() >= 8 && data.starts_with(b"8BIM") {
+        let id = u16::from_be_bytes([data[4], data[5]]);
+        // pascal string name, padded so that length byte + name is even
+        let name_length = data[6] as usize;
+        let name_end = 6 + (name_length + 2) / 2 * 2;
+        let Some(size) = data.get(name_end..name_end + 4) else {
+            break;
+        };
+        let size = u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize;
+        let start = name_end + 4;
+        let Some(block) = data.get(start..start + size) else {
+            break;
+        };
+        if id == resource_id {
+            blocks.push(block);
+        }
+        data = data.get(start + size + size % 2..).unwrap_or_default();
+    }
+    blocks
+}
@@ -1,9 +1,16 @@
-use chrono::NaiveDate;
+use std::collections::HashMap;
+
+use chrono::{NaiveDate, NaiveDateTime};
 use serde::{Deserialize, Serialize};
 use sqlx::types::chrono::{DateTime, Utc};
+use uuid::Uuid;
 
+pub use catalog::*;
+pub use geo::*;
 pub use view_models::*;
 
+mod catalog;
+mod geo;
 mod view_models;
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -15,6 +22,22 @@ pub struct Photo {
     pub date_taken: NaiveDate,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub location_id: Option<i32>,
+    /// 64-bit dHash of the image, see [`crate::images::perceptual_hash`]
+    pub perceptual_hash: Option<i64>,
+    /// hex SHA-256 of the stored original
+    pub content_sha256: Option<String>,
+}
+
+impl Photo {
+    pub fn coordinates(&self) -> Option<Coordinates> {
+        Some(Coordinates {
+            latitude: self.latitude?,
+            longitude: self.longitude?,
+        })
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -41,10 +64,186 @@ impl Category {
     }
 }
 
+#[derive(Debug)]
+pub struct NewPhoto {
+    pub title: String,
+    pub filename: String,
+    pub location_taken: String,
+    pub date_taken: NaiveDate,
+    pub coordinates: Option<Coordinates>,
+    pub location_id: Option<i32>,
+    pub perceptual_hash: Option<i64>,
+    pub content_sha256: Option<String>,
+}
+
+/// Fields to change on an existing photo; `None` keeps the current value.
+#[derive(Debug, Default)]
+pub struct PhotoChanges {
+    pub title: Option<String>,
+    pub filename: Option<String>,
+    pub location_taken: Option<String>,
+    pub date_taken: Option<NaiveDate>,
+    /// `Some(None)` clears the position
+    pub coordinates: Option<Option<Coordinates>>,
+    /// `Some(None)` clears the location
+    pub location_id: Option<Option<i32>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Location {
+    pub id: i32,
+    pub name: String,
+    pub kind: String,
+    pub parent_id: Option<i32>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct PhotoCategory {
-    pub id: i32,
     pub display_order: i32,
     pub photo_id: i32,
     pub category_id: i32,
 }
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PhotoExif {
+    pub photo_id: i32,
+    pub captured_at: Option<NaiveDateTime>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub lens_model: Option<String>,
+    pub exposure_time: Option<String>,
+    pub f_number: Option<f64>,
+    pub iso: Option<i32>,
+    pub focal_length: Option<f64>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub altitude: Option<f64>,
+    pub caption: Option<String>,
+    pub raw: serde_json::Value,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A resized and re-encoded copy of a photo, generated after upload.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PhotoVariant {
+    pub id: i32,
+    pub photo_id: i32,
+    pub variant: String,
+    pub format: String,
+    pub width: i32,
+    pub height: i32,
+    pub byte_size: i32,
+    pub storage_key: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A photo whose perceptual hash is within some Hamming distance of another.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SimilarPhoto {
+    pub photo_id: i32,
+    pub distance: i32,
+}
+
+/// A migration shipped with this build.
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub applied: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DuplicatePair {
+    pub photo_id: i32,
+    pub duplicate_id: i32,
+    pub distance: i32,
+}
+
+/// Photos that are transitively within some distance of each other.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    /// ascending
+    pub photo_ids: Vec<i32>,
+    /// largest distance between two photos linked within the group
+    pub max_distance: i32,
+}
+
+/// Joins `pairs` that share a photo into groups with union-find, ordered by
+/// `max_distance` and then by their lowest photo id.
+pub fn group_duplicates(pairs: &[DuplicatePair]) -> Vec<DuplicateGroup> {
+    fn root(parent: &mut HashMap<i32, i32>, id: i32) -> i32 {
+        let mut found = id;
+        while let Some(&next) = parent.get(&found).filter(|x| **x != found) {
+            found = next;
+        }
+        // point everything on the way straight at the root
+        let mut current = id;
+        while current != found {
+            let next = parent.insert(current, found).unwrap_or(found);
+            current = next;
+        }
+        found
+    }
+
+    let mut parent: HashMap<i32, i32> = HashMap::new();
+    for pair in pairs {
+        let a = root(&mut parent, pair.photo_id);
+        let b = root(&mut parent, pair.duplicate_id);
+        parent.insert(a.max(b), a.min(b));
+        parent.entry(a.min(b)).or_insert(a.min(b));
+    }
+
+    let mut groups: HashMap<i32, DuplicateGroup> = HashMap::new();
+    for pair in pairs {
+        let group = groups
+            .entry(root(&mut parent, pair.photo_id))
+            .or_insert(DuplicateGroup {
+                photo_ids: Vec::new(),
+                max_distance: 0,
+            });
+        group.max_distance = group.max_distance.max(pair.distance);
+    }
+    let ids: Vec<i32> = parent.keys().copied().collect();
+    for id in ids {
+        let group_root = root(&mut parent, id);
+        if let Some(group) = groups.get_mut(&group_root) {
+            group.photo_ids.push(id);
+        }
+    }
+
+    let mut groups: Vec<DuplicateGroup> = groups.into_values().collect();
+    for group in &mut groups {
+        group.photo_ids.sort_unstable();
+    }
+    groups.sort_by_key(|x| (x.max_distance, x.photo_ids.first().copied()));
+    groups
+}
+
+/// A row of the `jobs` queue. `payload` holds the serialized
+/// [`Job`](crate::jobs::Job).
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JobRecord {
+    pub id: i64,
+    pub kind: String,
+    pub payload: serde_json::Value,
+    pub status: String,
+    pub attempts: i32,
+    pub max_attempts: i32,
+    pub run_at: DateTime<Utc>,
+    pub locked_until: Option<DateTime<Utc>>,
+    pub locked_by: Option<String>,
+    pub last_error: Option<String>,
+    pub dedupe_key: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub result: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PhotoCloudflareResource {
+    pub photo_id: i32,
+    pub resource_id: Uuid,
+}
@@ -0,0 +1,296 @@
+use std::io::{Cursor, Read, Seek, Write};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use zip::write::SimpleFileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+use crate::models::{Category, Location, Photo, PhotoCategory, PhotoCloudflareResource, PhotoExif};
+
+/// Version of the catalog document layout. Bump this whenever a field is
+/// added to or removed from [`CatalogExport`]; older versions must still be
+/// readable.
+pub const CATALOG_EXPORT_VERSION: u32 = 4;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const PHOTOS_FILE: &str = "photos.json";
+const CATEGORIES_FILE: &str = "categories.json";
+const PHOTO_CATEGORIES_FILE: &str = "photo_categories.json";
+const LOCATIONS_FILE: &str = "locations.json";
+const CLOUDFLARE_RESOURCES_FILE: &str = "cloudflare_resources.json";
+const PHOTO_EXIF_FILE: &str = "photo_exif.json";
+
+/// The tables of a catalog, in the order they are written. In a JSON export
+/// they are the fields of [`CatalogExport`], in an archive the entries.
+const TABLES: [(&str, &str); 6] = [
+    ("photos", PHOTOS_FILE),
+    ("categories", CATEGORIES_FILE),
+    ("photo_categories", PHOTO_CATEGORIES_FILE),
+    ("locations", LOCATIONS_FILE),
+    ("cloudflare_resources", CLOUDFLARE_RESOURCES_FILE),
+    ("photo_exif", PHOTO_EXIF_FILE),
+];
+
+/// Snapshot of the whole gallery, used for backups and for cloning one
+/// environment into another.
+///
+/// What can be derived again from the stored originals is left out: the
+/// variants (`photo_variants`) and which photo a duplicate's contents
+/// belong to (`photos.content_duplicate_of`). An import queues the jobs
+/// that regenerate them.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CatalogExport {
+    pub version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub photos: Vec<Photo>,
+    pub categories: Vec<Category>,
+    pub photo_categories: Vec<PhotoCategory>,
+    #[serde(default)]
+    pub locations: Vec<Location>,
+    pub cloudflare_resources: Vec<PhotoCloudflareResource>,
+    #[serde(default)]
+    pub photo_exif: Vec<PhotoExif>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct CatalogManifest {
+    version: u32,
+    exported_at: DateTime<Utc>,
+}
+
+impl CatalogExport {
+    pub fn check_version(&self) -> Result<()> {
+        if self.version == 0 || self.version > CATALOG_EXPORT_VERSION {
+            bail!(
+                "unsupported catalog version {}, expected at most {}",
+                self.version,
+                CATALOG_EXPORT_VERSION
+            );
+        }
+        Ok(())
+    }
+
+    /// Reads a catalog previously written by [`ZipCatalogWriter`].
+    pub fn from_zip(bytes: &[u8]) -> Result<Self> {
+        let mut archive = ZipArchive::new(Cursor::new(bytes))?;
+        let manifest: CatalogManifest = read_zip_entry(&mut archive, MANIFEST_FILE)?;
+
+        Ok(Self {
+            version: manifest.version,
+            exported_at: manifest.exported_at,
+            photos: read_zip_entry(&mut archive, PHOTOS_FILE)?,
+            categories: read_zip_entry(&mut archive, CATEGORIES_FILE)?,
+            photo_categories: read_zip_entry(&mut archive, PHOTO_CATEGORIES_FILE)?,
+            // locations were added in version 3
+            locations: if archive.index_for_name(LOCATIONS_FILE).is_some() {
+                read_zip_entry(&mut archive, LOCATIONS_FILE)?
+            } else {
+                Vec::new()
+            },
+            cloudflare_resources: read_zip_entry(&mut archive, CLOUDFLARE_RESOURCES_FILE)?,
+            // and photo_exif in version 4
+            photo_exif: if archive.index_for_name(PHOTO_EXIF_FILE).is_some() {
+                read_zip_entry(&mut archive, PHOTO_EXIF_FILE)?
+            } else {
+                Vec::new()
+            },
+        })
+    }
+}
+
+fn read_zip_entry<T: DeserializeOwned>(
+    archive: &mut ZipArchive<Cursor<&[u8]>>,
+    name: &str,
+) -> Result<T> {
+    let mut file = archive
+        .by_name(name)
+        .with_context(|| format!("catalog archive is missing {}", name))?;
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    serde_json::from_slice(&contents).with_context(|| format!("failed to parse {}", name))
+}
+
+/// One row of an export, as read from the database table by table.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum CatalogRow {
+    Photo(Photo),
+    Category(Category),
+    PhotoCategory(PhotoCategory),
+    Location(Location),
+    CloudflareResource(PhotoCloudflareResource),
+    PhotoExif(PhotoExif),
+}
+
+impl CatalogRow {
+    /// Index of the row's table in [`TABLES`].
+    fn table(&self) -> usize {
+        match self {
+            CatalogRow::Photo(_) => 0,
+            CatalogRow::Category(_) => 1,
+            CatalogRow::PhotoCategory(_) => 2,
+            CatalogRow::Location(_) => 3,
+            CatalogRow::CloudflareResource(_) => 4,
+            CatalogRow::PhotoExif(_) => 5,
+        }
+    }
+}
+
+/// How many photos and categories an export wrote.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CatalogCounts {
+    pub photos: usize,
+    pub categories: usize,
+}
+
+/// A catalog format that can be written one row at a time.
+pub trait CatalogSink {
+    fn start_table(&mut self, table: usize) -> Result<()>;
+    fn write_row(&mut self, row: &CatalogRow) -> Result<()>;
+    fn finish_table(&mut self) -> Result<()>;
+}
+
+/// Writes `rows` to `sink` as they arrive, so the export never holds more
+/// than one row. The rows must come table by table in the order of
+/// [`TABLES`]; a table without rows is still written, empty.
+pub fn write_catalog(
+    sink: &mut impl CatalogSink,
+    rows: impl IntoIterator<Item = Result<CatalogRow>>,
+) -> Result<CatalogCounts> {
+    let mut counts = CatalogCounts::default();
+    // tables before this one have been started
+    let mut next_table = 0;
+    for row in rows {
+        let row = row?;
+        if row.table() + 1 < next_table {
+            bail!(
+                "{} rows came after {}",
+                TABLES[row.table()].0,
+                TABLES[next_table - 1].0
+            );
+        }
+        while next_table <= row.table() {
+            if next_table > 0 {
+                sink.finish_table()?;
+            }
+            sink.start_table(next_table)?;
+            next_table += 1;
+        }
+        match row {
+            CatalogRow::Photo(_) => counts.photos += 1,
+            CatalogRow::Category(_) => counts.categories += 1,
+            _ => {}
+        }
+        sink.write_row(&row)?;
+    }
+    while next_table < TABLES.len() {
+        if next_table > 0 {
+            sink.finish_table()?;
+        }
+        sink.start_table(next_table)?;
+        next_table += 1;
+    }
+    sink.finish_table()?;
+    Ok(counts)
+}
+
+/// Writes a catalog as the JSON form of [`CatalogExport`].
+pub struct JsonCatalogWriter<W: Write> {
+    out: W,
+    first_row: bool,
+}
+
+impl<W: Write> JsonCatalogWriter<W> {
+    pub fn new(mut out: W, exported_at: DateTime<Utc>) -> Result<Self> {
+        let manifest = serde_json::to_string(&CatalogManifest {
+            version: CATALOG_EXPORT_VERSION,
+            exported_at,
+        })?;
+        // the tables are added to the manifest's object
+        out.write_all(manifest.trim_end_matches('}').as_bytes())?;
+        Ok(Self {
+            out,
+            first_row: true,
+        })
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        self.out.write_all(b"}")?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> CatalogSink for JsonCatalogWriter<W> {
+    fn start_table(&mut self, table: usize) -> Result<()> {
+        write!(self.out, ",\"{}\":[", TABLES[table].0)?;
+        self.first_row = true;
+        Ok(())
+    }
+
+    fn write_row(&mut self, row: &CatalogRow) -> Result<()> {
+        if !self.first_row {
+            self.out.write_all(b",")?;
+        }
+        self.first_row = false;
+        serde_json::to_writer(&mut self.out, row)?;
+        Ok(())
+    }
+
+    fn finish_table(&mut self) -> Result<()> {
+        self.out.write_all(b"]")?;
+        Ok(())
+    }
+}
+
+/// Writes a catalog as a ZIP archive with one JSON file per table.
+pub struct ZipCatalogWriter<W: Write + Seek> {
+    zip: ZipWriter<W>,
+    first_row: bool,
+}
+
+impl<W: Write + Seek> ZipCatalogWriter<W> {
+    pub fn new(out: W, exported_at: DateTime<Utc>) -> Result<Self> {
+        let mut zip = ZipWriter::new(out);
+        let manifest = CatalogManifest {
+            version: CATALOG_EXPORT_VERSION,
+            exported_at,
+        };
+        zip.start_file(MANIFEST_FILE, SimpleFileOptions::default())?;
+        zip.write_all(&serde_json::to_vec_pretty(&manifest)?)?;
+        Ok(Self {
+            zip,
+            first_row: true,
+        })
+    }
+
+    pub fn finish(self) -> Result<W> {
+        Ok(self.zip.finish()?)
+    }
+}
+
+impl<W: Write + Seek> CatalogSink for ZipCatalogWriter<W> {
+    fn start_table(&mut self, table: usize) -> Result<()> {
+        self.zip
+            .start_file(TABLES[table].1, SimpleFileOptions::default())?;
+        self.zip.write_all(b"[")?;
+        self.first_row = true;
+        Ok(())
+    }
+
+    fn write_row(&mut self, row: &CatalogRow) -> Result<()> {
+        if !self.first_row {
+            self.zip.write_all(b",")?;
+        }
+        self.first_row = false;
+        serde_json::to_writer(&mut self.zip, row)?;
+        Ok(())
+    }
+
+    fn finish_table(&mut self) -> Result<()> {
+        self.zip.write_all(b"]")?;
+        Ok(())
+    }
+}
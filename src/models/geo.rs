@@ -0,0 +1,104 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Error, Result};
+use serde::{Deserialize, Serialize};
+
+/// A WGS84 position in decimal degrees.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct Coordinates {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Coordinates {
+    pub fn new(latitude: f64, longitude: f64) -> Result<Self> {
+        if !(-90.0..=90.0).contains(&latitude) {
+            bail!("latitude {} is out of range", latitude);
+        }
+        if !(-180.0..=180.0).contains(&longitude) {
+            bail!("longitude {} is out of range", longitude);
+        }
+        Ok(Self {
+            latitude,
+            longitude,
+        })
+    }
+
+    pub fn from_parts(latitude: Option<f64>, longitude: Option<f64>) -> Result<Option<Self>> {
+        match (latitude, longitude) {
+            (Some(latitude), Some(longitude)) => Self::new(latitude, longitude).map(Some),
+            (None, None) => Ok(None),
+            _ => bail!("latitude and longitude must be given together"),
+        }
+    }
+
+    /// Coordinates to change to, where a missing part is left alone and a
+    /// null one cleared. `Some(None)` clears the position.
+    pub fn from_changes(
+        latitude: Option<Option<f64>>,
+        longitude: Option<Option<f64>>,
+    ) -> Result<Option<Option<Self>>> {
+        match (latitude, longitude) {
+            (None, None) => Ok(None),
+            (Some(latitude), Some(longitude)) => Self::from_parts(latitude, longitude).map(Some),
+            _ => bail!("latitude and longitude must be given together"),
+        }
+    }
+}
+
+/// Parses `lat,lon`.
+impl FromStr for Coordinates {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let values = parse_floats(s)?;
+        match values[..] {
+            [latitude, longitude] => Self::new(latitude, longitude),
+            _ => bail!("expected lat,lon"),
+        }
+    }
+}
+
+/// An area bounded by two meridians and two parallels. When
+/// `min_longitude` is greater than `max_longitude` the box crosses the
+/// antimeridian.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct BoundingBox {
+    pub min_longitude: f64,
+    pub min_latitude: f64,
+    pub max_longitude: f64,
+    pub max_latitude: f64,
+}
+
+/// Parses `min_lon,min_lat,max_lon,max_lat`, the GeoJSON `bbox` order.
+impl FromStr for BoundingBox {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let values = parse_floats(s)?;
+        let [min_longitude, min_latitude, max_longitude, max_latitude] = values[..] else {
+            bail!("expected min_lon,min_lat,max_lon,max_lat");
+        };
+        let south_west = Coordinates::new(min_latitude, min_longitude)?;
+        let north_east = Coordinates::new(max_latitude, max_longitude)?;
+        if south_west.latitude > north_east.latitude {
+            bail!("min_lat must not be greater than max_lat");
+        }
+        Ok(Self {
+            min_longitude,
+            min_latitude,
+            max_longitude,
+            max_latitude,
+        })
+    }
+}
+
+fn parse_floats(s: &str) -> Result<Vec<f64>> {
+    s.split(',')
+        .map(|x| {
+            x.trim()
+                .parse::<f64>()
+                .with_context(|| format!("{} is not a number", x))
+        })
+        .collect()
+}
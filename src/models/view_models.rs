@@ -1,4 +1,6 @@
-use crate::models::{Category, Photo};
+use std::collections::HashMap;
+
+use crate::models::{Category, Coordinates, Location, Photo};
 use chrono::NaiveDate;
 use serde::{Deserialize, Serialize};
 
@@ -9,6 +11,10 @@ pub struct PhotoViewModel {
     pub filename: String,
     pub location_taken: String,
     pub date_taken: NaiveDate,
+    pub coordinates: Option<Coordinates>,
+    pub location: Option<LocationViewModel>,
+    #[serde(skip)]
+    location_id: Option<i32>,
 }
 
 impl PhotoViewModel {
@@ -18,6 +24,7 @@ impl PhotoViewModel {
         filename: String,
         location_taken: String,
         date_taken: NaiveDate,
+        coordinates: Option<Coordinates>,
     ) -> Self {
         Self {
             id,
@@ -25,14 +32,25 @@ impl PhotoViewModel {
             filename,
             location_taken,
             date_taken,
+            coordinates,
+            location: None,
+            location_id: None,
         }
     }
+
+    pub fn with_location(mut self, locations: &LocationIndex) -> Self {
+        self.location = self.location_id.and_then(|x| locations.view_model(x));
+        self
+    }
 }
 
 impl From<Photo> for PhotoViewModel {
     fn from(value: Photo) -> Self {
         Self {
             id: value.id,
+            coordinates: value.coordinates(),
+            location: None,
+            location_id: value.location_id,
             title: value.title,
             filename: value.filename,
             location_taken: value.location_taken,
@@ -41,6 +59,92 @@ impl From<Photo> for PhotoViewModel {
     }
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SimilarPhotoViewModel {
+    pub photo: PhotoViewModel,
+    /// bits, out of 64, in which the perceptual hashes differ
+    pub distance: i32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DuplicateGroupViewModel {
+    /// largest distance between two photos linked within the group
+    pub max_distance: i32,
+    pub photos: Vec<PhotoViewModel>,
+}
+
+/// A location along with the chain of locations that contain it, e.g.
+/// The Narrows → Zion National Park → Utah → United States.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LocationViewModel {
+    pub id: i32,
+    pub name: String,
+    pub kind: String,
+    pub parent: Option<Box<LocationViewModel>>,
+}
+
+/// All locations keyed by id, for resolving `location_id`s into
+/// [`LocationViewModel`]s without a query per photo.
+#[derive(Debug, Default)]
+pub struct LocationIndex(HashMap<i32, Location>);
+
+impl From<Vec<Location>> for LocationIndex {
+    fn from(value: Vec<Location>) -> Self {
+        Self(value.into_iter().map(|x| (x.id, x)).collect())
+    }
+}
+
+impl LocationIndex {
+    pub fn get(&self, id: i32) -> Option<&Location> {
+        self.0.get(&id)
+    }
+
+    /// Every location with its chain of parents, ordered by id.
+    pub fn view_models(&self) -> Vec<LocationViewModel> {
+        let mut ids: Vec<i32> = self.0.keys().copied().collect();
+        ids.sort_unstable();
+        ids.into_iter().filter_map(|x| self.view_model(x)).collect()
+    }
+
+    pub fn view_model(&self, id: i32) -> Option<LocationViewModel> {
+        // ancestors, nearest first; the depth bound guards against cycles
+        let mut chain = Vec::new();
+        let mut next = Some(id);
+        while let Some(location) = next.and_then(|x| self.0.get(&x)) {
+            if chain.len() > self.0.len() {
+                break;
+            }
+            chain.push(location);
+            next = location.parent_id;
+        }
+        chain
+            .into_iter()
+            .rev()
+            .fold(None, |parent, location| {
+                Some(LocationViewModel {
+                    id: location.id,
+                    name: location.name.clone(),
+                    kind: location.kind.clone(),
+                    parent: parent.map(Box::new),
+                })
+            })
+    }
+
+    /// Comma separated names from the location up to (not including) the
+    /// country, in the same shape as `photos.location_taken`.
+    pub fn display_name(&self, id: i32) -> Option<String> {
+        let mut names = Vec::new();
+        let mut next = self.view_model(id);
+        while let Some(location) = next {
+            if location.kind != "country" {
+                names.push(location.name);
+            }
+            next = location.parent.map(|x| *x);
+        }
+        (!names.is_empty()).then(|| names.join(", "))
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct CategoryViewModel {
     pub id: i32,
@@ -75,6 +179,17 @@ pub struct CategoryDisplayModel {
 
 pub type CategoryPhotos = (Category, Vec<Photo>);
 
+impl CategoryDisplayModel {
+    pub fn with_locations(mut self, locations: &LocationIndex) -> Self {
+        self.photos = self
+            .photos
+            .into_iter()
+            .map(|x| x.with_location(locations))
+            .collect();
+        self
+    }
+}
+
 impl From<CategoryPhotos> for CategoryDisplayModel {
     fn from(value: CategoryPhotos) -> Self {
         CategoryDisplayModel {
@@ -83,9 +198,7 @@ impl From<CategoryPhotos> for CategoryDisplayModel {
             photos: value
                 .1
                 .into_iter()
-                .map(|x| {
-                    PhotoViewModel::new(x.id, x.title, x.filename, x.location_taken, x.date_taken)
-                })
+                .map(|x| x.into())
                 .collect(),
         }
     }
@@ -0,0 +1,283 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+
+use anyhow::Result;
+use axum::extract::{ConnectInfo, FromRef, MatchedPath, Request, State};
+use axum::http::header::RETRY_AFTER;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use redis::Script;
+use tokio::sync::OnceCell;
+
+use crate::auth::{current_user, has_session_cookie};
+use crate::configuration::{RateLimit, RateLimitSettings};
+use crate::domain::AppState;
+use crate::sessions::SessionManager;
+
+const KEY_PREFIX: &str = "ratelimit";
+const API_KEY_HEADER: &str = "x-api-key";
+// waiting on a slow Redis would throttle everyone
+const REDIS_TIMEOUT: Duration = Duration::from_millis(500);
+
+// Refills the bucket for the time since it was last touched, then takes a
+// token if there is one. Uses the Redis clock so replicas agree on it.
+static TOKEN_BUCKET: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+local burst = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + tonumber(time[2]) / 1000
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated_at')
+local tokens = tonumber(bucket[1]) or burst
+local updated_at = tonumber(bucket[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - updated_at) * rate / 1000)
+local allowed = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+    allowed = 1
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated_at', tostring(now))
+-- once refilled the bucket is the same as a missing one
+redis.call('PEXPIRE', KEYS[1], math.ceil((burst - tokens) * 1000 / rate) + 1000)
+return {allowed, tostring(tokens)}
+",
+    )
+});
+
+/// Per-client token buckets for the routes in `RateLimitSettings`, which
+/// are read from the live settings on every request.
+///
+/// Clients are told apart by signed-in user, then a known `X-Api-Key`, then
+/// address. Requests are let through when Redis is unavailable.
+#[derive(Clone)]
+pub struct RateLimiter {
+    redis: redis::Client,
+    connection: Arc<OnceCell<ConnectionManager>>,
+}
+
+impl std::fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RateLimiter").finish_non_exhaustive()
+    }
+}
+
+/// Outcome of taking a token from a bucket.
+#[derive(Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: RateLimit,
+    /// tokens left, fractional while refilling
+    pub tokens: f64,
+}
+
+impl Decision {
+    fn seconds_until(&self, tokens: f64) -> u64 {
+        ((tokens - self.tokens).max(0.0) / self.limit.per_second).ceil() as u64
+    }
+
+    /// Whole seconds until the next token, at least one.
+    pub fn retry_after(&self) -> u64 {
+        self.seconds_until(1.0).max(1)
+    }
+
+    /// `RateLimit-*` headers as in draft-ietf-httpapi-ratelimit-headers.
+    pub fn headers(&self) -> HeaderMap {
+        let window = (self.limit.burst as f64 / self.limit.per_second).ceil() as u64;
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("ratelimit-limit", self.limit.burst.to_string()),
+            (
+                "ratelimit-remaining",
+                (self.tokens.floor() as u64).to_string(),
+            ),
+            (
+                "ratelimit-reset",
+                self.seconds_until(self.limit.burst as f64).to_string(),
+            ),
+            (
+                "ratelimit-policy",
+                format!("{};w={}", self.limit.burst, window),
+            ),
+        ] {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(HeaderName::from_static(name), value);
+            }
+        }
+        headers
+    }
+
+    /// The `429` for a request that found the bucket empty.
+    pub fn too_many_requests(&self) -> Response {
+        let retry_after = self.retry_after();
+        let mut response = (
+            StatusCode::TOO_MANY_REQUESTS,
+            format!("Too many requests, retry in {} seconds", retry_after),
+        )
+            .into_response();
+        response.headers_mut().extend(self.headers());
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        response
+    }
+}
+
+impl RateLimiter {
+    pub fn new(redis: redis::Client) -> Self {
+        Self {
+            redis,
+            connection: Arc::new(OnceCell::new()),
+        }
+    }
+
+    async fn connection(&self) -> Result<ConnectionManager> {
+        let connection = self
+            .connection
+            .get_or_try_init(|| {
+                let config = ConnectionManagerConfig::new()
+                    .set_connection_timeout(REDIS_TIMEOUT)
+                    .set_response_timeout(REDIS_TIMEOUT)
+                    .set_number_of_retries(1);
+                ConnectionManager::new_with_config(self.redis.clone(), config)
+            })
+            .await?;
+        Ok(connection.clone())
+    }
+
+    /// The bucket a request to `path` draws from and its limit.
+    fn limit_for(
+        settings: &RateLimitSettings,
+        method: &Method,
+        path: &str,
+    ) -> Option<(String, RateLimit)> {
+        settings
+            .routes
+            .iter()
+            .find(|route| {
+                route.path == path
+                    && route
+                        .method
+                        .as_deref()
+                        .is_none_or(|x| x.eq_ignore_ascii_case(method.as_str()))
+            })
+            .map(|route| {
+                let method = route.method.as_deref().unwrap_or("*");
+                (format!("{} {}", method, route.path), route.limit())
+            })
+            .or_else(|| settings.default.map(|x| ("default".into(), x)))
+    }
+
+    async fn client_key(
+        &self,
+        settings: &RateLimitSettings,
+        sessions: &SessionManager,
+        headers: &HeaderMap,
+        ip: Option<IpAddr>,
+    ) -> String {
+        // anonymous requests don't cost a session lookup
+        if has_session_cookie(headers) {
+            if let Some(user) = current_user(sessions, headers).await {
+                return format!("user:{}", user.sub);
+            }
+        }
+        // a made-up key would otherwise buy a fresh bucket on every request
+        if let Some(client) = headers
+            .get(API_KEY_HEADER)
+            .and_then(|x| settings.api_key_client(x.as_bytes()))
+        {
+            return format!("key:{}", client);
+        }
+        match ip {
+            Some(ip) => format!("ip:{}", ip),
+            None => "ip:unknown".into(),
+        }
+    }
+
+    fn client_ip(settings: &RateLimitSettings, request: &Request) -> Option<IpAddr> {
+        if settings.trust_forwarded_for {
+            // the proxy appends the address it saw; earlier entries are
+            // whatever the client sent
+            let forwarded = request
+                .headers()
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|x| x.to_str().ok())
+                .flat_map(|x| x.split(','))
+                .last()
+                .and_then(|x| x.trim().parse().ok());
+            if forwarded.is_some() {
+                return forwarded;
+            }
+        }
+        request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+    }
+
+    #[tracing::instrument(name = "redis.rate_limit", target = "redis", skip_all)]
+    async fn take(&self, bucket: &str, client: &str, limit: RateLimit) -> Result<Decision> {
+        let mut con = self.connection().await?;
+        let (allowed, tokens): (i64, String) = TOKEN_BUCKET
+            .key(format!("{}:{}:{}", KEY_PREFIX, bucket, client))
+            .arg(limit.burst)
+            .arg(limit.per_second)
+            .invoke_async(&mut con)
+            .await?;
+        Ok(Decision {
+            allowed: allowed == 1,
+            limit,
+            tokens: tokens.parse()?,
+        })
+    }
+}
+
+/// Answers 429 once a client has used up its bucket for the route.
+pub async fn rate_limit(State(app): State<AppState>, request: Request, next: Next) -> Response {
+    let settings = app.live_settings.settings();
+    let settings = &settings.rate_limit_settings;
+    let Some(limiter) = app.rate_limiter.as_ref().filter(|_| settings.enabled) else {
+        return next.run(request).await;
+    };
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|x| x.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".into());
+    let Some((bucket, limit)) = RateLimiter::limit_for(settings, request.method(), &path) else {
+        return next.run(request).await;
+    };
+    let ip = RateLimiter::client_ip(settings, &request);
+    let client = limiter
+        .client_key(settings, &SessionManager::from_ref(&app), request.headers(), ip)
+        .await;
+
+    let decision = match limiter.take(&bucket, &client, limit).await {
+        Ok(decision) => decision,
+        Err(e) => {
+            metrics::counter!("rate_limit_requests_total", "path" => path, "result" => "error")
+                .increment(1);
+            tracing::warn!(
+                "Failed to check rate limit, letting request through: {:?}",
+                e
+            );
+            return next.run(request).await;
+        }
+    };
+    if !decision.allowed {
+        metrics::counter!("rate_limit_requests_total", "path" => path, "result" => "limited")
+            .increment(1);
+        tracing::info!("rate limited {} on {}", client, bucket);
+        return decision.too_many_requests();
+    }
+
+    metrics::counter!("rate_limit_requests_total", "path" => path, "result" => "allowed")
+        .increment(1);
+    let mut response = next.run(request).await;
+    response.headers_mut().extend(decision.headers());
+    response
+}
@@ -0,0 +1,231 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use arc_swap::ArcSwap;
+use tokio::task::JoinHandle;
+use tower_http::cors::CorsLayer;
+use tracing::info;
+
+use crate::app::cors_layer;
+use crate::configuration::{
+    configuration_directory, DynamicSettings, ReloadSettings, StaticSettings,
+};
+use crate::shutdown::Shutdown;
+use crate::telemetry::LogFilter;
+
+/// The `DynamicSettings` currently in effect. Reloads replace them as a
+/// whole, so a request never sees half of one version and half of another.
+#[derive(Clone)]
+pub struct LiveSettings {
+    current: Arc<ArcSwap<Snapshot>>,
+    log_filter: Option<LogFilter>,
+    /// the settings a reload can't apply, as the process started with them
+    static_settings: Option<Arc<StaticSettings>>,
+}
+
+struct Snapshot {
+    settings: Arc<DynamicSettings>,
+    // built once per reload rather than per request
+    cors: CorsLayer,
+}
+
+impl Snapshot {
+    fn new(settings: DynamicSettings) -> Result<Self> {
+        let cors = cors_layer(&settings.cors)?;
+        Ok(Self {
+            settings: Arc::new(settings),
+            cors,
+        })
+    }
+}
+
+impl std::fmt::Debug for LiveSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LiveSettings")
+            .field("settings", &self.settings())
+            .finish()
+    }
+}
+
+impl Default for LiveSettings {
+    fn default() -> Self {
+        Self::new(DynamicSettings::default(), None).expect("the default CORS settings are valid")
+    }
+}
+
+impl LiveSettings {
+    /// `log_filter` is updated along with the settings, if given. Fails if
+    /// the CORS settings are invalid.
+    pub fn new(settings: DynamicSettings, log_filter: Option<LogFilter>) -> Result<Self> {
+        Ok(Self {
+            current: Arc::new(ArcSwap::from_pointee(Snapshot::new(settings)?)),
+            log_filter,
+            static_settings: None,
+        })
+    }
+
+    /// Reloads warn about changes to `static_settings`, which only take
+    /// effect on restart.
+    pub fn with_static_settings(mut self, static_settings: StaticSettings) -> Self {
+        self.static_settings = Some(Arc::new(static_settings));
+        self
+    }
+
+    pub fn settings(&self) -> Arc<DynamicSettings> {
+        self.current.load().settings.clone()
+    }
+
+    /// Set when logging was set up by this process.
+    pub fn log_filter(&self) -> Option<&LogFilter> {
+        self.log_filter.as_ref()
+    }
+
+    pub(crate) fn cors(&self) -> CorsLayer {
+        self.current.load().cors.clone()
+    }
+
+    /// Validates `settings` and puts them in effect, logging the outcome.
+    /// The current settings are kept if they're invalid. `trigger` says
+    /// what asked for the change.
+    pub fn apply(&self, settings: DynamicSettings, trigger: &str) -> Result<()> {
+        let result = self.swap(settings);
+        audit(trigger, &result);
+        result.map(|_| ())
+    }
+
+    /// Loads the configuration files again and applies their dynamic
+    /// settings, warning about changes to the rest.
+    pub async fn reload(&self, trigger: &str) {
+        match DynamicSettings::load().await {
+            Ok((settings, static_settings)) => {
+                if let Some(running) = &self.static_settings {
+                    let changed = static_settings.changed_from(running);
+                    if !changed.is_empty() {
+                        tracing::warn!(
+                            target: "audit",
+                            trigger,
+                            ?changed,
+                            "settings changed that only take effect on restart"
+                        );
+                    }
+                }
+                // rejections are audited by `apply`
+                let _ = self.apply(settings, trigger);
+            }
+            Err(e) => audit(trigger, &Err(e)),
+        }
+    }
+
+    /// Returns the names of the settings that changed.
+    fn swap(&self, settings: DynamicSettings) -> Result<Vec<&'static str>> {
+        settings.validate()?;
+        let snapshot = Snapshot::new(settings)?;
+        // left alone otherwise, so `RUST_LOG` and changes made through
+        // `/admin/log-filter` survive reloads
+        if snapshot.settings.log_level != self.settings().log_level {
+            if let Some(log_filter) = &self.log_filter {
+                log_filter.set(&snapshot.settings.log_level)?;
+            }
+        }
+        let settings = snapshot.settings.clone();
+        let previous = self.current.swap(Arc::new(snapshot));
+        Ok(settings.changed_from(&previous.settings))
+    }
+
+    /// Reloads on SIGHUP and, if `settings.watch` is set, whenever a file
+    /// in `config/` changes, until `shutdown` is triggered.
+    pub fn watch(&self, settings: &ReloadSettings, shutdown: Shutdown) -> JoinHandle<()> {
+        let live = self.clone();
+        let watch = settings.watch;
+        let mut interval = tokio::time::interval(settings.poll_interval());
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        tokio::spawn(async move {
+            let directory = match configuration_directory() {
+                Ok(directory) => directory,
+                Err(e) => {
+                    tracing::error!("Not watching the configuration: {:?}", e);
+                    return;
+                }
+            };
+            let mut hangup = Hangup::new();
+            let mut last_modified = modified_times(&directory).await;
+            loop {
+                let trigger = tokio::select! {
+                    _ = shutdown.wait() => return,
+                    _ = hangup.recv() => "SIGHUP",
+                    _ = interval.tick(), if watch => {
+                        let modified = modified_times(&directory).await;
+                        if modified == last_modified {
+                            continue;
+                        }
+                        last_modified = modified;
+                        "file change"
+                    }
+                };
+                live.reload(trigger).await;
+            }
+        })
+    }
+}
+
+fn audit(trigger: &str, result: &Result<Vec<&'static str>>) {
+    match result {
+        Ok(changed) => {
+            metrics::counter!("config_reloads_total", "result" => "applied").increment(1);
+            info!(target: "audit", trigger, ?changed, "applied dynamic settings");
+        }
+        Err(e) => {
+            metrics::counter!("config_reloads_total", "result" => "rejected").increment(1);
+            tracing::error!(
+                target: "audit",
+                trigger,
+                "Rejected dynamic settings, keeping the current ones: {:#}",
+                e
+            );
+        }
+    }
+}
+
+/// Modification times of the files in `directory`. Links are followed, as
+/// Kubernetes mounts ConfigMaps as links it swaps on update.
+async fn modified_times(directory: &Path) -> Vec<(String, SystemTime)> {
+    let mut times = Vec::new();
+    let Ok(mut entries) = tokio::fs::read_dir(directory).await else {
+        return times;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if let Ok(modified) = tokio::fs::metadata(entry.path())
+            .await
+            .and_then(|x| x.modified())
+        {
+            times.push((entry.file_name().to_string_lossy().into_owned(), modified));
+        }
+    }
+    times.sort();
+    times
+}
+
+/// SIGHUP, which reloads the settings and the TLS certificate.
+pub(crate) struct Hangup {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl Hangup {
+    pub(crate) fn new() -> Self {
+        Self {
+            #[cfg(unix)]
+            signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                .expect("failed to install SIGHUP handler"),
+        }
+    }
+
+    pub(crate) async fn recv(&mut self) {
+        #[cfg(unix)]
+        self.signal.recv().await;
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+    }
+}
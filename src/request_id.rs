@@ -0,0 +1,57 @@
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+
+/// Accepted from clients and generated when missing or unusable, echoed on
+/// responses and forwarded to other services.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest request id taken from a client.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Whether a client's request id can be used as is: short, and only made of
+/// characters that are safe to log and to forward.
+pub fn is_valid_request_id(value: &[u8]) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_REQUEST_ID_LEN
+        && value
+            .iter()
+            .all(|x| x.is_ascii_alphanumeric() || b"-_.:".contains(x))
+}
+
+/// Drops a request id that `is_valid_request_id` refuses, so that
+/// `SetRequestIdLayer` generates a new one.
+pub async fn drop_invalid_request_id(mut request: Request, next: Next) -> Response {
+    let invalid = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .is_some_and(|x| !is_valid_request_id(x.as_bytes()));
+    if invalid {
+        tracing::debug!("replacing an unusable {} header", REQUEST_ID_HEADER);
+        request.headers_mut().remove(REQUEST_ID_HEADER);
+    }
+    next.run(request).await
+}
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Id of the request being handled by the current task, if any.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|x| x.clone()).ok()
+}
+
+/// Makes the request id set by `SetRequestIdLayer` available to
+/// `current_request_id` while the request is handled.
+pub async fn scope_request_id(request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .map(str::to_owned);
+    match request_id {
+        Some(request_id) => REQUEST_ID.scope(request_id, next.run(request)).await,
+        None => next.run(request).await,
+    }
+}
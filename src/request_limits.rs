@@ -0,0 +1,73 @@
+use axum::body::Body;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http_body_util::Limited;
+
+use crate::configuration::RequestLimit;
+use crate::domain::AppState;
+use crate::error_handling::Problem;
+
+/// Applies the timeout and body limit configured for the route. Both are
+/// answered with a problem response.
+pub async fn request_limits(State(app): State<AppState>, request: Request, next: Next) -> Response {
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|x| x.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".into());
+    let limit = app
+        .request_limit_settings
+        .limit_for(request.method().as_str(), &path);
+
+    let declared_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse::<u64>().ok());
+    if declared_length.is_some_and(|x| x > limit.body_limit_bytes as u64) {
+        return too_large(&limit).into_response();
+    }
+    // bodies without a length are cut off while they're read, which the
+    // extractors answer with a plain 413
+    let request = request.map(|body| Body::new(Limited::new(body, limit.body_limit_bytes)));
+
+    match tokio::time::timeout(limit.timeout(), next.run(request)).await {
+        Ok(response)
+            if response.status() == StatusCode::PAYLOAD_TOO_LARGE && !is_problem(&response) =>
+        {
+            too_large(&limit).into_response()
+        }
+        Ok(response) => response,
+        Err(_) => {
+            metrics::counter!("http_request_timeouts_total", "path" => path.clone()).increment(1);
+            tracing::warn!("request to {} timed out after {:?}", path, limit.timeout());
+            Problem::new(
+                StatusCode::REQUEST_TIMEOUT,
+                format!(
+                    "The request didn't complete within {} seconds",
+                    limit.timeout_secs
+                ),
+            )
+            .into_response()
+        }
+    }
+}
+
+fn too_large(limit: &RequestLimit) -> Problem {
+    Problem::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!(
+            "The request body exceeds this route's limit of {} bytes",
+            limit.body_limit_bytes
+        ),
+    )
+}
+
+fn is_problem(response: &Response) -> bool {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|x| x.as_bytes().starts_with(Problem::CONTENT_TYPE.as_bytes()))
+}
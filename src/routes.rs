@@ -1,7 +1,17 @@
+pub mod catalog;
 pub mod categories;
 pub mod health;
+pub mod jobs;
+pub mod locations;
+pub mod logging;
+pub mod maintenance;
 pub mod photos;
 
+pub use catalog::*;
 pub use categories::*;
 pub use health::*;
+pub use jobs::*;
+pub use locations::*;
+pub use logging::*;
+pub use maintenance::*;
 pub use photos::*;
@@ -0,0 +1,269 @@
+use crate::auth::User;
+use crate::cache::{CacheTag, ResponseCache};
+use crate::database::{CatalogNotEmptyError, PhotoRepository};
+use crate::domain::AppState;
+use crate::jobs::JobQueue;
+use crate::models::{write_catalog, CatalogExport, JsonCatalogWriter, ZipCatalogWriter};
+
+use std::fs::File;
+use std::io::{self, Write};
+
+use axum::body::{Body, Bytes};
+use axum::extract::{Query, State};
+use axum::http::header;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{response, Json, Router};
+use chrono::Utc;
+use hyper::HeaderMap;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::mpsc;
+use tokio_util::io::ReaderStream;
+use tracing::{info, Span};
+use uuid::Uuid;
+
+pub fn catalog_router() -> Router<AppState> {
+    // catalog archives carry the whole gallery, see `request_limit_settings`
+    Router::new()
+        .route("/export", get(export_catalog))
+        .route("/import", post(import_catalog))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Zip,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+#[tracing::instrument(name = "Export catalog", skip(photo_repo))]
+pub async fn export_catalog(
+    State(photo_repo): State<PhotoRepository>,
+    _user: User,
+    Query(query): Query<ExportQuery>,
+) -> response::Result<Response, (StatusCode, String)> {
+    let exported_at = Utc::now();
+    let mut rows = photo_repo.export_catalog();
+    // a database that can't be read fails before the first row, while the
+    // status can still say so
+    let first = match rows.recv().await {
+        Some(Err(e)) => return Err(export_failed(e)),
+        first => first,
+    };
+    let rows = first
+        .into_iter()
+        .chain(std::iter::from_fn(move || rows.blocking_recv()));
+
+    let stem = format!(
+        "enchanted-natures-catalog-{}",
+        exported_at.format("%Y%m%dT%H%M%SZ")
+    );
+    match query.format {
+        ExportFormat::Json => {
+            let (chunks, body) = mpsc::channel(4);
+            let span = Span::current();
+            tokio::task::spawn_blocking(move || {
+                let _span = span.entered();
+                let result = JsonCatalogWriter::new(BodyWriter::new(chunks.clone()), exported_at)
+                    .and_then(|mut writer| {
+                        let counts = write_catalog(&mut writer, rows)?;
+                        writer.finish()?;
+                        Ok(counts)
+                    });
+                match result {
+                    Ok(counts) => info!(
+                        "exported {} photos and {} categories",
+                        counts.photos, counts.categories
+                    ),
+                    Err(e) => {
+                        tracing::error!("Failed to export catalog: {:?}", e);
+                        // fails the body, so the client doesn't mistake what
+                        // was sent for a whole catalog
+                        let _ = chunks.blocking_send(Err(io::Error::other(e)));
+                    }
+                }
+            });
+            Ok((
+                [
+                    (header::CONTENT_TYPE, "application/json".to_string()),
+                    (
+                        header::CONTENT_DISPOSITION,
+                        format!("attachment; filename=\"{}.json\"", stem),
+                    ),
+                ],
+                Body::from_stream(futures::stream::unfold(body, |mut body| async move {
+                    body.recv().await.map(|chunk| (chunk, body))
+                })),
+            )
+                .into_response())
+        }
+        ExportFormat::Zip => {
+            // an archive's entries are only complete once the writer seeks
+            // back to their headers, so it is spooled to disk rather than
+            // sent as it is written
+            let path = std::env::temp_dir().join(format!("{}-{}.zip", stem, Uuid::new_v4()));
+            let spool = path.clone();
+            let span = Span::current();
+            let result = tokio::task::spawn_blocking(move || {
+                let _span = span.entered();
+                let mut writer = ZipCatalogWriter::new(File::create(&spool)?, exported_at)?;
+                let counts = write_catalog(&mut writer, rows)?;
+                writer.finish()?;
+                Ok::<_, anyhow::Error>(counts)
+            })
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|x| x);
+            let archive = match result {
+                Ok(counts) => {
+                    info!(
+                        "exported {} photos and {} categories",
+                        counts.photos, counts.categories
+                    );
+                    tokio::fs::File::open(&path).await.map_err(Into::into)
+                }
+                Err(e) => Err(e),
+            };
+            // an open file stays readable once it is unlinked
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                if e.kind() != io::ErrorKind::NotFound {
+                    tracing::warn!("Failed to remove {}: {}", path.display(), e);
+                }
+            }
+            let archive = archive.map_err(export_failed)?;
+            Ok((
+                [
+                    (header::CONTENT_TYPE, "application/zip".to_string()),
+                    (
+                        header::CONTENT_DISPOSITION,
+                        format!("attachment; filename=\"{}.zip\"", stem),
+                    ),
+                ],
+                Body::from_stream(ReaderStream::new(archive)),
+            )
+                .into_response())
+        }
+    }
+}
+
+fn export_failed(e: anyhow::Error) -> (StatusCode, String) {
+    tracing::error!("Failed to export catalog: {:?}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Failed to export catalog: {}", e),
+    )
+}
+
+/// Sends what the JSON writer writes as body chunks, a buffer at a time.
+/// Only usable off the async runtime, as sending blocks until the client
+/// has taken earlier chunks.
+struct BodyWriter {
+    chunks: mpsc::Sender<io::Result<Bytes>>,
+    buffer: Vec<u8>,
+}
+
+impl BodyWriter {
+    const CHUNK_BYTES: usize = 64 * 1024;
+
+    fn new(chunks: mpsc::Sender<io::Result<Bytes>>) -> Self {
+        Self {
+            chunks,
+            buffer: Vec::with_capacity(Self::CHUNK_BYTES),
+        }
+    }
+}
+
+impl Write for BodyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= Self::CHUNK_BYTES {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::replace(
+            &mut self.buffer,
+            Vec::with_capacity(Self::CHUNK_BYTES),
+        ));
+        self.chunks
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the client went away"))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportQuery {
+    #[serde(default)]
+    pub replace: bool,
+}
+
+#[tracing::instrument(name = "Import catalog", skip(photo_repo, cache, jobs, headers, body))]
+pub async fn import_catalog(
+    State(photo_repo): State<PhotoRepository>,
+    State(cache): State<ResponseCache>,
+    State(jobs): State<JobQueue>,
+    _user: User,
+    Query(query): Query<ImportQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> response::Result<impl IntoResponse, (StatusCode, String)> {
+    let is_zip = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .is_some_and(|x| x.starts_with("application/zip"));
+
+    let catalog = if is_zip {
+        CatalogExport::from_zip(&body)
+    } else {
+        serde_json::from_slice::<CatalogExport>(&body).map_err(Into::into)
+    }
+    .and_then(|catalog| catalog.check_version().map(|_| catalog))
+    .map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid catalog document: {}", e),
+        )
+    })?;
+
+    let photos = catalog.photos.len();
+    let categories = catalog.categories.len();
+    match photo_repo.import_catalog(catalog, query.replace).await {
+        Ok(()) => {
+            info!("imported {} photos and {} categories", photos, categories);
+            // the catalog is imported either way; the jobs are queued
+            // again when the server starts
+            if let Err(e) = jobs.enqueue_after_import().await {
+                tracing::error!("Failed to queue processing of the imported photos: {:?}", e);
+            }
+            cache
+                .invalidate(&[CacheTag::Photos, CacheTag::Categories, CacheTag::Locations])
+                .await;
+            Ok((
+                StatusCode::CREATED,
+                Json(json!({ "photos": photos, "categories": categories })),
+            ))
+        }
+        Err(e) if e.is::<CatalogNotEmptyError>() => Err((StatusCode::CONFLICT, e.to_string())),
+        Err(e) => {
+            tracing::error!("Failed to import catalog: {:?}", e);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to import catalog: {}", e),
+            ))
+        }
+    }
+}
@@ -1,8 +1,10 @@
 use crate::auth::User;
+use crate::cache::{CacheTag, ResponseCache};
 
 use crate::database::PhotoRepository;
 use crate::models::CategoryDisplayModel;
 use crate::models::CategoryViewModel;
+use crate::routes::location_index;
 
 use axum::extract::Path;
 use axum::extract::State;
@@ -41,9 +43,10 @@ pub struct CreateCategoryRequest {
     pub name: String,
 }
 
-#[tracing::instrument(name = "add photo to category", skip(photo_repo))]
+#[tracing::instrument(name = "add photo to category", skip(photo_repo, cache))]
 pub async fn add_photo_to_category(
     State(photo_repo): State<PhotoRepository>,
+    State(cache): State<ResponseCache>,
     Path(category_id): Path<i32>,
     user: User,
     Json(request): Json<AddPhotoToCategoryRequest>,
@@ -52,7 +55,10 @@ pub async fn add_photo_to_category(
         .add_photo_to_category(request.photo_id, category_id, request.display_order)
         .await
     {
-        Ok(response) => Ok((StatusCode::OK, Json(response))),
+        Ok(response) => {
+            cache.invalidate(&[CacheTag::Categories]).await;
+            Ok((StatusCode::OK, Json(response)))
+        }
         Err(e) => {
             tracing::error!("Failed to add photo to category: {:?}", e);
             Err((
@@ -63,25 +69,31 @@ pub async fn add_photo_to_category(
     }
 }
 
-#[tracing::instrument(name = "Get Categories", skip(photo_repo))]
+#[tracing::instrument(name = "Get Categories", skip(photo_repo, cache))]
 pub async fn get_categories(
     State(photo_repo): State<PhotoRepository>,
+    State(cache): State<ResponseCache>,
 ) -> response::Result<impl IntoResponse, (StatusCode, String)> {
-    match photo_repo.get_categories().await {
-        Ok(resp) => {
-            info!("got {} categories", resp.len());
-            // info!("{:?}", resp);
-            let view_model: Vec<CategoryViewModel> = resp.into_iter().map(|x| x.into()).collect();
-            Ok((StatusCode::OK, Json(view_model)))
-        }
-        Err(e) => {
-            tracing::error!("Failed to get categories: {:?}", e);
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to get categories: {}", e),
-            ))
-        }
-    }
+    cache
+        .json("categories", "all", &[CacheTag::Categories], async {
+            match photo_repo.get_categories().await {
+                Ok(resp) => {
+                    info!("got {} categories", resp.len());
+                    // info!("{:?}", resp);
+                    let view_model: Vec<CategoryViewModel> =
+                        resp.into_iter().map(|x| x.into()).collect();
+                    Ok(view_model)
+                }
+                Err(e) => {
+                    tracing::error!("Failed to get categories: {:?}", e);
+                    Err((
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("Failed to get categories: {}", e),
+                    ))
+                }
+            }
+        })
+        .await
 }
 
 #[tracing::instrument(name = "Get Category", skip(app))]
@@ -89,24 +101,31 @@ pub async fn categories_by_id(
     State(app): State<AppState>,
     Path(id): Path<i32>,
 ) -> response::Result<impl IntoResponse, (StatusCode, String)> {
-    match app.repo.get_category(id).await {
-        Ok(resp) => {
-            info!("Category retrieved successfully");
-            Ok((StatusCode::OK, Json(CategoryDisplayModel::from(resp))))
-        }
-        Err(e) => {
-            tracing::error!("Failed to get category: {:?}", e);
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to get category: {}", e),
-            ))
-        }
-    }
+    let tags = [CacheTag::Categories, CacheTag::Photos, CacheTag::Locations];
+    app.cache
+        .json("category", &id.to_string(), &tags, async {
+            match app.repo.get_category(id).await {
+                Ok(resp) => {
+                    info!("Category retrieved successfully");
+                    let locations = location_index(&app.repo).await?;
+                    Ok(CategoryDisplayModel::from(resp).with_locations(&locations))
+                }
+                Err(e) => {
+                    tracing::error!("Failed to get category: {:?}", e);
+                    Err((
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("Failed to get category: {}", e),
+                    ))
+                }
+            }
+        })
+        .await
 }
 
-#[tracing::instrument(name = "add category", skip(photo_repository))]
+#[tracing::instrument(name = "add category", skip(photo_repository, cache))]
 pub async fn post_category(
     State(photo_repository): State<PhotoRepository>,
+    State(cache): State<ResponseCache>,
     user: User,
     Json(payload): Json<CreateCategoryRequest>,
 ) -> response::Result<impl IntoResponse, (StatusCode, String)> {
@@ -115,6 +134,7 @@ pub async fn post_category(
         .await
         .unwrap()
         .into();
+    cache.invalidate(&[CacheTag::Categories]).await;
     let redirect_url = format!("/{}", category.id);
     let mut response_headers: HeaderMap = HeaderMap::new();
     response_headers.insert(
@@ -131,14 +151,14 @@ pub async fn delete_category(
     _user: User,
     Path(id): Path<i32>,
 ) -> response::Result<impl IntoResponse, (StatusCode, String)> {
-    sqlx::query!(
-        r#" DELETE FROM categories
-            WHERE id = $1 "#,
-        id
-    )
-    .execute(&*app.repo.db_pool)
-    .await
-    .unwrap();
+    app.repo.delete_category(id).await.map_err(|e| {
+        tracing::error!("Failed to delete category: {:?}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to delete category: {}", e),
+        )
+    })?;
+    app.cache.invalidate(&[CacheTag::Categories]).await;
 
     Ok(StatusCode::NO_CONTENT)
 }
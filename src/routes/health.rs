@@ -1,27 +1,162 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use axum::extract::State;
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
-use axum::Json;
+use axum::routing::get;
+use axum::{Json, Router};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+use crate::domain::AppState;
+use crate::sessions::SessionManager;
+
+pub fn health_router() -> Router<AppState> {
+    Router::new()
+        .route("/health/live", get(health_check))
+        .route("/health/ready", get(readiness))
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum HealthStatusEnum {
     Ok,
     Error,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct HealthStatus {
     status: HealthStatusEnum,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    components: BTreeMap<String, ComponentHealth>,
+}
+
+/// Whether a dependency is up and how long it took to answer; why it isn't
+/// up is logged rather than told to whoever asks.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ComponentHealth {
+    status: HealthStatusEnum,
+    /// unset for components that weren't probed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<f64>,
 }
 
 impl HealthStatus {
     pub(crate) fn new() -> Self {
         HealthStatus {
             status: HealthStatusEnum::Ok,
+            components: BTreeMap::new(),
         }
     }
+
+    fn from_components(components: BTreeMap<String, ComponentHealth>) -> Self {
+        let status = if components
+            .values()
+            .all(|x| x.status == HealthStatusEnum::Ok)
+        {
+            HealthStatusEnum::Ok
+        } else {
+            HealthStatusEnum::Error
+        };
+        HealthStatus { status, components }
+    }
 }
 
+/// Liveness: the process is up and serving requests.
 pub async fn health_check() -> impl IntoResponse {
     (StatusCode::OK, Json(HealthStatus::new()))
 }
+
+/// Readiness: every dependency needed to serve traffic responds.
+#[tracing::instrument(name = "Readiness", skip(app, sessions))]
+pub async fn readiness(
+    State(app): State<AppState>,
+    State(sessions): State<SessionManager>,
+) -> impl IntoResponse {
+    if app.shutdown.is_triggered() {
+        // load balancers should stop sending traffic before the listener
+        // closes; dependencies may already be going away, so skip probing
+        tracing::info!("not ready: shutting down");
+        let draining = ComponentHealth {
+            status: HealthStatusEnum::Error,
+            latency_ms: None,
+        };
+        let health =
+            HealthStatus::from_components(BTreeMap::from([("server".to_string(), draining)]));
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(health));
+    }
+
+    let timeout = Duration::from_millis(app.health_settings.timeout_ms);
+    let (database, redis, migrations, oauth) = tokio::join!(
+        probe(timeout, app.repo.ping()),
+        probe(timeout, sessions.ping()),
+        probe(timeout, async {
+            match app.repo.pending_migrations().await? {
+                pending if pending.is_empty() => Ok(()),
+                pending => anyhow::bail!("pending migrations: {:?}", pending),
+            }
+        }),
+        async {
+            let url = app.health_settings.oauth_discovery_url.as_ref()?;
+            Some(
+                probe(timeout, async {
+                    app.http_client.get(url).send().await?.error_for_status()?;
+                    Ok(())
+                })
+                .await,
+            )
+        },
+    );
+
+    let mut probes = vec![
+        ("database", database),
+        ("redis", redis),
+        ("migrations", migrations),
+    ];
+    if let Some(oauth) = oauth {
+        probes.push(("oauth", oauth));
+    }
+    let mut components = BTreeMap::new();
+    for (name, probe) in probes {
+        let status = match probe.result {
+            Ok(()) => {
+                tracing::debug!(component = name, latency = ?probe.latency, "ready");
+                HealthStatusEnum::Ok
+            }
+            Err(e) => {
+                tracing::warn!(
+                    component = name,
+                    latency = ?probe.latency,
+                    "not ready: {:#}",
+                    e
+                );
+                HealthStatusEnum::Error
+            }
+        };
+        let latency_ms = Some(probe.latency.as_secs_f64() * 1000.0);
+        components.insert(name.to_string(), ComponentHealth { status, latency_ms });
+    }
+    let health = HealthStatus::from_components(components);
+    let status = match health.status {
+        HealthStatusEnum::Ok => StatusCode::OK,
+        HealthStatusEnum::Error => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(health))
+}
+
+struct Probe {
+    result: anyhow::Result<()>,
+    latency: Duration,
+}
+
+async fn probe(timeout: Duration, check: impl Future<Output = anyhow::Result<()>>) -> Probe {
+    let start = Instant::now();
+    let result = match tokio::time::timeout(timeout, check).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow::anyhow!("timed out after {:?}", timeout)),
+    };
+    Probe {
+        result,
+        latency: start.elapsed(),
+    }
+}
@@ -0,0 +1,112 @@
+use crate::auth::User;
+use crate::database::{is_unique_violation, PhotoRepository};
+use crate::domain::AppState;
+
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::{get, post};
+use axum::{response, Json, Router};
+use serde::Deserialize;
+use tracing::info;
+
+const DEFAULT_JOBS_LIMIT: i64 = 100;
+
+pub fn jobs_router() -> Router<AppState> {
+    Router::new()
+        .route("/admin/jobs", get(get_jobs))
+        .route("/admin/jobs/:id", get(get_job))
+        .route("/admin/jobs/:id/retry", post(retry_job))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JobsQuery {
+    /// `pending`, `running`, `completed` or `dead`
+    pub status: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[tracing::instrument(name = "Get jobs", skip(photo_repo))]
+pub async fn get_jobs(
+    State(photo_repo): State<PhotoRepository>,
+    _user: User,
+    Query(query): Query<JobsQuery>,
+) -> response::Result<impl IntoResponse, (StatusCode, String)> {
+    let limit = query.limit.unwrap_or(DEFAULT_JOBS_LIMIT).clamp(1, 1000);
+    match photo_repo.get_jobs(query.status.as_deref(), limit).await {
+        Ok(jobs) => {
+            info!("got {} jobs", jobs.len());
+            Ok((StatusCode::OK, Json(jobs)))
+        }
+        Err(e) => {
+            tracing::error!("Failed to get jobs: {:?}", e);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to get jobs: {}", e),
+            ))
+        }
+    }
+}
+
+#[tracing::instrument(name = "Get job", skip(photo_repo))]
+pub async fn get_job(
+    State(photo_repo): State<PhotoRepository>,
+    _user: User,
+    Path(id): Path<i64>,
+) -> response::Result<impl IntoResponse, (StatusCode, String)> {
+    match photo_repo.get_job(id).await {
+        Ok(Some(job)) => Ok((StatusCode::OK, Json(job))),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            format!("Job with id: {} not found", id),
+        )),
+        Err(e) => {
+            tracing::error!("Failed to get job: {:?}", e);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to get job: {}", e),
+            ))
+        }
+    }
+}
+
+#[tracing::instrument(name = "Retry job", skip(photo_repo))]
+pub async fn retry_job(
+    State(photo_repo): State<PhotoRepository>,
+    _user: User,
+    Path(id): Path<i64>,
+) -> response::Result<impl IntoResponse, (StatusCode, String)> {
+    let internal_error = |e: anyhow::Error| {
+        tracing::error!("Failed to retry job: {:?}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to retry job: {}", e),
+        )
+    };
+    let retried = photo_repo.retry_job(id).await.map_err(|e| {
+        if is_unique_violation(&e) {
+            (
+                StatusCode::CONFLICT,
+                format!("Job with id: {} has been queued again as another job", id),
+            )
+        } else {
+            internal_error(e)
+        }
+    })?;
+    match retried {
+        Some(job) => {
+            info!("job {} queued for retry", id);
+            Ok((StatusCode::OK, Json(job)))
+        }
+        None => match photo_repo.get_job(id).await.map_err(internal_error)? {
+            Some(job) => Err((
+                StatusCode::CONFLICT,
+                format!("Job with id: {} is {} and can't be retried", id, job.status),
+            )),
+            None => Err((
+                StatusCode::NOT_FOUND,
+                format!("Job with id: {} not found", id),
+            )),
+        },
+    }
+}
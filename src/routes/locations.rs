@@ -0,0 +1,80 @@
+use std::sync::Arc;
+
+use crate::cache::{CacheTag, ResponseCache};
+use crate::database::PhotoRepository;
+use crate::models::{LocationIndex, PhotoViewModel};
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{response, Json, Router};
+use tracing::info;
+
+use crate::domain::AppState;
+
+pub fn locations_router() -> Router<AppState> {
+    Router::new()
+        .route("/locations", get(get_locations))
+        .route("/locations/:id/photos", get(get_location_photos))
+}
+
+/// Every location, so photo view models can be given their structured
+/// location without a query per photo.
+pub async fn location_index(
+    photo_repo: &PhotoRepository,
+) -> response::Result<Arc<LocationIndex>, (StatusCode, String)> {
+    photo_repo.get_location_index().await.map_err(|e| {
+        tracing::error!("Failed to get locations: {:?}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to get locations: {}", e),
+        )
+    })
+}
+
+#[tracing::instrument(name = "Get locations", skip(photo_repo, cache))]
+pub async fn get_locations(
+    State(photo_repo): State<PhotoRepository>,
+    State(cache): State<ResponseCache>,
+) -> response::Result<impl IntoResponse, (StatusCode, String)> {
+    cache
+        .json("locations", "all", &[CacheTag::Locations], async {
+            let locations = location_index(&photo_repo).await?;
+            let view_models = locations.view_models();
+            info!("got {} locations", view_models.len());
+            Ok(view_models)
+        })
+        .await
+}
+
+#[tracing::instrument(name = "Get location photos", skip(photo_repo))]
+pub async fn get_location_photos(
+    State(photo_repo): State<PhotoRepository>,
+    Path(id): Path<i32>,
+) -> response::Result<impl IntoResponse, (StatusCode, String)> {
+    let locations = location_index(&photo_repo).await?;
+    if locations.get(id).is_none() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Location with id: {} not found", id),
+        ));
+    }
+    match photo_repo.get_photos_in_location(id).await {
+        Ok(response) => {
+            info!("retrieved {} photos", response.len());
+            let view_models: Vec<PhotoViewModel> = response
+                .into_iter()
+                .map(|x| PhotoViewModel::from(x).with_location(&locations))
+                .collect();
+            Ok((StatusCode::OK, Json(view_models)))
+        }
+        Err(e) => {
+            tracing::error!("Failed to get photos: {:?}", e);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to get photos: {}", e),
+            ))
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use crate::auth::User;
+use crate::domain::AppState;
+use crate::reload::LiveSettings;
+use crate::telemetry::LogFilter;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{response, Json, Router};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+pub fn logging_router() -> Router<AppState> {
+    Router::new().route("/admin/log-filter", get(get_log_filter).put(put_log_filter))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LogFilterBody {
+    /// `EnvFilter` directives, e.g. `info,api::jobs=debug`
+    pub directives: String,
+}
+
+fn log_filter(live_settings: &LiveSettings) -> Result<&LogFilter, (StatusCode, String)> {
+    live_settings.log_filter().ok_or_else(|| {
+        (
+            StatusCode::NOT_IMPLEMENTED,
+            "Logging isn't set up in this process".to_string(),
+        )
+    })
+}
+
+#[tracing::instrument(name = "Get log filter", skip(live_settings))]
+pub async fn get_log_filter(
+    State(live_settings): State<LiveSettings>,
+    _user: User,
+) -> response::Result<impl IntoResponse, (StatusCode, String)> {
+    let directives = log_filter(&live_settings)?.current().unwrap_or_default();
+    Ok(Json(LogFilterBody { directives }))
+}
+
+/// Replaces the log filter until the process restarts or a reload changes
+/// `log_level`. Only this process is affected; every replica has to be
+/// called to change the logging of the whole deployment.
+#[tracing::instrument(name = "Set log filter", skip(live_settings))]
+pub async fn put_log_filter(
+    State(live_settings): State<LiveSettings>,
+    user: User,
+    Json(body): Json<LogFilterBody>,
+) -> response::Result<impl IntoResponse, (StatusCode, String)> {
+    log_filter(&live_settings)?
+        .set(&body.directives)
+        .map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid log filter: {}", e),
+            )
+        })?;
+    info!(
+        target: "audit",
+        user = %user.sub,
+        directives = %body.directives,
+        "changed log filter"
+    );
+    Ok(Json(body))
+}
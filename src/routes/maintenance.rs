@@ -0,0 +1,52 @@
+use crate::auth::User;
+use crate::domain::AppState;
+use crate::jobs::Job;
+
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::post;
+use axum::{response, Json, Router};
+use tracing::info;
+
+pub fn maintenance_router() -> Router<AppState> {
+    Router::new().route("/maintenance/verify", post(verify_storage))
+}
+
+/// Queues a job that re-hashes every stored original. The findings end up
+/// in the job's `result`; a verification that is already outstanding is
+/// returned instead of queueing another.
+#[tracing::instrument(name = "Verify storage", skip(app))]
+pub async fn verify_storage(
+    State(app): State<AppState>,
+    _user: User,
+) -> response::Result<impl IntoResponse, (StatusCode, String)> {
+    let internal_error = |e: anyhow::Error| {
+        tracing::error!("Failed to queue storage verification: {:?}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to queue storage verification: {}", e),
+        )
+    };
+    let job = Job::VerifyStorage;
+    let dedupe_key = job.dedupe_key().unwrap_or_default();
+    let record = match app.jobs.enqueue(job).await.map_err(internal_error)? {
+        Some(record) => {
+            info!("queued storage verification as job {}", record.id);
+            record
+        }
+        None => app
+            .repo
+            .get_outstanding_job(&dedupe_key)
+            .await
+            .map_err(internal_error)?
+            .ok_or_else(|| {
+                internal_error(anyhow::anyhow!("verification completed while queueing"))
+            })?,
+    };
+    Ok((
+        StatusCode::ACCEPTED,
+        [(header::LOCATION, format!("/admin/jobs/{}", record.id))],
+        Json(record),
+    ))
+}
@@ -1,26 +1,54 @@
 use crate::auth::User;
+use crate::cache::{CacheTag, ResponseCache};
+use crate::configuration::ImageSettings;
 use crate::database::PhotoRepository;
 use crate::domain::AppState;
 use crate::error_handling::AppError;
-use crate::models::{Photo, PhotoViewModel};
+use crate::images::{
+    best_variant, decode_image, original_content_type, perceptual_hash, variant_storage,
+    VariantFormat, MAX_DUPLICATE_DISTANCE,
+};
+use crate::jobs::Job;
+use crate::metadata::{extract_metadata, PhotoMetadata};
+use crate::models::{
+    group_duplicates, BoundingBox, Coordinates, DuplicateGroupViewModel, LocationIndex, NewPhoto,
+    Photo, PhotoChanges, PhotoViewModel, SimilarPhoto, SimilarPhotoViewModel,
+};
+use crate::routes::location_index;
+use crate::storage::{content_hash, ObjectExistsError};
 use anyhow::Result;
 use axum::extract::{Multipart, Path, Query, State};
+use axum::http::header;
 use axum::http::StatusCode;
-use axum::response::IntoResponse;
+use axum::response::{IntoResponse, Response};
 use axum::routing::get;
 use axum::{response, Json, Router};
 use chrono::NaiveDate;
-use serde::{Deserialize, Serialize};
+use hyper::http::HeaderValue;
+use hyper::HeaderMap;
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::json;
-use tracing::info;
+use std::collections::HashMap;
+use tracing::{debug, info};
+
+const DEFAULT_NEAR_RADIUS_KM: f64 = 25.0;
 
 pub fn photo_router() -> Router<AppState> {
     Router::new()
-        .route("/photos", get(get_photos))
+        // original photos are large, see `request_limit_settings`
+        .route("/photos", get(get_photos).post(upload_photo))
+        .route("/photos.geojson", get(get_photos_geojson))
         .route(
             "/photos/:id",
             get(get_photo).delete(delete_photo).put(put_photo),
         )
+        .route(
+            "/photos/:id/metadata",
+            get(get_photo_metadata).post(refresh_photo_metadata),
+        )
+        .route("/photos/:id/image", get(get_photo_image))
+        .route("/photos/:id/similar", get(get_similar_photos))
+        .route("/duplicates", get(get_duplicates))
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -32,7 +60,13 @@ pub async fn delete_photo(
     Path(id): Path<i32>,
     user: User,
 ) -> Result<impl IntoResponse, AppError> {
-    app.repo.delete_photo(id).await?;
+    let resources = app.repo.delete_photo(id).await?;
+    app.cache.invalidate(&[CacheTag::Photos]).await;
+    // queued even without cloudflare_settings, so the images aren't
+    // forgotten and can be retried once it is configured
+    for resource_id in resources {
+        queue_job(&app, Job::PurgeCloudflare { resource_id }).await;
+    }
     Ok((StatusCode::NO_CONTENT, Json(json!({ "deleted": &id }))))
 }
 
@@ -56,6 +90,36 @@ pub struct PhotoUpdateRequest {
     pub location_taken: Option<String>,
     pub date_taken: Option<NaiveDate>,
     pub filename: Option<String>,
+    /// `null` clears the position, leaving it out keeps it
+    #[serde(
+        default,
+        deserialize_with = "nullable",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub latitude: Option<Option<f64>>,
+    #[serde(
+        default,
+        deserialize_with = "nullable",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub longitude: Option<Option<f64>>,
+    /// `null` clears the location, leaving it out keeps it
+    #[serde(
+        default,
+        deserialize_with = "nullable",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub location_id: Option<Option<i32>>,
+}
+
+/// Reads a field given as `null` as `Some(None)`, which `Option` alone
+/// can't tell apart from a missing field.
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -74,19 +138,47 @@ pub async fn put_photo(
     user: User,
     Json(payload): Json<PhotoUpdateRequest>,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
-    info!("creating photo");
-    println!("{:?}", payload);
+    info!("updating photo");
+    debug!("{:?}", payload);
+    let coordinates = Coordinates::from_changes(payload.latitude, payload.longitude)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    if let Some(Some(location_id)) = payload.location_id {
+        let locations = location_index(&app.repo).await?;
+        if locations.get(location_id).is_none() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Location with id: {} not found", location_id),
+            ));
+        }
+    }
     let photo = app
         .repo
         .update_photo(
             id,
-            payload.title,
-            payload.filename,
-            payload.location_taken,
-            payload.date_taken,
+            PhotoChanges {
+                title: payload.title,
+                filename: payload.filename,
+                location_taken: payload.location_taken,
+                date_taken: payload.date_taken,
+                coordinates,
+                location_id: payload.location_id,
+            },
         )
         .await
-        .unwrap();
+        .map_err(|e| match e.downcast_ref::<sqlx::Error>() {
+            Some(sqlx::Error::RowNotFound) => (
+                StatusCode::NOT_FOUND,
+                format!("Photo with id: {} not found", id),
+            ),
+            _ => {
+                tracing::error!("Failed to update photo: {:?}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to update photo: {}", e),
+                )
+            }
+        })?;
+    app.cache.invalidate(&[CacheTag::Photos]).await;
     info!("photo Updated");
     Ok((StatusCode::OK, Json(photo)))
 }
@@ -97,33 +189,90 @@ pub enum GetPhotosResponses {
 }
 
 #[derive(Deserialize, Debug)]
-pub struct CategoryQuery {
-    pub category_id: i32,
+pub struct PhotosQuery {
+    pub category_id: Option<i32>,
+    /// `min_lon,min_lat,max_lon,max_lat`
+    pub bbox: Option<String>,
+    /// `lat,lon`
+    pub near: Option<String>,
+    /// kilometres around `near`
+    pub radius: Option<f64>,
 }
 
-#[tracing::instrument(name = "Get photos", skip(photo_repo))]
-pub async fn get_photos(
-    query: Option<Query<CategoryQuery>>,
-    State(photo_repo): State<PhotoRepository>,
-) -> response::Result<impl IntoResponse, (StatusCode, String)> {
-    info!("getting all photos");
-    let query_result = match query {
-        Some(category_query) => {
-            photo_repo
-                .get_photos_in_category(category_query.category_id)
-                .await
+#[derive(Debug, Default)]
+pub struct GeoFilter {
+    pub bbox: Option<BoundingBox>,
+    pub near: Option<(Coordinates, f64)>,
+}
+
+impl GeoFilter {
+    pub fn is_empty(&self) -> bool {
+        self.bbox.is_none() && self.near.is_none()
+    }
+
+    /// Cache key for photos matching this filter and `category_id`. The
+    /// same for every query string that parses the same, so reordered or
+    /// unknown parameters don't add entries. `None` when filtering by
+    /// coordinates, which any client can vary without end.
+    pub fn cache_key(&self, category_id: Option<i32>) -> Option<String> {
+        if !self.is_empty() {
+            return None;
         }
-        None => photo_repo.get_photos().await,
-    };
-    match query_result {
-        Ok(response) => {
-            info!("retrieved {} photos", response.len());
-            let view_models: Vec<PhotoViewModel> = response.into_iter().map(|x| x.into()).collect();
+        let category = category_id.map(|x| x.to_string()).unwrap_or_default();
+        Some(format!("category={}", category))
+    }
+}
 
-            Ok((StatusCode::OK, Json(view_models)))
+impl TryFrom<&PhotosQuery> for GeoFilter {
+    type Error = (StatusCode, String);
+
+    fn try_from(query: &PhotosQuery) -> Result<Self, Self::Error> {
+        let bbox = query
+            .bbox
+            .as_deref()
+            .map(str::parse::<BoundingBox>)
+            .transpose()
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid bbox: {}", e)))?;
+        let near = query
+            .near
+            .as_deref()
+            .map(str::parse::<Coordinates>)
+            .transpose()
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid near: {}", e)))?;
+        if near.is_none() && query.radius.is_some() {
+            return Err((StatusCode::BAD_REQUEST, "radius needs near".into()));
+        }
+        let radius = query.radius.unwrap_or(DEFAULT_NEAR_RADIUS_KM);
+        if !(radius.is_finite() && radius > 0.0) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "radius must be a positive number of kilometres".into(),
+            ));
         }
+        Ok(Self {
+            bbox,
+            near: near.map(|x| (x, radius)),
+        })
+    }
+}
+
+/// [`GeoFilter::cache_key`], except for categories that don't exist: any id
+/// a client makes up would get an entry of its own.
+async fn photos_cache_key(
+    photo_repo: &PhotoRepository,
+    geo_filter: &GeoFilter,
+    category_id: Option<i32>,
+) -> Result<Option<String>, (StatusCode, String)> {
+    let Some(key) = geo_filter.cache_key(category_id) else {
+        return Ok(None);
+    };
+    let Some(category_id) = category_id else {
+        return Ok(Some(key));
+    };
+    match photo_repo.category_exists(category_id).await {
+        Ok(exists) => Ok(exists.then_some(key)),
         Err(e) => {
-            tracing::error!("Failed to get photos: {:?}", e);
+            tracing::error!("Failed to look up category: {:?}", e);
             Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
                 format!("Failed to get photos: {}", e),
@@ -132,6 +281,110 @@ pub async fn get_photos(
     }
 }
 
+#[tracing::instrument(name = "Get photos", skip(photo_repo, cache))]
+pub async fn get_photos(
+    Query(query): Query<PhotosQuery>,
+    State(photo_repo): State<PhotoRepository>,
+    State(cache): State<ResponseCache>,
+) -> response::Result<impl IntoResponse, (StatusCode, String)> {
+    info!("getting all photos");
+    let geo_filter = GeoFilter::try_from(&query)?;
+    let tags = [CacheTag::Photos, CacheTag::Categories, CacheTag::Locations];
+    let key = photos_cache_key(&photo_repo, &geo_filter, query.category_id).await?;
+    let load = async {
+        let query_result = match (query.category_id, geo_filter) {
+            (category_id, geo_filter) if !geo_filter.is_empty() => {
+                photo_repo
+                    .search_photos(category_id, geo_filter.bbox, geo_filter.near)
+                    .await
+            }
+            (Some(category_id), _) => photo_repo.get_photos_in_category(category_id).await,
+            (None, _) => photo_repo.get_photos().await,
+        };
+        match query_result {
+            Ok(response) => {
+                info!("retrieved {} photos", response.len());
+                let locations = location_index(&photo_repo).await?;
+                let view_models: Vec<PhotoViewModel> = response
+                    .into_iter()
+                    .map(|x| PhotoViewModel::from(x).with_location(&locations))
+                    .collect();
+                Ok(view_models)
+            }
+            Err(e) => {
+                tracing::error!("Failed to get photos: {:?}", e);
+                Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to get photos: {}", e),
+                ))
+            }
+        }
+    };
+    match key {
+        Some(key) => cache.json("photos", &key, &tags, load).await,
+        None => cache.uncached("application/json", load).await,
+    }
+}
+
+/// Photos with coordinates as a GeoJSON FeatureCollection, for the map view.
+#[tracing::instrument(name = "Get photos geojson", skip(photo_repo, cache))]
+pub async fn get_photos_geojson(
+    Query(query): Query<PhotosQuery>,
+    State(photo_repo): State<PhotoRepository>,
+    State(cache): State<ResponseCache>,
+) -> response::Result<impl IntoResponse, (StatusCode, String)> {
+    let geo_filter = GeoFilter::try_from(&query)?;
+    let tags = [CacheTag::Photos, CacheTag::Categories];
+    let key = photos_cache_key(&photo_repo, &geo_filter, query.category_id).await?;
+    let load = async {
+        match photo_repo
+            .search_photos(query.category_id, geo_filter.bbox, geo_filter.near)
+            .await
+        {
+            Ok(photos) => {
+                let features: Vec<serde_json::Value> = photos
+                    .into_iter()
+                    .filter_map(|photo| {
+                        let coordinates = photo.coordinates()?;
+                        Some(json!({
+                            "type": "Feature",
+                            "id": photo.id,
+                            "geometry": {
+                                "type": "Point",
+                                "coordinates": [coordinates.longitude, coordinates.latitude],
+                            },
+                            "properties": {
+                                "title": photo.title,
+                                "filename": photo.filename,
+                                "location_taken": photo.location_taken,
+                                "date_taken": photo.date_taken,
+                            },
+                        }))
+                    })
+                    .collect();
+                info!("retrieved {} located photos", features.len());
+                Ok(json!({ "type": "FeatureCollection", "features": features }))
+            }
+            Err(e) => {
+                tracing::error!("Failed to get photos: {:?}", e);
+                Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to get photos: {}", e),
+                ))
+            }
+        }
+    };
+    let content_type = "application/geo+json";
+    match key {
+        Some(key) => {
+            cache
+                .json_as(content_type, "photos_geojson", &key, &tags, load)
+                .await
+        }
+        None => cache.uncached(content_type, load).await,
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GetPhotoParams {
     // PhotoId
@@ -150,16 +403,21 @@ pub async fn get_photo(
     State(app): State<AppState>,
     Path(id): Path<i32>,
 ) -> response::Result<impl IntoResponse, (StatusCode, String)> {
-    match app.repo.get_photo(id).await {
-        Ok(photo) => {
-            let view_model: PhotoViewModel = photo.into();
-            Ok((StatusCode::OK, Json(view_model)))
-        }
-        Err(_) => Err((
-            StatusCode::NOT_FOUND,
-            format!("Photo with id: {} not found", id),
-        )),
-    }
+    let tags = [CacheTag::Photos, CacheTag::Locations];
+    app.cache
+        .json("photo", &id.to_string(), &tags, async {
+            match app.repo.get_photo(id).await {
+                Ok(photo) => {
+                    let locations = location_index(&app.repo).await?;
+                    Ok(PhotoViewModel::from(photo).with_location(&locations))
+                }
+                Err(_) => Err((
+                    StatusCode::NOT_FOUND,
+                    format!("Photo with id: {} not found", id),
+                )),
+            }
+        })
+        .await
 }
 
 #[derive(Debug)]
@@ -176,6 +434,8 @@ pub struct PhotoCreateRequestBuilder {
     location_taken: String,
     date_taken: String,
     filename: String,
+    coordinates: Option<Coordinates>,
+    location_id: Option<i32>,
 }
 
 impl PhotoCreateRequestBuilder {
@@ -203,6 +463,46 @@ impl PhotoCreateRequestBuilder {
         self
     }
 
+    pub fn coordinates(mut self, coordinates: Option<Coordinates>) -> Self {
+        self.coordinates = coordinates;
+        self
+    }
+
+    /// Sets the structured location, filling `location_taken` from its
+    /// hierarchy when no free-text location was given.
+    pub fn location(mut self, location_id: Option<i32>, locations: &LocationIndex) -> Self {
+        self.location_id = location_id;
+        if self.location_taken.is_empty() {
+            if let Some(name) = location_id.and_then(|x| locations.display_name(x)) {
+                self.location_taken = name;
+            }
+        }
+        self
+    }
+
+    /// Fills any field the uploader left empty from the image's own metadata.
+    pub fn with_metadata(mut self, metadata: &PhotoMetadata) -> Self {
+        if self.title.is_empty() {
+            if let Some(title) = metadata.headline.as_ref().or(metadata.caption.as_ref()) {
+                self.title = title.clone();
+            }
+        }
+        if self.location_taken.is_empty() {
+            if let Some(location) = &metadata.location {
+                self.location_taken = location.clone();
+            }
+        }
+        if self.date_taken.is_empty() {
+            if let Some(date_taken) = metadata.date_taken() {
+                self.date_taken = date_taken.to_string();
+            }
+        }
+        if self.coordinates.is_none() {
+            self.coordinates = metadata.coordinates();
+        }
+        self
+    }
+
     pub fn build(self) -> Result<PhotoCreateRequest, PhotoCreateRequestBuilderError> {
         if self.title.is_empty() {
             return Err(PhotoCreateRequestBuilderError::TitleRequired);
@@ -222,6 +522,8 @@ impl PhotoCreateRequestBuilder {
                 location_taken: self.location_taken,
                 date_taken,
                 filename: self.filename,
+                coordinates: self.coordinates,
+                location_id: self.location_id,
             }),
             Err(_) => Err(PhotoCreateRequestBuilderError::DateTakenRequired),
         }
@@ -234,21 +536,50 @@ pub struct PhotoCreateRequest {
     pub location_taken: String,
     pub date_taken: NaiveDate,
     pub filename: String,
+    pub coordinates: Option<Coordinates>,
+    pub location_id: Option<i32>,
 }
+
+impl From<PhotoCreateRequest> for NewPhoto {
+    fn from(request: PhotoCreateRequest) -> Self {
+        Self {
+            title: request.title,
+            filename: request.filename,
+            location_taken: request.location_taken,
+            date_taken: request.date_taken,
+            coordinates: request.coordinates,
+            location_id: request.location_id,
+            perceptual_hash: None,
+            content_sha256: None,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UploadedPhotoViewModel {
+    photo: PhotoViewModel,
     file_size: usize,
+    /// existing photos that look the same as the upload
+    possible_duplicates: Vec<SimilarPhoto>,
 }
 
 impl UploadedPhotoViewModel {
-    pub fn new(file_size: usize) -> Self {
-        Self { file_size }
+    pub fn new(
+        photo: PhotoViewModel,
+        file_size: usize,
+        possible_duplicates: Vec<SimilarPhoto>,
+    ) -> Self {
+        Self {
+            photo,
+            file_size,
+            possible_duplicates,
+        }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum UploadPhotoResponses {
-    Success(UploadedPhotoViewModel),
+    Success(Box<UploadedPhotoViewModel>),
     UploadError,
 }
 
@@ -259,116 +590,576 @@ pub async fn store_photo_details(
 ) -> Result<()> {
     info!("inserting photo");
     info!("{}", payload.title);
-    let _photo = photo_repo
-        .add_photo(
-            payload.title,
-            payload.filename,
-            payload.location_taken,
-            payload.date_taken,
-        )
-        .await
-        .unwrap();
+    let _photo = photo_repo.add_photo(payload.into()).await.unwrap();
 
     info!("photo created");
     Ok(())
 }
 
-// #[tracing::instrument(name = "Save file", skip(app, multipart))]
-// pub async fn upload_photo(
-//     Path(file_name): Path<String>,
-//     State(app): State<AppState>,
-//     user: User,
-//     mut multipart: Multipart,
-// ) -> Result<impl IntoResponse, (StatusCode, String)> {
-//     tracing::info!("Uploading file for user: {:?}", user);
-//     let mut checksum: Option<String> = None;
-
-//     let mut title: Option<String> = None;
-//     let mut filename: Option<String> = None;
-//     let mut location_taken: Option<String> = None;
-//     let mut date_taken: Option<String> = None;
-
-//     while let Some(mut field) = multipart.next_field().await.unwrap() {
-//         let content_type = field.content_type();
-//         let name = field.name().unwrap();
-//         match name {
-//             "file" => {
-//                 filename = Some(field.file_name().unwrap().to_string());
-
-//                 let mut buffer = Vec::new();
-
-//                 while let Some(chunk) = field
-//                     .chunk()
-//                     .await
-//                     .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?
-//                 {
-//                     info!("received {} bytes", chunk.len());
-//                     buffer.extend_from_slice(&chunk);
-//                 }
-
-//                 tracing::info!("Uploading photo {:?}", &filename);
-
-//                 let buffer_len = &buffer.len();
-//                 let result = app.upload_photo(buffer, &file_name).await.unwrap();
-//                 tracing::info!("Uploaded file: {:?} with size: {}", &filename, buffer_len);
-//                 checksum = result.checksum_crc32;
-//             }
-//             "title" => {
-//                 title = Some(field.text().await.unwrap());
-//                 tracing::info!("Uploaded title: {:?}", &title);
-//             }
-//             "location_taken" => {
-//                 location_taken = Some(field.text().await.unwrap());
-//                 tracing::info!("Uploaded location_taken: {:?}", &location_taken);
-//             }
-//             "date_taken" => {
-//                 date_taken = Some(field.text().await.unwrap());
-//                 tracing::info!("Uploaded date_taken: {:?}", &date_taken);
-//             }
-//             _ => {
-//                 tracing::info!(
-//                     "Skipping field: {:?} with content type: {:?}",
-//                     name,
-//                     content_type
-//                 );
-//             }
-//         }
-//     }
-
-//     let photo_create_request_builder = PhotoCreateRequestBuilder::new()
-//         .title(title.unwrap())
-//         .filename(filename.unwrap())
-//         .location_taken(location_taken.unwrap())
-//         .date_taken(date_taken.unwrap());
-
-//     match checksum {
-//         Some(checksum_crc32) => {
-//             let photo_create_request = photo_create_request_builder.build();
-//             match photo_create_request {
-//                 Ok(photo_create_request) => {
-//                     store_photo_details(app.repo.clone(), user, photo_create_request)
-//                         .await
-//                         .unwrap();
-//                     Ok((StatusCode::CREATED, Json(json!({"key": checksum_crc32}))))
-//                 }
-//                 Err(e) => {
-//                     tracing::error!("Failed to upload file {}, {:?}", file_name, e);
-//                     Err((
-//                         StatusCode::INTERNAL_SERVER_ERROR,
-//                         "Failed to upload file".into(),
-//                     ))
-//                 }
-//             }
-//         }
-//         None => {
-//             tracing::error!("Failed to upload file {}", file_name);
-//             Err((
-//                 StatusCode::INTERNAL_SERVER_ERROR,
-//                 "Failed to upload file".into(),
-//             ))
-//         }
-//     }
-// }
+#[tracing::instrument(name = "Save file", skip(app, multipart))]
+pub async fn upload_photo(
+    State(app): State<AppState>,
+    user: User,
+    mut multipart: Multipart,
+) -> Result<Response, (StatusCode, String)> {
+    tracing::info!("Uploading file for user: {:?}", user);
+    let mut buffer: Option<Vec<u8>> = None;
+
+    let mut title: Option<String> = None;
+    let mut filename: Option<String> = None;
+    let mut location_taken: Option<String> = None;
+    let mut date_taken: Option<String> = None;
+    let mut latitude: Option<f64> = None;
+    let mut longitude: Option<f64> = None;
+    let mut location_id: Option<i32> = None;
+
+    while let Some(mut field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?
+    {
+        let name = field.name().unwrap_or_default().to_string();
+        match name.as_str() {
+            "file" => {
+                filename = field.file_name().map(|x| x.to_string());
+
+                let mut bytes = Vec::new();
+                while let Some(chunk) = field
+                    .chunk()
+                    .await
+                    .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?
+                {
+                    bytes.extend_from_slice(&chunk);
+                }
+                tracing::info!("Received file: {:?} with size: {}", &filename, bytes.len());
+                buffer = Some(bytes);
+            }
+            "title" | "location_taken" | "date_taken" => {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+                tracing::info!("Uploaded {}: {:?}", name, &text);
+                match name.as_str() {
+                    "title" => title = Some(text),
+                    "location_taken" => location_taken = Some(text),
+                    _ => date_taken = Some(text),
+                }
+            }
+            "latitude" | "longitude" => {
+                let value = field
+                    .text()
+                    .await
+                    .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?
+                    .trim()
+                    .parse::<f64>()
+                    .map_err(|err| (StatusCode::BAD_REQUEST, format!("{}: {}", name, err)))?;
+                if name == "latitude" {
+                    latitude = Some(value);
+                } else {
+                    longitude = Some(value);
+                }
+            }
+            "location_id" => {
+                let value = field
+                    .text()
+                    .await
+                    .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?
+                    .trim()
+                    .parse::<i32>()
+                    .map_err(|err| (StatusCode::BAD_REQUEST, format!("{}: {}", name, err)))?;
+                location_id = Some(value);
+            }
+            _ => {
+                tracing::info!(
+                    "Skipping field: {:?} with content type: {:?}",
+                    name,
+                    field.content_type()
+                );
+            }
+        }
+    }
+
+    let (Some(buffer), Some(filename)) = (buffer, filename) else {
+        return Err((StatusCode::BAD_REQUEST, "A file is required".into()));
+    };
+
+    let coordinates = Coordinates::from_parts(latitude, longitude)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let locations = location_index(&app.repo).await?;
+    if let Some(id) = location_id.filter(|x| locations.get(*x).is_none()) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Location with id: {} not found", id),
+        ));
+    }
+    let metadata = extract_metadata(&buffer);
+    let photo_create_request = PhotoCreateRequestBuilder::new()
+        .title(title.unwrap_or_default())
+        .filename(filename)
+        .location_taken(location_taken.unwrap_or_default())
+        .date_taken(date_taken.unwrap_or_default())
+        .coordinates(coordinates)
+        .location(location_id, &locations)
+        .with_metadata(&metadata)
+        .build()
+        .map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid photo details: {:?}", e),
+            )
+        })?;
+
+    let (buffer, hash, content_sha256) = hash_upload(buffer).await?;
+    match app.repo.find_photo_by_content_hash(&content_sha256).await {
+        Ok(None) => {}
+        Ok(Some(existing)) => return Ok(duplicate_upload(existing)),
+        Err(e) => {
+            tracing::error!("Failed to look up content hash: {:?}", e);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to upload file".into(),
+            ));
+        }
+    }
+
+    if let Err(e) = app.storage.path(&photo_create_request.filename) {
+        return Err((StatusCode::BAD_REQUEST, e.to_string()));
+    }
+
+    let possible_duplicates = app
+        .repo
+        .find_similar_photos(hash, app.image_settings.duplicate_distance, None)
+        .await
+        .unwrap_or_else(|e| {
+            tracing::error!("Failed to look for duplicates: {:?}", e);
+            Vec::new()
+        });
+    if !possible_duplicates.is_empty() {
+        tracing::warn!(
+            "{} looks like a duplicate of photos {:?}",
+            photo_create_request.filename,
+            possible_duplicates
+                .iter()
+                .map(|x| x.photo_id)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    app.storage
+        .put_new(&photo_create_request.filename, &buffer)
+        .await
+        .map_err(|e| {
+            if e.is::<ObjectExistsError>() {
+                return (StatusCode::CONFLICT, e.to_string());
+            }
+            tracing::error!("Failed to store file: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to upload file".into(),
+            )
+        })?;
+
+    let filename = photo_create_request.filename.clone();
+    let mut new_photo = NewPhoto::from(photo_create_request);
+    new_photo.perceptual_hash = Some(hash);
+    new_photo.content_sha256 = Some(content_sha256.clone());
+    // the photo and its metadata are written together; the stored file is
+    // removed if that fails
+    let photo = match app.repo.add_photo_with_exif(new_photo, &metadata).await {
+        Ok(photo) => photo,
+        Err(e) => {
+            if let Err(e) = app.storage.delete(&filename).await {
+                tracing::error!("Failed to remove {}: {:?}", filename, e);
+            }
+            // the same bytes may have been uploaded concurrently
+            if let Ok(Some(existing)) = app.repo.find_photo_by_content_hash(&content_sha256).await {
+                return Ok(duplicate_upload(existing));
+            }
+            tracing::error!("Failed to insert photo: {:?}", e);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to upload file".into(),
+            ));
+        }
+    };
+    queue_job(&app, Job::GenerateVariants { photo_id: photo.id }).await;
+    if photo.location_id.is_none() {
+        queue_job(
+            &app,
+            Job::AssignLocations {
+                photo_id: Some(photo.id),
+            },
+        )
+        .await;
+    }
+    if app.jobs.syncs_cloudflare() {
+        queue_job(&app, Job::SyncCloudflare { photo_id: photo.id }).await;
+    }
+    app.cache.invalidate(&[CacheTag::Photos]).await;
+
+    let mut response_headers: HeaderMap = HeaderMap::new();
+    response_headers.insert(
+        header::LOCATION,
+        HeaderValue::from_str(&format!("/photos/{}", photo.id)).unwrap(),
+    );
+    Ok((
+        StatusCode::CREATED,
+        response_headers,
+        Json(UploadedPhotoViewModel::new(
+            PhotoViewModel::from(photo).with_location(&locations),
+            buffer.len(),
+            possible_duplicates,
+        )),
+    )
+        .into_response())
+}
+
+/// 409 pointing at the photo that already has the uploaded contents.
+fn duplicate_upload(photo_id: i32) -> Response {
+    (
+        StatusCode::CONFLICT,
+        [(header::LOCATION, format!("/photos/{}", photo_id))],
+        Json(json!({
+            "message": "A photo with the same contents already exists",
+            "photo_id": photo_id,
+        })),
+    )
+        .into_response()
+}
+
+#[tracing::instrument(name = "Get photo metadata", skip(photo_repo))]
+pub async fn get_photo_metadata(
+    State(photo_repo): State<PhotoRepository>,
+    Path(id): Path<i32>,
+) -> response::Result<impl IntoResponse, (StatusCode, String)> {
+    match photo_repo.get_photo_exif(id).await {
+        Ok(Some(exif)) => Ok((StatusCode::OK, Json(exif))),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            format!("Metadata for photo with id: {} not found", id),
+        )),
+        Err(e) => {
+            tracing::error!("Failed to get photo metadata: {:?}", e);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to get photo metadata: {}", e),
+            ))
+        }
+    }
+}
+
+/// Re-reads the metadata of a photo whose file was registered in storage
+/// outside of the upload endpoint.
+#[tracing::instrument(name = "Refresh photo metadata", skip(app))]
+pub async fn refresh_photo_metadata(
+    State(app): State<AppState>,
+    Path(id): Path<i32>,
+    _user: User,
+) -> response::Result<impl IntoResponse, (StatusCode, String)> {
+    let photo = app.repo.get_photo(id).await.map_err(|_| {
+        (
+            StatusCode::NOT_FOUND,
+            format!("Photo with id: {} not found", id),
+        )
+    })?;
+    let buffer = app.storage.get(&photo.filename).await.map_err(|e| {
+        tracing::error!("Failed to read {}: {:?}", photo.filename, e);
+        (
+            StatusCode::NOT_FOUND,
+            format!("File {} not found in storage", photo.filename),
+        )
+    })?;
+    let metadata = extract_metadata(&buffer);
+    if let (None, Some(coordinates)) = (photo.coordinates(), metadata.coordinates()) {
+        app.repo
+            .update_photo(
+                id,
+                PhotoChanges {
+                    coordinates: Some(Some(coordinates)),
+                    ..Default::default()
+                },
+            )
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to update photo coordinates: {:?}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to update photo coordinates: {}", e),
+                )
+            })?;
+        app.cache.invalidate(&[CacheTag::Photos]).await;
+    }
+    match app.repo.upsert_photo_exif(id, &metadata).await {
+        Ok(exif) => {
+            queue_job(&app, Job::GenerateVariants { photo_id: id }).await;
+            Ok((StatusCode::OK, Json(exif)))
+        }
+        Err(e) => {
+            tracing::error!("Failed to store photo metadata: {:?}", e);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to store photo metadata: {}", e),
+            ))
+        }
+    }
+}
+
+/// SHA-256 and dHash of an upload. Uploads that aren't an image we can
+/// decode are rejected, including ones that make the decoder panic.
+async fn hash_upload(
+    buffer: Vec<u8>,
+) -> response::Result<(Vec<u8>, i64, String), (StatusCode, String)> {
+    let result = tokio::task::spawn_blocking(move || {
+        let content_sha256 = content_hash(&buffer);
+        let hash = decode_image(&buffer).map(|x| perceptual_hash(&x));
+        (buffer, hash, content_sha256)
+    })
+    .await;
+    match result {
+        Ok((buffer, Ok(hash), content_sha256)) => Ok((buffer, hash, content_sha256)),
+        Ok((_, Err(e), _)) => {
+            tracing::warn!("Failed to decode upload: {:?}", e);
+            Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("The file isn't an image that can be read: {}", e),
+            ))
+        }
+        Err(e) if e.is_panic() => {
+            tracing::warn!("Decoding upload panicked: {}", e);
+            Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "The file isn't an image that can be read".into(),
+            ))
+        }
+        Err(e) => {
+            tracing::error!("Hashing task failed: {:?}", e);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to upload file".into(),
+            ))
+        }
+    }
+}
+
+async fn queue_job(app: &AppState, job: Job) {
+    // the photo is stored either way; the job can be queued again later
+    if let Err(e) = app.jobs.enqueue(job.clone()).await {
+        tracing::error!("Failed to queue {:?}: {:?}", job, e);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImageQuery {
+    pub variant: Option<String>,
+    pub width: Option<u32>,
+}
+
+/// Serves the variant closest to the requested size in the best format the
+/// client accepts, or the original until variants have been generated.
+#[tracing::instrument(name = "Get photo image", skip(app, headers))]
+pub async fn get_photo_image(
+    State(app): State<AppState>,
+    Path(id): Path<i32>,
+    Query(query): Query<ImageQuery>,
+    headers: HeaderMap,
+) -> response::Result<Response, (StatusCode, String)> {
+    let photo = app.repo.get_photo(id).await.map_err(|_| {
+        (
+            StatusCode::NOT_FOUND,
+            format!("Photo with id: {} not found", id),
+        )
+    })?;
+    let variants = app.repo.get_photo_variants(id).await.map_err(|e| {
+        tracing::error!("Failed to get photo variants: {:?}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to get photo variants: {}", e),
+        )
+    })?;
+
+    if variants.is_empty() {
+        let bytes = app.storage.get(&photo.filename).await.map_err(|e| {
+            tracing::error!("Failed to read {}: {:?}", photo.filename, e);
+            (
+                StatusCode::NOT_FOUND,
+                format!("File {} not found in storage", photo.filename),
+            )
+        })?;
+        // the same URL serves a variant chosen by Accept once they exist
+        return Ok((
+            [
+                (header::CONTENT_TYPE, original_content_type(&photo.filename)),
+                (header::VARY, "Accept"),
+            ],
+            bytes,
+        )
+            .into_response());
+    }
+
+    if let Some(name) = query.variant.as_deref() {
+        if !variants.iter().any(|x| x.variant == name) {
+            return Err((
+                StatusCode::NOT_FOUND,
+                format!("Photo with id: {} has no variant {}", id, name),
+            ));
+        }
+    }
+    let accept = headers.get(header::ACCEPT).and_then(|x| x.to_str().ok());
+    let Some(variant) = best_variant(&variants, query.variant.as_deref(), query.width, accept)
+    else {
+        return Ok((
+            StatusCode::NOT_ACCEPTABLE,
+            [(header::VARY, "Accept")],
+            "None of the available image formats are acceptable",
+        )
+            .into_response());
+    };
+    let format: VariantFormat = variant
+        .format
+        .parse()
+        .map_err(|e: anyhow::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let bytes = match variant_storage(&app.storage) {
+        Ok(storage) => storage.get(&variant.storage_key).await,
+        Err(e) => Err(e),
+    }
+    .map_err(|e| {
+        tracing::error!("Failed to read {}: {:?}", variant.storage_key, e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to read variant {}", variant.variant),
+        )
+    })?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.mime_type()),
+            (header::VARY, "Accept"),
+        ],
+        bytes,
+    )
+        .into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SimilarityQuery {
+    /// most differing bits, out of 64, for photos to count as the same
+    pub max_distance: Option<i32>,
+}
+
+impl SimilarityQuery {
+    /// Clamped to `0..=MAX_DUPLICATE_DISTANCE`; comparing every pair of
+    /// photos gets expensive when most of them match.
+    fn max_distance(&self, settings: &ImageSettings) -> i32 {
+        self.max_distance
+            .unwrap_or(settings.duplicate_distance)
+            .clamp(0, MAX_DUPLICATE_DISTANCE)
+    }
+}
+
+#[tracing::instrument(name = "Get similar photos", skip(app))]
+pub async fn get_similar_photos(
+    State(app): State<AppState>,
+    Path(id): Path<i32>,
+    Query(query): Query<SimilarityQuery>,
+) -> response::Result<impl IntoResponse, (StatusCode, String)> {
+    let photo = app.repo.get_photo(id).await.map_err(|_| {
+        (
+            StatusCode::NOT_FOUND,
+            format!("Photo with id: {} not found", id),
+        )
+    })?;
+    let Some(hash) = photo.perceptual_hash else {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("Photo with id: {} has not been hashed yet", id),
+        ));
+    };
+    let max_distance = query.max_distance(&app.image_settings);
+    let internal_error = |e: anyhow::Error| {
+        tracing::error!("Failed to get similar photos: {:?}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to get similar photos: {}", e),
+        )
+    };
+    let matches = app
+        .repo
+        .find_similar_photos(hash, max_distance, Some(id))
+        .await
+        .map_err(internal_error)?;
+    let ids: Vec<i32> = matches.iter().map(|x| x.photo_id).collect();
+    let mut photos: HashMap<i32, Photo> = app
+        .repo
+        .get_photos_by_ids(&ids)
+        .await
+        .map_err(internal_error)?
+        .into_iter()
+        .map(|x| (x.id, x))
+        .collect();
+    let locations = location_index(&app.repo).await?;
+    let view_models: Vec<SimilarPhotoViewModel> = matches
+        .into_iter()
+        .filter_map(|x| {
+            Some(SimilarPhotoViewModel {
+                photo: PhotoViewModel::from(photos.remove(&x.photo_id)?).with_location(&locations),
+                distance: x.distance,
+            })
+        })
+        .collect();
+    info!("found {} similar photos", view_models.len());
+    Ok((StatusCode::OK, Json(view_models)))
+}
+
+/// Groups photos that are transitively within `max_distance` of each other.
+#[tracing::instrument(name = "Get duplicates", skip(app))]
+pub async fn get_duplicates(
+    State(app): State<AppState>,
+    Query(query): Query<SimilarityQuery>,
+) -> response::Result<impl IntoResponse, (StatusCode, String)> {
+    let max_distance = query.max_distance(&app.image_settings);
+    let internal_error = |e: anyhow::Error| {
+        tracing::error!("Failed to find duplicates: {:?}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to find duplicates: {}", e),
+        )
+    };
+    let pairs = app
+        .repo
+        .find_duplicate_pairs(max_distance)
+        .await
+        .map_err(internal_error)?;
+
+    let groups = group_duplicates(&pairs);
+    let ids: Vec<i32> = groups
+        .iter()
+        .flat_map(|x| x.photo_ids.iter().copied())
+        .collect();
+
+    let mut photos: HashMap<i32, Photo> = app
+        .repo
+        .get_photos_by_ids(&ids)
+        .await
+        .map_err(internal_error)?
+        .into_iter()
+        .map(|x| (x.id, x))
+        .collect();
+    let locations = location_index(&app.repo).await?;
+    let view_models: Vec<DuplicateGroupViewModel> = groups
+        .into_iter()
+        .map(|group| DuplicateGroupViewModel {
+            max_distance: group.max_distance,
+            photos: group
+                .photo_ids
+                .into_iter()
+                .filter_map(|x| photos.remove(&x))
+                .map(|x| PhotoViewModel::from(x).with_location(&locations))
+                .collect(),
+        })
+        .collect();
+    info!("found {} groups of duplicates", view_models.len());
+    Ok((StatusCode::OK, Json(view_models)))
+}
 
 // pub async fn add_photo_cloudflare_resource(
 //     State(_photo_repo): State<PhotoRepository>,
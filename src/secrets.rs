@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use config::{Config, Source, Value, ValueKind};
+use serde::Deserialize;
+
+use crate::configuration::VaultSettings;
+
+const FILE_SUFFIX: &str = "_file";
+
+/// A configuration value that must not end up in logs. `Debug` prints
+/// `[redacted]`; use `expose` where the value is actually needed.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+/// Somewhere secrets are kept outside of the configuration. A setting of
+/// `<scheme>://<path>#<key>` is replaced by the `key` field of the secret at
+/// `path` in the source registered for `scheme`.
+#[async_trait]
+pub trait SecretSource: Send + Sync {
+    fn scheme(&self) -> &'static str;
+
+    async fn fetch(&self, path: &str, key: &str) -> Result<String>;
+}
+
+/// Reads secrets from a Vault KV engine over its HTTP API. Works with both
+/// KV v1 (`secret/<name>`) and v2 (`secret/data/<name>`) paths.
+pub struct VaultSource {
+    client: reqwest::Client,
+    settings: VaultSettings,
+}
+
+impl VaultSource {
+    pub fn new(settings: VaultSettings) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(settings.timeout_ms))
+            .build()?;
+        Ok(Self { client, settings })
+    }
+}
+
+#[derive(Deserialize)]
+struct VaultResponse {
+    data: HashMap<String, serde_json::Value>,
+}
+
+#[async_trait]
+impl SecretSource for VaultSource {
+    fn scheme(&self) -> &'static str {
+        "vault"
+    }
+
+    async fn fetch(&self, path: &str, key: &str) -> Result<String> {
+        let url = format!(
+            "{}/v1/{}",
+            self.settings.address.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        );
+        let response: VaultResponse = self
+            .client
+            .get(&url)
+            .header("X-Vault-Token", self.settings.token.expose())
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        // KV v2 nests the secret under `data.data`, v1 returns it as `data`
+        let value = match response.data.get("data") {
+            Some(serde_json::Value::Object(data)) => data.get(key),
+            _ => response.data.get(key),
+        };
+        match value {
+            Some(serde_json::Value::String(value)) => Ok(value.clone()),
+            Some(_) => anyhow::bail!("{} in {} isn't a string", key, path),
+            None => anyhow::bail!("{} has no {}", path, key),
+        }
+    }
+}
+
+/// Replaces every `<name>_file` setting with a `<name>` setting holding the
+/// contents of that file, e.g. `client_secret_file: /run/secrets/oauth`
+/// sets `client_secret`. The file takes precedence over `<name>` itself.
+pub fn resolve_files(config: Config) -> Result<Config> {
+    let mut overrides = Vec::new();
+    visit_strings(&config.collect()?, "", &mut |key, value| {
+        if let Some(key) = key.strip_suffix(FILE_SUFFIX) {
+            let contents = std::fs::read_to_string(value)
+                .with_context(|| format!("failed to read {}{} from {}", key, FILE_SUFFIX, value))?;
+            // editors and `echo` leave a trailing newline
+            overrides.push((
+                key.to_owned(),
+                contents.trim_end_matches(['\r', '\n']).into(),
+            ));
+        }
+        Ok(())
+    })?;
+    apply(config, overrides)
+}
+
+/// Replaces settings referring to a secret in one of `sources` with the
+/// secret's value.
+pub async fn resolve_references(
+    config: Config,
+    sources: &[Box<dyn SecretSource>],
+) -> Result<Config> {
+    if sources.is_empty() {
+        return Ok(config);
+    }
+    let mut references = Vec::new();
+    visit_strings(&config.collect()?, "", &mut |key, value| {
+        if let Some((scheme, rest)) = value.split_once("://") {
+            if let Some(source) = sources.iter().find(|x| x.scheme() == scheme) {
+                let (path, field) = rest.split_once('#').with_context(|| {
+                    format!("{} should look like {}://<path>#<key>", key, scheme)
+                })?;
+                references.push((key.to_owned(), source, path.to_owned(), field.to_owned()));
+            }
+        }
+        Ok(())
+    })?;
+
+    let mut overrides = Vec::new();
+    for (key, source, path, field) in references {
+        let value = source
+            .fetch(&path, &field)
+            .await
+            .with_context(|| format!("failed to fetch {} from {}", key, source.scheme()))?;
+        overrides.push((key, value));
+    }
+    apply(config, overrides)
+}
+
+/// Calls `f` with the dotted key and value of every string setting. Arrays
+/// aren't descended into.
+fn visit_strings(
+    table: &HashMap<String, Value>,
+    prefix: &str,
+    f: &mut impl FnMut(&str, &str) -> Result<()>,
+) -> Result<()> {
+    for (key, value) in table {
+        let key = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match &value.kind {
+            ValueKind::String(value) => f(&key, value)?,
+            ValueKind::Table(table) => visit_strings(table, &key, f)?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn apply(config: Config, overrides: Vec<(String, String)>) -> Result<Config> {
+    if overrides.is_empty() {
+        return Ok(config);
+    }
+    let mut builder = Config::builder().add_source(config);
+    for (key, value) in overrides {
+        builder = builder.set_override(key, value)?;
+    }
+    Ok(builder.build()?)
+}
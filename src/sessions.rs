@@ -1,3 +1,5 @@
+use std::time::Instant;
+
 use anyhow::Result;
 use async_session::Session;
 use redis::AsyncCommands;
@@ -12,19 +14,44 @@ impl SessionManager {
         Self { redis }
     }
 
-    pub(crate) async fn get_session<'a>(&self, session_id: &'a str) -> Result<Option<Session>> {
-        let mut con = self.redis.get_async_connection().await?;
-        let session: String = con.get(session_id).await?;
+    pub(crate) async fn get_session(&self, session_id: &str) -> Result<Option<Session>> {
+        let start = Instant::now();
+        let session = self.lookup_session(session_id).await;
+        let result = match &session {
+            Ok(Some(_)) => "hit",
+            Ok(None) => "miss",
+            Err(_) => "error",
+        };
+        metrics::counter!("session_lookups_total", "result" => result).increment(1);
+        metrics::histogram!("session_lookup_duration_seconds")
+            .record(start.elapsed().as_secs_f64());
+        session
+    }
+
+    #[tracing::instrument(name = "redis.ping", target = "redis", skip_all)]
+    pub async fn ping(&self) -> Result<()> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        redis::cmd("PING").query_async::<()>(&mut con).await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "redis.get_session", target = "redis", skip_all)]
+    async fn lookup_session(&self, session_id: &str) -> Result<Option<Session>> {
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        let Some(session): Option<String> = con.get(session_id).await? else {
+            return Ok(None);
+        };
         let session: Session = serde_json::from_str(&session)?;
-        con.expire(session.id(), 300).await?;
+        con.expire::<_, ()>(session.id(), 300).await?;
         Ok(Some(session))
     }
 
+    #[tracing::instrument(name = "redis.set_session", target = "redis", skip_all)]
     pub(crate) async fn set_session(&self, session: &Session) -> Result<String> {
-        let mut con = self.redis.get_async_connection().await?;
-        con.set(session.id(), serde_json::to_string(session)?)
+        let mut con = self.redis.get_multiplexed_async_connection().await?;
+        con.set::<_, _, ()>(session.id(), serde_json::to_string(session)?)
             .await?;
-        con.expire(session.id(), 300).await?;
+        con.expire::<_, ()>(session.id(), 300).await?;
         Ok(session.id().to_string())
     }
 }
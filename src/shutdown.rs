@@ -0,0 +1,186 @@
+use std::future::IntoFuture;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use axum_server::Handle;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::info;
+
+use crate::configuration::ShutdownSettings;
+
+/// Set once the process starts shutting down. Shared by the listeners, the
+/// job workers and `/health/ready`.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    /// when shutdown was triggered
+    sender: Arc<watch::Sender<Option<Instant>>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, _) = watch::channel(None);
+        Self {
+            sender: Arc::new(sender),
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.sender.send_if_modified(|triggered_at| {
+            let first = triggered_at.is_none();
+            triggered_at.get_or_insert_with(Instant::now);
+            first
+        });
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.sender.borrow().is_some()
+    }
+
+    /// Resolves once shutdown is triggered, right away if it already was.
+    pub async fn wait(&self) {
+        self.triggered_at().await;
+    }
+
+    /// When whatever is still running gets dropped: `drain_delay` and then
+    /// `timeout` after shutdown was triggered. The listeners and the job
+    /// workers share it, so workers don't get a timeout of their own after
+    /// requests have drained. Resolves once shutdown is triggered.
+    pub async fn deadline(&self, settings: &ShutdownSettings) -> Instant {
+        self.triggered_at().await + settings.drain_delay() + settings.timeout()
+    }
+
+    async fn triggered_at(&self) -> Instant {
+        let mut receiver = self.sender.subscribe();
+        loop {
+            if let Some(triggered_at) = *receiver.borrow_and_update() {
+                return triggered_at;
+            }
+            // the sender lives as long as `self`, so this can't fail
+            let _ = receiver.changed().await;
+        }
+    }
+
+    /// Triggers shutdown on the first SIGTERM or SIGINT.
+    pub fn trigger_on_signal(&self) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            signal().await;
+            shutdown.trigger();
+        });
+    }
+}
+
+async fn signal() {
+    let interrupt = tokio::signal::ctrl_c();
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => info!("received SIGINT, shutting down"),
+        _ = terminate => info!("received SIGTERM, shutting down"),
+    }
+}
+
+/// Serves `router` until `shutdown` is triggered. The listener stays open
+/// for `drain_delay` after that, then stops accepting connections and gives
+/// in-flight requests until the shutdown deadline to finish before they're
+/// dropped.
+pub async fn serve(
+    listener: TcpListener,
+    router: Router,
+    shutdown: Shutdown,
+    settings: ShutdownSettings,
+) -> io::Result<()> {
+    let drain_delay = settings.drain_delay();
+    let timeout = settings.timeout();
+    let close_listener = {
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown.wait().await;
+            tokio::time::sleep(drain_delay).await;
+            info!("closing listener, waiting for in-flight requests");
+        }
+    };
+    let server = axum::serve(
+        listener,
+        router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(close_listener)
+    .into_future();
+
+    tokio::select! {
+        result = server => result,
+        _ = async { tokio::time::sleep_until(shutdown.deadline(&settings).await).await } => {
+            tracing::warn!("requests still running after {:?}, dropping them", timeout);
+            Ok(())
+        }
+    }
+}
+
+/// Like [`serve`], terminating TLS with `config`, which may be reloaded while
+/// serving.
+pub async fn serve_tls(
+    listener: TcpListener,
+    router: Router,
+    config: RustlsConfig,
+    shutdown: Shutdown,
+    settings: ShutdownSettings,
+) -> io::Result<()> {
+    let drain_delay = settings.drain_delay();
+    let handle = Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            let deadline = shutdown.deadline(&settings).await;
+            tokio::time::sleep(drain_delay).await;
+            info!("closing listener, waiting for in-flight requests");
+            // connections still open at the deadline are dropped
+            handle.graceful_shutdown(Some(deadline.saturating_duration_since(Instant::now())));
+        }
+    });
+    axum_server::from_tcp_rustls(listener.into_std()?, config)
+        .handle(handle)
+        .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+}
+
+/// Waits for job workers to finish their current job. Jobs still running at
+/// the shutdown deadline are abandoned and retried elsewhere once their
+/// lease expires.
+pub async fn drain_workers(
+    workers: Vec<JoinHandle<()>>,
+    shutdown: &Shutdown,
+    settings: &ShutdownSettings,
+) {
+    let deadline = shutdown.deadline(settings).await;
+    let joined = tokio::time::timeout_at(deadline, async {
+        for worker in workers {
+            if let Err(e) = worker.await {
+                tracing::error!("Worker failed: {:?}", e);
+            }
+        }
+    })
+    .await;
+    if joined.is_err() {
+        tracing::warn!("workers still busy at the shutdown deadline, abandoning their jobs");
+    }
+}
@@ -0,0 +1,137 @@
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+use uuid::Uuid;
+
+/// Hex SHA-256 of `bytes`, as recorded in `photos.content_sha256`.
+pub fn content_hash(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+/// Stores original photo files on the local filesystem, keyed by filename.
+#[derive(Debug, Clone)]
+pub struct PhotoStorage {
+    root: Arc<PathBuf>,
+}
+
+impl PhotoStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: Arc::new(root.into()),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Storage rooted at a subdirectory, e.g. for generated copies of the
+    /// originals.
+    pub fn child(&self, name: &str) -> Result<Self> {
+        Ok(Self::new(self.path(name)?))
+    }
+
+    pub fn path(&self, key: &str) -> Result<PathBuf> {
+        let mut components = Path::new(key).components();
+        match (components.next(), components.next()) {
+            (Some(std::path::Component::Normal(_)), None) => Ok(self.root.join(key)),
+            _ => bail!("invalid storage key: {}", key),
+        }
+    }
+
+    pub async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(tokio::fs::try_exists(self.path(key)?).await?)
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Vec<u8>> {
+        Ok(tokio::fs::read(self.path(key)?).await?)
+    }
+
+    /// Hashes the object under `key` without reading it into memory at once.
+    pub async fn sha256(&self, key: &str) -> Result<String> {
+        let mut file = tokio::fs::File::open(self.path(key)?).await?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        Ok(hex::encode(hasher.finalize()))
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<()> {
+        Ok(tokio::fs::remove_file(self.path(key)?).await?)
+    }
+
+    /// Writes `bytes` under `key`, replacing what was there. The file is
+    /// written next to its final location and renamed into place so readers
+    /// never see a partial file.
+    pub async fn put(&self, key: &str, bytes: &[u8]) -> Result<()> {
+        let path = self.path(key)?;
+        let partial = self.write_partial(key, bytes).await?;
+        if let Err(e) = tokio::fs::rename(&partial, &path).await {
+            remove_partial(&partial).await;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Writes `bytes` under `key` unless something is stored there already,
+    /// in which case it fails with [`ObjectExistsError`]. Of two concurrent
+    /// writes to the same key only one succeeds.
+    pub async fn put_new(&self, key: &str, bytes: &[u8]) -> Result<()> {
+        let path = self.path(key)?;
+        let partial = self.write_partial(key, bytes).await?;
+        // unlike a rename, linking never replaces the target
+        let linked = tokio::fs::hard_link(&partial, &path).await;
+        remove_partial(&partial).await;
+        match linked {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                Err(ObjectExistsError(key.into()).into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes `bytes` to a temporary file of its own next to `key`.
+    async fn write_partial(&self, key: &str, bytes: &[u8]) -> Result<PathBuf> {
+        tokio::fs::create_dir_all(self.root.as_path()).await?;
+        let partial = self
+            .root
+            .join(format!(".{}.{}.partial", key, Uuid::new_v4()));
+        if let Err(e) = tokio::fs::write(&partial, bytes).await {
+            remove_partial(&partial).await;
+            return Err(e.into());
+        }
+        Ok(partial)
+    }
+}
+
+async fn remove_partial(partial: &Path) {
+    if let Err(e) = tokio::fs::remove_file(partial).await {
+        if e.kind() != ErrorKind::NotFound {
+            tracing::warn!("Failed to remove {}: {:?}", partial.display(), e);
+        }
+    }
+}
+
+/// Returned by [`PhotoStorage::put_new`] when the key is already taken.
+#[derive(Debug)]
+pub struct ObjectExistsError(pub String);
+
+impl fmt::Display for ObjectExistsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "File {} already exists", self.0)
+    }
+}
+
+impl std::error::Error for ObjectExistsError {}
@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+
+use anyhow::Result;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::{header, Extensions, HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use metrics_exporter_prometheus::{
+    Matcher, PrometheusBuilder, PrometheusHandle, PrometheusRecorder,
+};
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, Tracer, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use reqwest::{IntoUrl, Method, RequestBuilder};
+use sqlx::PgPool;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::configuration::TracingSettings;
+use crate::request_id::{current_request_id, REQUEST_ID_HEADER};
+
+// credentials, logged as `[redacted]`
+const SENSITIVE_HEADERS: [&str; 4] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "x-api-key",
+];
+
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// A Prometheus recorder with this API's histogram buckets.
+pub fn build_recorder() -> Result<PrometheusRecorder> {
+    Ok(PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("duration_seconds".into()), LATENCY_BUCKETS)?
+        .build_recorder())
+}
+
+/// Installs the global Prometheus recorder. Metrics recorded before this,
+/// or in processes that never call it, are dropped.
+pub fn install_recorder() -> Result<PrometheusHandle> {
+    let recorder = build_recorder()?;
+    let handle = recorder.handle();
+    metrics::set_global_recorder(recorder)?;
+    Ok(handle)
+}
+
+#[derive(Clone)]
+struct MetricsState {
+    handle: PrometheusHandle,
+    pool: PgPool,
+}
+
+/// Serves `/metrics`, meant for its own listener so it isn't exposed
+/// alongside the API.
+pub fn metrics_router(handle: PrometheusHandle, pool: PgPool) -> Router {
+    Router::new()
+        .route("/metrics", get(render_metrics))
+        .with_state(MetricsState { handle, pool })
+}
+
+async fn render_metrics(State(state): State<MetricsState>) -> impl IntoResponse {
+    // pool usage is sampled at scrape time rather than on every checkout
+    let size = state.pool.size() as f64;
+    let idle = state.pool.num_idle() as f64;
+    metrics::gauge!("db_pool_connections", "state" => "idle").set(idle);
+    metrics::gauge!("db_pool_connections", "state" => "active").set(size - idle);
+    metrics::gauge!("db_pool_max_connections")
+        .set(state.pool.options().get_max_connections() as f64);
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.handle.render(),
+    )
+}
+
+/// Records count, latency and in-flight requests by route template, so
+/// `/photos/1` and `/photos/2` share a series.
+pub async fn track_requests(request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|x| x.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".into());
+
+    let in_flight = InFlight::start();
+    let start = Instant::now();
+    let response = next.run(request).await;
+    drop(in_flight);
+
+    let labels = [
+        ("method", method),
+        ("path", path),
+        ("status", response.status().as_u16().to_string()),
+    ];
+    metrics::counter!("http_requests_total", &labels).increment(1);
+    metrics::histogram!("http_request_duration_seconds", &labels)
+        .record(start.elapsed().as_secs_f64());
+    response
+}
+
+/// Counts a request in `http_requests_in_flight` until dropped, which also
+/// happens when the request's future is dropped because the client went
+/// away or a timeout fired.
+struct InFlight(metrics::Gauge);
+
+impl InFlight {
+    fn start() -> Self {
+        let gauge = metrics::gauge!("http_requests_in_flight");
+        gauge.increment(1);
+        Self(gauge)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.decrement(1);
+    }
+}
+
+/// Filled in with the signed-in user's `sub` once the request has been
+/// authenticated, for the access log.
+#[derive(Clone, Default)]
+pub(crate) struct AccessLogUser(Arc<OnceLock<String>>);
+
+impl AccessLogUser {
+    pub(crate) fn record(extensions: &Extensions, sub: &str) {
+        if let Some(user) = extensions.get::<Self>() {
+            let _ = user.0.set(sub.to_owned());
+        }
+    }
+}
+
+/// Logs a line under the `access` target for every request.
+pub async fn access_log(mut request: Request, next: Next) -> Response {
+    let user = AccessLogUser::default();
+    request.extensions_mut().insert(user.clone());
+    let method = request.method().clone();
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|x| x.as_str().to_owned());
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .map(str::to_owned);
+    let headers = redacted_headers(request.headers());
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    tracing::info!(
+        target: "access",
+        method = %method,
+        path,
+        status = response.status().as_u16(),
+        latency_ms = start.elapsed().as_millis() as u64,
+        user = user.0.get().map(String::as_str),
+        request_id,
+        headers = %headers,
+        "{} {} {}",
+        method,
+        path.as_deref().unwrap_or("unmatched"),
+        response.status().as_u16()
+    );
+    response
+}
+
+fn redacted_headers(headers: &HeaderMap) -> serde_json::Value {
+    headers
+        .keys()
+        .map(|name| {
+            let value = if SENSITIVE_HEADERS.contains(&name.as_str()) {
+                "[redacted]".into()
+            } else {
+                // a repeated header means the same as its values joined by
+                // commas
+                headers
+                    .get_all(name)
+                    .iter()
+                    .map(|x| String::from_utf8_lossy(x.as_bytes()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            (name.as_str().to_owned(), serde_json::Value::String(value))
+        })
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+/// Changes which spans and events the subscriber installed by
+/// `setup_logging` lets through.
+#[derive(Clone)]
+pub struct LogFilter(reload::Handle<EnvFilter, Registry>);
+
+impl std::fmt::Debug for LogFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("LogFilter").field(&self.current()).finish()
+    }
+}
+
+impl LogFilter {
+    pub(crate) fn new(handle: reload::Handle<EnvFilter, Registry>) -> Self {
+        Self(handle)
+    }
+
+    /// Replaces the filter with `directives`, e.g. `info,api::jobs=debug`.
+    pub fn set(&self, directives: &str) -> Result<()> {
+        self.0.reload(EnvFilter::try_new(directives)?)?;
+        Ok(())
+    }
+
+    pub fn current(&self) -> Option<String> {
+        self.0.with_current(|x| x.to_string()).ok()
+    }
+}
+
+/// Sets up W3C trace context propagation and, if an OTLP endpoint is
+/// configured, a tracer exporting spans to it.
+pub fn init_tracer(settings: &TracingSettings) -> Result<Option<Tracer>> {
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    let Some(endpoint) = &settings.otlp_endpoint else {
+        return Ok(None);
+    };
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        // follow the caller's sampling decision, sample new traces by ratio
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            settings.sample_ratio,
+        ))))
+        .with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            settings.service_name.clone(),
+        )]))
+        .build();
+    let tracer = provider.tracer("enchanted_natures");
+    opentelemetry::global::set_tracer_provider(provider.clone());
+    let _ = TRACER_PROVIDER.set(provider);
+    Ok(Some(tracer))
+}
+
+// tracers hold on to their provider, so dropping the global one doesn't
+// flush; it has to be shut down explicitly
+static TRACER_PROVIDER: OnceLock<TracerProvider> = OnceLock::new();
+
+/// Flushes spans that haven't been exported yet. Blocks until the
+/// exporter is done.
+pub fn shutdown_tracer() -> Result<()> {
+    if let Some(provider) = TRACER_PROVIDER.get() {
+        provider.shutdown()?;
+    }
+    Ok(())
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|x| x.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|x| x.as_str()).collect()
+    }
+}
+
+/// Continues the trace of an incoming request's `traceparent` header, if
+/// it has one.
+pub fn set_remote_parent(span: &tracing::Span, headers: &HeaderMap) {
+    let context = TraceContextPropagator::new().extract(&HeaderExtractor(headers));
+    span.set_parent(context);
+}
+
+/// `traceparent` (and `tracestate`) headers for calling another service
+/// from within the current span, along with the request id.
+pub fn trace_headers() -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+    let context = tracing::Span::current().context();
+    TraceContextPropagator::new().inject_context(&context, &mut headers);
+    if let Some(request_id) = current_request_id() {
+        headers.insert(REQUEST_ID_HEADER.into(), request_id);
+    }
+    headers
+}
+
+/// `reqwest::Client` that adds `trace_headers` to every request, so calls
+/// made while handling a request can be matched to it.
+#[derive(Debug, Clone, Default)]
+pub struct HttpClient(reqwest::Client);
+
+impl HttpClient {
+    pub fn new(client: reqwest::Client) -> Self {
+        Self(client)
+    }
+
+    pub fn get(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.request(Method::GET, url)
+    }
+
+    pub fn post(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.request(Method::POST, url)
+    }
+
+    pub fn request(&self, method: Method, url: impl IntoUrl) -> RequestBuilder {
+        trace_headers()
+            .into_iter()
+            .fold(self.0.request(method, url), |builder, (name, value)| {
+                builder.header(name, value)
+            })
+    }
+}
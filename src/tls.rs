@@ -0,0 +1,119 @@
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use axum::http::uri::Authority;
+use axum::http::{header, HeaderMap, StatusCode, Uri};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use tokio::task::JoinHandle;
+use tracing::info;
+
+use crate::configuration::{ReloadSettings, TlsSettings};
+use crate::reload::Hangup;
+use crate::shutdown::Shutdown;
+
+/// Loads the certificate and key named in `settings`. HTTP/2 is offered
+/// ahead of HTTP/1.1.
+pub async fn rustls_config(settings: &TlsSettings) -> Result<RustlsConfig> {
+    RustlsConfig::from_pem_file(&settings.cert_path, &settings.key_path)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to load TLS certificate {:?} and key {:?}",
+                settings.cert_path, settings.key_path
+            )
+        })
+}
+
+/// Reloads `config` on SIGHUP and, if `reload.watch` is set, when the
+/// certificate or key changes. A pair that fails to load is logged and the
+/// previous one kept, so a renewal caught halfway is retried once the second
+/// file is written.
+pub fn watch(
+    config: RustlsConfig,
+    settings: TlsSettings,
+    reload: &ReloadSettings,
+    shutdown: Shutdown,
+) -> JoinHandle<()> {
+    let watch = reload.watch;
+    let mut interval = tokio::time::interval(reload.poll_interval());
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    tokio::spawn(async move {
+        let mut hangup = Hangup::new();
+        let mut last_modified = modified_times(&settings).await;
+        loop {
+            let trigger = tokio::select! {
+                _ = shutdown.wait() => return,
+                _ = hangup.recv() => "SIGHUP",
+                _ = interval.tick(), if watch => {
+                    let modified = modified_times(&settings).await;
+                    if modified == last_modified {
+                        continue;
+                    }
+                    last_modified = modified;
+                    "file change"
+                }
+            };
+            match config
+                .reload_from_pem_file(&settings.cert_path, &settings.key_path)
+                .await
+            {
+                Ok(()) => {
+                    metrics::counter!("tls_certificate_reloads_total", "result" => "applied")
+                        .increment(1);
+                    info!(target: "audit", trigger, "reloaded TLS certificate");
+                }
+                Err(e) => {
+                    metrics::counter!("tls_certificate_reloads_total", "result" => "rejected")
+                        .increment(1);
+                    tracing::error!(
+                        trigger,
+                        "Failed to reload TLS certificate, keeping the previous one: {:?}",
+                        e
+                    );
+                }
+            }
+        }
+    })
+}
+
+async fn modified_times(settings: &TlsSettings) -> [Option<SystemTime>; 2] {
+    async fn modified(path: &Path) -> Option<SystemTime> {
+        tokio::fs::metadata(path).await.ok()?.modified().ok()
+    }
+    [
+        modified(&settings.cert_path).await,
+        modified(&settings.key_path).await,
+    ]
+}
+
+/// Answers every request with a permanent redirect to the same URL over
+/// HTTPS on `https_port`.
+pub fn redirect_router(https_port: u16) -> Router {
+    Router::new().fallback(move |uri: Uri, headers: HeaderMap| async move {
+        redirect(&uri, &headers, https_port)
+    })
+}
+
+fn redirect(uri: &Uri, headers: &HeaderMap, https_port: u16) -> Response {
+    // HTTP/1.1 requests only name the host in the Host header
+    let authority = uri.authority().cloned().or_else(|| {
+        headers
+            .get(header::HOST)?
+            .to_str()
+            .ok()?
+            .parse::<Authority>()
+            .ok()
+    });
+    let Some(authority) = authority else {
+        return (StatusCode::BAD_REQUEST, "Host header is required").into_response();
+    };
+    let path = uri.path_and_query().map_or("/", |x| x.as_str());
+    let url = match https_port {
+        443 => format!("https://{}{}", authority.host(), path),
+        port => format!("https://{}:{}{}", authority.host(), port, path),
+    };
+    Redirect::permanent(&url).into_response()
+}
@@ -1,39 +1,97 @@
-use api::auth::create_oauth_client;
-use api::configuration::Settings;
+use api::auth::{create_oauth_client, User};
+use api::cache::{conditional_get, CacheTag, ResponseCache};
+use api::cloudflare::CloudflareImages;
+use api::configuration::{
+    CacheSettings, CloudflareSettings, CorsSettings, DatabaseSettings, DynamicSettings,
+    Environment, HealthSettings, ImageSettings, JobSettings, LocationSettings, RateLimit,
+    RateLimitSettings, ReloadSettings, RequestLimitSettings, Settings, ShutdownSettings, SslMode,
+    TlsSettings, VaultSettings,
+};
 use api::connect_database;
-use api::database::PhotoRepository;
+use api::database::{is_unique_violation, PhotoRepository};
 use api::domain::AppState;
+use api::error_handling::AppError;
+use api::images::{perceptual_hash, record_content_hash, variant_storage};
+use api::jobs::{Job, JobContext, JobQueue, Worker};
+use api::metadata::{extract_metadata, PhotoMetadata};
+use api::models::{
+    group_duplicates, CatalogExport, Coordinates, DuplicateGroup, DuplicatePair, JobRecord,
+    NewPhoto,
+};
+use api::rate_limit::{Decision, RateLimiter};
+use api::reload::LiveSettings;
+use api::request_id::scope_request_id;
+use api::request_limits::request_limits;
+use api::routes::catalog::{export_catalog, import_catalog, ImportQuery};
+use api::routes::categories::delete_category;
+use api::routes::photos::{put_photo, upload_photo, GeoFilter, PhotosQuery};
+use api::secrets::{resolve_files, resolve_references, Secret, SecretSource, VaultSource};
 use api::sessions::SessionManager;
-use api::{app,  setup_logging};
+use api::shutdown::{self, Shutdown};
+use api::storage::{content_hash, ObjectExistsError, PhotoStorage};
+use api::telemetry::{
+    build_recorder, set_remote_parent, trace_headers, track_requests, HttpClient,
+};
+use api::{app, cors_layer, setup_logging};
+use axum_extra::headers::{HeaderMapExt, LastModified};
 
+use sqlx::postgres::{PgConnectOptions, PgSslMode};
 use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::{Layer, Registry};
 
 use utoipa_swagger_ui::{Config, SwaggerUi};
 
-use axum::http::StatusCode;
+use axum::http::{header, HeaderMap, StatusCode};
 use hyper::Request;
+use oauth2::basic::BasicClient;
+use oauth2::{AuthUrl, ClientId};
 
 use axum::{
     body::Body,
+    extract::{FromRequest, Multipart, Path, Query, State},
     http::{self},
+    middleware,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
 };
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
 use tower::ServiceExt;
 
 #[tokio::test]
 async fn default() {
-    setup_logging();
+    let settings = Settings::load_config().await.unwrap();
+    setup_logging(&settings.tracing_settings, &settings.dynamic.log_level).unwrap();
     // check_env().expect("Environment Variable must be set");
 
-    let settings = Settings::load_config().unwrap();
-
-    let pool: PgPool = connect_database(settings.database_settings).await;
+    let pool: PgPool = connect_database(settings.database_settings).await.unwrap();
 
     let oauth_client = create_oauth_client(settings.auth_settings).unwrap();
-    let session_manager = SessionManager::new(redis::Client::open(settings.redis_url).unwrap());
+    let redis = redis::Client::open(settings.redis_url.expose()).unwrap();
+    let session_manager = SessionManager::new(redis.clone());
+    let cache = ResponseCache::new(redis, settings.cache_settings);
 
     let photo_repo = PhotoRepository::new(pool.clone());
     photo_repo.migrate().await.unwrap();
-    let app_state = AppState::new(photo_repo, oauth_client, session_manager);
+    let storage = PhotoStorage::new(settings.storage_settings.path);
+    let jobs = JobQueue::new(photo_repo.clone(), &settings.job_settings);
+    let app_state = AppState::new(
+        photo_repo,
+        oauth_client,
+        session_manager,
+        storage,
+        jobs,
+        Arc::new(settings.image_settings),
+        cache,
+    );
     let swagger_config = Config::from("/enchanted-natures.openapi.spec.yaml");
     let swagger_ui = SwaggerUi::new("/swagger-ui").config(swagger_config);
     let app = app(swagger_ui, app_state);
@@ -47,3 +105,2751 @@ async fn default() {
     assert_eq!(resp.status(), StatusCode::OK);
     // Arrange
 }
+
+#[tokio::test]
+async fn unreachable_database_is_reported() {
+    let mut settings = Settings::load_config().await.unwrap().database_settings;
+    settings.url = None;
+    settings.host = "127.0.0.1".into();
+    settings.port = 1;
+    settings.pool.acquire_timeout_secs = 1;
+    let e = connect_database(settings).await.unwrap_err();
+    assert!(format!("{:#}", e).starts_with("Can't connect to the database"));
+}
+
+#[tokio::test]
+async fn in_flight_request_completes_during_shutdown() {
+    let (started_tx, started_rx) = oneshot::channel();
+    let started_tx = Arc::new(std::sync::Mutex::new(Some(started_tx)));
+    let router = Router::new().route(
+        "/slow",
+        get(move || async move {
+            if let Some(tx) = started_tx.lock().unwrap().take() {
+                tx.send(()).unwrap();
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            "done"
+        }),
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let shutdown = Shutdown::new();
+    let settings = ShutdownSettings {
+        drain_delay_secs: 0,
+        timeout_secs: 5,
+    };
+    let server = tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move { shutdown::serve(listener, router, shutdown, settings).await }
+    });
+
+    let request = tokio::spawn(reqwest::get(format!("http://{}/slow", addr)));
+    started_rx.await.unwrap();
+    shutdown.trigger();
+
+    let response = request.await.unwrap().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await.unwrap(), "done");
+
+    tokio::time::timeout(Duration::from_secs(1), server)
+        .await
+        .expect("server didn't stop after draining")
+        .unwrap()
+        .unwrap();
+    assert!(TcpStream::connect(addr).await.is_err());
+}
+
+#[tokio::test]
+async fn workers_drain_until_the_deadline_the_listeners_share() {
+    let shutdown = Shutdown::new();
+    let settings = ShutdownSettings {
+        drain_delay_secs: 0,
+        timeout_secs: 1,
+    };
+    let busy = tokio::spawn(tokio::time::sleep(Duration::from_secs(10)));
+    shutdown.trigger();
+    let deadline = shutdown.deadline(&settings).await;
+    // most of the time went to draining requests
+    tokio::time::sleep(Duration::from_millis(700)).await;
+
+    let started = tokio::time::Instant::now();
+    shutdown::drain_workers(vec![busy], &shutdown, &settings).await;
+    assert!(tokio::time::Instant::now() >= deadline);
+    assert!(started.elapsed() < Duration::from_millis(600));
+
+    // triggering again doesn't push the deadline back
+    shutdown.trigger();
+    assert_eq!(shutdown.deadline(&settings).await, deadline);
+}
+
+/// State that never connects anywhere, for testing layers that answer
+/// before reaching a handler.
+fn state_with_settings(live_settings: LiveSettings) -> AppState {
+    let pool = PgPool::connect_lazy("postgres://localhost/enchanted").unwrap();
+    state_with_repo(PhotoRepository::new(pool), CacheSettings::default())
+        .with_live_settings(live_settings)
+}
+
+fn state_with_repo(photo_repo: PhotoRepository, cache_settings: CacheSettings) -> AppState {
+    let redis = redis::Client::open("redis://127.0.0.1:6379").unwrap();
+    let oauth_client = BasicClient::new(
+        ClientId::new("test".into()),
+        None,
+        AuthUrl::new("http://localhost/authorize".into()).unwrap(),
+        None,
+    );
+    AppState::new(
+        photo_repo.clone(),
+        oauth_client,
+        SessionManager::new(redis.clone()),
+        PhotoStorage::new("./photos"),
+        JobQueue::new(photo_repo, &JobSettings::default()),
+        Arc::new(ImageSettings::default()),
+        ResponseCache::new(redis, cache_settings),
+    )
+}
+
+fn app_with_settings(live_settings: LiveSettings) -> Router {
+    app(
+        SwaggerUi::new("/swagger-ui"),
+        state_with_settings(live_settings),
+    )
+}
+
+fn app_with_cors(cors: CorsSettings) -> Router {
+    app_with_settings(
+        LiveSettings::new(
+            DynamicSettings {
+                cors,
+                ..Default::default()
+            },
+            None,
+        )
+        .unwrap(),
+    )
+}
+
+fn preflight(origin: &str, method: &str) -> Request<Body> {
+    Request::builder()
+        .uri("/api/v0/photos/1")
+        .method(http::Method::OPTIONS)
+        .header(header::ORIGIN, origin)
+        .header(header::ACCESS_CONTROL_REQUEST_METHOD, method)
+        .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn local_preflight_allows_admin_methods_with_credentials() {
+    let app = app_with_cors(CorsSettings::for_environment(&Environment::Local));
+
+    for method in ["PUT", "DELETE"] {
+        let resp = app
+            .clone()
+            .oneshot(preflight("http://localhost:3000", method))
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let headers = resp.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "http://localhost:3000"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert!(headers[header::ACCESS_CONTROL_ALLOW_METHODS]
+            .to_str()
+            .unwrap()
+            .contains(method));
+        assert!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS]
+            .to_str()
+            .unwrap()
+            .contains("content-type"));
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "60");
+    }
+}
+
+#[tokio::test]
+async fn production_preflight_only_allows_configured_origins() {
+    let app = app_with_cors(CorsSettings::for_environment(&Environment::Production));
+
+    let resp = app
+        .clone()
+        .oneshot(preflight("https://enchantednatures.com", "DELETE"))
+        .await
+        .unwrap();
+    assert_eq!(
+        resp.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+        "https://enchantednatures.com"
+    );
+    assert_eq!(resp.headers()[header::ACCESS_CONTROL_MAX_AGE], "3600");
+
+    let resp = app
+        .oneshot(preflight("http://localhost:3000", "DELETE"))
+        .await
+        .unwrap();
+    assert!(resp
+        .headers()
+        .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+        .is_none());
+}
+
+#[tokio::test]
+async fn reloading_unchanged_files_changes_nothing() {
+    let settings = Settings::load_config().await.unwrap();
+    let (dynamic, static_settings) = DynamicSettings::load().await.unwrap();
+    assert_eq!(dynamic, settings.dynamic);
+    assert_eq!(
+        static_settings.changed_from(&settings.static_settings),
+        Vec::<String>::new()
+    );
+}
+
+#[tokio::test]
+async fn applied_settings_take_effect_unless_invalid() {
+    let live_settings = LiveSettings::new(DynamicSettings::default(), None).unwrap();
+    let app = app_with_settings(live_settings.clone());
+    let origin = "https://preview.enchantednatures.com";
+    let allowed_origin = |resp: axum::response::Response| {
+        resp.headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .cloned()
+    };
+
+    let resp = app.clone().oneshot(preflight(origin, "GET")).await.unwrap();
+    assert!(allowed_origin(resp).is_none());
+
+    let mut settings = (*live_settings.settings()).clone();
+    settings.cors.allowed_origins.push(origin.into());
+    live_settings.apply(settings.clone(), "test").unwrap();
+    let resp = app.clone().oneshot(preflight(origin, "GET")).await.unwrap();
+    assert_eq!(allowed_origin(resp).unwrap(), origin);
+
+    settings.cors.allowed_origins = vec!["*".into()];
+    assert!(live_settings.apply(settings, "test").is_err());
+    let resp = app.oneshot(preflight(origin, "GET")).await.unwrap();
+    assert_eq!(allowed_origin(resp).unwrap(), origin);
+}
+
+#[tokio::test]
+async fn readiness_reports_whether_each_dependency_is_up_and_how_fast() {
+    let db = ScratchDatabase::new().await;
+    // nothing listens there
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let discovery_url = format!("http://{}/.well-known", closed.local_addr().unwrap());
+    drop(closed);
+    let state = state_with_repo(db.repo.clone(), CacheSettings::default()).with_health_settings(
+        Arc::new(HealthSettings {
+            timeout_ms: 2000,
+            oauth_discovery_url: Some(discovery_url),
+        }),
+    );
+    let app = app(SwaggerUi::new("/swagger-ui"), state.clone());
+    let ready = || async {
+        let resp = app
+            .clone()
+            .oneshot(get_request("/health/ready", &[]))
+            .await
+            .unwrap();
+        let status = resp.status();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (
+            status,
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+        )
+    };
+
+    let (status, health) = ready().await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(health["status"], "Error");
+    let components = health["components"].as_object().unwrap();
+    assert_eq!(
+        components.keys().collect::<Vec<_>>(),
+        ["database", "migrations", "oauth", "redis"]
+    );
+    for (name, status) in [("database", "Ok"), ("migrations", "Ok"), ("oauth", "Error")] {
+        let component = components[name].as_object().unwrap();
+        // why it is down is only logged
+        assert_eq!(
+            component.keys().collect::<Vec<_>>(),
+            ["latency_ms", "status"],
+            "{}",
+            name
+        );
+        assert_eq!(component["status"], status, "{}", name);
+        let latency_ms = component["latency_ms"].as_f64().unwrap();
+        assert!((0.0..2500.0).contains(&latency_ms), "{}", name);
+    }
+    assert!(components["redis"]["latency_ms"].is_number());
+
+    state.shutdown.trigger();
+    let (status, health) = ready().await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(
+        health["components"],
+        serde_json::json!({ "server": { "status": "Error" } })
+    );
+    db.remove().await;
+}
+
+#[tokio::test]
+async fn request_id_is_echoed_or_generated() {
+    let app = app_with_cors(CorsSettings::default());
+    let request = |id: Option<&str>| {
+        let builder = Request::builder().uri("/health/live");
+        match id {
+            Some(id) => builder.header("x-request-id", id),
+            None => builder,
+        }
+        .body(Body::empty())
+        .unwrap()
+    };
+
+    let resp = app
+        .clone()
+        .oneshot(request(Some("ticket-42")))
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()["x-request-id"], "ticket-42");
+
+    let resp = app.clone().oneshot(request(None)).await.unwrap();
+    let generated = resp.headers()["x-request-id"].to_str().unwrap();
+    assert!(uuid::Uuid::parse_str(generated).is_ok());
+
+    let longest = "a".repeat(128);
+    let too_long = "a".repeat(129);
+    for (id, kept) in [
+        ("0b7c:run-3.step_2", true),
+        (longest.as_str(), true),
+        (too_long.as_str(), false),
+        ("ticket 42", false),
+        ("<script>", false),
+        ("ticket-42\"", false),
+    ] {
+        let resp = app.clone().oneshot(request(Some(id))).await.unwrap();
+        let echoed = resp.headers()["x-request-id"].to_str().unwrap();
+        if kept {
+            assert_eq!(echoed, id);
+        } else {
+            assert!(uuid::Uuid::parse_str(echoed).is_ok(), "{} was kept", id);
+        }
+    }
+}
+
+#[tokio::test]
+async fn plain_text_errors_become_problems_with_the_request_id() {
+    let app = app_with_cors(CorsSettings::default());
+    // the path extractor rejects it with a plain-text 400
+    let resp = app
+        .oneshot(get_request(
+            "/api/v0/photos/not-a-number",
+            &[(header::HeaderName::from_static("x-request-id"), "ticket-42")],
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        resp.headers()[header::CONTENT_TYPE],
+        "application/problem+json"
+    );
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(problem["status"], 400);
+    assert_eq!(problem["request_id"], "ticket-42");
+    assert!(problem["detail"].as_str().unwrap().contains("Cannot parse"));
+}
+
+/// Records the fields of every event logged under the `access` target while
+/// it's the default subscriber.
+#[derive(Clone, Default)]
+struct AccessLines(Arc<Mutex<Vec<HashMap<String, String>>>>);
+
+struct Fields<'a>(&'a mut HashMap<String, String>);
+
+impl Visit for Fields<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.insert(field.name().into(), format!("{:?}", value));
+    }
+}
+
+impl<S: Subscriber> Layer<S> for AccessLines {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if event.metadata().target() == "access" {
+            let mut fields = HashMap::new();
+            event.record(&mut Fields(&mut fields));
+            self.0.lock().unwrap().push(fields);
+        }
+    }
+}
+
+#[tokio::test]
+async fn handler_errors_are_problems_with_the_request_id() {
+    let app = Router::new()
+        .route(
+            "/",
+            get(|| async { Err::<(), AppError>(anyhow::anyhow!("disk on fire").into()) }),
+        )
+        .layer(middleware::from_fn(scope_request_id));
+    let resp = app
+        .oneshot(get_request(
+            "/",
+            &[(header::HeaderName::from_static("x-request-id"), "ticket-43")],
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(
+        resp.headers()[header::CONTENT_TYPE],
+        "application/problem+json"
+    );
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(problem["type"], "about:blank");
+    assert_eq!(problem["title"], "Internal Server Error");
+    assert_eq!(problem["status"], 500);
+    assert_eq!(problem["request_id"], "ticket-43");
+    assert!(problem["detail"].as_str().unwrap().contains("disk on fire"));
+}
+
+#[tokio::test]
+async fn access_log_has_the_request_fields_and_redacts_credentials() {
+    let app = app_with_cors(CorsSettings::default());
+    let lines = AccessLines::default();
+    let guard = tracing::subscriber::set_default(Registry::default().with(lines.clone()));
+    let resp = app
+        .oneshot(get_request(
+            "/api/v0/photos/not-a-number",
+            &[
+                (header::HeaderName::from_static("x-request-id"), "ticket-42"),
+                (header::AUTHORIZATION, "Bearer first"),
+                (header::AUTHORIZATION, "Bearer second"),
+                (header::COOKIE, "session=secret"),
+                (header::ACCEPT, "application/json"),
+                (header::ACCEPT, "text/plain"),
+            ],
+        ))
+        .await
+        .unwrap();
+    drop(guard);
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let lines = lines.0.lock().unwrap().clone();
+    let [line] = &lines[..] else {
+        panic!("expected one access log line, got {:?}", lines);
+    };
+    assert_eq!(line["method"], "GET");
+    assert_eq!(line["path"], "/api/v0/photos/:id");
+    assert_eq!(line["status"], "400");
+    assert_eq!(line["request_id"], "ticket-42");
+    assert!(line["latency_ms"].parse::<u64>().is_ok());
+    // nobody signed in
+    assert!(!line.contains_key("user"));
+    let headers: serde_json::Value = serde_json::from_str(&line["headers"]).unwrap();
+    assert_eq!(headers["authorization"], "[redacted]");
+    assert_eq!(headers["cookie"], "[redacted]");
+    assert_eq!(headers["accept"], "application/json, text/plain");
+    assert!(!line["headers"].contains("secret") && !line["headers"].contains("Bearer"));
+}
+
+fn import_request(body: Vec<u8>, content_length: bool) -> Request<Body> {
+    let builder = Request::builder()
+        .method(http::Method::POST)
+        .uri("/api/v0/import")
+        .header(header::CONTENT_TYPE, "application/json");
+    match content_length {
+        true => builder.header(header::CONTENT_LENGTH, body.len()),
+        // as for a chunked upload, where the size is only known once read
+        false => builder,
+    }
+    .body(Body::from(body))
+    .unwrap()
+}
+
+/// `/api/v0/import` reading its body without signing in, under `limits`.
+fn app_with_request_limits(limits: RequestLimitSettings) -> Router {
+    let app_state =
+        state_with_settings(LiveSettings::new(DynamicSettings::default(), None).unwrap())
+            .with_request_limit_settings(Arc::new(limits));
+    Router::new()
+        .route(
+            "/api/v0/import",
+            post(|body: axum::body::Bytes| async move { body.len().to_string() }),
+        )
+        .layer(axum::extract::DefaultBodyLimit::disable())
+        .layer(middleware::from_fn_with_state(app_state, request_limits))
+}
+
+#[tokio::test]
+async fn large_import_is_accepted_by_default() {
+    let app = app_with_request_limits(RequestLimitSettings::default());
+    // over axum's 2 MiB default but within the import route's limit
+    for content_length in [true, false] {
+        let resp = app
+            .clone()
+            .oneshot(import_request(vec![b'{'; 3 * 1024 * 1024], content_length))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
+
+#[tokio::test]
+async fn oversized_body_gets_a_problem_response() {
+    let mut limits = RequestLimitSettings::default();
+    for route in &mut limits.routes {
+        route.body_limit_bytes = Some(1024);
+    }
+    // a declared length is turned away before the handler signs anyone in
+    let app = app(
+        SwaggerUi::new("/swagger-ui"),
+        state_with_settings(LiveSettings::new(DynamicSettings::default(), None).unwrap())
+            .with_request_limit_settings(Arc::new(limits.clone())),
+    );
+    let resp = app
+        .oneshot(import_request(vec![b'{'; 4096], true))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(
+        resp.headers()[header::CONTENT_TYPE],
+        "application/problem+json"
+    );
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(problem["status"], 413);
+    assert!(problem["request_id"].is_string());
+
+    // one without is cut off as it's read
+    let resp = app_with_request_limits(limits)
+        .oneshot(import_request(vec![b'{'; 4096], false))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(
+        resp.headers()[header::CONTENT_TYPE],
+        "application/problem+json"
+    );
+}
+
+#[tokio::test]
+async fn responses_are_compressed_when_accepted() {
+    let app = app_with_cors(CorsSettings::default());
+    let request = |encoding: &str| {
+        Request::builder()
+            .uri("/enchanted-natures.openapi.spec.yaml")
+            .header(header::ACCEPT_ENCODING, encoding)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    for encoding in ["gzip", "br", "zstd"] {
+        let resp = app.clone().oneshot(request(encoding)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_ENCODING], encoding);
+    }
+
+    let resp = app.oneshot(request("identity")).await.unwrap();
+    assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+}
+
+#[tokio::test]
+async fn tls_reloads_a_renewed_pair_and_keeps_the_last_good_one() {
+    let fixtures = std::path::Path::new("tests/fixtures/tls");
+    let dir = std::env::temp_dir().join(format!("tls-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir(&dir).unwrap();
+    let settings = TlsSettings {
+        cert_path: dir.join("server.crt"),
+        key_path: dir.join("server.key"),
+        redirect_port: None,
+    };
+    let install = |name: &str| {
+        for (from, to) in [("crt", &settings.cert_path), ("key", &settings.key_path)] {
+            std::fs::copy(fixtures.join(format!("{}.{}", name, from)), to).unwrap();
+        }
+    };
+    install("first");
+    let config = api::tls::rustls_config(&settings).await.unwrap();
+    let first = config.get_inner();
+    let shutdown = Shutdown::new();
+    let reload = ReloadSettings {
+        watch: true,
+        poll_interval_ms: 20,
+    };
+    let watcher = api::tls::watch(config.clone(), settings.clone(), &reload, shutdown.clone());
+    // let the watcher note the current modification times
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // as a renewal caught between writing the two files
+    std::fs::write(&settings.cert_path, "-----BEGIN CERTIFICATE-----\n").unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(Arc::ptr_eq(&config.get_inner(), &first));
+
+    install("second");
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while Arc::ptr_eq(&config.get_inner(), &first) {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("the renewed pair wasn't loaded");
+
+    shutdown.trigger();
+    watcher.await.unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn plain_http_redirects_to_https() {
+    let app = api::tls::redirect_router(8443);
+    let resp = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/api/v0/photos?category_id=1")
+                .header(header::HOST, "enchantednatures.com:8080")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(
+        resp.headers()[header::LOCATION],
+        "https://enchantednatures.com:8443/api/v0/photos?category_id=1"
+    );
+
+    let resp = app
+        .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn only_configured_api_keys_name_a_client() {
+    let settings = RateLimitSettings {
+        api_keys: [("importer".to_string(), Secret::new("s3cret"))].into(),
+        ..Default::default()
+    };
+    assert_eq!(settings.api_key_client(b"s3cret"), Some("importer"));
+    assert_eq!(settings.api_key_client(b"made-up"), None);
+    assert_eq!(settings.api_key_client(b""), None);
+}
+
+#[test]
+fn empty_bucket_says_when_to_retry() {
+    let limit = RateLimit {
+        burst: 10,
+        per_second: 0.5,
+    };
+    let decision = Decision {
+        allowed: false,
+        limit,
+        tokens: 0.25,
+    };
+    // 0.75 tokens short at half a token a second
+    assert_eq!(decision.retry_after(), 2);
+    let resp = decision.too_many_requests();
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers()[header::RETRY_AFTER], "2");
+    assert_eq!(resp.headers()["ratelimit-limit"], "10");
+    assert_eq!(resp.headers()["ratelimit-remaining"], "0");
+    // refilling all ten takes (10 - 0.25) / 0.5 seconds
+    assert_eq!(resp.headers()["ratelimit-reset"], "20");
+    assert_eq!(resp.headers()["ratelimit-policy"], "10;w=20");
+
+    // a fast bucket still asks for at least a second
+    let decision = Decision {
+        allowed: false,
+        limit: RateLimit {
+            burst: 5,
+            per_second: 100.0,
+        },
+        tokens: 0.5,
+    };
+    assert_eq!(decision.retry_after(), 1);
+}
+
+#[tokio::test]
+async fn exhausted_bucket_answers_429_until_refilled() {
+    let redis = redis::Client::open("redis://127.0.0.1:6379").unwrap();
+    if redis.get_multiplexed_async_connection().await.is_err() {
+        eprintln!("skipping, Redis isn't reachable");
+        return;
+    }
+    let live_settings = LiveSettings::new(
+        DynamicSettings {
+            rate_limit_settings: RateLimitSettings {
+                enabled: true,
+                default: Some(RateLimit {
+                    burst: 2,
+                    per_second: 2.0,
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        None,
+    )
+    .unwrap();
+    let app = app(
+        SwaggerUi::new("/swagger-ui"),
+        state_with_settings(live_settings).with_rate_limiter(RateLimiter::new(redis)),
+    );
+    // a bucket of its own so reruns don't share one
+    let ip = format!("203.0.113.{}", std::process::id() % 250);
+    let request = || {
+        let mut request = Request::builder()
+            .uri("/authorize")
+            .body(Body::empty())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(axum::extract::ConnectInfo(std::net::SocketAddr::new(
+                ip.parse().unwrap(),
+                1234,
+            )));
+        request
+    };
+
+    for remaining in ["1", "0"] {
+        let resp = app.clone().oneshot(request()).await.unwrap();
+        assert_ne!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()["ratelimit-remaining"], remaining);
+    }
+    let resp = app.clone().oneshot(request()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers()[header::RETRY_AFTER], "1");
+
+    // two tokens a second puts one back within 500ms
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    let resp = app.oneshot(request()).await.unwrap();
+    assert_ne!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[test]
+fn wildcard_origin_with_credentials_is_rejected() {
+    let cors = CorsSettings {
+        allowed_origins: vec!["*".into()],
+        ..CorsSettings::for_environment(&Environment::Local)
+    };
+    assert!(cors_layer(&cors).is_err());
+    // at startup too, rather than falling back to denying every origin
+    let settings = DynamicSettings {
+        cors: cors.clone(),
+        ..Default::default()
+    };
+    assert!(settings.validate().is_err());
+    assert!(LiveSettings::new(settings, None).is_err());
+
+    let cors = CorsSettings {
+        allow_credentials: false,
+        ..cors
+    };
+    assert!(cors_layer(&cors).is_ok());
+}
+
+#[tokio::test]
+async fn secrets_resolve_from_files_and_vault() {
+    // answers like Vault's KV v2 engine
+    let vault = Router::new().route(
+        "/v1/secret/data/enchanted-natures",
+        get(|headers: HeaderMap| async move {
+            if headers.get("x-vault-token").map(|x| x.as_bytes()) != Some(b"vault-token") {
+                return Err(StatusCode::FORBIDDEN);
+            }
+            Ok(Json(serde_json::json!({
+                "data": {
+                    "data": {
+                        "client_secret": "from-vault",
+                        "importer_api_key": "importer-key"
+                    },
+                    "metadata": { "version": 1 }
+                }
+            })))
+        }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let vault_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, vault).await });
+
+    let dir = std::env::temp_dir().join(format!("enchanted-secrets-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("vault-token"), "vault-token\n").unwrap();
+    std::fs::write(dir.join("redis-url"), "redis://:hunter2@127.0.0.1:6379\n").unwrap();
+
+    let yaml = format!(
+        r#"
+redis_url_file: {dir}/redis-url
+auth_settings:
+  client_secret: vault://secret/data/enchanted-natures#client_secret
+secrets:
+  vault:
+    address: http://{vault_addr}
+    token_file: {dir}/vault-token
+"#,
+        dir = dir.display(),
+    );
+    let config = config::Config::builder()
+        .add_source(config::File::from_str(&yaml, config::FileFormat::Yaml))
+        .build()
+        .unwrap();
+
+    let config = resolve_files(config).unwrap();
+    let vault: VaultSettings = config.get("secrets.vault").unwrap();
+    let sources: Vec<Box<dyn SecretSource>> = vec![Box::new(VaultSource::new(vault).unwrap())];
+    let config = resolve_references(config, &sources).await.unwrap();
+
+    let client_secret: Secret = config.get("auth_settings.client_secret").unwrap();
+    assert_eq!(client_secret.expose(), "from-vault");
+    let redis_url: Secret = config.get("redis_url").unwrap();
+    assert_eq!(redis_url.expose(), "redis://:hunter2@127.0.0.1:6379");
+    assert_eq!(format!("{:?}", redis_url), "[redacted]");
+
+    // reloads resolve them as well, API keys are dynamic settings
+    let yaml = format!(
+        r#"
+rate_limit_settings:
+  api_keys:
+    importer: vault://secret/data/enchanted-natures#importer_api_key
+secrets:
+  vault:
+    address: http://{vault_addr}
+    token_file: {dir}/vault-token
+"#,
+        dir = dir.display(),
+    );
+    let config = config::Config::builder()
+        .add_source(
+            config::Config::try_from(&serde_json::json!({ "cors": CorsSettings::default() }))
+                .unwrap(),
+        )
+        .add_source(config::File::from_str(&yaml, config::FileFormat::Yaml))
+        .build()
+        .unwrap();
+    let dynamic = DynamicSettings::resolve(resolve_files(config).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(
+        dynamic.rate_limit_settings.api_key_client(b"importer-key"),
+        Some("importer")
+    );
+
+    let wrong_token = VaultSettings {
+        address: format!("http://{}", vault_addr),
+        token: Secret::new("nope"),
+        timeout_ms: 1000,
+    };
+    let source = VaultSource::new(wrong_token).unwrap();
+    assert!(source
+        .fetch("secret/data/enchanted-natures", "client_secret")
+        .await
+        .is_err());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// A JPEG with nothing but a Photoshop APP13 segment holding `resources`.
+fn jpeg_with_resources(resources: &[u8]) -> Vec<u8> {
+    let mut segment = b"Photoshop 3.0\0".to_vec();
+    segment.extend_from_slice(resources);
+    let mut jpeg = vec![0xff, 0xd8, 0xff, 0xed];
+    jpeg.extend_from_slice(&(segment.len() as u16 + 2).to_be_bytes());
+    jpeg.extend_from_slice(&segment);
+    jpeg.extend_from_slice(&[0xff, 0xda, 0x00, 0x02]);
+    jpeg
+}
+
+/// An image resource block with an empty name, padded to an even size.
+fn resource_block(id: u16, data: &[u8]) -> Vec<u8> {
+    let mut block = b"8BIM".to_vec();
+    block.extend_from_slice(&id.to_be_bytes());
+    block.extend_from_slice(&[0, 0]);
+    block.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    block.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        block.push(0);
+    }
+    block
+}
+
+/// An IPTC-IIM application record dataset.
+fn iptc_dataset(dataset: u8, value: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![0x1c, 2, dataset];
+    bytes.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(value);
+    bytes
+}
+
+#[test]
+fn iptc_fills_in_photo_metadata() {
+    let iptc = [
+        iptc_dataset(105, b"Old Faithful"),
+        iptc_dataset(120, b" Erupting at dawn "),
+        iptc_dataset(92, b"Upper Geyser Basin"),
+        iptc_dataset(90, b"Yellowstone"),
+        iptc_dataset(101, b"USA"),
+        iptc_dataset(25, b"geyser"),
+        iptc_dataset(25, b"caf\xe9"),
+    ]
+    .concat();
+    // an odd sized block before the IPTC one exercises the padding
+    let resources = [
+        resource_block(0x03ed, b"abc"),
+        resource_block(0x0404, &iptc),
+    ]
+    .concat();
+
+    let metadata = extract_metadata(&jpeg_with_resources(&resources));
+    assert_eq!(metadata.headline.as_deref(), Some("Old Faithful"));
+    assert_eq!(metadata.caption.as_deref(), Some("Erupting at dawn"));
+    assert_eq!(
+        metadata.location.as_deref(),
+        Some("Upper Geyser Basin, Yellowstone, USA")
+    );
+    assert_eq!(
+        metadata.raw["iptc"]["Keywords"],
+        serde_json::json!(["geyser", "caf\u{fffd}"])
+    );
+}
+
+#[test]
+fn malformed_iptc_keeps_what_was_read_before_it() {
+    let headline = iptc_dataset(105, b"Old Faithful");
+    let caption = iptc_dataset(120, b"Erupting at dawn");
+    let iptc_jpeg = |iptc: &[u8]| jpeg_with_resources(&resource_block(0x0404, iptc));
+    let mut truncated_dataset = [headline.clone(), caption.clone()].concat();
+    truncated_dataset.truncate(headline.len() + 8);
+    let mut past_the_end = iptc_jpeg(&[headline.clone(), caption.clone()].concat());
+    past_the_end.truncate(30);
+    let mut oversized_resource = resource_block(0x0404, &headline);
+    oversized_resource[8..12].copy_from_slice(&1000u32.to_be_bytes());
+
+    let cases: [(&str, Vec<u8>, Option<&str>); 8] = [
+        (
+            "dataset cut short",
+            iptc_jpeg(&truncated_dataset),
+            Some("Old Faithful"),
+        ),
+        (
+            "dataset header cut short",
+            iptc_jpeg(&[headline.clone(), vec![0x1c, 2]].concat()),
+            Some("Old Faithful"),
+        ),
+        (
+            "extended dataset",
+            iptc_jpeg(
+                &[
+                    headline.clone(),
+                    vec![0x1c, 2, 120, 0x80, 0x04, 0, 0, 0, 16],
+                ]
+                .concat(),
+            ),
+            Some("Old Faithful"),
+        ),
+        (
+            "garbage between datasets",
+            iptc_jpeg(&[headline.clone(), vec![0xff], caption.clone()].concat()),
+            Some("Old Faithful"),
+        ),
+        (
+            "resource larger than its segment",
+            jpeg_with_resources(&oversized_resource),
+            None,
+        ),
+        ("segment past the end of the file", past_the_end, None),
+        (
+            "segment shorter than its length field",
+            vec![0xff, 0xd8, 0xff, 0xed, 0x00, 0x01, 0xff, 0xda],
+            None,
+        ),
+        ("not a jpeg", [&[0x89][..], &headline].concat(), None),
+    ];
+    for (name, bytes, expected) in cases {
+        let metadata = extract_metadata(&bytes);
+        assert_eq!(metadata.headline.as_deref(), expected, "{}", name);
+        // nothing after the damage is trusted
+        assert_eq!(metadata.caption, None, "{}", name);
+    }
+}
+
+/// A 9x8 greyscale image, the size dHash shrinks to, so resizing leaves
+/// the pixels as they are.
+fn dhash_image(pixel: impl Fn(u32, u32) -> u8) -> image::DynamicImage {
+    image::DynamicImage::ImageLuma8(image::GrayImage::from_fn(9, 8, |x, y| {
+        image::Luma([pixel(x, y)])
+    }))
+}
+
+#[test]
+fn dhash_sets_a_bit_where_the_right_neighbour_is_brighter() {
+    let rising = dhash_image(|x, _| x as u8 * 20);
+    let falling = dhash_image(|x, _| 200 - x as u8 * 20);
+    let flat = dhash_image(|_, _| 128);
+    let stripes = dhash_image(|x, _| if x % 2 == 0 { 0 } else { 255 });
+    assert_eq!(perceptual_hash(&rising), -1);
+    assert_eq!(perceptual_hash(&falling), 0);
+    assert_eq!(perceptual_hash(&flat), 0);
+    assert_eq!(perceptual_hash(&stripes) as u64, 0xAAAA_AAAA_AAAA_AAAA);
+
+    // one brighter pixel only sets the bit of its left-hand neighbour
+    let edited = dhash_image(|x, y| if (x, y) == (4, 0) { 255 } else { 128 });
+    let distance = (perceptual_hash(&flat) ^ perceptual_hash(&edited)).count_ones();
+    assert_eq!(distance, 1);
+
+    // a larger copy of the same picture hashes the same
+    let larger = rising.resize_exact(90, 80, image::imageops::FilterType::Nearest);
+    assert_eq!(perceptual_hash(&larger), perceptual_hash(&rising));
+}
+
+#[test]
+fn duplicate_pairs_are_grouped_transitively() {
+    let pair = |photo_id, duplicate_id, distance| DuplicatePair {
+        photo_id,
+        duplicate_id,
+        distance,
+    };
+    let groups = group_duplicates(&[
+        pair(4, 5, 1),
+        pair(6, 7, 8),
+        // 9 joins 1 through 3, which was seen last
+        pair(3, 9, 2),
+        pair(1, 2, 3),
+        pair(2, 3, 5),
+    ]);
+    assert_eq!(
+        groups,
+        vec![
+            DuplicateGroup {
+                photo_ids: vec![4, 5],
+                max_distance: 1,
+            },
+            DuplicateGroup {
+                photo_ids: vec![1, 2, 3, 9],
+                max_distance: 5,
+            },
+            DuplicateGroup {
+                photo_ids: vec![6, 7],
+                max_distance: 8,
+            },
+        ]
+    );
+    assert!(group_duplicates(&[]).is_empty());
+}
+
+/// An empty, migrated database of its own, so tests can change data
+/// without stepping on each other. Dropped by `remove`; one left behind by a failed
+/// test is named `enchanted_test_*`.
+struct ScratchDatabase {
+    admin: PgPool,
+    name: String,
+    repo: PhotoRepository,
+}
+
+impl ScratchDatabase {
+    /// A migrated database without the catalog the migrations seed or the
+    /// jobs they queue for it.
+    async fn new() -> Self {
+        let db = Self::seeded().await;
+        sqlx::query("TRUNCATE photos, categories, jobs CASCADE")
+            .execute(&*db.repo.db_pool)
+            .await
+            .unwrap();
+        db
+    }
+
+    /// A migrated database, as deployed.
+    async fn seeded() -> Self {
+        let settings = Settings::load_config().await.unwrap();
+        let options = PgConnectOptions::try_from(&settings.database_settings).unwrap();
+        let admin = PgPool::connect_with(options.clone()).await.unwrap();
+        let name = format!("enchanted_test_{}", uuid::Uuid::new_v4().simple());
+        sqlx::query(&format!("CREATE DATABASE {}", name))
+            .execute(&admin)
+            .await
+            .unwrap();
+        let pool = PgPool::connect_with(options.database(&name)).await.unwrap();
+        let repo = PhotoRepository::new(pool);
+        repo.migrate().await.unwrap();
+        Self { admin, name, repo }
+    }
+
+    async fn remove(self) {
+        self.repo.db_pool.close().await;
+        // the server may not have noticed the pool closing yet
+        sqlx::query(&format!("DROP DATABASE {} WITH (FORCE)", self.name))
+            .execute(&self.admin)
+            .await
+            .unwrap();
+    }
+}
+
+#[tokio::test]
+async fn migrations_revert_the_latest_or_down_to_a_version() {
+    let db = ScratchDatabase::new().await;
+    let versions: Vec<i64> = db
+        .repo
+        .migration_status()
+        .await
+        .unwrap()
+        .iter()
+        .map(|x| x.version)
+        .collect();
+    let latest = versions[versions.len() - 1];
+
+    assert_eq!(db.repo.revert_migrations(None).await.unwrap(), [latest]);
+    assert_eq!(db.repo.pending_migrations().await.unwrap(), [latest]);
+
+    let target = versions[3];
+    assert_eq!(
+        db.repo.revert_migrations(Some(target)).await.unwrap(),
+        versions[4..versions.len() - 1]
+    );
+    assert_eq!(db.repo.pending_migrations().await.unwrap(), versions[4..]);
+
+    // every down migration runs, and the schema can be rebuilt afterwards
+    assert_eq!(
+        db.repo.revert_migrations(Some(0)).await.unwrap(),
+        versions[..4]
+    );
+    assert_eq!(db.repo.pending_migrations().await.unwrap(), versions);
+    db.repo.migrate().await.unwrap();
+    assert!(db.repo.pending_migrations().await.unwrap().is_empty());
+    db.remove().await;
+}
+
+/// Uploads a small PNG as `filename` straight to the handler, since the
+/// route wants a session.
+async fn upload(app: &AppState, filename: &str) -> StatusCode {
+    upload_with(app, filename, &[]).await
+}
+
+/// `upload` with more form fields.
+async fn upload_with(app: &AppState, filename: &str, fields: &[(&str, &str)]) -> StatusCode {
+    let mut png = Vec::new();
+    dhash_image(|x, y| (x * 20 + y) as u8)
+        .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    let mut body = Vec::new();
+    for (name, value) in [
+        ("title", "Old Faithful"),
+        ("location_taken", "Yellowstone"),
+        ("date_taken", "2023-07-07"),
+    ]
+    .iter()
+    .chain(fields)
+    {
+        body.extend_from_slice(
+            format!(
+                "--X\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                name, value
+            )
+            .as_bytes(),
+        );
+    }
+    body.extend_from_slice(
+        format!(
+            "--X\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\
+             Content-Type: image/png\r\n\r\n",
+            filename
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(&png);
+    body.extend_from_slice(b"\r\n--X--\r\n");
+    let request = Request::builder()
+        .method(http::Method::POST)
+        .header(header::CONTENT_TYPE, "multipart/form-data; boundary=X")
+        .body(Body::from(body))
+        .unwrap();
+    let multipart = Multipart::from_request(request, &()).await.unwrap();
+    let user = User {
+        email: "test@example.com".into(),
+        sub: "test".into(),
+    };
+    match upload_photo(State(app.clone()), user, multipart).await {
+        Ok(resp) => resp.status(),
+        Err((status, _)) => status,
+    }
+}
+
+#[tokio::test]
+async fn uploads_never_replace_a_stored_file() {
+    let db = ScratchDatabase::new().await;
+    let root = std::env::temp_dir().join(format!("upload-{}", uuid::Uuid::new_v4()));
+    let cache = CacheSettings {
+        enabled: false,
+        ..Default::default()
+    };
+    let mut app = state_with_repo(db.repo.clone(), cache);
+    app.storage = PhotoStorage::new(&root);
+    app.storage.put("taken.png", b"stored").await.unwrap();
+
+    assert_eq!(upload(&app, "taken.png").await, StatusCode::CONFLICT);
+    assert_eq!(app.storage.get("taken.png").await.unwrap(), b"stored");
+    let photos: i64 = sqlx::query_scalar("SELECT count(*) FROM photos")
+        .fetch_one(&*db.repo.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(photos, 0);
+
+    // of concurrent writes to one key exactly one lands
+    let writes = (0..8).map(|n| {
+        let storage = app.storage.clone();
+        async move { (n, storage.put_new("race.png", &[n]).await) }
+    });
+    let results = futures::future::join_all(writes).await;
+    let stored: Vec<u8> = results
+        .iter()
+        .filter(|(_, result)| result.is_ok())
+        .map(|(n, _)| *n)
+        .collect();
+    assert_eq!(stored.len(), 1);
+    for (_, result) in &results {
+        if let Err(e) = result {
+            assert!(e.is::<ObjectExistsError>(), "{:?}", e);
+        }
+    }
+    assert_eq!(app.storage.get("race.png").await.unwrap(), stored);
+
+    // and none of them leaves its temporary file behind
+    let mut names: Vec<String> = std::fs::read_dir(&root)
+        .unwrap()
+        .map(|x| x.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["race.png", "taken.png"]);
+
+    std::fs::remove_dir_all(root).unwrap();
+    db.remove().await;
+}
+
+#[tokio::test]
+async fn failed_upload_keeps_neither_the_photo_nor_its_file() {
+    let db = ScratchDatabase::new().await;
+    let root = std::env::temp_dir().join(format!("upload-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&root).unwrap();
+    let cache = CacheSettings {
+        enabled: false,
+        ..Default::default()
+    };
+    let mut app = state_with_repo(db.repo.clone(), cache);
+    app.storage = PhotoStorage::new(&root);
+    for statement in [
+        "CREATE FUNCTION refuse_exif() RETURNS trigger AS $$ \
+         BEGIN RAISE EXCEPTION 'refused'; END $$ LANGUAGE plpgsql",
+        "CREATE TRIGGER refuse_exif BEFORE INSERT ON photo_exif \
+         FOR EACH ROW EXECUTE FUNCTION refuse_exif()",
+    ] {
+        sqlx::query(statement)
+            .execute(&*db.repo.db_pool)
+            .await
+            .unwrap();
+    }
+    let count = |table: &'static str| {
+        let pool = db.repo.db_pool.clone();
+        async move {
+            sqlx::query_scalar::<_, i64>(&format!("SELECT count(*) FROM {}", table))
+                .fetch_one(&*pool)
+                .await
+                .unwrap()
+        }
+    };
+
+    assert_eq!(
+        upload(&app, "faithful.png").await,
+        StatusCode::INTERNAL_SERVER_ERROR
+    );
+    assert_eq!(count("photos").await, 0);
+    assert!(!app.storage.exists("faithful.png").await.unwrap());
+
+    sqlx::query("DROP TRIGGER refuse_exif ON photo_exif")
+        .execute(&*db.repo.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(upload(&app, "faithful.png").await, StatusCode::CREATED);
+    assert_eq!(count("photos").await, 1);
+    assert_eq!(count("photo_exif").await, 1);
+    assert!(app.storage.exists("faithful.png").await.unwrap());
+
+    std::fs::remove_dir_all(root).unwrap();
+    db.remove().await;
+}
+
+/// A photo as uploaded before content hashes were kept, with its variants
+/// and perceptual hash already in place.
+#[tokio::test]
+async fn catalogs_restore_from_their_export() {
+    let source = ScratchDatabase::new().await;
+    let mut ids = Vec::new();
+    for filename in ["arches.jpg", "bryce.jpg", "zion.jpg"] {
+        ids.push(add_legacy_photo(&source.repo, filename).await);
+    }
+    source
+        .repo
+        .upsert_photo_exif(
+            ids[1],
+            &PhotoMetadata {
+                camera_make: Some("Nikon".into()),
+                iso: Some(200),
+                raw: serde_json::json!({ "Make": "Nikon" }),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    let user = || User {
+        email: "test@example.com".into(),
+        sub: "test".into(),
+    };
+    let cache = || {
+        ResponseCache::new(
+            redis::Client::open("redis://127.0.0.1:6379").unwrap(),
+            CacheSettings {
+                enabled: false,
+                ..Default::default()
+            },
+        )
+    };
+    let export = |repo: PhotoRepository| async move {
+        let uri: http::Uri = "/api/v0/export?format=json".parse().unwrap();
+        let resp = export_catalog(State(repo), user(), Query::try_from_uri(&uri).unwrap())
+            .await
+            .unwrap();
+        axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap()
+    };
+    let import = |repo: PhotoRepository, replace: bool, body: axum::body::Bytes| async move {
+        import_catalog(
+            State(repo.clone()),
+            State(cache()),
+            State(JobQueue::new(repo, &JobSettings::default())),
+            user(),
+            Query(ImportQuery { replace }),
+            HeaderMap::new(),
+            body,
+        )
+        .await
+        .map(|resp| resp.into_response().status())
+        .map_err(|(status, _)| status)
+    };
+    let exported = export(source.repo.clone()).await;
+
+    // the source still has its photos
+    assert_eq!(
+        import(source.repo.clone(), false, exported.clone()).await,
+        Err(StatusCode::CONFLICT)
+    );
+
+    let target = ScratchDatabase::new().await;
+    assert_eq!(
+        import(target.repo.clone(), false, exported.clone()).await,
+        Ok(StatusCode::CREATED)
+    );
+    let exif = target.repo.get_photo_exif(ids[1]).await.unwrap().unwrap();
+    assert_eq!(exif.camera_make.as_deref(), Some("Nikon"));
+    assert_eq!(exif.iso, Some(200));
+
+    // exporting the restored catalog gives back the same tables
+    let without_timestamp = |body: &[u8]| {
+        let mut catalog: serde_json::Value = serde_json::from_slice(body).unwrap();
+        catalog.as_object_mut().unwrap().remove("exported_at");
+        catalog
+    };
+    assert_eq!(
+        without_timestamp(&export(target.repo.clone()).await),
+        without_timestamp(&exported)
+    );
+
+    // the variants weren't exported, so they're regenerated
+    let queued: Vec<String> = sqlx::query_scalar(
+        "SELECT dedupe_key FROM jobs WHERE status = 'pending' ORDER BY dedupe_key",
+    )
+    .fetch_all(&*target.repo.db_pool)
+    .await
+    .unwrap();
+    let mut expected: Vec<String> = ids
+        .iter()
+        .map(|id| format!("generate_variants:{}", id))
+        .collect();
+    expected.push("assign_locations".into());
+    expected.sort();
+    assert_eq!(queued, expected);
+
+    // new photos don't collide with the imported ids
+    let added = add_legacy_photo(&target.repo, "yosemite.jpg").await;
+    assert!(added > *ids.iter().max().unwrap());
+
+    // replacing drops what was there before
+    assert_eq!(
+        import(target.repo.clone(), true, exported).await,
+        Ok(StatusCode::CREATED)
+    );
+    let restored: Vec<i32> = sqlx::query_scalar("SELECT id FROM photos ORDER BY id")
+        .fetch_all(&*target.repo.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(restored, ids);
+
+    source.remove().await;
+    target.remove().await;
+}
+
+async fn add_legacy_photo(repo: &PhotoRepository, filename: &str) -> i32 {
+    let photo = repo
+        .add_photo(NewPhoto {
+            title: filename.into(),
+            filename: filename.into(),
+            location_taken: "Zion".into(),
+            date_taken: chrono::NaiveDate::from_ymd_opt(2023, 7, 7).unwrap(),
+            coordinates: None,
+            location_id: None,
+            perceptual_hash: Some(0),
+            content_sha256: None,
+        })
+        .await
+        .unwrap();
+    sqlx::query(
+        "INSERT INTO photo_variants (photo_id, variant, format, width, height, storage_key, byte_size)
+         VALUES ($1, 'thumb', 'jpeg', 1, 1, $2, 1)",
+    )
+    .bind(photo.id)
+    .bind(format!("{}-thumb.jpg", photo.id))
+    .execute(&*repo.db_pool)
+    .await
+    .unwrap();
+    photo.id
+}
+
+#[tokio::test]
+async fn catalog_exports_stream_every_table() {
+    let db = ScratchDatabase::new().await;
+    let mut ids = Vec::new();
+    for filename in ["arches.jpg", "bryce.jpg", "zion.jpg"] {
+        ids.push(add_legacy_photo(&db.repo, filename).await);
+    }
+    let user = || User {
+        email: "test@example.com".into(),
+        sub: "test".into(),
+    };
+    let export = |format: &'static str| {
+        let uri: http::Uri = format!("/api/v0/export?format={}", format).parse().unwrap();
+        export_catalog(
+            State(db.repo.clone()),
+            user(),
+            Query::try_from_uri(&uri).unwrap(),
+        )
+    };
+
+    for format in ["json", "zip"] {
+        let resp = export(format).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let catalog = match format {
+            "zip" => CatalogExport::from_zip(&body).unwrap(),
+            _ => serde_json::from_slice::<CatalogExport>(&body).unwrap(),
+        };
+        catalog.check_version().unwrap();
+        let exported: Vec<_> = catalog.photos.iter().map(|x| x.id).collect();
+        assert_eq!(exported, ids, "{}", format);
+        // tables without rows are still there
+        assert!(catalog.categories.is_empty());
+        assert!(catalog.photo_categories.is_empty());
+        assert!(catalog.cloudflare_resources.is_empty());
+    }
+
+    // failing before the first row still gets an error status
+    db.repo.db_pool.close().await;
+    for format in ["json", "zip"] {
+        let Err((status, _)) = export(format).await else {
+            panic!("{} export of a closed database succeeded", format);
+        };
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    db.remove().await;
+}
+
+/// Records the `target:name` of every span opened while it's the default
+/// subscriber.
+#[derive(Clone, Default)]
+struct SpanNames(Arc<Mutex<Vec<String>>>);
+
+impl<S: Subscriber> Layer<S> for SpanNames {
+    fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+        let metadata = attrs.metadata();
+        self.0
+            .lock()
+            .unwrap()
+            .push(format!("{}:{}", metadata.target(), metadata.name()));
+    }
+}
+
+#[tokio::test]
+async fn category_deletes_are_traced_as_database_calls() {
+    let db = ScratchDatabase::new().await;
+    let category = db.repo.add_category("Canyons".into()).await.unwrap();
+    let state = state_with_repo(db.repo.clone(), CacheSettings::default());
+    let user = User {
+        email: "test@example.com".into(),
+        sub: "test".into(),
+    };
+
+    let spans = SpanNames::default();
+    let guard = tracing::subscriber::set_default(Registry::default().with(spans.clone()));
+    let resp = delete_category(State(state), user, Path(category.id))
+        .await
+        .unwrap()
+        .into_response();
+    drop(guard);
+
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert!(db.repo.get_categories().await.unwrap().is_empty());
+    let spans = spans.0.lock().unwrap().clone();
+    assert!(
+        spans.contains(&"db:db.delete_category".to_string()),
+        "{:?}",
+        spans
+    );
+    db.remove().await;
+}
+
+#[test]
+fn outgoing_calls_continue_the_incoming_trace() {
+    use opentelemetry::trace::TracerProvider as _;
+
+    // no exporter; spans only need ids
+    let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("test"));
+    let _guard = tracing::subscriber::set_default(Registry::default().with(layer));
+
+    let trace_id = "0af7651916cd43dd8448eb211c80319c";
+    let parent_id = "b7ad6b7169203331";
+    let mut incoming = HeaderMap::new();
+    incoming.insert(
+        "traceparent",
+        format!("00-{}-{}-01", trace_id, parent_id).parse().unwrap(),
+    );
+    let span = tracing::info_span!("request");
+    set_remote_parent(&span, &incoming);
+    let outgoing = span.in_scope(trace_headers);
+
+    let traceparent: Vec<_> = outgoing["traceparent"].split('-').collect();
+    assert_eq!(traceparent[1], trace_id);
+    // the call is a child of this service's span, not the caller's
+    assert_ne!(traceparent[2], parent_id);
+    assert_eq!(traceparent[3], "01");
+
+    // without an incoming trace, a new one is started
+    let span = tracing::info_span!("request");
+    set_remote_parent(&span, &HeaderMap::new());
+    let outgoing = span.in_scope(trace_headers);
+    assert_ne!(outgoing["traceparent"].split('-').nth(1), Some(trace_id));
+}
+
+#[tokio::test]
+async fn photo_images_are_negotiated_by_accept() {
+    let db = ScratchDatabase::new().await;
+    let root = std::env::temp_dir().join(format!("negotiate-{}", uuid::Uuid::new_v4()));
+    let storage = PhotoStorage::new(&root);
+    storage.put("falls.jpg", b"original").await.unwrap();
+    let id = add_legacy_photo(&db.repo, "falls.jpg").await;
+    sqlx::query("DELETE FROM photo_variants WHERE photo_id = $1")
+        .bind(id)
+        .execute(&*db.repo.db_pool)
+        .await
+        .unwrap();
+    let mut state = state_with_repo(db.repo.clone(), CacheSettings::default());
+    state.storage = storage.clone();
+    let app = app(SwaggerUi::new("/swagger-ui"), state);
+    let uri = format!("/api/v0/photos/{}/image", id);
+    let get_image = |accept: Option<&'static str>| {
+        let headers: Vec<_> = accept.map(|x| (header::ACCEPT, x)).into_iter().collect();
+        app.clone().oneshot(get_request(&uri, &headers))
+    };
+
+    // until variants exist the original is served whatever is accepted
+    let resp = get_image(Some("image/avif")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()[header::CONTENT_TYPE], "image/jpeg");
+    assert_eq!(resp.headers()[header::VARY], "Accept");
+
+    let variants = variant_storage(&storage).unwrap();
+    for format in ["jpeg", "webp", "avif"] {
+        let key = format!("{}-thumb.{}", id, format);
+        variants.put(&key, format.as_bytes()).await.unwrap();
+        sqlx::query(
+            "INSERT INTO photo_variants (photo_id, variant, format, width, height, storage_key, byte_size)
+             VALUES ($1, 'thumb', $2, 320, 240, $3, 4)",
+        )
+        .bind(id)
+        .bind(format)
+        .bind(key)
+        .execute(&*db.repo.db_pool)
+        .await
+        .unwrap();
+    }
+    for (accept, expected) in [
+        (None, Some("jpeg")),
+        (Some("*/*"), Some("jpeg")),
+        (Some("image/*"), Some("jpeg")),
+        (Some("image/*, image/jpeg;q=0"), Some("webp")),
+        // lossless WebP is larger than JPEG
+        (Some("image/webp,image/jpeg"), Some("jpeg")),
+        (Some("image/avif,image/webp,image/jpeg"), Some("avif")),
+        (Some("image/webp,*/*;q=0.8"), Some("webp")),
+        (Some("image/jpeg;q=0.5, image/webp"), Some("webp")),
+        (Some("image/png"), None),
+    ] {
+        let resp = get_image(accept).await.unwrap();
+        assert_eq!(resp.headers()[header::VARY], "Accept", "{:?}", accept);
+        match expected {
+            Some(format) => {
+                assert_eq!(resp.status(), StatusCode::OK, "{:?}", accept);
+                assert_eq!(
+                    resp.headers()[header::CONTENT_TYPE],
+                    format!("image/{}", format).as_str(),
+                    "{:?}",
+                    accept
+                );
+                let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                assert_eq!(body, format.as_bytes());
+            }
+            None => assert_eq!(resp.status(), StatusCode::NOT_ACCEPTABLE, "{:?}", accept),
+        }
+    }
+
+    std::fs::remove_dir_all(root).unwrap();
+    db.remove().await;
+}
+
+#[tokio::test]
+async fn legacy_duplicates_are_hashed_once() {
+    let db = ScratchDatabase::new().await;
+    let repo = &db.repo;
+    let first = add_legacy_photo(repo, "a.jpg").await;
+    let second = add_legacy_photo(repo, "b.jpg").await;
+    assert_eq!(
+        repo.get_unprocessed_photo_ids().await.unwrap(),
+        [first, second]
+    );
+
+    // hashed at once, as by two workers
+    let hash = content_hash(b"same bytes");
+    let (a, b) = tokio::join!(
+        record_content_hash(repo, first, hash.clone()),
+        record_content_hash(repo, second, hash.clone()),
+    );
+    let recorded = [a.unwrap(), b.unwrap()];
+    assert_eq!(recorded.iter().filter(|x| **x).count(), 1);
+    assert!(repo.get_unprocessed_photo_ids().await.unwrap().is_empty());
+
+    // the copy is hashed again once the photo holding the hash is gone
+    let (holder, copy) = match recorded {
+        [true, _] => (first, second),
+        _ => (second, first),
+    };
+    repo.delete_photo(holder).await.unwrap();
+    assert_eq!(repo.get_unprocessed_photo_ids().await.unwrap(), [copy]);
+    assert!(record_content_hash(repo, copy, hash).await.unwrap());
+    db.remove().await;
+}
+
+#[tokio::test]
+async fn dead_jobs_dont_hold_their_dedupe_key() {
+    let db = ScratchDatabase::new().await;
+    let jobs = JobQueue::new(db.repo.clone(), &JobSettings::default());
+    let dead = jobs.enqueue(Job::VerifyStorage).await.unwrap().unwrap();
+    assert!(jobs.enqueue(Job::VerifyStorage).await.unwrap().is_none());
+
+    sqlx::query("UPDATE jobs SET status = 'dead' WHERE id = $1")
+        .bind(dead.id)
+        .execute(&*db.repo.db_pool)
+        .await
+        .unwrap();
+    let queued = jobs.enqueue(Job::VerifyStorage).await.unwrap().unwrap();
+    assert_ne!(queued.id, dead.id);
+    let outstanding = db.repo.get_outstanding_job("verify_storage").await.unwrap();
+    assert_eq!(outstanding.unwrap().id, queued.id);
+
+    // retrying the dead one would queue the same work twice
+    let e = db.repo.retry_job(dead.id).await.unwrap_err();
+    assert!(is_unique_violation(&e));
+    db.remove().await;
+}
+
+fn job_context(repo: &PhotoRepository, storage: PhotoStorage) -> JobContext {
+    JobContext {
+        repo: repo.clone(),
+        storage,
+        image_settings: Arc::new(ImageSettings::default()),
+        location_settings: Arc::new(LocationSettings::default()),
+        cache: ResponseCache::new(
+            redis::Client::open("redis://127.0.0.1:6379").unwrap(),
+            CacheSettings {
+                enabled: false,
+                ..Default::default()
+            },
+        ),
+        cloudflare: None,
+    }
+}
+
+fn quick_job_settings() -> JobSettings {
+    JobSettings {
+        poll_interval_ms: 50,
+        ..Default::default()
+    }
+}
+
+/// Runs one worker until `done` holds for `job`.
+async fn run_worker_until(
+    context: JobContext,
+    settings: JobSettings,
+    job: &JobRecord,
+    done: impl Fn(&JobRecord) -> bool,
+) -> JobRecord {
+    let shutdown = Shutdown::new();
+    let repo = context.repo.clone();
+    let worker = tokio::spawn(Worker::new("test".into(), context, settings).run(shutdown.clone()));
+    let job = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let job = repo.get_job(job.id).await.unwrap().unwrap();
+            if done(&job) {
+                return job;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("job didn't get there");
+    shutdown.trigger();
+    worker.await.unwrap();
+    job
+}
+
+fn is_finished(job: &JobRecord) -> bool {
+    job.status != "pending" && job.status != "running"
+}
+
+/// Runs one worker until `job` is no longer outstanding.
+async fn run_job(repo: &PhotoRepository, storage: PhotoStorage, job: &JobRecord) -> JobRecord {
+    run_worker_until(
+        job_context(repo, storage),
+        quick_job_settings(),
+        job,
+        is_finished,
+    )
+    .await
+}
+
+#[tokio::test]
+async fn storage_verification_reports_every_kind_of_problem() {
+    let db = ScratchDatabase::new().await;
+    let root = std::env::temp_dir().join(format!("verify-{}", uuid::Uuid::new_v4()));
+    let storage = PhotoStorage::new(&root);
+    storage.put("intact.jpg", b"intact").await.unwrap();
+    storage.put("changed.jpg", b"changed").await.unwrap();
+    // a directory exists but can't be read as a file
+    std::fs::create_dir(root.join("unreadable.jpg")).unwrap();
+    let mut ids = Vec::new();
+    for (filename, contents) in [
+        ("intact.jpg", b"intact".as_slice()),
+        ("changed.jpg", b"original"),
+        ("unreadable.jpg", b"unreadable"),
+        ("missing.jpg", b"missing"),
+    ] {
+        let id = add_legacy_photo(&db.repo, filename).await;
+        db.repo
+            .set_photo_content_hash(id, &content_hash(contents))
+            .await
+            .unwrap();
+        ids.push(id);
+    }
+
+    let jobs = JobQueue::new(db.repo.clone(), &JobSettings::default());
+    let job = jobs.enqueue(Job::VerifyStorage).await.unwrap().unwrap();
+    let job = run_job(&db.repo, storage, &job).await;
+    assert_eq!(job.status, "completed");
+    let report = job.result.unwrap();
+    assert_eq!(report["checked"], 4);
+    assert_eq!(report["corrupted"][0]["photo_id"], ids[1]);
+    assert_eq!(report["unreadable"][0]["photo_id"], ids[2]);
+    assert_eq!(report["missing"][0]["photo_id"], ids[3]);
+    std::fs::remove_dir_all(root).unwrap();
+    db.remove().await;
+}
+
+#[tokio::test]
+async fn claimed_jobs_are_leased_to_one_worker() {
+    let db = ScratchDatabase::new().await;
+    let jobs = JobQueue::new(db.repo.clone(), &JobSettings::default());
+    for photo_id in 1..=5 {
+        jobs.enqueue(Job::GenerateVariants { photo_id })
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    let claims = (0..10).map(|n| {
+        let repo = db.repo.clone();
+        tokio::spawn(async move { repo.claim_job(&format!("worker-{}", n), 30.0).await })
+    });
+    let mut claimed = Vec::new();
+    for claim in claims {
+        if let Some(job) = claim.await.unwrap().unwrap() {
+            assert_eq!((job.status.as_str(), job.attempts), ("running", 1));
+            claimed.push(job);
+        }
+    }
+    let mut ids: Vec<i64> = claimed.iter().map(|x| x.id).collect();
+    ids.sort_unstable();
+    ids.dedup();
+    assert_eq!(ids.len(), 5);
+
+    // a lease that ran out is handed to the next worker, and the worker
+    // that lost it can't record an outcome any more
+    let abandoned = &claimed[0];
+    sqlx::query("UPDATE jobs SET locked_until = now() - interval '1 second' WHERE id = $1")
+        .bind(abandoned.id)
+        .execute(&*db.repo.db_pool)
+        .await
+        .unwrap();
+    let reclaimed = db.repo.claim_job("late", 30.0).await.unwrap().unwrap();
+    assert_eq!(reclaimed.id, abandoned.id);
+    assert_eq!(reclaimed.attempts, 2);
+    let previous = abandoned.locked_by.as_deref().unwrap();
+    db.repo
+        .complete_job(abandoned.id, previous, None)
+        .await
+        .unwrap();
+    assert!(!db
+        .repo
+        .renew_job_lease(abandoned.id, previous, 30.0)
+        .await
+        .unwrap());
+    let job = db.repo.get_job(abandoned.id).await.unwrap().unwrap();
+    assert_eq!(job.status, "running");
+    assert_eq!(job.locked_by.as_deref(), Some("late"));
+
+    // one that ran out on its last attempt is dead instead
+    let last = &claimed[1];
+    sqlx::query(
+        "UPDATE jobs SET max_attempts = attempts, locked_until = now() - interval '1 second' \
+         WHERE id = $1",
+    )
+    .bind(last.id)
+    .execute(&*db.repo.db_pool)
+    .await
+    .unwrap();
+    assert!(db.repo.claim_job("late", 30.0).await.unwrap().is_none());
+    let job = db.repo.get_job(last.id).await.unwrap().unwrap();
+    assert_eq!(job.status, "dead");
+    assert!(job.last_error.unwrap().contains("visibility timeout"));
+    db.remove().await;
+}
+
+#[tokio::test]
+async fn jobs_running_past_their_visibility_timeout_keep_their_lease() {
+    let db = ScratchDatabase::new().await;
+    let storage = PhotoStorage::new("./photos");
+    let jobs = JobQueue::new(db.repo.clone(), &JobSettings::default());
+    let job = jobs
+        .enqueue(Job::AssignLocations { photo_id: None })
+        .await
+        .unwrap()
+        .unwrap();
+    let settings = JobSettings {
+        visibility_timeout_secs: 1,
+        ..quick_job_settings()
+    };
+
+    // the job waits on the lock for well past its visibility timeout
+    let mut lock = db.repo.db_pool.begin().await.unwrap();
+    sqlx::query("LOCK TABLE photos IN ACCESS EXCLUSIVE MODE")
+        .execute(&mut *lock)
+        .await
+        .unwrap();
+    let shutdown = Shutdown::new();
+    let workers: Vec<_> = ["first", "second"]
+        .into_iter()
+        .map(|name| {
+            let worker = Worker::new(
+                name.into(),
+                job_context(&db.repo, storage.clone()),
+                settings.clone(),
+            );
+            tokio::spawn(worker.run(shutdown.clone()))
+        })
+        .collect();
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    let running = db.repo.get_job(job.id).await.unwrap().unwrap();
+    assert_eq!(running.status, "running");
+    assert_eq!(running.attempts, 1);
+    lock.rollback().await.unwrap();
+
+    let job = run_worker_until(job_context(&db.repo, storage), settings, &job, |x| {
+        x.status != "running"
+    })
+    .await;
+    shutdown.trigger();
+    for worker in workers {
+        worker.await.unwrap();
+    }
+    assert_eq!(job.status, "completed");
+    assert_eq!(job.attempts, 1);
+    db.remove().await;
+}
+
+#[test]
+fn job_backoff_doubles_up_to_the_maximum() {
+    let settings = JobSettings {
+        backoff_base_secs: 10,
+        backoff_max_secs: 60,
+        ..Default::default()
+    };
+    let delays: Vec<u64> = (1..=5).map(|x| settings.backoff(x).as_secs()).collect();
+    assert_eq!(delays, [10, 20, 40, 60, 60]);
+}
+
+#[tokio::test]
+async fn failing_jobs_back_off_then_go_dead() {
+    let db = ScratchDatabase::new().await;
+    let settings = JobSettings {
+        max_attempts: 3,
+        backoff_base_secs: 60,
+        ..quick_job_settings()
+    };
+    let jobs = JobQueue::new(db.repo.clone(), &settings);
+    // fails every time, as there is no Cloudflare to sync with
+    let job = jobs
+        .enqueue(Job::SyncCloudflare { photo_id: 1 })
+        .await
+        .unwrap()
+        .unwrap();
+    let context = job_context(&db.repo, PhotoStorage::new("./photos"));
+
+    let retrying = run_worker_until(context.clone(), settings.clone(), &job, |x| {
+        x.status == "pending" && x.attempts == 1
+    })
+    .await;
+    assert!(retrying
+        .last_error
+        .unwrap()
+        .contains("cloudflare_settings aren't configured"));
+    let delay = retrying.run_at - chrono::Utc::now();
+    assert!(delay > chrono::Duration::seconds(50), "{:?}", delay);
+
+    sqlx::query("UPDATE jobs SET run_at = now() WHERE id = $1")
+        .bind(job.id)
+        .execute(&*db.repo.db_pool)
+        .await
+        .unwrap();
+    let settings = JobSettings {
+        backoff_base_secs: 0,
+        ..settings
+    };
+    let dead = run_worker_until(context, settings, &job, is_finished).await;
+    assert_eq!(dead.status, "dead");
+    assert_eq!(dead.attempts, 3);
+    db.remove().await;
+}
+
+/// What the fake Cloudflare Images API was asked to do.
+#[derive(Clone, Default)]
+struct FakeCloudflare {
+    images: Arc<std::sync::Mutex<Vec<uuid::Uuid>>>,
+    uploads: Arc<std::sync::atomic::AtomicUsize>,
+}
+
+async fn fake_cloudflare() -> (FakeCloudflare, CloudflareSettings) {
+    async fn upload(
+        axum::extract::State(fake): axum::extract::State<FakeCloudflare>,
+        headers: HeaderMap,
+    ) -> Json<serde_json::Value> {
+        assert_eq!(headers[header::AUTHORIZATION], "Bearer token");
+        let id = uuid::Uuid::new_v4();
+        fake.images.lock().unwrap().push(id);
+        fake.uploads
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        Json(serde_json::json!({ "success": true, "errors": [], "result": { "id": id } }))
+    }
+    async fn delete(
+        axum::extract::State(fake): axum::extract::State<FakeCloudflare>,
+        axum::extract::Path(id): axum::extract::Path<uuid::Uuid>,
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        let mut images = fake.images.lock().unwrap();
+        match images.iter().position(|x| *x == id) {
+            Some(index) => {
+                images.remove(index);
+                (StatusCode::OK, Json(serde_json::json!({ "success": true })))
+            }
+            None => (
+                StatusCode::NOT_FOUND,
+                Json(serde_json::json!({
+                    "success": false,
+                    "errors": [{ "code": 5404, "message": "Image not found" }],
+                })),
+            ),
+        }
+    }
+
+    let fake = FakeCloudflare::default();
+    let router = Router::new()
+        .route("/accounts/account/images/v1", post(upload))
+        .route(
+            "/accounts/account/images/v1/:id",
+            axum::routing::delete(delete),
+        )
+        .with_state(fake.clone());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let settings = CloudflareSettings {
+        api_url: format!("http://{}", listener.local_addr().unwrap()),
+        account_id: "account".into(),
+        api_token: Secret::new("token"),
+    };
+    tokio::spawn(async move { axum::serve(listener, router).await });
+    (fake, settings)
+}
+
+#[tokio::test]
+async fn cloudflare_jobs_upload_once_and_remove_deleted_photos() {
+    let db = ScratchDatabase::new().await;
+    let root = std::env::temp_dir().join(format!("cloudflare-{}", uuid::Uuid::new_v4()));
+    let storage = PhotoStorage::new(&root);
+    storage.put("geyser.jpg", b"geyser").await.unwrap();
+    let photo_id = add_legacy_photo(&db.repo, "geyser.jpg").await;
+    db.repo
+        .set_photo_content_hash(photo_id, &content_hash(b"geyser"))
+        .await
+        .unwrap();
+    let (fake, settings) = fake_cloudflare().await;
+    let context = JobContext {
+        cloudflare: Some(CloudflareImages::new(HttpClient::default(), settings)),
+        ..job_context(&db.repo, storage)
+    };
+    let jobs = JobQueue::new(db.repo.clone(), &JobSettings::default()).with_cloudflare(true);
+    let run = |job: Job| {
+        let (jobs, context) = (jobs.clone(), context.clone());
+        async move {
+            let job = jobs.enqueue(job).await.unwrap().unwrap();
+            run_worker_until(context, quick_job_settings(), &job, is_finished).await
+        }
+    };
+
+    let synced = run(Job::SyncCloudflare { photo_id }).await;
+    assert_eq!(synced.status, "completed");
+    let images = fake.images.lock().unwrap().clone();
+    assert_eq!(images.len(), 1);
+    assert_eq!(synced.result.unwrap()["resource_id"], images[0].to_string());
+    assert_eq!(
+        db.repo.get_cloudflare_resource(photo_id).await.unwrap(),
+        Some(images[0])
+    );
+    // a photo that is already there isn't uploaded again
+    assert_eq!(jobs.enqueue_unprocessed_photos().await.unwrap(), 0);
+    assert_eq!(
+        run(Job::SyncCloudflare { photo_id }).await.status,
+        "completed"
+    );
+    assert_eq!(fake.uploads.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    let resources = db.repo.delete_photo(photo_id).await.unwrap();
+    assert_eq!(resources, images);
+    for _ in 0..2 {
+        // the second time the image is already gone
+        let purged = run(Job::PurgeCloudflare {
+            resource_id: resources[0],
+        })
+        .await;
+        assert_eq!(purged.status, "completed");
+    }
+    assert!(fake.images.lock().unwrap().is_empty());
+
+    std::fs::remove_dir_all(root).unwrap();
+    db.remove().await;
+}
+
+fn photos_cache_key(uri: &str) -> Option<String> {
+    let query = Query::<PhotosQuery>::try_from_uri(&uri.parse().unwrap()).unwrap();
+    GeoFilter::try_from(&query.0)
+        .unwrap()
+        .cache_key(query.category_id)
+}
+
+#[test]
+fn photo_queries_that_filter_alike_share_a_cache_key() {
+    let key = photos_cache_key("/photos?category_id=3").unwrap();
+    for uri in [
+        "/photos?category_id=03",
+        "/photos?category_id=3&utm_source=mail",
+    ] {
+        assert_eq!(photos_cache_key(uri).unwrap(), key, "{}", uri);
+    }
+    assert_ne!(photos_cache_key("/photos").unwrap(), key);
+    assert_ne!(photos_cache_key("/photos?category_id=4").unwrap(), key);
+    // coordinates would give every client a key of its own
+    for uri in [
+        "/photos?near=44.5,-110.8",
+        "/photos?category_id=3&near=44.5,-110.8&radius=5",
+        "/photos?bbox=-114,36,-112,38",
+    ] {
+        assert_eq!(photos_cache_key(uri), None, "{}", uri);
+    }
+}
+
+#[tokio::test]
+async fn photos_of_unknown_categories_are_not_cached() {
+    let redis = redis::Client::open("redis://127.0.0.1:6379").unwrap();
+    if redis.get_multiplexed_async_connection().await.is_err() {
+        eprintln!("skipping, Redis isn't reachable");
+        return;
+    }
+    let db = ScratchDatabase::new().await;
+    let category = db.repo.add_category("Canyons".into()).await.unwrap();
+    let state = state_with_repo(db.repo.clone(), CacheSettings::default());
+    // other scratch databases have had categories with the same id
+    state.cache.invalidate(&[CacheTag::Categories]).await;
+    let app = app(SwaggerUi::new("/swagger-ui"), state);
+    let x_cache = |uri: String| {
+        let app = app.clone();
+        async move {
+            let resp = app.oneshot(get_request(&uri, &[])).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK, "{}", uri);
+            resp.headers()["x-cache"].to_str().unwrap().to_owned()
+        }
+    };
+
+    let known = format!("/api/v0/photos?category_id={}", category.id);
+    assert_eq!(x_cache(known.clone()).await, "MISS");
+    assert_eq!(x_cache(known).await, "HIT");
+    let unknown = "/api/v0/photos?category_id=999999".to_string();
+    assert_eq!(x_cache(unknown.clone()).await, "BYPASS");
+    assert_eq!(x_cache(unknown).await, "BYPASS");
+
+    db.remove().await;
+}
+
+#[test]
+fn geo_filters_reject_what_they_cannot_search() {
+    let parse = |uri: &str| {
+        let query = Query::<PhotosQuery>::try_from_uri(&uri.parse().unwrap()).unwrap();
+        GeoFilter::try_from(&query.0)
+    };
+    for uri in [
+        "/photos?bbox=-114,36,-112,38",
+        // crossing the antimeridian
+        "/photos?bbox=170,-20,-170,-10",
+        "/photos?near=-90,180",
+        "/photos?near=44.5,-110.8&radius=0.5",
+    ] {
+        assert!(parse(uri).is_ok(), "{}", uri);
+    }
+    for uri in [
+        "/photos?bbox=-114,36,-112",
+        "/photos?bbox=-114,36,-112,38,1",
+        "/photos?bbox=west,36,-112,38",
+        "/photos?bbox=-114,38,-112,36",
+        "/photos?bbox=-114,-91,-112,38",
+        "/photos?bbox=-181,36,-112,38",
+        "/photos?near=44.5",
+        "/photos?near=91,0",
+        "/photos?near=-90.5,0",
+        "/photos?near=0,180.5",
+        "/photos?near=0,-181",
+        "/photos?near=44.5,-110.8&radius=0",
+        "/photos?near=44.5,-110.8&radius=-5",
+        "/photos?near=44.5,-110.8&radius=NaN",
+        "/photos?near=44.5,-110.8&radius=inf",
+        "/photos?radius=5",
+        "/photos?bbox=-114,36,-112,38&radius=5",
+    ] {
+        match parse(uri) {
+            Err((status, _)) => assert_eq!(status, StatusCode::BAD_REQUEST, "{}", uri),
+            Ok(filter) => panic!("{} parsed as {:?}", uri, filter),
+        }
+    }
+}
+
+#[tokio::test]
+async fn photo_updates_clear_coordinates_set_to_null() {
+    let db = ScratchDatabase::new().await;
+    let id = add_legacy_photo(&db.repo, "delicate-arch.jpg").await;
+    let app = state_with_repo(db.repo.clone(), CacheSettings::default());
+    let put = |body: serde_json::Value| {
+        let user = User {
+            email: "test@example.com".into(),
+            sub: "test".into(),
+        };
+        put_photo(
+            State(app.clone()),
+            Path(id),
+            user,
+            Json(serde_json::from_value(body).unwrap()),
+        )
+    };
+    let coordinates = || async { db.repo.get_photo(id).await.unwrap().coordinates() };
+
+    put(serde_json::json!({ "latitude": 38.74, "longitude": -109.5 }))
+        .await
+        .unwrap();
+    let arch = Some(Coordinates::new(38.74, -109.5).unwrap());
+    assert_eq!(coordinates().await, arch);
+    // leaving them out keeps them
+    put(serde_json::json!({ "title": "Delicate Arch" }))
+        .await
+        .unwrap();
+    assert_eq!(coordinates().await, arch);
+    for body in [
+        serde_json::json!({ "latitude": null }),
+        serde_json::json!({ "latitude": null, "longitude": -109.5 }),
+        serde_json::json!({ "latitude": 38.74 }),
+        serde_json::json!({ "latitude": 95.0, "longitude": -109.5 }),
+    ] {
+        let Err((status, _)) = put(body.clone()).await else {
+            panic!("{} was accepted", body);
+        };
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    }
+    assert_eq!(coordinates().await, arch);
+    put(serde_json::json!({ "latitude": null, "longitude": null }))
+        .await
+        .unwrap();
+    assert_eq!(coordinates().await, None);
+
+    db.remove().await;
+}
+
+async fn add_photo_taken_at(repo: &PhotoRepository, location_taken: &str) -> i32 {
+    repo.add_photo(NewPhoto {
+        title: location_taken.into(),
+        filename: format!("{}.jpg", uuid::Uuid::new_v4()),
+        location_taken: location_taken.into(),
+        date_taken: chrono::NaiveDate::from_ymd_opt(2023, 7, 7).unwrap(),
+        coordinates: None,
+        location_id: None,
+        perceptual_hash: None,
+        content_sha256: None,
+    })
+    .await
+    .unwrap()
+    .id
+}
+
+async fn json_body(resp: axum::response::Response) -> serde_json::Value {
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn assigning_locations_merges_spellings_and_skips_placed_photos() {
+    let db = ScratchDatabase::new().await;
+    let duluth = add_photo_taken_at(&db.repo, "Duluth, MN").await;
+    let lowercase = add_photo_taken_at(&db.repo, "duluth, Minnesota").await;
+    let studio = add_photo_taken_at(&db.repo, "Studio").await;
+    let location_id = |id: i32| {
+        let repo = db.repo.clone();
+        async move { repo.get_photo(id).await.unwrap().location_id }
+    };
+
+    let assigned = db
+        .repo
+        .assign_locations("United States", None)
+        .await
+        .unwrap();
+    assert_eq!(assigned, 2);
+    let city = location_id(duluth).await.unwrap();
+    assert_eq!(location_id(lowercase).await, Some(city));
+    // nowhere to put it on the map
+    assert_eq!(location_id(studio).await, None);
+    let locations = db.repo.get_location_index().await.unwrap();
+    let city = locations.view_model(city).unwrap();
+    assert_eq!((city.name.as_str(), city.kind.as_str()), ("Duluth", "city"));
+    let state = city.parent.unwrap();
+    assert_eq!(
+        (state.name.as_str(), state.kind.as_str()),
+        ("Minnesota", "state")
+    );
+    let country = state.parent.unwrap();
+    assert_eq!(
+        (country.name.as_str(), country.kind.as_str()),
+        ("United States", "country")
+    );
+    assert!(country.parent.is_none());
+
+    // only the given photo, and only once
+    let ely = add_photo_taken_at(&db.repo, "Ely, MN").await;
+    let other = add_photo_taken_at(&db.repo, "Ely, MN").await;
+    let assign = |photo_id| db.repo.assign_locations("United States", photo_id);
+    assert_eq!(assign(Some(ely)).await.unwrap(), 1);
+    assert_eq!(location_id(other).await, None);
+    assert_eq!(assign(Some(ely)).await.unwrap(), 0);
+    assert_eq!(assign(None).await.unwrap(), 1);
+    assert_eq!(location_id(other).await, location_id(ely).await);
+    assert_eq!(assign(None).await.unwrap(), 0);
+
+    db.remove().await;
+}
+
+#[tokio::test]
+async fn deploying_places_the_seeded_photos_into_locations() {
+    let db = ScratchDatabase::seeded().await;
+    let job_id: i64 =
+        sqlx::query_scalar("SELECT id FROM jobs WHERE dedupe_key = 'assign_locations'")
+            .fetch_one(&*db.repo.db_pool)
+            .await
+            .unwrap();
+    let job = db.repo.get_job(job_id).await.unwrap().unwrap();
+    let root = std::env::temp_dir().join(format!("locations-{}", uuid::Uuid::new_v4()));
+    let job = run_job(&db.repo, PhotoStorage::new(&root), &job).await;
+    assert_eq!(job.status, "completed", "{:?}", job.last_error);
+    let cache = CacheSettings {
+        enabled: false,
+        ..Default::default()
+    };
+    let app = app(
+        SwaggerUi::new("/swagger-ui"),
+        state_with_repo(db.repo.clone(), cache),
+    );
+
+    let resp = app
+        .clone()
+        .oneshot(get_request("/api/v0/locations", &[]))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let locations = json_body(resp).await;
+    let locations = locations.as_array().unwrap();
+    let named = |name: &str| -> Vec<&serde_json::Value> {
+        locations
+            .iter()
+            .filter(|x| x["name"].as_str().unwrap().eq_ignore_ascii_case(name))
+            .collect()
+    };
+    let zion = named("Zion National Park");
+    assert_eq!(zion.len(), 1);
+    let zion = zion[0];
+    assert_eq!(zion["kind"], "park");
+    assert_eq!(zion["parent"]["name"], "Utah");
+    assert_eq!(zion["parent"]["kind"], "state");
+    assert_eq!(zion["parent"]["parent"]["name"], "United States");
+    assert_eq!(zion["parent"]["parent"]["kind"], "country");
+    // spelled with and without a comma before the park
+    assert_eq!(named("The Narrows").len(), 1);
+    assert_eq!(named("Minnesota").len(), 1);
+    assert!(named("Mn").is_empty());
+
+    let uri = format!("/api/v0/locations/{}/photos", zion["id"]);
+    let resp = app.clone().oneshot(get_request(&uri, &[])).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let photos = json_body(resp).await;
+    let photos = photos.as_array().unwrap();
+    let ids: Vec<i64> = photos.iter().map(|x| x["id"].as_i64().unwrap()).collect();
+    // both diptych halves, filed under The Narrows
+    assert!(ids.contains(&1) && ids.contains(&2), "{:?}", ids);
+    for photo in photos {
+        let mut location = &photo["location"];
+        while location["kind"] != "park" {
+            location = &location["parent"];
+        }
+        assert_eq!(location["id"], zion["id"]);
+    }
+
+    let resp = app
+        .oneshot(get_request("/api/v0/locations/999999/photos", &[]))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    db.remove().await;
+}
+
+#[tokio::test]
+async fn photo_locations_must_exist_and_clear_when_set_to_null() {
+    let db = ScratchDatabase::new().await;
+    let arches = add_photo_taken_at(&db.repo, "Arches National Park, Utah").await;
+    db.repo
+        .assign_locations("United States", None)
+        .await
+        .unwrap();
+    let park = db
+        .repo
+        .get_photo(arches)
+        .await
+        .unwrap()
+        .location_id
+        .unwrap();
+    let id = add_legacy_photo(&db.repo, "delicate-arch.jpg").await;
+    let root = std::env::temp_dir().join(format!("locations-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&root).unwrap();
+    let cache = CacheSettings {
+        enabled: false,
+        ..Default::default()
+    };
+    let mut app = state_with_repo(db.repo.clone(), cache);
+    app.storage = PhotoStorage::new(&root);
+    let put = |body: serde_json::Value| {
+        let user = User {
+            email: "test@example.com".into(),
+            sub: "test".into(),
+        };
+        put_photo(
+            State(app.clone()),
+            Path(id),
+            user,
+            Json(serde_json::from_value(body).unwrap()),
+        )
+    };
+    let location_id = || async { db.repo.get_photo(id).await.unwrap().location_id };
+
+    let Err((status, _)) = put(serde_json::json!({ "location_id": 999999 })).await else {
+        panic!("an unknown location was accepted");
+    };
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    put(serde_json::json!({ "location_id": park }))
+        .await
+        .unwrap();
+    assert_eq!(location_id().await, Some(park));
+    // leaving it out keeps it
+    put(serde_json::json!({ "title": "Delicate Arch" }))
+        .await
+        .unwrap();
+    assert_eq!(location_id().await, Some(park));
+    put(serde_json::json!({ "location_id": null }))
+        .await
+        .unwrap();
+    assert_eq!(location_id().await, None);
+
+    let status = upload_with(&app, "arch.png", &[("location_id", "999999")]).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    // placed by a job from its location_taken
+    let status = upload_with(
+        &app,
+        "arch.png",
+        &[(
+            "location_taken",
+            "Delicate Arch, Arches National Park, Utah",
+        )],
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let uploaded: i32 = sqlx::query_scalar("SELECT id FROM photos WHERE filename = 'arch.png'")
+        .fetch_one(&*db.repo.db_pool)
+        .await
+        .unwrap();
+    let job_id: i64 = sqlx::query_scalar("SELECT id FROM jobs WHERE dedupe_key = $1")
+        .bind(format!("assign_locations:{}", uploaded))
+        .fetch_one(&*db.repo.db_pool)
+        .await
+        .unwrap();
+    let job = db.repo.get_job(job_id).await.unwrap().unwrap();
+    let job = run_job(&db.repo, PhotoStorage::new(&root), &job).await;
+    assert_eq!(job.status, "completed", "{:?}", job.last_error);
+    let place = db.repo.get_photo(uploaded).await.unwrap().location_id;
+    let locations = db.repo.get_location_index().await.unwrap();
+    let place = locations.view_model(place.unwrap()).unwrap();
+    assert_eq!(place.name, "Delicate Arch");
+    assert_eq!(place.parent.unwrap().id, park);
+
+    db.remove().await;
+}
+
+#[derive(serde::Serialize)]
+struct Cached(u32);
+
+#[tokio::test]
+async fn invalidating_while_loading_drops_what_was_loaded() {
+    let redis = redis::Client::open("redis://127.0.0.1:6379").unwrap();
+    if redis.get_multiplexed_async_connection().await.is_err() {
+        eprintln!("skipping, Redis isn't reachable");
+        return;
+    }
+    let cache = ResponseCache::new(redis, CacheSettings::default());
+    let key = uuid::Uuid::new_v4().to_string();
+    let get = |value: u32, invalidate: bool| {
+        let cache = cache.clone();
+        let key = key.clone();
+        async move {
+            let resp = cache
+                .json("test", &key, &[CacheTag::Photos], async {
+                    if invalidate {
+                        // as a write landing while this request loads
+                        cache.invalidate(&[CacheTag::Photos]).await;
+                    }
+                    Ok(vec![Cached(value)])
+                })
+                .await
+                .unwrap();
+            let status = resp.headers()["x-cache"].to_str().unwrap().to_owned();
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        }
+    };
+
+    assert_eq!(get(1, true).await, ("MISS".into(), "[1]".into()));
+    // what the first request loaded predates the write
+    assert_eq!(get(2, false).await, ("MISS".into(), "[2]".into()));
+    assert_eq!(get(3, false).await, ("HIT".into(), "[2]".into()));
+    // other tags leave it alone
+    cache.invalidate(&[CacheTag::Categories]).await;
+    assert_eq!(get(4, false).await, ("HIT".into(), "[2]".into()));
+    cache.invalidate(&[CacheTag::Photos]).await;
+    assert_eq!(get(5, false).await, ("MISS".into(), "[5]".into()));
+}
+
+fn get_request(uri: &str, headers: &[(header::HeaderName, &str)]) -> Request<Body> {
+    let mut request = Request::builder().uri(uri);
+    for (name, value) in headers {
+        request = request.header(name, *value);
+    }
+    request.body(Body::empty()).unwrap()
+}
+
+#[tokio::test]
+async fn listing_answers_not_modified_until_it_changes() {
+    let db = ScratchDatabase::new().await;
+    db.repo.add_category("Deserts".into()).await.unwrap();
+    let settings = CacheSettings {
+        enabled: false,
+        ..Default::default()
+    };
+    let app = app(
+        SwaggerUi::new("/swagger-ui"),
+        state_with_repo(db.repo.clone(), settings.clone()),
+    );
+    let uri = "/api/v0/categories";
+
+    let resp = app.clone().oneshot(get_request(uri, &[])).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers()[header::CACHE_CONTROL],
+        settings.cache_control().as_str()
+    );
+    let etag = resp.headers()[header::ETAG].to_str().unwrap().to_owned();
+    // without Redis there is no record of when the categories changed
+    assert!(!resp.headers().contains_key(header::LAST_MODIFIED));
+
+    let resp = app
+        .clone()
+        .oneshot(get_request(uri, &[(header::IF_NONE_MATCH, &etag)]))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(resp.headers()[header::ETAG], etag.as_str());
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert!(body.is_empty());
+
+    // nothing to compare a date against
+    let future = "Fri, 01 Jan 2100 00:00:00 GMT";
+    let resp = app
+        .clone()
+        .oneshot(get_request(uri, &[(header::IF_MODIFIED_SINCE, future)]))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    db.repo.add_category("Forests".into()).await.unwrap();
+    let resp = app
+        .oneshot(get_request(uri, &[(header::IF_NONE_MATCH, &etag)]))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_ne!(resp.headers()[header::ETAG], etag.as_str());
+
+    db.remove().await;
+}
+
+#[tokio::test]
+async fn last_modified_is_when_a_tag_last_changed() {
+    let redis = redis::Client::open("redis://127.0.0.1:6379").unwrap();
+    if redis.get_multiplexed_async_connection().await.is_err() {
+        eprintln!("skipping, Redis isn't reachable");
+        return;
+    }
+    let cache = ResponseCache::new(redis, CacheSettings::default());
+    let key = uuid::Uuid::new_v4().to_string();
+    let app = Router::new()
+        .route(
+            "/listing",
+            get({
+                let cache = cache.clone();
+                move || {
+                    let (cache, key) = (cache.clone(), key.clone());
+                    async move {
+                        cache
+                            .json("test", &key, &[CacheTag::Locations], async {
+                                Ok(vec![Cached(1)])
+                            })
+                            .await
+                    }
+                }
+            }),
+        )
+        .layer(middleware::from_fn(conditional_get));
+    let last_modified = |resp: &axum::response::Response| {
+        let header = resp.headers().typed_get::<LastModified>().unwrap();
+        std::time::SystemTime::from(header)
+    };
+
+    cache.invalidate(&[CacheTag::Locations]).await;
+    let resp = app
+        .clone()
+        .oneshot(get_request("/listing", &[]))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let before = last_modified(&resp);
+    let since = resp.headers()[header::LAST_MODIFIED]
+        .to_str()
+        .unwrap()
+        .to_owned();
+    let resp = app
+        .clone()
+        .oneshot(get_request(
+            "/listing",
+            &[(header::IF_MODIFIED_SINCE, &since)],
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(last_modified(&resp), before);
+
+    // as a delete would, which leaves no updated_at behind; the header
+    // only has whole seconds
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    cache.invalidate(&[CacheTag::Locations]).await;
+    let resp = app
+        .oneshot(get_request(
+            "/listing",
+            &[(header::IF_MODIFIED_SINCE, &since)],
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(last_modified(&resp) > before);
+}
+
+#[test]
+fn request_metrics_are_labelled_by_route_and_survive_cancellation() {
+    let recorder = build_recorder().unwrap();
+    let handle = recorder.handle();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    // the recorder only applies to this thread, so other tests don't count
+    metrics::with_local_recorder(&recorder, || {
+        runtime.block_on(async {
+            let app = Router::new()
+                .route("/photos/:id", get(|| async { "photo" }))
+                .route("/hang", get(std::future::pending::<&str>))
+                .layer(middleware::from_fn(track_requests));
+            let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+            for id in [1, 2] {
+                let resp = app
+                    .clone()
+                    .oneshot(get(&format!("/photos/{}", id)))
+                    .await
+                    .unwrap();
+                assert_eq!(resp.status(), StatusCode::OK);
+            }
+            // as when the client goes away mid-request
+            let hung = tokio::time::timeout(Duration::from_millis(50), app.oneshot(get("/hang")));
+            assert!(hung.await.is_err());
+        })
+    });
+
+    let rendered = handle.render();
+    let line = |prefix: &str| {
+        rendered
+            .lines()
+            .find(|x| x.starts_with(prefix))
+            .unwrap_or_else(|| panic!("no {} in\n{}", prefix, rendered))
+            .to_owned()
+    };
+    assert!(
+        line(r#"http_requests_total{method="GET",path="/photos/:id",status="200"}"#)
+            .ends_with(" 2")
+    );
+    assert!(line(
+        r#"http_request_duration_seconds_bucket{method="GET",path="/photos/:id",status="200",le="0.005"}"#
+    )
+    .ends_with(" 2"));
+    assert_eq!(line("http_requests_in_flight"), "http_requests_in_flight 0");
+    assert!(!rendered.contains(r#"path="/hang",status"#));
+}
+
+fn database_settings(yaml: &str) -> Result<DatabaseSettings, config::ConfigError> {
+    config::Config::builder()
+        .add_source(config::File::from_str(yaml, config::FileFormat::Yaml))
+        .build()?
+        .try_deserialize()
+}
+
+#[test]
+fn database_connections_verify_the_server_unless_told_otherwise() {
+    let settings = database_settings(
+        "host: db.internal\nport: 5432\ndatabase: enchanted\nuser: api\napplication_name: api",
+    )
+    .unwrap();
+    assert_eq!(settings.ssl_mode, SslMode::VerifyCa);
+    assert_eq!(
+        settings.ssl_root_cert.as_deref(),
+        Some(std::path::Path::new("./certs/ca.crt"))
+    );
+    let options = PgConnectOptions::try_from(&settings).unwrap();
+    assert!(matches!(options.get_ssl_mode(), PgSslMode::VerifyCa));
+
+    // a URL's own sslmode doesn't weaken it either
+    let settings = database_settings(
+        "url: postgres://api@db.internal/photos?sslmode=disable\nhost: unused\nport: 5432\n\
+         database: unused\nuser: unused\napplication_name: api\nssl_mode: verify_full",
+    )
+    .unwrap();
+    let options = PgConnectOptions::try_from(&settings).unwrap();
+    assert_eq!(options.get_host(), "db.internal");
+    assert_eq!(options.get_database(), Some("photos"));
+    assert!(matches!(options.get_ssl_mode(), PgSslMode::VerifyFull));
+}
+
+#[test]
+fn database_settings_are_required() {
+    let e = database_settings("ssl_mode: disable").unwrap_err();
+    assert!(e.to_string().contains("host"), "{}", e);
+
+    let e = config::Config::builder()
+        .add_source(config::File::from_str(
+            "redis_url: redis://127.0.0.1:6379",
+            config::FileFormat::Yaml,
+        ))
+        .build()
+        .unwrap()
+        .try_deserialize::<Settings>()
+        .unwrap_err();
+    assert!(e.to_string().contains("database_settings"), "{}", e);
+}